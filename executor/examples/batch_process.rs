@@ -9,14 +9,14 @@ async fn main() -> anyhow::Result<()> {
     let block_number: u64 = env_block_number.parse().unwrap();
     let task = stdenv::var("TASK").unwrap_or("lr".to_string());
     let task_id = "0";
-    // base_dir is eigen_prover
+    // base_dir is the basedir of the prover, the chunks are written by its ProofLayout
     let base_dir = stdenv::var("BASEDIR").unwrap_or("/tmp".to_string());
     let url = stdenv::var("URL").unwrap_or(String::from("http://localhost:8123"));
     let chain_id = stdenv::var("CHAINID").unwrap_or(String::from("1"));
     let suite_json = stdenv::var("SUITE_JSON").unwrap_or(String::from("/tmp/suite.json"));
     let client = Provider::<Http>::try_from(url).unwrap();
     let client = Arc::new(client);
-    let (_res, json_string, cnt_chunks) = batch_process(
+    let (_res, json_string, batch_digest, cnt_chunks) = batch_process(
         client,
         block_number,
         chain_id.parse::<u64>().unwrap(),
@@ -26,6 +26,9 @@ async fn main() -> anyhow::Result<()> {
     )
     .await;
     std::fs::write(suite_json, json_string).expect("Unable to write file");
-    println!("Generated {} chunks", cnt_chunks);
+    println!(
+        "Generated {} chunks, the digest of the batch data: {}",
+        cnt_chunks, batch_digest
+    );
     Ok(())
 }
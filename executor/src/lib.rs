@@ -6,6 +6,8 @@ use ethers_core::types::{
 };
 use ethers_providers::{Http, Middleware, Provider};
 use powdr::number::FieldElement;
+use proof_layout::blob_store::BlobStore;
+use proof_layout::layout::ProofLayout;
 use revm::{
     db::{CacheDB, EthersDB, PlainAccount, StateBuilder},
     inspector_handle_register,
@@ -17,7 +19,6 @@ use revm::{
 };
 use ruint::Uint;
 use std::collections::BTreeMap;
-use std::path::Path;
 use std::sync::Arc;
 use std::{fs, io::Write};
 use zkvm::zkvm_generate_chunks;
@@ -156,8 +157,10 @@ fn fill_test_env(
     test_env
 }
 
+/// Write the chunks into the chunks dir of the ProofLayout under `base_dir`, where the prover reads them
 fn generate_chunks(task: &str, task_id: &str, base_dir: &str, json_string: &String) -> usize {
-    let output_path = format!("{}/{}/{}", base_dir, task_id, task);
+    let layout = ProofLayout::new(base_dir);
+    let output_path = layout.chunks_dir(task_id, task);
     log::debug!("output_path: {}", output_path);
 
     let project_root_path = project_root::get_project_root()
@@ -174,7 +177,7 @@ fn generate_chunks(task: &str, task_id: &str, base_dir: &str, json_string: &Stri
     log::debug!("Generated {} chunks", cnt_chunks);
     // save the chunks
     let bi_files: Vec<_> = (0..cnt_chunks)
-        .map(|i| Path::new(output_path.as_str()).join(format!("{task}_chunks_{i}.data")))
+        .map(|i| layout.chunk_input(task_id, task, &i.to_string()))
         .collect();
     log::debug!("bi_files: {:#?}", bi_files);
     bootloader_inputs
@@ -336,6 +339,8 @@ async fn fill_test_pre(
     test_pre
 }
 
+/// Execute the block, write its chunks and deposit its batch data into the BlobStore under `base_dir`.
+/// Return the results of the transactions, the batch data, the digest of the batch data and the number of chunks.
 pub async fn batch_process(
     client: Arc<Provider<Http>>,
     block_number: u64,
@@ -343,7 +348,7 @@ pub async fn batch_process(
     task: &str,
     task_id: &str,
     base_dir: &str,
-) -> (ExecResult, String, usize) {
+) -> (ExecResult, String, String, usize) {
     //let client = Provider::<Http>::try_from(url).unwrap();
     //let client = Arc::new(client);
     let block: ethers_core::types::Block<ethers_core::types::Transaction> =
@@ -470,7 +475,10 @@ pub async fn batch_process(
     log::debug!("test_unit: {}", json_string);
 
     let cnt_chunks = generate_chunks(task, task_id, base_dir, &json_string);
-    (Ok(all_result), json_string, cnt_chunks)
+    let batch_digest = BlobStore::new(base_dir)
+        .put(json_string.as_bytes())
+        .unwrap_or_else(|e| panic!("Failed to save the batch data: {:?}", e));
+    (Ok(all_result), json_string, batch_digest, cnt_chunks)
 }
 
 #[cfg(test)]
@@ -489,7 +497,8 @@ mod tests {
         let suite_json = fs::read_to_string(test_file).unwrap();
         let task: String = stdenv::var("TASK").unwrap_or(String::from("evm"));
         let task_id = "0";
-        let output_path = format!("../prover/data/proof/{}/{}", task_id, task);
+        let layout = ProofLayout::new("../prover/data");
+        let output_path = layout.chunks_dir(task_id, &task);
         let workspace = format!("program/{}", task);
         let bootloader_inputs =
             zkvm_generate_chunks(workspace.as_str(), &suite_json, output_path.as_str()).unwrap();
@@ -497,7 +506,7 @@ mod tests {
         log::debug!("Generated {} chunks", cnt_chunks);
         // save the chunks
         let bi_files: Vec<_> = (0..cnt_chunks)
-            .map(|i| Path::new(output_path.as_str()).join(format!("{task}_chunks_{i}.data")))
+            .map(|i| layout.chunk_input(task_id, &task, &i.to_string()))
             .collect();
         log::debug!("bi_files: {:#?}", bi_files);
         bootloader_inputs
@@ -0,0 +1,124 @@
+use crate::layout::ProofLayout;
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// The content addressed store of the batch data under `{basedir}/blobs`.
+/// The executor deposits the batch data once, the stages, the checkpoints and the RPCs
+/// carry its digest, and the batch prover loads it by the digest.
+/// The workers share the basedir with the executor, as they read the chunks written by it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlobStore {
+    pub basedir: String,
+}
+
+impl BlobStore {
+    pub fn new(basedir: &str) -> Self {
+        BlobStore {
+            basedir: basedir.to_string(),
+        }
+    }
+
+    /// The sha256 of the data in hex
+    pub fn digest(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    /// Return true if it's a digest returned by `put`
+    pub fn is_digest(digest: &str) -> bool {
+        digest.len() == 64
+            && digest
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    }
+
+    pub fn path(&self, digest: &str) -> PathBuf {
+        Path::new(&self.basedir).join(ProofLayout::blob_path(digest))
+    }
+
+    pub fn contains(&self, digest: &str) -> bool {
+        Self::is_digest(digest) && self.path(digest).is_file()
+    }
+
+    /// Save the data unless it's saved already, and return its digest
+    pub fn put(&self, data: &[u8]) -> Result<String> {
+        let digest = Self::digest(data);
+        let path = self.path(&digest);
+        if !path.exists() {
+            let dir = path.parent().unwrap_or(Path::new(&self.basedir));
+            std::fs::create_dir_all(dir)?;
+            // write then rename, so it's never half written
+            let tmp = dir.join(format!("{}.{}.tmp", digest, uuid::Uuid::new_v4()));
+            std::fs::write(&tmp, data)?;
+            std::fs::rename(&tmp, &path)
+                .with_context(|| format!("save the blob {:?} failed", path))?;
+        }
+        Ok(digest)
+    }
+
+    /// Load the data of the digest, it bails if the blob is missing or corrupted
+    pub fn get(&self, digest: &str) -> Result<Vec<u8>> {
+        if !Self::is_digest(digest) {
+            bail!("invalid blob digest: {:?}", digest);
+        }
+        let path = self.path(digest);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                bail!("the blob {} is not found in {}", digest, self.basedir)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if Self::digest(&data) != digest {
+            bail!("the blob {:?} is corrupted", path);
+        }
+        Ok(data)
+    }
+
+    /// Load the batch data of the digest, the empty digest is the empty batch data
+    pub fn get_batch_data(&self, digest: &str) -> Result<String> {
+        if digest.is_empty() {
+            return Ok(String::new());
+        }
+        Ok(String::from_utf8(self.get(digest)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_blob_store() {
+        let basedir = env::temp_dir().join(format!("blob_store_{}", Uuid::new_v4()));
+        let store = BlobStore::new(basedir.to_str().unwrap());
+
+        let digest = store.put(b"data").unwrap();
+        assert!(BlobStore::is_digest(&digest));
+        assert_eq!(digest, BlobStore::digest(b"data"));
+        assert!(store.contains(&digest));
+        assert_eq!(
+            store.path(&digest),
+            basedir.join(format!("blobs/{}/{}", &digest[..2], digest))
+        );
+        // the identical data is saved once
+        assert_eq!(store.put(b"data").unwrap(), digest);
+        let blobs = std::fs::read_dir(store.path(&digest).parent().unwrap()).unwrap();
+        assert_eq!(blobs.count(), 1);
+        assert_eq!(store.get_batch_data(&digest).unwrap(), "data");
+        assert_eq!(store.get_batch_data("").unwrap(), "");
+
+        let missing = BlobStore::digest(b"missing");
+        assert!(!store.contains(&missing));
+        assert!(store.get(&missing).is_err());
+        assert!(store.get("../../etc/passwd").is_err());
+        assert!(!BlobStore::is_digest(&digest.to_uppercase()));
+
+        std::fs::write(store.path(&digest), "corrupted").unwrap();
+        assert!(store.get(&digest).is_err());
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The curve of a proof, GL for the stark stages,
+/// BN128 or BLS12381 for the final stark and its groth16 proof.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum Curve {
+    GL,
+    #[default]
+    BN128,
+    BLS12381,
+}
+
+impl Curve {
+    /// The curves of the final proof
+    pub const SNARK_CURVES: [Curve; 2] = [Curve::BN128, Curve::BLS12381];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Curve::GL => "GL",
+            Curve::BN128 => "BN128",
+            Curve::BLS12381 => "BLS12381",
+        }
+    }
+
+    /// The prime of the circuits compiled by circom
+    pub fn prime(&self) -> &'static str {
+        match self {
+            Curve::GL => "goldilocks",
+            Curve::BN128 => "bn128",
+            Curve::BLS12381 => "bls12381",
+        }
+    }
+
+    /// Parse the curve of a final proof requested by a client, GL is rejected
+    pub fn parse_snark(name: &str) -> Result<Self> {
+        let curve: Curve = name.parse()?;
+        if !Self::SNARK_CURVES.contains(&curve) {
+            bail!(
+                "unsupported curve of the final proof: {}, it should be BN128 or BLS12381",
+                name
+            );
+        }
+        Ok(curve)
+    }
+}
+
+impl FromStr for Curve {
+    type Err = anyhow::Error;
+
+    /// The name is case insensitive
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_uppercase().as_str() {
+            "GL" => Ok(Curve::GL),
+            "BN128" => Ok(Curve::BN128),
+            "BLS12381" => Ok(Curve::BLS12381),
+            _ => bail!(
+                "unsupported curve: {}, it should be GL, BN128 or BLS12381",
+                name
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Curve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_curve() {
+        assert_eq!("BN128".parse::<Curve>().unwrap(), Curve::BN128);
+        assert_eq!("bls12381".parse::<Curve>().unwrap(), Curve::BLS12381);
+        assert_eq!(Curve::BLS12381.to_string(), "BLS12381");
+        assert_eq!(Curve::BLS12381.prime(), "bls12381");
+        assert!("BN254".parse::<Curve>().is_err());
+        assert!("".parse::<Curve>().is_err());
+
+        assert_eq!(Curve::parse_snark("BLS12381").unwrap(), Curve::BLS12381);
+        assert!(Curve::parse_snark("GL").is_err());
+        assert_eq!(
+            serde_json::to_string(&Curve::BLS12381).unwrap(),
+            r#""BLS12381""#
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use crate::curve::Curve;
+use std::path::{Path, PathBuf};
+
+/// The dir of the proofs under the basedir, one dir per task
+pub const PROOF_DIR: &str = "proof";
+/// The dir of the BlobStore under the basedir
+pub const BLOB_DIR: &str = "blobs";
+/// The block proof job in the task dir
+pub const JOB_FILE: &str = "job.json";
+/// The outputs of the final stage in its workdir
+pub const PROOF_FILE: &str = "proof.json";
+pub const PUBLIC_INPUT_FILE: &str = "public_input.json";
+pub const VERIFICATION_KEY_FILE: &str = "verification_key.json";
+pub const PROVING_KEY_FILE: &str = "g16.key";
+
+/// The files of a circuit named `{circuit}` in the workdir of a stage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitFile {
+    Circom,
+    R1cs,
+    Wasm,
+    Pil,
+    PilJson,
+    Const,
+    Exec,
+    Commit,
+    Zkin,
+}
+
+/// The directory scheme of the artifacts under the basedir,
+/// the executor, the provers, the task stores and the retention all build their paths by it:
+///
+/// ```text
+/// {basedir}/{task_name}/{name}.stark_struct.json       the stark structs of the program
+/// {basedir}/proof/{task_id}/job.json                   the block proof job
+/// {basedir}/proof/{task_id}/{task_name}/               the chunks written by the executor, the batch circuits
+/// {basedir}/proof/{task_id}/batch_proof_{chunk_id}/    the batch stage of the chunk
+/// {basedir}/proof/{id}_agg/agg_zkin.json               the joined input of the aggregate stage
+/// {basedir}/proof/{id}_agg/agg_proof/                  the aggregate stage, the setup of the final stage
+/// {basedir}/proof/{id}/snark_proof/                    the final stage
+/// {basedir}/blobs/{digest[..2]}/{digest}               the batch data deposited by the executor
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProofLayout {
+    pub basedir: String,
+}
+
+impl ProofLayout {
+    pub fn new(basedir: &str) -> Self {
+        ProofLayout {
+            basedir: basedir.to_string(),
+        }
+    }
+
+    /// The workdir of the batch stage, relative to the basedir
+    pub fn batch_path(task_id: &str, chunk_id: &str) -> String {
+        format!("{PROOF_DIR}/{task_id}/batch_proof_{chunk_id}")
+    }
+
+    /// The workdir of the aggregate stage `{id}_agg`, relative to the basedir
+    pub fn agg_path(task_id: &str) -> String {
+        format!("{PROOF_DIR}/{task_id}/agg_proof")
+    }
+
+    /// The workdir of the final stage, relative to the basedir
+    pub fn final_path(task_id: &str) -> String {
+        format!("{PROOF_DIR}/{task_id}/snark_proof")
+    }
+
+    /// The blob of the digest, relative to the basedir, the blobs are fanned out by the first byte
+    pub fn blob_path(digest: &str) -> String {
+        let fanout = digest.get(..2).unwrap_or(digest);
+        format!("{BLOB_DIR}/{fanout}/{digest}")
+    }
+
+    /// The path relative to the basedir
+    pub fn path(&self, relative: &str) -> String {
+        format!("{}/{}", self.basedir, relative)
+    }
+
+    pub fn proof_dir(&self) -> PathBuf {
+        Path::new(&self.basedir).join(PROOF_DIR)
+    }
+
+    pub fn task_dir(&self, task_id: &str) -> PathBuf {
+        self.proof_dir().join(task_id)
+    }
+
+    pub fn job_file(&self, task_id: &str) -> PathBuf {
+        self.task_dir(task_id).join(JOB_FILE)
+    }
+
+    /// A file of the circuit in the workdir `stage_path`
+    pub fn circuit_file(&self, stage_path: &str, circuit: &str, file: CircuitFile) -> String {
+        let dir = self.path(stage_path);
+        match file {
+            CircuitFile::Circom => format!("{dir}/{circuit}.circom"),
+            CircuitFile::R1cs => format!("{dir}/{circuit}.r1cs"),
+            CircuitFile::Wasm => format!("{dir}/{circuit}_js/{circuit}.wasm"),
+            CircuitFile::Pil => format!("{dir}/{circuit}.pil"),
+            CircuitFile::PilJson => format!("{dir}/{circuit}.pil.json"),
+            CircuitFile::Const => format!("{dir}/{circuit}.const"),
+            CircuitFile::Exec => format!("{dir}/{circuit}.exec"),
+            CircuitFile::Commit => format!("{dir}/{circuit}.cm"),
+            CircuitFile::Zkin => format!("{dir}/{circuit}.zkin.json"),
+        }
+    }
+
+    /// The stark struct `name` of the program, e.g. batch, c12 or final
+    pub fn stark_struct(&self, task_name: &str, name: &str) -> String {
+        format!("{}/{}/{}.stark_struct.json", self.basedir, task_name, name)
+    }
+
+    /// The stark struct of the final stark, its verification hash is on the curve of the snark,
+    /// the BN128 one keeps the name `final`, the others are `final.{prime}`
+    pub fn final_stark_struct(&self, task_name: &str, curve: Curve) -> String {
+        match curve {
+            Curve::BN128 => self.stark_struct(task_name, "final"),
+            _ => self.stark_struct(task_name, &format!("final.{}", curve.prime())),
+        }
+    }
+
+    /// The dir of the chunks written by the executor, the zkvm writes the batch circuits into it too
+    pub fn chunks_dir(&self, task_id: &str, task_name: &str) -> String {
+        format!("{}/{PROOF_DIR}/{}/{}", self.basedir, task_id, task_name)
+    }
+
+    /// The input of the bootloader of the chunk, written by the executor
+    pub fn chunk_input(&self, task_id: &str, task_name: &str, chunk_id: &str) -> String {
+        format!(
+            "{}/{}_chunks_{}.data",
+            self.chunks_dir(task_id, task_name),
+            task_name,
+            chunk_id
+        )
+    }
+
+    /// A file of the batch circuit of the chunk, the circom, const, commit and zkin are
+    /// written by the zkvm into the chunks dir, the others into the workdir of the batch stage
+    pub fn batch_circuit_file(
+        &self,
+        task_id: &str,
+        task_name: &str,
+        chunk_id: &str,
+        file: CircuitFile,
+    ) -> String {
+        let chunks_dir = self.chunks_dir(task_id, task_name);
+        let circuit = format!("{task_name}_chunk_{chunk_id}");
+        match file {
+            CircuitFile::Circom => format!("{chunks_dir}/{circuit}.circom"),
+            CircuitFile::Const => format!("{chunks_dir}/constants.bin"),
+            CircuitFile::Commit => format!("{chunks_dir}/{circuit}/commits.bin"),
+            CircuitFile::Zkin => format!("{chunks_dir}/{circuit}/{task_name}_proof.bin"),
+            _ => self.circuit_file(&Self::batch_path(task_id, chunk_id), &circuit, file),
+        }
+    }
+
+    /// The recursive1 zkin of the chunk, written by the batch stage
+    pub fn chunk_zkin(&self, task_id: &str, task_name: &str, chunk: usize) -> String {
+        self.circuit_file(
+            &Self::batch_path(task_id, &chunk.to_string()),
+            &format!("{task_name}.recursive1"),
+            CircuitFile::Zkin,
+        )
+    }
+
+    /// The zkin joined from the previous chunks and the chunk by the aggregate stage
+    pub fn joined_zkin(&self, task_id: &str, task_name: &str, chunk: usize) -> String {
+        self.circuit_file(
+            &Self::batch_path(task_id, &chunk.to_string()),
+            &format!("{task_name}_input.recursive1"),
+            CircuitFile::Zkin,
+        )
+    }
+
+    /// The input of the aggregate stage `{id}_agg`
+    pub fn agg_zkin(&self, task_id: &str) -> String {
+        format!("{}/{PROOF_DIR}/{}/agg_zkin.json", self.basedir, task_id)
+    }
+
+    /// A file of the final stage, e.g. PROOF_FILE
+    pub fn final_file(&self, task_id: &str, name: &str) -> String {
+        format!("{}/{}", self.path(&Self::final_path(task_id)), name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proof_layout() {
+        let layout = ProofLayout::new("/data");
+        assert_eq!(ProofLayout::batch_path("7", "1"), "proof/7/batch_proof_1");
+        assert_eq!(ProofLayout::agg_path("a_agg"), "proof/a_agg/agg_proof");
+        assert_eq!(ProofLayout::final_path("a"), "proof/a/snark_proof");
+        assert_eq!(
+            layout.chunk_input("7", "evm", "1"),
+            "/data/proof/7/evm/evm_chunks_1.data"
+        );
+        assert_eq!(
+            layout.batch_circuit_file("7", "evm", "1", CircuitFile::Commit),
+            "/data/proof/7/evm/evm_chunk_1/commits.bin"
+        );
+        assert_eq!(
+            layout.batch_circuit_file("7", "evm", "1", CircuitFile::Exec),
+            "/data/proof/7/batch_proof_1/evm_chunk_1.exec"
+        );
+        assert_eq!(
+            layout.final_stark_struct("evm", Curve::BN128),
+            "/data/evm/final.stark_struct.json"
+        );
+        assert_eq!(
+            layout.final_stark_struct("evm", Curve::BLS12381),
+            "/data/evm/final.bls12381.stark_struct.json"
+        );
+        assert_eq!(
+            layout.final_file("a", PROOF_FILE),
+            "/data/proof/a/snark_proof/proof.json"
+        );
+        assert_eq!(
+            layout.job_file("7"),
+            PathBuf::from("/data/proof/7/job.json")
+        );
+        assert_eq!(ProofLayout::blob_path("00ff"), "blobs/00/00ff");
+    }
+}
@@ -0,0 +1,5 @@
+//! The on-disk layout of the proof artifacts, shared by the executor and the prover
+//! without pulling in the provers.
+pub mod blob_store;
+pub mod curve;
+pub mod layout;
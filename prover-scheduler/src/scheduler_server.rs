@@ -1,18 +1,22 @@
 use anyhow::bail;
 use anyhow::Result;
 use prover::scheduler::{
-    AddServiceResult, Event, ProofResult, ResultStatus, TakeTaskResult, TaskResult,
+    AddServiceResult, Capabilities, Event, ProofResult, ResultStatus, ServiceNotice,
+    TakeTaskResult, TaskResult,
 };
 use scheduler_service::scheduler_service_server::SchedulerService;
 use scheduler_service::scheduler_service_server::SchedulerServiceServer;
 use scheduler_service::{
-    batch_prover_message, scheduler_message, BatchProofResult, BatchProverMessage, Registry,
-    SchedulerMessage,
+    batch_prover_message, scheduler_message, BatchProofResult, BatchProverMessage,
+    CancelBatchProofTask, Registry, RejectBatchProofResult, SchedulerMessage,
 };
 use scheduler_service::{BatchContextBytes, TakeBatchProofTaskResponse};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_stream::{Stream, StreamExt};
 use tonic::transport::Server;
 use tonic::{async_trait, Request, Response, Status, Streaming};
@@ -23,6 +27,13 @@ pub mod scheduler_service {
     tonic::include_proto!("scheduler.v1");
 }
 
+/// How often the batch prover sends the heartbeat to the scheduler
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// The batch prover is regarded as lost if nothing is received from it in the timeout,
+/// it's removed from the scheduler, and the task it's proving is requeued.
+/// The batch prover which predates the leases isn't checked, it only sends the results.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[allow(dead_code)]
 pub struct SchedulerServiceSVC {
     scheduler_sender: mpsc::Sender<Event>,
@@ -73,52 +84,134 @@ impl SchedulerService for SchedulerServiceSVC {
         let scheduler_sender = self.scheduler_sender.clone();
         let result_sender = self.result_sender.clone();
         let handle_clone = self.handler.clone();
+        // the scheduler sends the notices to the service by notice_tx
+        let (notice_tx, mut notice_rx) = mpsc::channel::<ServiceNotice>(10);
 
         tokio::spawn(async move {
+            let mut prover_id = String::new();
+            // the last time a message is received from the batch prover
+            let mut last_seen = Instant::now();
+            // the batch prover predating the leases sends no heartbeat, it's bounded by the stage deadline only
+            let mut legacy = false;
+            // the handler which prepares the reply, it may wait long for a task,
+            // so it runs in another task, and the heartbeats are received meanwhile
+            let mut replying: Option<JoinHandle<Result<SchedulerMessage>>> = None;
             loop {
                 tokio::select! {
-                    Some(result) = stream.next() => {
+                    Some(notice) = notice_rx.recv() => {
+                        log::debug!("[scheduler] notify batch prover: {}, {:?}", prover_id, notice);
+                        let msg = match notice {
+                            ServiceNotice::CancelTask { task_id, chunk_id } => SchedulerMessage {
+                                id: "".into(),
+                                message_type: Some(scheduler_message::MessageType::CancelBatchProofTask(
+                                    CancelBatchProofTask {
+                                        prover_id: prover_id.clone(),
+                                        task_id,
+                                        chunk_id,
+                                    },
+                                )),
+                            },
+                            ServiceNotice::RejectResult { task_id, chunk_id, lease_id, reason } => SchedulerMessage {
+                                id: "".into(),
+                                message_type: Some(scheduler_message::MessageType::RejectBatchProofResult(
+                                    RejectBatchProofResult {
+                                        prover_id: prover_id.clone(),
+                                        task_id,
+                                        chunk_id,
+                                        lease_id,
+                                        reason,
+                                    },
+                                )),
+                            },
+                        };
+                        if let Err(e) = tx.send(Ok(msg)).await {
+                            log::error!("Failed to send message: {}", e);
+                            break;
+                        }
+                    }
+                    reply = async { (&mut replying.as_mut().unwrap()).await }, if replying.is_some() => {
+                        replying = None;
+                        let Ok(Ok(resp)) = reply else {
+                            // close the connection
+                            break;
+                        };
+                        if let Err(e) = tx.send(Ok(resp)).await {
+                            log::error!("Failed to send message: {}", e);
+                            break;
+                        }
+                    }
+                    _ = tokio::time::sleep_until(last_seen + HEARTBEAT_TIMEOUT), if !legacy => {
+                        log::warn!(
+                            "[scheduler] batch prover: {} is lost, no heartbeat in {:?}",
+                            prover_id,
+                            HEARTBEAT_TIMEOUT
+                        );
+                        break;
+                    }
+                    result = stream.next() => {
+                        // client already closed the connection
+                        let Some(result) = result else {
+                            log::info!("[scheduler] batch prover: {} disconnected", prover_id);
+                            break;
+                        };
+                        last_seen = Instant::now();
                         match result {
                             Ok(batch_prover_msg) => {
                                 if let Some(msg) = batch_prover_msg.message_type {
-                                    let resp = match msg {
+                                    let handler = handle_clone.clone();
+                                    let scheduler_sender = scheduler_sender.clone();
+                                    let reply = match msg {
+                                        batch_prover_message::MessageType::Heartbeat(r) => {
+                                            log::trace!("[scheduler] heartbeat: {:?}", r);
+                                            if !prover_id.is_empty() {
+                                                // the heartbeat is not worth waiting for, the lease is renewed by the next one
+                                                let event = Event::RenewLease {
+                                                    service_id: prover_id.clone(),
+                                                    lease_id: r.lease_id,
+                                                };
+                                                if let Err(e) = scheduler_sender.try_send(event) {
+                                                    log::warn!("[scheduler] Failed to renew the lease: {}", e);
+                                                }
+                                            }
+                                            continue;
+                                        }
                                         // update pb, we don't need too much information
                                         batch_prover_message::MessageType::Registry(r) => {
                                             log::debug!("[scheduler] register batch prover: {:?}", r);
-                                            if let Ok(schdeduler_msg) = handle_clone.handle_batch_prover_registry(r, scheduler_sender.clone()).await {
-                                                schdeduler_msg
-                                            } else {
-                                                // close the connection
-                                                break;
-                                            }
+                                            prover_id = r.prover_id.clone();
+                                            legacy = r
+                                                .capabilities
+                                                .clone()
+                                                .map(Capabilities::from)
+                                                .unwrap_or_default()
+                                                .predates_leases();
+                                            let notice_tx = notice_tx.clone();
+                                            tokio::spawn(async move {
+                                                handler.handle_batch_prover_registry(r, scheduler_sender, notice_tx).await
+                                            })
                                         }
                                         // update pb, we don't need GeneBatchProofResponse
                                         // just wait for the result, don't need to get again
                                         batch_prover_message::MessageType::TakeBatchProofTask(r) => {
                                             // TODO: id
                                             log::debug!("[scheduler] take batch proof: {:?}", r);
-                                            if let Ok(scheduler_msg) = handle_clone.handle_gen_batch_proof_response(r.prover_id.clone(), scheduler_sender.clone()).await {
-                                                scheduler_msg
-                                            } else {
-                                                // close the connection
-                                                break;
-                                            }
+                                            tokio::spawn(async move {
+                                                handler.handle_gen_batch_proof_response(r.prover_id, scheduler_sender).await
+                                            })
                                         }
                                         // receive proof, trigger next batch_proof task
                                         batch_prover_message::MessageType::BatchProofResult(r) => {
                                             log::debug!("[scheduler] return proof: {:?}", r);
-                                            if let Ok(scheduler_msg) = handle_clone.handle_get_proof_response(r, scheduler_sender.clone(), result_sender.clone()).await {
-                                                scheduler_msg
-                                            } else {
-                                                // close the connection
-                                                break;
-                                            }
+                                            let result_sender = result_sender.clone();
+                                            tokio::spawn(async move {
+                                                handler.handle_get_proof_response(r, scheduler_sender, result_sender).await
+                                            })
                                         }
                                     };
-
-                                    if let Err(e) = tx.send(Ok(resp)).await {
-                                        log::error!("Failed to send message: {}", e);
-                                        break;
+                                    // the batch prover asks again before the reply, the previous one is dropped
+                                    if let Some(previous) = replying.replace(reply) {
+                                        log::warn!("[scheduler] batch prover: {} asks before the reply", prover_id);
+                                        previous.abort();
                                     }
                                 }
                             }
@@ -126,26 +219,23 @@ impl SchedulerService for SchedulerServiceSVC {
                                 // some error occurred
                                 // now, we choose to close the connection
                                 // TODO: process according to the status code, eg. retry, close, etc.
-                                // send Event::Shutdown to the scheduler, wait for the event result
-                                // exit the loop
                                 log::error!("Failed to receive message, close: {}", e);
                                 // try to send error_message to client?
                                 break;
                             }
                         }
-
-                    }
-                    else => {
-                        // client already closed the connection
-                        // send Event::Shutdown to the scheduler,
-                        // to notify the scheduler to remove the service
-                        // wait for the event result
-                        // exit the loop
-                        // TODO: put Event::Shutdown ant wait
-                        break;
                     }
                 }
             }
+            // the connection is closed or lost, remove the service, its task is requeued by the scheduler
+            if let Some(replying) = replying {
+                replying.abort();
+            }
+            if !prover_id.is_empty() {
+                handle_clone
+                    .remove_service(prover_id, scheduler_sender)
+                    .await;
+            }
         });
 
         Ok(Response::new(Box::pin(
@@ -160,6 +250,7 @@ pub trait SchedulerHandler {
         &self,
         r: Registry,
         scheduler_sender: mpsc::Sender<Event>,
+        notice_sender: mpsc::Sender<ServiceNotice>,
     ) -> Result<SchedulerMessage>;
     async fn handle_gen_batch_proof_response(
         &self,
@@ -174,9 +265,22 @@ pub trait SchedulerHandler {
         result_sender: mpsc::Sender<TaskResult>,
     ) -> Result<SchedulerMessage>;
 
+    /// Called after the connection of the batch prover is closed or lost
     async fn remove_service(&self, service_id: String, scheduler_sender: mpsc::Sender<Event>);
 }
 
+impl From<scheduler_service::Capabilities> for Capabilities {
+    fn from(c: scheduler_service::Capabilities) -> Self {
+        Capabilities {
+            task_names: c.task_names,
+            cores: c.cores,
+            memory: c.memory,
+            cpu_features: c.cpu_features,
+            build_version: c.build_version,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct SchedulerServerHandler {}
 
@@ -186,13 +290,22 @@ impl SchedulerHandler for SchedulerServerHandler {
         &self,
         r: Registry,
         scheduler_sender: mpsc::Sender<Event>,
+        notice_sender: mpsc::Sender<ServiceNotice>,
     ) -> Result<SchedulerMessage> {
         // send Event::AddService to the scheduler, registry the service to the scheduler
         // wait for the event result from the relay channel
         let (relay_to, mut relay) = mpsc::channel::<AddServiceResult>(1);
+        // the batch prover of the old version doesn't advertise its capabilities
+        let capabilities = r
+            .capabilities
+            .clone()
+            .map(Capabilities::from)
+            .unwrap_or_default();
         let event = Event::AddService {
             service_id: r.prover_id.clone(),
             relay_to,
+            notice_to: notice_sender,
+            capabilities,
         };
         if let Err(e) = scheduler_sender.send(event.clone()).await {
             // can't send event to scheduler, close the connection
@@ -245,7 +358,7 @@ impl SchedulerHandler for SchedulerServerHandler {
         // wait for the event result
         if let Some(take_task_result) = relay.recv().await {
             match take_task_result {
-                TakeTaskResult::Success(batch_ctx) => {
+                TakeTaskResult::Success(batch_ctx, lease_id) => {
                     Ok(SchedulerMessage {
                         // TODO: received id
                         id: "".into(),
@@ -257,6 +370,7 @@ impl SchedulerHandler for SchedulerServerHandler {
                                     batch_context_bytes: Some(BatchContextBytes {
                                         data: serde_json::to_vec(&batch_ctx).unwrap(),
                                     }),
+                                    lease_id,
                                 },
                             ),
                         ),
@@ -278,24 +392,24 @@ impl SchedulerHandler for SchedulerServerHandler {
         scheduler_sender: mpsc::Sender<Event>,
         result_sender: mpsc::Sender<TaskResult>,
     ) -> Result<SchedulerMessage> {
-        let task_result = if r.result == scheduler_service::Result::Ok as i32 {
-            TaskResult {
-                service_id: r.prover_id.clone(),
-                recursive_proof: ProofResult {
-                    task_id: r.task_id.clone(),
-                    chunk_id: r.chunk_id.clone(),
-                    result_code: ResultStatus::Success,
-                },
-            }
+        let result_code = if r.result == scheduler_service::Result::Ok as i32 {
+            ResultStatus::Success
+        } else if r.result == scheduler_service::Result::Cancelled as i32 {
+            ResultStatus::Cancelled
+        } else if r.result == scheduler_service::Result::NonRetryable as i32 {
+            ResultStatus::NonRetryable
         } else {
-            TaskResult {
-                service_id: r.prover_id.clone(),
-                recursive_proof: ProofResult {
-                    task_id: r.task_id.clone(),
-                    chunk_id: r.chunk_id.clone(),
-                    result_code: ResultStatus::Fail,
-                },
-            }
+            ResultStatus::Fail
+        };
+        let task_result = TaskResult {
+            service_id: r.prover_id.clone(),
+            recursive_proof: ProofResult {
+                task_id: r.task_id.clone(),
+                chunk_id: r.chunk_id.clone(),
+                result_code,
+                error_message: r.error_message.clone(),
+                lease_id: r.lease_id.clone(),
+            },
         };
 
         if let Err(e) = result_sender.send(task_result.clone()).await {
@@ -323,7 +437,6 @@ impl SchedulerHandler for SchedulerServerHandler {
         }
     }
 
-    #[allow(dead_code)]
     async fn remove_service(&self, service_id: String, scheduler_sender: mpsc::Sender<Event>) {
         // send Event::RemoveService to the scheduler, remove the service from the scheduler
         let event = Event::RemoveService {
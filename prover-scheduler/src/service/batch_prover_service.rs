@@ -1,12 +1,18 @@
+use crate::scheduler_server::HEARTBEAT_INTERVAL;
 use prover::contexts::BatchContext;
 use prover::provers;
 use prover::provers::Prover;
+use prover::retry::NonRetryableError;
+use prover::scheduler::Capabilities;
 use scheduler_service::scheduler_service_client::SchedulerServiceClient;
-use scheduler_service::TakeBatchProofTaskResponse;
+use scheduler_service::Capabilities as CapabilitiesMessage;
 use scheduler_service::{batch_prover_message, scheduler_message, BatchProverMessage};
-use scheduler_service::{BatchProofResult, Registry};
+use scheduler_service::{BatchProofResult, Heartbeat, Registry};
+use scheduler_service::{CancelBatchProofTask, TakeBatchProofTaskResponse};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::async_trait;
 use uuid::Uuid;
@@ -18,6 +24,8 @@ pub mod scheduler_service {
 pub struct BatchProverService {
     addr: String,
     pub batch_prover_handler: Arc<dyn BatchProverHandler + Send + Sync>,
+    /// Advertised to the scheduler when registering, the default runs any program
+    pub capabilities: Capabilities,
 }
 
 impl BatchProverService {
@@ -28,6 +36,7 @@ impl BatchProverService {
         BatchProverService {
             addr,
             batch_prover_handler,
+            capabilities: Capabilities::default(),
         }
     }
 
@@ -50,7 +59,14 @@ impl BatchProverService {
             id: "".to_string(),
             message_type: Some(batch_prover_message::MessageType::Registry(Registry {
                 prover_name: batch_prover_id,
-                prover_id: batch_prover_name,
+                prover_id: batch_prover_name.clone(),
+                capabilities: Some(CapabilitiesMessage {
+                    task_names: self.capabilities.task_names.clone(),
+                    cores: self.capabilities.cores,
+                    memory: self.capabilities.memory,
+                    cpu_features: self.capabilities.cpu_features.clone(),
+                    build_version: self.capabilities.build_version.clone(),
+                }),
             })),
         })
         .await?;
@@ -58,16 +74,86 @@ impl BatchProverService {
         let request = ReceiverStream::new(rx);
         let response = (client.scheduler_stream(request)).await?;
         let mut stream = response.into_inner();
-        while let Some(recv_msg) = stream.message().await? {
-            if let Some(msg_type) = recv_msg.message_type {
-                let send_msg = match msg_type {
-                    scheduler_message::MessageType::TakeBatchProofTaskResponse(r) => {
-                        self.batch_prover_handler
-                            .handle_take_batch_proof_task_response(r)
-                            .await
+
+        // the task being proved, the proof runs in another task, so that the CancelBatchProofTask can be received
+        let mut proving: Option<Proving> = None;
+        // keep sending the heartbeat while proving, or the lease expires and the scheduler requeues the task
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    let msg = BatchProverMessage {
+                        id: "".to_string(),
+                        message_type: Some(batch_prover_message::MessageType::Heartbeat(Heartbeat {
+                            prover_id: batch_prover_name.clone(),
+                            lease_id: proving.as_ref().map(|p| p.lease_id.clone()).unwrap_or_default(),
+                        })),
+                    };
+                    tx.send(msg).await?;
+                }
+                recv_msg = stream.message() => {
+                    let Some(recv_msg) = recv_msg? else {
+                        break;
+                    };
+                    match recv_msg.message_type {
+                        Some(scheduler_message::MessageType::TakeBatchProofTaskResponse(r)) => {
+                            let (task_id, chunk_id) = r
+                                .batch_context_bytes
+                                .as_ref()
+                                .and_then(|b| serde_json::from_slice::<BatchContext>(&b.data).ok())
+                                .map(|ctx| (ctx.task_id, ctx.chunk_id))
+                                .unwrap_or_default();
+                            let lease_id = r.lease_id.clone();
+                            let cancelled = Arc::new(AtomicBool::new(false));
+                            let handler = self.batch_prover_handler.clone();
+                            let flag = cancelled.clone();
+                            let handle = tokio::spawn(async move {
+                                handler.handle_take_batch_proof_task_response(r, flag).await
+                            });
+                            proving = Some(Proving {
+                                task_id,
+                                chunk_id,
+                                lease_id,
+                                cancelled,
+                                cancel: None,
+                                handle,
+                            });
+                        }
+                        Some(scheduler_message::MessageType::CancelBatchProofTask(r)) => {
+                            match proving.as_mut() {
+                                Some(p) if p.task_id == r.task_id && p.chunk_id == r.chunk_id => {
+                                    // the prover stops at its next phase, RESULT_CANCELLED is sent once it returns,
+                                    // or the next task would be proved alongside
+                                    log::info!("[batch-prover] cancel task: {}-{}", p.task_id, p.chunk_id);
+                                    p.cancelled.store(true, Ordering::Relaxed);
+                                    // the lease is revoked before the cancel
+                                    p.lease_id = "".to_string();
+                                    p.cancel = Some(r);
+                                }
+                                _ => {
+                                    // the task has been finished
+                                    log::info!("[batch-prover] ignore the cancel of task: {}-{}", r.task_id, r.chunk_id);
+                                }
+                            }
+                        }
+                        Some(scheduler_message::MessageType::RejectBatchProofResult(r)) => {
+                            log::warn!(
+                                "[batch-prover] the result of task: {}-{} is rejected, {}",
+                                r.task_id,
+                                r.chunk_id,
+                                r.reason
+                            );
+                        }
+                        None => {}
                     }
-                };
-                tx.send(send_msg).await?;
+                }
+                send_msg = async { (&mut proving.as_mut().unwrap().handle).await }, if proving.is_some() => {
+                    let send_msg = match proving.take().and_then(|p| p.cancel) {
+                        Some(r) => self.batch_prover_handler.handle_cancel_task(r).await,
+                        None => send_msg?,
+                    };
+                    tx.send(send_msg).await?;
+                }
             }
         }
 
@@ -75,12 +161,47 @@ impl BatchProverService {
     }
 }
 
+/// The task being proved by the BatchProverService
+struct Proving {
+    task_id: String,
+    chunk_id: String,
+    lease_id: String,
+    /// Set on the CancelBatchProofTask, the prover checks it between its phases
+    cancelled: Arc<AtomicBool>,
+    /// Replied with RESULT_CANCELLED once the prover returns
+    cancel: Option<CancelBatchProofTask>,
+    handle: JoinHandle<BatchProverMessage>,
+}
+
 #[async_trait]
 pub trait BatchProverHandler {
+    /// Prove the task, `cancelled` is set when the scheduler cancels it, the prover should stop as soon as it can.
     async fn handle_take_batch_proof_task_response(
         &self,
         take_batch_proof_task_response: TakeBatchProofTaskResponse,
+        cancelled: Arc<AtomicBool>,
     ) -> BatchProverMessage;
+
+    /// The cancelled task has returned, return RESULT_CANCELLED to take the next task.
+    async fn handle_cancel_task(
+        &self,
+        cancel_batch_proof_task: CancelBatchProofTask,
+    ) -> BatchProverMessage {
+        BatchProverMessage {
+            id: "".to_string(),
+            message_type: Some(batch_prover_message::MessageType::BatchProofResult(
+                BatchProofResult {
+                    prover_id: cancel_batch_proof_task.prover_id,
+                    task_id: cancel_batch_proof_task.task_id,
+                    chunk_id: cancel_batch_proof_task.chunk_id,
+                    result: scheduler_service::Result::Cancelled as i32,
+                    error_message: "".to_string(),
+                    // the lease is revoked before the cancel
+                    lease_id: "".to_string(),
+                },
+            )),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -91,6 +212,7 @@ impl BatchProverHandler for BatchProverServiceHandler {
     async fn handle_take_batch_proof_task_response(
         &self,
         take_batch_proof_task_response: TakeBatchProofTaskResponse,
+        cancelled: Arc<AtomicBool>,
     ) -> BatchProverMessage {
         let ctx = serde_json::from_slice::<BatchContext>(
             &take_batch_proof_task_response
@@ -106,7 +228,14 @@ impl BatchProverHandler for BatchProverServiceHandler {
             ctx.task_id,
             ctx.chunk_id
         );
-        match provers::BatchProver::new().prove(&ctx) {
+        // the prove blocks, keep it off the runtime which serves the stream
+        let batch_ctx = ctx.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            provers::BatchProver::with_cancel(cancelled).prove(&batch_ctx)
+        })
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("the batch prover panicked: {}", e)));
+        match result {
             Ok(_) => {
                 log::info!("batch prove success, task id: {}", ctx.task_id.clone());
                 // Return Result and Trigger next task
@@ -118,6 +247,8 @@ impl BatchProverHandler for BatchProverServiceHandler {
                             task_id: ctx.task_id.clone(),
                             chunk_id: ctx.chunk_id.clone(),
                             result: 1,
+                            error_message: "".to_string(),
+                            lease_id: take_batch_proof_task_response.lease_id,
                         },
                     )),
                 }
@@ -132,7 +263,14 @@ impl BatchProverHandler for BatchProverServiceHandler {
                             prover_id: take_batch_proof_task_response.prover_id,
                             task_id: ctx.task_id.clone(),
                             chunk_id: ctx.chunk_id.clone(),
-                            result: 0, // Indicate failure
+                            // Indicate failure, the scheduler doesn't retry the NonRetryableError
+                            result: if e.chain().any(|e| e.is::<NonRetryableError>()) {
+                                scheduler_service::Result::NonRetryable as i32
+                            } else {
+                                0
+                            },
+                            error_message: format!("{:#}", e),
+                            lease_id: take_batch_proof_task_response.lease_id,
                         },
                     )),
                 }
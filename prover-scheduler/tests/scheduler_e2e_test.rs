@@ -1,6 +1,8 @@
 use anyhow::anyhow;
+use prover::args::LinkConfig;
+use prover::blob_store::BlobStore;
 use prover::contexts::BatchContext;
-use prover::scheduler::{Event, TaskResult};
+use prover::scheduler::{Event, ServiceNotice, TaskResult};
 use prover_scheduler::scheduler_server::scheduler_service::{
     scheduler_message as server_scheduler_message, BatchContextBytes as ServerBatchContextBytes,
     BatchProofResult as ServerBatchProofResult, Registry as ServerRegistry,
@@ -17,6 +19,7 @@ use prover_scheduler::service::batch_prover_service::scheduler_service::{
 };
 use prover_scheduler::service::batch_prover_service::{BatchProverHandler, BatchProverService};
 
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 use tonic::async_trait;
@@ -97,6 +100,7 @@ impl SchedulerHandler for MockSchedulerServerHandler {
         &self,
         r: ServerRegistry,
         _scheduler_sender: Sender<Event>,
+        _notice_sender: Sender<ServiceNotice>,
     ) -> anyhow::Result<ServerSchedulerMessage> {
         // here we don't need to send message to scheduler_sender,
         let basedir = "/tmp";
@@ -108,14 +112,18 @@ impl SchedulerHandler for MockSchedulerServerHandler {
                 .unwrap_or("../executor/test-vectors/solidityExample.json".to_string()),
         )
         .unwrap();
+        let batch_digest = BlobStore::new(basedir)
+            .put(l2_batch_data.as_bytes())
+            .unwrap();
         let force_bits = 18;
         let first_task = BatchContext::new(
             basedir,
             task_id,
             task_name,
             chunk_id,
-            l2_batch_data,
+            batch_digest,
             force_bits,
+            &LinkConfig::default(),
         );
         // just test the server and client communication
         // we will test the message sending in lib prover
@@ -134,6 +142,7 @@ impl SchedulerHandler for MockSchedulerServerHandler {
                         batch_context_bytes: Some(ServerBatchContextBytes {
                             data: serde_json::to_vec(&first_task).unwrap(),
                         }),
+                        lease_id: "".to_string(),
                     },
                 ),
             ),
@@ -164,8 +173,8 @@ impl SchedulerHandler for MockSchedulerServerHandler {
         return Err(anyhow!("close the connection"));
     }
 
-    async fn remove_service(&self, _service_id: String, _scheduler_sender: Sender<Event>) {
-        todo!()
+    async fn remove_service(&self, service_id: String, _scheduler_sender: Sender<Event>) {
+        log::info!("[Scheduler Server] remove [Prover Service: {}]", service_id);
     }
 }
 
@@ -177,6 +186,7 @@ impl BatchProverHandler for MockBatchProverHandler {
     async fn handle_take_batch_proof_task_response(
         &self,
         take_batch_proof_task_response: ClientTakeBatchProofTaskResponse,
+        _cancelled: Arc<AtomicBool>,
     ) -> ClientBatchProverMessage {
         let ctx = serde_json::from_slice::<BatchContext>(
             &take_batch_proof_task_response
@@ -203,6 +213,8 @@ impl BatchProverHandler for MockBatchProverHandler {
                     task_id: ctx.task_id.clone(),
                     chunk_id: ctx.chunk_id.clone(),
                     result: 1,
+                    error_message: "".to_string(),
+                    lease_id: take_batch_proof_task_response.lease_id,
                 },
             )),
         }
@@ -1,7 +1,9 @@
 use std::env;
 use std::sync::Arc;
 
-use prover::pipeline::Pipeline;
+use prover::config::{Component, PipelineConfig};
+use prover::curve::Curve;
+use prover::pipeline::{Pipeline, ProverModel};
 use prover::scheduler::Scheduler;
 use prover_scheduler::scheduler_server::{SchedulerServerHandler, SchedulerServiceSVC};
 
@@ -10,22 +12,33 @@ use prover_scheduler::scheduler_server::{SchedulerServerHandler, SchedulerServic
 async fn prover_scheduler_e2e_full_test() {
     // init log
     env::set_var("RUST_LOG", "info");
-    env::set_var("PROVER_MODEL", "grpc");
     env_logger::try_init().unwrap_or_default();
 
+    // init pipeline, the batch stages are sent to the scheduler
+    let config = PipelineConfig::load(Vec::<String>::new(), Component::Service).unwrap();
+    let config = PipelineConfig {
+        basedir: env::var("BASEDIR").unwrap_or("data".to_string()),
+        prover_model: ProverModel::GRPC,
+        ..config
+    };
+    let mut pipeline =
+        Pipeline::with_config(&config, env::var("TASK_NAME").unwrap_or("evm".to_string())).unwrap();
+
     // init scheduler
     let (task_tx, task_rx) = tokio::sync::mpsc::channel(128);
     let (event_tx, event_rx) = tokio::sync::mpsc::channel(128);
     let (result_sender, result_receiver) = tokio::sync::mpsc::channel(128);
     let task_tx_clone: tokio::sync::mpsc::Sender<prover::contexts::BatchContext> = task_tx.clone();
-    let mut scheduler = Scheduler::new(result_receiver, event_rx, task_rx, task_tx_clone);
-
-    // init pipeline.
-    let mut pipeline = Pipeline::new(
-        env::var("BASEDIR").unwrap_or("data".to_string()),
-        env::var("TASK_NAME").unwrap_or("evm".to_string()),
+    let mut scheduler = Scheduler::new(
+        result_receiver,
+        event_rx,
+        task_rx,
+        task_tx_clone,
+        pipeline.task_store(),
+        pipeline.task_events(),
     );
     pipeline.set_task_sender(task_tx);
+    pipeline.set_ack_receiver(scheduler.ack_sender.subscribe());
 
     // Start the server
     log::info!("====================1. Start the server====================");
@@ -51,23 +64,25 @@ async fn prover_scheduler_e2e_full_test() {
             .unwrap_or("../executor/test-vectors/solidityExample.json".to_string()),
     )
     .unwrap();
+
+    let batch_digest = pipeline.put_batch_data(&l2_batch_data).unwrap();
     log::info!("====================4. Task incoming ====================");
     let task1 = pipeline
-        .batch_prove("0".into(), "0".into(), l2_batch_data.clone())
+        .batch_prove("0".into(), "0".into(), batch_digest.clone())
         .unwrap();
     pipeline.prove().unwrap();
     log::info!("task: {task1}");
     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
 
     let task2 = pipeline
-        .batch_prove("0".into(), "1".into(), l2_batch_data.clone())
+        .batch_prove("0".into(), "1".into(), batch_digest.clone())
         .unwrap();
     pipeline.prove().unwrap();
     log::info!("task2: {task2}");
     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
 
     let task3 = pipeline
-        .batch_prove("0".into(), "2".into(), l2_batch_data)
+        .batch_prove("0".into(), "2".into(), batch_digest)
         .unwrap();
     pipeline.prove().unwrap();
     log::info!("task3: {task3}");
@@ -92,8 +107,8 @@ async fn prover_scheduler_e2e_full_test() {
 
     let task5 = pipeline
         .final_prove(
-            task4,
-            "BN128".into(),
+            task4.clone(),
+            Curve::BN128,
             "273030697313060285579891744179749754319274977764".into(),
         )
         .unwrap();
@@ -105,11 +120,13 @@ async fn prover_scheduler_e2e_full_test() {
         .unwrap();
     pipeline.prove().unwrap();
     log::info!("agg task: {task6}");
+    // the identical aggregation is proved once
+    assert_eq!(task6, task4);
 
     let task7 = pipeline
         .final_prove(
             task6,
-            "BN128".into(),
+            Curve::BN128,
             "273030697313060285579891744179749754319274977764".into(),
         )
         .unwrap();
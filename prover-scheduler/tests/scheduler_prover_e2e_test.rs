@@ -10,8 +10,10 @@ use prover_scheduler::service::batch_prover_service::scheduler_service::{
 };
 use prover_scheduler::service::batch_prover_service::{BatchProverHandler, BatchProverService};
 
-use prover::pipeline::Pipeline;
+use prover::config::PipelineConfig;
+use prover::pipeline::{Pipeline, ProverModel};
 use prover::scheduler::Scheduler;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tonic::async_trait;
 
@@ -19,22 +21,32 @@ use tonic::async_trait;
 async fn prover_scheduler_e2e_mock_test() {
     // init log
     env::set_var("RUST_LOG", "info");
-    env::set_var("PROVER_MODEL", "grpc");
     env_logger::try_init().unwrap_or_default();
 
+    // init pipeline, the batch stages are sent to the scheduler
+    let config = PipelineConfig {
+        basedir: env::var("BASEDIR").unwrap_or("data".to_string()),
+        prover_model: ProverModel::GRPC,
+        ..Default::default()
+    };
+    let mut pipeline =
+        Pipeline::with_config(&config, env::var("TASK_NAME").unwrap_or("evm".to_string())).unwrap();
+
     // init scheduler
     let (task_tx, task_rx) = tokio::sync::mpsc::channel(128);
     let (event_tx, event_rx) = tokio::sync::mpsc::channel(128);
     let (result_sender, result_receiver) = tokio::sync::mpsc::channel(128);
     let task_tx_clone = task_tx.clone();
-    let mut scheduler = Scheduler::new(result_receiver, event_rx, task_rx, task_tx_clone);
-
-    // init pipeline.
-    let mut pipeline = Pipeline::new(
-        env::var("BASEDIR").unwrap_or("data".to_string()),
-        env::var("TASK_NAME").unwrap_or("evm".to_string()),
+    let mut scheduler = Scheduler::new(
+        result_receiver,
+        event_rx,
+        task_rx,
+        task_tx_clone,
+        pipeline.task_store(),
+        pipeline.task_events(),
     );
     pipeline.set_task_sender(task_tx);
+    pipeline.set_ack_receiver(scheduler.ack_sender.subscribe());
 
     // Start the server
     log::info!("====================1. Start the server====================");
@@ -61,20 +73,22 @@ async fn prover_scheduler_e2e_mock_test() {
     )
     .unwrap();
 
+    let batch_digest = pipeline.put_batch_data(&l2_batch_data).unwrap();
+
     tokio::spawn(async move {
         log::info!("====================1. Task incoming ====================");
         let task1 = pipeline
-            .batch_prove("0".into(), "0".into(), l2_batch_data.clone())
+            .batch_prove("0".into(), "0".into(), batch_digest.clone())
             .unwrap();
         log::info!("task: {task1}");
 
         let task2 = pipeline
-            .batch_prove("0".into(), "1".into(), l2_batch_data.clone())
+            .batch_prove("0".into(), "1".into(), batch_digest.clone())
             .unwrap();
         log::info!("task2: {task2}");
 
         let task3 = pipeline
-            .batch_prove("0".into(), "2".into(), l2_batch_data)
+            .batch_prove("0".into(), "2".into(), batch_digest)
             .unwrap();
         log::info!("task3: {task3}");
 
@@ -145,6 +159,7 @@ impl BatchProverHandler for MockBatchProverHandler {
     async fn handle_take_batch_proof_task_response(
         &self,
         take_batch_proof_task_response: ClientTakeBatchProofTaskResponse,
+        _cancelled: Arc<AtomicBool>,
     ) -> ClientBatchProverMessage {
         let ctx = serde_json::from_slice::<BatchContext>(
             &take_batch_proof_task_response
@@ -171,6 +186,8 @@ impl BatchProverHandler for MockBatchProverHandler {
                     task_id: ctx.task_id.clone(),
                     chunk_id: ctx.chunk_id.clone(),
                     result: 1,
+                    error_message: "".to_string(),
+                    lease_id: take_batch_proof_task_response.lease_id,
                 },
             )),
         }
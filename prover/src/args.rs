@@ -1,5 +1,7 @@
+use crate::curve::Curve;
+use crate::layout::{CircuitFile, ProofLayout};
+
 use serde::{Deserialize, Serialize};
-use std::env::var;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct CircomCompileArgs {
@@ -12,26 +14,38 @@ pub struct CircomCompileArgs {
 }
 
 impl CircomCompileArgs {
-    pub fn new(basedir: &str, task_path: &str, task_name: &str, curve: &str) -> Self {
+    pub fn new(
+        layout: &ProofLayout,
+        task_path: &str,
+        task_name: &str,
+        curve: Curve,
+        links: &LinkConfig,
+    ) -> Self {
         CircomCompileArgs {
-            circom_file: format!("{basedir}/{task_path}/{task_name}.circom",),
-            link_directories: load_link(curve),
-            output: format!("{basedir}/{task_path}"),
+            circom_file: layout.circuit_file(task_path, task_name, CircuitFile::Circom),
+            link_directories: links.links(curve),
+            output: layout.path(task_path),
         }
     }
 
+    /// The batch circuit of the chunk is written by the zkvm
     pub fn new_batch(
-        evm_output: &str,
-        basedir: &str,
-        task_path: &str,
+        layout: &ProofLayout,
+        task_id: &str,
         task_name: &str,
         chunk_id: &str,
-        curve: &str,
+        curve: Curve,
+        links: &LinkConfig,
     ) -> Self {
         CircomCompileArgs {
-            circom_file: format!("{evm_output}/{task_name}_chunk_{chunk_id}.circom",),
-            link_directories: load_link(curve),
-            output: format!("{basedir}/{task_path}"),
+            circom_file: layout.batch_circuit_file(
+                task_id,
+                task_name,
+                chunk_id,
+                CircuitFile::Circom,
+            ),
+            link_directories: links.links(curve),
+            output: layout.path(&ProofLayout::batch_path(task_id, chunk_id)),
         }
     }
 }
@@ -40,7 +54,7 @@ impl CircomCompileArgs {
 pub struct StarkProveArgs {
     pub commit_file: String,
     pub const_file: String,
-    pub curve_type: String,
+    pub curve_type: Curve,
     pub exec_file: String,
     pub pil_file: String,
     pub piljson: String,
@@ -50,52 +64,68 @@ pub struct StarkProveArgs {
 }
 
 impl StarkProveArgs {
-    pub fn new(basedir: &str, task_path: &str, task_name: &str, curve: &str) -> Self {
+    pub fn new(layout: &ProofLayout, task_path: &str, task_name: &str, curve: Curve) -> Self {
+        let file = |file| layout.circuit_file(task_path, task_name, file);
         StarkProveArgs {
-            commit_file: format!("{basedir}/{task_path}/{task_name}.cm",),
-            const_file: format!("{basedir}/{task_path}/{task_name}.const",),
-            curve_type: curve.to_string(),
-            exec_file: format!("{basedir}/{task_path}/{task_name}.exec",),
-            pil_file: format!("{basedir}/{task_path}/{task_name}.pil",),
-            piljson: format!("{basedir}/{task_path}/{task_name}.pil.json",),
-            r1cs_file: format!("{basedir}/{task_path}/{task_name}.r1cs",),
-            wasm_file: format!("{basedir}/{task_path}/{task_name}_js/{task_name}.wasm",),
-            zkin: format!("{basedir}/{task_path}/{task_name}.zkin.json",),
+            commit_file: file(CircuitFile::Commit),
+            const_file: file(CircuitFile::Const),
+            curve_type: curve,
+            exec_file: file(CircuitFile::Exec),
+            pil_file: file(CircuitFile::Pil),
+            piljson: file(CircuitFile::PilJson),
+            r1cs_file: file(CircuitFile::R1cs),
+            wasm_file: file(CircuitFile::Wasm),
+            zkin: file(CircuitFile::Zkin),
+        }
+    }
+
+    /// The batch stark of the chunk, the zkvm writes its const, commit and zkin into the chunks dir
+    pub fn new_batch(layout: &ProofLayout, task_id: &str, task_name: &str, chunk_id: &str) -> Self {
+        let file = |file| layout.batch_circuit_file(task_id, task_name, chunk_id, file);
+        StarkProveArgs {
+            commit_file: file(CircuitFile::Commit),
+            const_file: file(CircuitFile::Const),
+            curve_type: Curve::GL,
+            exec_file: file(CircuitFile::Exec),
+            pil_file: file(CircuitFile::Pil),
+            piljson: file(CircuitFile::PilJson),
+            r1cs_file: file(CircuitFile::R1cs),
+            wasm_file: file(CircuitFile::Wasm),
+            zkin: file(CircuitFile::Zkin),
         }
     }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct FinalProveArgs {
-    pub curve_type: String,
+    pub curve_type: Curve,
     pub pk_file: String,
     pub proof_file: String,
     pub public_input_file: String,
     pub vk_file: String,
 }
 
-pub fn load_link(curve_type: &str) -> Vec<String> {
-    let mut links: Vec<String> = vec![];
-    match curve_type {
-        "GL" => {
-            if let Ok(pilstark) = var("STARK_VERIFIER_GL") {
-                links.push(pilstark);
-            }
-        }
-        "BN128" => {
-            if let Ok(pilstark) = var("STARK_VERIFIER_BN128") {
-                links.push(pilstark);
-            }
-        }
-        "BLS12381" => {
-            if let Ok(pilstark) = var("STARK_VERIFIER_BLS12381") {
-                links.push(pilstark);
-            }
-        }
-        &_ => todo!(),
-    }
-    if let Ok(circomlib) = var("CIRCOMLIB") {
-        links.push(circomlib);
+/// The directories of the circom libraries, they are linked when compiling the circuits
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkConfig {
+    pub circomlib: Option<String>,
+    pub stark_verifier_gl: Option<String>,
+    pub stark_verifier_bn128: Option<String>,
+    pub stark_verifier_bls12381: Option<String>,
+}
+
+impl LinkConfig {
+    pub fn links(&self, curve: Curve) -> Vec<String> {
+        let stark_verifier = match curve {
+            Curve::GL => &self.stark_verifier_gl,
+            Curve::BN128 => &self.stark_verifier_bn128,
+            Curve::BLS12381 => &self.stark_verifier_bls12381,
+        };
+        stark_verifier
+            .iter()
+            .chain(self.circomlib.iter())
+            .cloned()
+            .collect()
     }
-    links
 }
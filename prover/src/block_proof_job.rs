@@ -0,0 +1,277 @@
+use crate::curve::Curve;
+use crate::layout::{ProofLayout, JOB_FILE};
+use crate::stage::Stage;
+use crate::task_store::TaskStatus;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// The state of a BlockProofJob, it advances as the stages finish:
+/// Chunks -> Aggregate -> Final -> Succeeded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobState {
+    Chunks,
+    Aggregate,
+    Final,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// What the pipeline should do for the job
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobAction {
+    /// The current stages are not finished
+    Wait,
+    /// All the chunks are proved, aggregate them
+    Aggregate(String, String),
+    /// The aggregation is proved, generate the final proof of it
+    Final(String),
+    Succeed,
+    Fail(String),
+    Cancel,
+}
+
+/// Prove the block(s) of a batch end to end: the chunks, the aggregation of the chunks,
+/// and the final proof of the aggregation.
+/// It's saved to `{basedir}/proof/{task_id}/job.json`, so it's resumed after restart.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockProofJob {
+    /// The task id of the batch proof
+    pub task_id: String,
+    /// The program of the job
+    pub task_name: String,
+    pub chunk_count: usize,
+    /// The curve of the final proof, saved as `curve_name` by the earlier builds
+    #[serde(alias = "curve_name")]
+    pub curve: Curve,
+    pub prover_addr: String,
+    /// Returned by the aggregation, the final proof is derived from it with the curve and the prover address
+    pub agg_task_id: Option<String>,
+    pub state: JobState,
+    pub error: Option<String>,
+}
+
+impl BlockProofJob {
+    pub fn new(
+        task_id: String,
+        task_name: String,
+        chunk_count: usize,
+        curve: Curve,
+        prover_addr: String,
+    ) -> Self {
+        BlockProofJob {
+            task_id,
+            task_name,
+            chunk_count,
+            curve,
+            prover_addr,
+            agg_task_id: None,
+            state: JobState::Chunks,
+            error: None,
+        }
+    }
+
+    /// The key of the job in the task events, it's not a stage
+    pub fn key(&self) -> String {
+        format!("{}_block", self.task_id)
+    }
+
+    pub fn chunk_keys(&self) -> Vec<String> {
+        (0..self.chunk_count)
+            .map(|i| format!("{}_{}", self.task_id, i))
+            .collect()
+    }
+
+    pub fn agg_key(&self) -> Option<String> {
+        self.agg_task_id.as_ref().map(|id| format!("{}_agg", id))
+    }
+
+    pub fn final_key(&self) -> Option<String> {
+        self.agg_task_id
+            .as_ref()
+            .map(|id| Stage::Final(id.clone(), self.curve, self.prover_addr.clone()).key())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.state,
+            JobState::Succeeded | JobState::Failed | JobState::Cancelled
+        )
+    }
+
+    /// The status of the job as a task
+    pub fn status(&self) -> TaskStatus {
+        match self.state {
+            JobState::Succeeded => TaskStatus::Succeeded,
+            JobState::Failed => TaskStatus::Failed,
+            JobState::Cancelled => TaskStatus::Cancelled,
+            _ => TaskStatus::Queued,
+        }
+    }
+
+    /// Decide the next action from the status of the stages in the current state,
+    /// the unknown stages are treated as queued.
+    pub fn poll<F>(&self, status_of: F) -> Result<JobAction>
+    where
+        F: Fn(&str) -> Result<Option<TaskStatus>>,
+    {
+        let keys = match self.state {
+            JobState::Chunks => self.chunk_keys(),
+            JobState::Aggregate => self.agg_key().into_iter().collect(),
+            JobState::Final => self.final_key().into_iter().collect(),
+            _ => return Ok(JobAction::Wait),
+        };
+
+        let mut finished = true;
+        for key in keys.iter() {
+            match status_of(key)? {
+                Some(TaskStatus::Succeeded) => {}
+                Some(TaskStatus::Failed) => {
+                    return Ok(JobAction::Fail(format!("task: {} failed", key)))
+                }
+                Some(TaskStatus::Cancelled) => return Ok(JobAction::Cancel),
+                Some(TaskStatus::Queued) | Some(TaskStatus::Running) | None => finished = false,
+            }
+        }
+        if !finished || keys.is_empty() {
+            return Ok(JobAction::Wait);
+        }
+
+        let action = match self.state {
+            JobState::Chunks => JobAction::Aggregate(
+                format!("{}_chunk_0", self.task_id),
+                format!("{}_chunk_{}", self.task_id, self.chunk_count - 1),
+            ),
+            JobState::Aggregate => JobAction::Final(self.agg_task_id.clone().unwrap_or_default()),
+            _ => JobAction::Succeed,
+        };
+        Ok(action)
+    }
+
+    pub fn path(basedir: &str, task_id: &str) -> PathBuf {
+        ProofLayout::new(basedir).job_file(task_id)
+    }
+
+    pub fn save(&self, basedir: &str) -> Result<()> {
+        let path = Self::path(basedir, &self.task_id);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // write then rename, so the job is never half written
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Return None if the job does not exist
+    pub fn load(basedir: &str, task_id: &str) -> Result<Option<Self>> {
+        let path = Self::path(basedir, task_id);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let job = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Ok(Some(job))
+    }
+
+    /// All the jobs saved under the basedir
+    pub fn list(basedir: &str) -> Result<Vec<Self>> {
+        let proof_dir = ProofLayout::new(basedir).proof_dir();
+        if !proof_dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut jobs = vec![];
+        for task_dir in std::fs::read_dir(proof_dir)? {
+            let path = task_dir?.path().join(JOB_FILE);
+            if path.is_file() {
+                jobs.push(serde_json::from_str(&std::fs::read_to_string(path)?)?);
+            }
+        }
+        Ok(jobs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_poll_block_proof_job() {
+        let mut job = BlockProofJob::new("0".into(), "evm".into(), 2, Curve::BN128, "addr".into());
+        let mut statuses = HashMap::new();
+        let poll = |job: &BlockProofJob, statuses: &HashMap<String, TaskStatus>| {
+            job.poll(|key| Ok(statuses.get(key).copied())).unwrap()
+        };
+
+        statuses.insert("0_0".to_string(), TaskStatus::Succeeded);
+        assert_eq!(poll(&job, &statuses), JobAction::Wait);
+        statuses.insert("0_1".to_string(), TaskStatus::Succeeded);
+        assert_eq!(
+            poll(&job, &statuses),
+            JobAction::Aggregate("0_chunk_0".into(), "0_chunk_1".into())
+        );
+
+        job.agg_task_id = Some("a".into());
+        job.state = JobState::Aggregate;
+        assert_eq!(poll(&job, &statuses), JobAction::Wait);
+        statuses.insert("a_agg".to_string(), TaskStatus::Succeeded);
+        assert_eq!(poll(&job, &statuses), JobAction::Final("a".into()));
+
+        job.state = JobState::Final;
+        let final_key = job.final_key().unwrap();
+        assert_eq!(
+            final_key,
+            Stage::Final("a".into(), Curve::BN128, "addr".into()).key()
+        );
+        statuses.insert(final_key.clone(), TaskStatus::Failed);
+        assert_eq!(
+            poll(&job, &statuses),
+            JobAction::Fail(format!("task: {} failed", final_key))
+        );
+        statuses.insert(final_key.clone(), TaskStatus::Cancelled);
+        assert_eq!(poll(&job, &statuses), JobAction::Cancel);
+        statuses.insert(final_key, TaskStatus::Succeeded);
+        assert_eq!(poll(&job, &statuses), JobAction::Succeed);
+
+        job.state = JobState::Succeeded;
+        assert_eq!(poll(&job, &statuses), JobAction::Wait);
+        assert_eq!(job.status(), TaskStatus::Succeeded);
+    }
+
+    #[test]
+    fn test_save_block_proof_job() {
+        let basedir = env::temp_dir()
+            .join(format!("block_proof_job_{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .to_string();
+
+        assert!(BlockProofJob::list(&basedir).unwrap().is_empty());
+        assert!(BlockProofJob::load(&basedir, "0").unwrap().is_none());
+        let mut job = BlockProofJob::new("0".into(), "evm".into(), 2, Curve::BN128, "addr".into());
+        job.save(&basedir).unwrap();
+        job.agg_task_id = Some("a".into());
+        job.state = JobState::Aggregate;
+        job.save(&basedir).unwrap();
+
+        assert_eq!(
+            BlockProofJob::load(&basedir, "0").unwrap(),
+            Some(job.clone())
+        );
+        assert_eq!(BlockProofJob::list(&basedir).unwrap(), vec![job.clone()]);
+
+        // the job saved by the earlier builds
+        let legacy = serde_json::to_string(&job)
+            .unwrap()
+            .replace(r#""curve":"#, r#""curve_name":"#);
+        let legacy: BlockProofJob = serde_json::from_str(&legacy).unwrap();
+        assert_eq!(legacy, job);
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+}
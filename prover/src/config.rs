@@ -0,0 +1,600 @@
+use crate::args::LinkConfig;
+use crate::deadline::StageDeadlines;
+use crate::pipeline::ProverModel;
+use crate::retention::RetentionPolicy;
+use crate::retry::RetryPolicy;
+use crate::scheduler::TaskRequirements;
+use crate::worker_pool::WorkerPoolConfig;
+
+use serde::{Deserialize, Deserializer};
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The name of the config file in CONF_DIR
+pub const CONFIG_FILE: &str = "base_config.toml";
+
+/// The TOML key and the env of each setting, the CLI flag is `--` followed by the key with `-` instead of `_`
+pub const SETTINGS: &[(&str, &str)] = &[
+    ("addr", "SERVICE_ADDR"),
+    ("scheduler_addr", "SCHEDULER_ADDR"),
+    ("basedir", "BASEDIR"),
+    ("task_name", "TASK_NAME"),
+    ("cache_dir", "CACHE_DIR"),
+    ("prover_model", "PROVER_MODEL"),
+    ("force_bits", "FORCE_BIT"),
+    ("fork_id", "PROVER_FORK_ID"),
+    ("url", "URL"),
+    ("max_queue_depth", "MAX_QUEUE_DEPTH"),
+    ("task_store", "TASK_STORE"),
+    ("task_names", "TASK_NAMES"),
+    ("links.circomlib", "CIRCOMLIB"),
+    ("links.stark_verifier_gl", "STARK_VERIFIER_GL"),
+    ("links.stark_verifier_bn128", "STARK_VERIFIER_BN128"),
+    ("links.stark_verifier_bls12381", "STARK_VERIFIER_BLS12381"),
+    ("worker_pool.threads", "LOCAL_WORKER_THREADS"),
+    ("worker_pool.memory_budget", "LOCAL_MEMORY_BUDGET"),
+    ("worker_pool.batch_memory", "BATCH_PROVE_MEMORY"),
+    ("worker_pool.aggregate_memory", "AGG_PROVE_MEMORY"),
+    ("worker_pool.final_memory", "FINAL_PROVE_MEMORY"),
+    ("retry.max_attempts", "RETRY_MAX_ATTEMPTS"),
+    ("retry.initial_backoff", "RETRY_INITIAL_BACKOFF"),
+    ("retry.max_backoff", "RETRY_MAX_BACKOFF"),
+    ("retry.non_retryable", "RETRY_NON_RETRYABLE"),
+    ("deadlines.batch", "BATCH_DEADLINE"),
+    ("deadlines.aggregate", "AGG_DEADLINE"),
+    ("deadlines.final", "FINAL_DEADLINE"),
+    ("retention.keep_final_only", "RETENTION_KEEP_FINAL_ONLY"),
+    ("retention.keep_latest", "RETENTION_KEEP_LATEST"),
+    ("retention.max_bytes", "RETENTION_MAX_BYTES"),
+    ("requirements.min_cores", "BATCH_MIN_CORES"),
+    ("requirements.min_memory", "BATCH_MIN_MEMORY"),
+    ("requirements.cpu_features", "BATCH_CPU_FEATURES"),
+    ("requirements.build_version", "BATCH_BUILD_VERSION"),
+];
+
+/// The binary which loads the configuration, each one requires different settings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component {
+    /// The `service` binary: the prover service, the scheduler and the local pipelines
+    Service,
+    /// The `batch-prover` binary: the remote worker of the scheduler
+    BatchProver,
+    /// The `gc` binary: prune the artifacts of the proved tasks
+    Gc,
+}
+
+/// All the invalid or missing settings found while loading the configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for error in self.errors.iter() {
+            write!(f, "\n  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The configuration of the `service` and the `batch-prover`.
+///
+/// The settings are loaded in the order below, the later ones override the earlier ones:
+///   1. the defaults
+///   2. the TOML file, `--config <file>` or `$CONF_DIR/base_config.toml`, CONF_DIR defaults to `conf`
+///   3. the env, see SETTINGS
+///   4. the CLI flags, `--<key> <value>` or `--<key>=<value>`, e.g. `--force-bits 18`, `--links.circomlib <dir>`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// The listening address of the service
+    pub addr: String,
+    /// The scheduler which the batch prover takes the tasks from
+    pub scheduler_addr: String,
+    pub basedir: String,
+    /// The program of the requests without program name
+    pub task_name: String,
+    /// The setup of the final stage, empty means it's generated under the basedir
+    pub cache_dir: String,
+    pub prover_model: ProverModel,
+    /// The force bits of the compressed setup
+    pub force_bits: usize,
+    pub fork_id: u64,
+    /// The RPC url of the L2 node
+    pub url: String,
+    /// The max number of the queued stages of a pipeline, 0 means unbounded
+    pub max_queue_depth: usize,
+    /// The backend of the task store, fs or sqlite
+    pub task_store: String,
+    /// The programs whose setup the batch prover has, empty means any program
+    pub task_names: Vec<String>,
+    pub links: LinkConfig,
+    pub worker_pool: WorkerPoolConfig,
+    pub retry: RetryPolicy,
+    pub deadlines: StageDeadlines,
+    pub retention: RetentionPolicy,
+    /// What the batch tasks require of the batch provers, checked by the scheduler
+    pub requirements: TaskRequirements,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            addr: "0.0.0.0:50061".to_string(),
+            scheduler_addr: "http://127.0.0.1:50051".to_string(),
+            basedir: "/tmp/prover/data".to_string(),
+            task_name: "fibonacci".to_string(),
+            cache_dir: "".to_string(),
+            prover_model: ProverModel::Local,
+            force_bits: 0,
+            fork_id: 0,
+            url: "http://localhost:8545".to_string(),
+            max_queue_depth: 0,
+            task_store: "fs".to_string(),
+            task_names: vec![],
+            links: LinkConfig::default(),
+            worker_pool: WorkerPoolConfig::default(),
+            retry: RetryPolicy::default(),
+            deadlines: StageDeadlines::default(),
+            retention: RetentionPolicy::default(),
+            requirements: TaskRequirements::default(),
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Load and validate the configuration of the binary, `args` are the CLI flags without the program name
+    pub fn load<I: IntoIterator<Item = String>>(
+        args: I,
+        component: Component,
+    ) -> Result<Self, ConfigError> {
+        Self::load_with(args, |name| env::var(name).ok(), component)
+    }
+
+    /// Load the configuration of the binary from its CLI flags,
+    /// print the usage on `--help`, or all the invalid settings and exit if it's invalid.
+    pub fn load_or_exit(component: Component) -> Self {
+        Self::load_args_or_exit(env::args().skip(1).collect(), component)
+    }
+
+    /// Same as load_or_exit with the given flags, e.g. the ones left after the binary takes its own
+    pub fn load_args_or_exit(args: Vec<String>, component: Component) -> Self {
+        if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+            println!("{}", Self::usage());
+            std::process::exit(0);
+        }
+        match Self::load(args, component) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    fn load_with<I, F>(args: I, env: F, component: Component) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut errors = vec![];
+        let (config_file, flags) = parse_args(args, &mut errors);
+
+        // the default file is optional, the one given explicitly must exist
+        let (path, required) = match (config_file, env("CONF_DIR")) {
+            (Some(file), _) => (PathBuf::from(file), true),
+            (None, Some(dir)) => (Path::new(&dir).join(CONFIG_FILE), true),
+            (None, None) => (Path::new("conf").join(CONFIG_FILE), false),
+        };
+        let mut config = match Self::from_file(&path, required) {
+            Ok(config) => config,
+            Err(e) => {
+                errors.push(e);
+                Self::default()
+            }
+        };
+
+        config.apply_env(env, &mut errors);
+        for (flag, key, value) in flags {
+            if let Err(e) = config.set(&key, &value) {
+                errors.push(format!("{}: {}", flag, e));
+            }
+        }
+        errors.extend(config.check(component));
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+
+    fn from_file(path: &Path, required: bool) -> Result<Self, String> {
+        if !path.is_file() {
+            if required {
+                return Err(format!("config file {} does not exist", path.display()));
+            }
+            log::info!("{} does not exist, use the default config", path.display());
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("can not read config file {}, {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("config file {}: {}", path.display(), e))
+    }
+
+    fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, env: F, errors: &mut Vec<String>) {
+        for (key, name) in SETTINGS {
+            if let Some(value) = env(name) {
+                if let Err(e) = self.set(key, &value) {
+                    errors.push(format!("env {}: {}", name, e));
+                }
+            }
+        }
+    }
+
+    /// Set the setting by its TOML key
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let path = || (!value.is_empty()).then(|| value.to_string());
+        match key {
+            "addr" => self.addr = value.to_string(),
+            "scheduler_addr" => self.scheduler_addr = value.to_string(),
+            "basedir" => self.basedir = value.to_string(),
+            "task_name" => self.task_name = value.to_string(),
+            "cache_dir" => self.cache_dir = value.to_string(),
+            "prover_model" => self.prover_model = value.parse()?,
+            "force_bits" => self.force_bits = parse_number(value)?,
+            "fork_id" => self.fork_id = parse_number(value)?,
+            "url" => self.url = value.to_string(),
+            "max_queue_depth" => self.max_queue_depth = parse_number(value)?,
+            "task_store" => self.task_store = value.to_string(),
+            "task_names" => self.task_names = parse_list(value),
+            "links.circomlib" => self.links.circomlib = path(),
+            "links.stark_verifier_gl" => self.links.stark_verifier_gl = path(),
+            "links.stark_verifier_bn128" => self.links.stark_verifier_bn128 = path(),
+            "links.stark_verifier_bls12381" => self.links.stark_verifier_bls12381 = path(),
+            "worker_pool.threads" => self.worker_pool.threads = parse_number(value)?,
+            "worker_pool.memory_budget" => self.worker_pool.memory_budget = parse_number(value)?,
+            "worker_pool.batch_memory" => self.worker_pool.batch_memory = parse_number(value)?,
+            "worker_pool.aggregate_memory" => {
+                self.worker_pool.aggregate_memory = parse_number(value)?
+            }
+            "worker_pool.final_memory" => self.worker_pool.final_memory = parse_number(value)?,
+            "retry.max_attempts" => self.retry.max_attempts = parse_number(value)?,
+            "retry.initial_backoff" => {
+                self.retry.initial_backoff = Duration::from_secs(parse_number(value)?)
+            }
+            "retry.max_backoff" => {
+                self.retry.max_backoff = Duration::from_secs(parse_number(value)?)
+            }
+            "retry.non_retryable" => self.retry.non_retryable = parse_list(value),
+            "deadlines.batch" => self.deadlines.batch = Duration::from_secs(parse_number(value)?),
+            "deadlines.aggregate" => {
+                self.deadlines.aggregate = Duration::from_secs(parse_number(value)?)
+            }
+            "deadlines.final" => {
+                self.deadlines.final_stage = Duration::from_secs(parse_number(value)?)
+            }
+            "retention.keep_final_only" => {
+                self.retention.keep_final_only = value
+                    .parse()
+                    .map_err(|_| format!("can not parse `{}` to bool", value))?
+            }
+            "retention.keep_latest" => self.retention.keep_latest = parse_number(value)?,
+            "retention.max_bytes" => self.retention.max_bytes = parse_number(value)?,
+            "requirements.min_cores" => self.requirements.min_cores = parse_number(value)?,
+            "requirements.min_memory" => self.requirements.min_memory = parse_number(value)?,
+            "requirements.cpu_features" => self.requirements.cpu_features = parse_list(value),
+            "requirements.build_version" => self.requirements.build_version = value.to_string(),
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Return the invalid or missing settings required by the component
+    pub fn check(&self, component: Component) -> Vec<String> {
+        let mut errors = vec![];
+        if self.basedir.is_empty() {
+            errors.push(missing("basedir"));
+        }
+        if self.task_name.is_empty() {
+            errors.push(missing("task_name"));
+        }
+        if !matches!(self.task_store.as_str(), "fs" | "sqlite") {
+            errors.push(format!(
+                "task_store: `{}` is not fs or sqlite",
+                self.task_store
+            ));
+        }
+        if self.worker_pool.threads == 0 {
+            errors.push("worker_pool.threads: must be at least 1".to_string());
+        }
+        if self.retry.max_attempts == 0 {
+            errors.push("retry.max_attempts: must be at least 1".to_string());
+        }
+        if self.retry.initial_backoff > self.retry.max_backoff {
+            errors.push(format!(
+                "retry.initial_backoff: {}s is larger than retry.max_backoff: {}s",
+                self.retry.initial_backoff.as_secs(),
+                self.retry.max_backoff.as_secs()
+            ));
+        }
+
+        // the circuits of the stages are compiled with the libraries, the gc doesn't prove
+        let required = component != Component::Gc;
+        let links = [
+            ("links.circomlib", &self.links.circomlib, required),
+            (
+                "links.stark_verifier_gl",
+                &self.links.stark_verifier_gl,
+                required,
+            ),
+            (
+                "links.stark_verifier_bn128",
+                &self.links.stark_verifier_bn128,
+                required,
+            ),
+            (
+                "links.stark_verifier_bls12381",
+                &self.links.stark_verifier_bls12381,
+                false,
+            ),
+        ];
+        for (key, dir, required) in links {
+            match dir {
+                Some(dir) if !Path::new(dir).is_dir() => {
+                    errors.push(format!("{}: `{}` is not a directory", key, dir))
+                }
+                None if required => errors.push(missing(key)),
+                _ => {}
+            }
+        }
+
+        match component {
+            Component::Service => {
+                if let Err(e) = self.addr.parse::<SocketAddr>() {
+                    errors.push(format!(
+                        "addr: `{}` is not a socket address, {}",
+                        self.addr, e
+                    ));
+                }
+                if self.url.is_empty() {
+                    errors.push(missing("url"));
+                }
+            }
+            Component::BatchProver => {
+                if self.scheduler_addr.is_empty() {
+                    errors.push(missing("scheduler_addr"));
+                }
+            }
+            Component::Gc => {
+                if !self.retention.is_enabled() {
+                    errors.push(
+                        "retention: nothing to prune, set retention.keep_final_only, retention.keep_latest or retention.max_bytes"
+                            .to_string(),
+                    );
+                }
+            }
+        }
+        errors
+    }
+
+    /// The CLI flags and their env, printed by `--help`
+    pub fn usage() -> String {
+        let mut usage = format!(
+            "Options, they override the env, which overrides the config file:\n  --config <file>    default: $CONF_DIR/{}\n",
+            CONFIG_FILE
+        );
+        for (key, name) in SETTINGS {
+            usage.push_str(&format!("  {:<38} env: {}\n", flag_of(key), name));
+        }
+        usage
+    }
+}
+
+impl FromStr for ProverModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(ProverModel::Local),
+            "grpc" => Ok(ProverModel::GRPC),
+            _ => Err(format!("`{}` is not local or grpc", s)),
+        }
+    }
+}
+
+/// Deserialize the duration from the seconds
+pub(crate) fn deserialize_secs<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    Ok(Duration::from_secs(u64::deserialize(d)?))
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("can not parse `{}` to number", value))
+}
+
+/// Parse the comma separated list, the empty items are skipped
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn flag_of(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+fn missing(key: &str) -> String {
+    let name = SETTINGS
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, name)| *name)
+        .unwrap_or_default();
+    format!(
+        "{} is missing, set it in the config file, the env {} or the flag {}",
+        key,
+        name,
+        flag_of(key)
+    )
+}
+
+/// Split the CLI flags into the config file and the settings: (flag, key, value)
+fn parse_args<I: IntoIterator<Item = String>>(
+    args: I,
+    errors: &mut Vec<String>,
+) -> (Option<String>, Vec<(String, String, String)>) {
+    let mut config_file = None;
+    let mut flags = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            errors.push(format!("unexpected argument `{}`", arg));
+            continue;
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (flag.to_string(), args.next()),
+        };
+        let Some(value) = value else {
+            errors.push(format!("--{} requires a value", name));
+            continue;
+        };
+
+        if name == "config" {
+            config_file = Some(value);
+            continue;
+        }
+        let key = name.replace('-', "_");
+        if SETTINGS.iter().any(|(k, _)| *k == key) {
+            flags.push((format!("--{}", name), key, value));
+        } else {
+            errors.push(format!("unknown flag --{}", name));
+        }
+    }
+    (config_file, flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_load_config() {
+        let dir = env::temp_dir().join(format!("pipeline_config_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let links = dir.to_string_lossy().to_string();
+        let file = dir.join(CONFIG_FILE);
+        std::fs::write(
+            &file,
+            format!(
+                r#"
+addr = "127.0.0.1:50061"
+force_bits = 16
+task_name = "evm"
+
+[links]
+circomlib = "{links}"
+stark_verifier_gl = "{links}"
+stark_verifier_bn128 = "{links}"
+
+[retry]
+initial_backoff = 5
+
+[requirements]
+min_memory = 1024
+"#
+            ),
+        )
+        .unwrap();
+
+        // the env overrides the file, the flags override the env
+        let vars: HashMap<&str, &str> = [
+            ("CONF_DIR", links.as_str()),
+            ("FORCE_BIT", "17"),
+            ("PROVER_MODEL", "grpc"),
+            ("TASK_NAME", "lr"),
+            ("BATCH_CPU_FEATURES", "avx2, avx512f"),
+        ]
+        .into();
+        let env = |name: &str| vars.get(name).map(|v| v.to_string());
+        let args = ["--force-bits", "18", "--worker-pool.threads=4"].map(String::from);
+        let config = PipelineConfig::load_with(args, env, Component::Service).unwrap();
+        assert_eq!(config.addr, "127.0.0.1:50061");
+        assert_eq!(config.force_bits, 18);
+        assert_eq!(config.task_name, "lr");
+        assert_eq!(config.prover_model, ProverModel::GRPC);
+        assert_eq!(config.worker_pool.threads, 4);
+        assert_eq!(config.retry.initial_backoff, Duration::from_secs(5));
+        assert_eq!(
+            config.retry.max_attempts,
+            RetryPolicy::default().max_attempts
+        );
+        assert_eq!(config.links.circomlib.as_deref(), Some(links.as_str()));
+        assert_eq!(config.requirements.min_memory, 1024);
+        assert_eq!(config.requirements.cpu_features, vec!["avx2", "avx512f"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let vars: HashMap<&str, &str> = [
+            ("CONF_DIR", "/nonexistent"),
+            ("FORCE_BIT", "abc"),
+            ("PROVER_MODEL", "remote"),
+            ("CIRCOMLIB", "/nonexistent"),
+        ]
+        .into();
+        let env = |name: &str| vars.get(name).map(|v| v.to_string());
+        let args = ["--max-queue-depth", "-1", "--unknown", "1", "--addr"].map(String::from);
+        let err = PipelineConfig::load_with(args, env, Component::Service).unwrap_err();
+
+        // all the errors are reported at once
+        assert_eq!(
+            err.errors,
+            vec![
+                "unknown flag --unknown".to_string(),
+                "--addr requires a value".to_string(),
+                "config file /nonexistent/base_config.toml does not exist".to_string(),
+                "env PROVER_MODEL: `remote` is not local or grpc".to_string(),
+                "env FORCE_BIT: can not parse `abc` to number".to_string(),
+                "--max-queue-depth: can not parse `-1` to number".to_string(),
+                "links.circomlib: `/nonexistent` is not a directory".to_string(),
+                missing("links.stark_verifier_gl"),
+                missing("links.stark_verifier_bn128"),
+            ]
+        );
+        assert!(err.to_string().contains("\n  - env FORCE_BIT"));
+
+        // the file must be valid too
+        let dir = env::temp_dir().join(format!("pipeline_config_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("invalid.toml");
+        std::fs::write(&file, "force_bit = 18\n").unwrap();
+        let args = ["--config".to_string(), file.to_string_lossy().to_string()];
+        let err = PipelineConfig::load_with(args, |_| None, Component::BatchProver).unwrap_err();
+        assert!(err.errors[0].contains("unknown field `force_bit`"));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // the gc doesn't need the links, but something to prune
+        let err = PipelineConfig::load_with(vec![], |_| None, Component::Gc).unwrap_err();
+        assert_eq!(err.errors.len(), 1);
+        assert!(err.errors[0].starts_with("retention: nothing to prune"));
+        let args = ["--retention.keep-latest", "2"].map(String::from);
+        let config = PipelineConfig::load_with(args, |_| None, Component::Gc).unwrap();
+        assert_eq!(config.retention.keep_latest, 2);
+    }
+}
@@ -1,9 +1,13 @@
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use super::ProveDataCache;
 use crate::args::CircomCompileArgs;
+use crate::args::LinkConfig;
 use crate::args::StarkProveArgs;
-use crate::stage::Stage;
+use crate::curve::Curve;
+use crate::layout::ProofLayout;
+use crate::retry::NonRetryableError;
 use std::sync::{Arc, Mutex};
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -23,6 +27,7 @@ pub struct AggContext {
 }
 
 impl AggContext {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         basedir: &str,
         task_id: &str,
@@ -30,9 +35,11 @@ impl AggContext {
         input: String,
         input2: String,
         force_bits: usize,
+        links: &LinkConfig,
         prove_data_cache: Arc<Mutex<ProveDataCache>>,
     ) -> Self {
-        let task_path = Stage::Aggregate(task_id.to_string(), input.clone(), input2.clone()).path();
+        let layout = ProofLayout::new(basedir);
+        let task_path = ProofLayout::agg_path(task_id);
         let r2_task_name = format!("{}.recursive2", task_name);
 
         AggContext {
@@ -40,12 +47,47 @@ impl AggContext {
             task_name: task_name.to_string(),
             input,
             input2,
-            agg_zkin: format!("{}/proof/{}/agg_zkin.json", basedir, task_id),
-            agg_struct: format!("{}/{}/c12.stark_struct.json", basedir, task_name), // should be same as c12
-            agg_stark: StarkProveArgs::new(basedir, &task_path, &r2_task_name, "GL"),
-            agg_circom: CircomCompileArgs::new(basedir, &task_path, &r2_task_name, "GL"),
+            agg_zkin: layout.agg_zkin(task_id),
+            agg_struct: layout.stark_struct(task_name, "c12"), // should be same as c12
+            agg_stark: StarkProveArgs::new(&layout, &task_path, &r2_task_name, Curve::GL),
+            agg_circom: CircomCompileArgs::new(
+                &layout,
+                &task_path,
+                &r2_task_name,
+                Curve::GL,
+                links,
+            ),
             prove_data_cache,
             force_bits,
         }
     }
+
+    /// The task id and the range of the chunks, e.g. `0_chunk_0` to `0_chunk_2` is ("0", 0, 2)
+    pub fn chunks(&self) -> Result<(String, usize, usize)> {
+        let parse = |input: &str| {
+            let (task_id, chunk_id) = input.split_once("_chunk_")?;
+            Some((task_id.to_string(), chunk_id.parse::<usize>().ok()?))
+        };
+        match (parse(&self.input), parse(&self.input2)) {
+            (Some((task_id, start)), Some((task_id2, end))) if task_id == task_id2 => {
+                Ok((task_id, start, end))
+            }
+            // the input is invalid, retrying the stage can't succeed
+            _ => Err(NonRetryableError(format!(
+                "can not aggregate {} to {}, they should be the chunks of a task",
+                self.input, self.input2
+            ))
+            .into()),
+        }
+    }
+
+    /// The recursive1 zkin of the chunk, written by the batch stage
+    pub fn chunk_zkin(&self, task_id: &str, chunk: usize) -> String {
+        ProofLayout::new(&self.basedir).chunk_zkin(task_id, &self.task_name, chunk)
+    }
+
+    /// The zkin joined from the previous chunks and the chunk
+    pub fn joined_zkin(&self, task_id: &str, chunk: usize) -> String {
+        ProofLayout::new(&self.basedir).joined_zkin(task_id, &self.task_name, chunk)
+    }
 }
@@ -1,13 +1,18 @@
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use crate::args::CircomCompileArgs;
+use crate::args::LinkConfig;
 use crate::args::StarkProveArgs;
-use crate::stage::Stage;
+use crate::blob_store::BlobStore;
+use crate::curve::Curve;
+use crate::layout::ProofLayout;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct BatchContext {
     pub basedir: String,
-    pub l2_batch_data: String,
+    /// The digest of the batch data in the BlobStore, empty if there is no batch data
+    pub batch_digest: String,
     pub batch_circom: CircomCompileArgs,
     pub batch_stark: StarkProveArgs,
     pub batch_struct: String,
@@ -29,57 +34,61 @@ impl BatchContext {
         task_id: &str,
         task_name: &str,
         chunk_id: &str,
-        l2_batch_data: String,
+        batch_digest: String,
         force_bits: usize,
+        links: &LinkConfig,
     ) -> Self {
-        //TODO : don't clone the l2 batch data
-        let task_path = Stage::Batch(
-            task_id.to_string(),
-            chunk_id.to_string(),
-            l2_batch_data.clone(),
-        )
-        .path();
+        let layout = ProofLayout::new(basedir);
+        let task_path = ProofLayout::batch_path(task_id, chunk_id);
         let c12_task_name = format!("{}.c12", task_name);
-
         let r1_task_name = format!("{}.recursive1", task_name);
-        let evm_output = format!("{basedir}/{task_path}/../{task_name}",);
 
         BatchContext {
             basedir: basedir.to_string(),
-            l2_batch_data,
+            batch_digest,
             task_id: task_id.to_string(),
             task_name: task_name.to_string(),
-            batch_struct: format!("{}/{}/batch.stark_struct.json", basedir, task_name),
-            c12_struct: format!("{}/{}/c12.stark_struct.json", basedir, task_name),
+            batch_struct: layout.stark_struct(task_name, "batch"),
+            c12_struct: layout.stark_struct(task_name, "c12"),
             batch_circom: CircomCompileArgs::new_batch(
-                &evm_output,
-                basedir,
-                &task_path,
+                &layout,
+                task_id,
                 task_name,
                 chunk_id,
-                "GL",
+                Curve::GL,
+                links,
             ),
-
-            batch_stark: StarkProveArgs {
-                commit_file: format!("{evm_output}/{task_name}_chunk_{chunk_id}/commits.bin",),
-                const_file: format!("{evm_output}/constants.bin",),
-                curve_type: "GL".to_string(),
-                exec_file: format!("{basedir}/{task_path}/{task_name}_chunk_{chunk_id}.exec",),
-                pil_file: format!("{basedir}/{task_path}/{task_name}_chunk_{chunk_id}.pil", ),
-                piljson: format!("{basedir}/{task_path}/{task_name}_chunk_{chunk_id}.pil.json", ),
-                r1cs_file: format!("{basedir}/{task_path}/{task_name}_chunk_{chunk_id}.r1cs",),
-                wasm_file: format!("{basedir}/{task_path}/{task_name}_chunk_{chunk_id}_js/{task_name}_chunk_{chunk_id}.wasm",),
-                zkin: format!("{evm_output}/{task_name}_chunk_{chunk_id}/{task_name}_proof.bin",),
-            },
-
-            evm_output,
+            batch_stark: StarkProveArgs::new_batch(&layout, task_id, task_name, chunk_id),
+            evm_output: layout.chunks_dir(task_id, task_name),
             chunk_id: chunk_id.to_string(),
-            c12_stark: StarkProveArgs::new(basedir, &task_path, &c12_task_name, "GL"),
-            c12_circom: CircomCompileArgs::new(basedir, &task_path, &c12_task_name, "GL"),
+            c12_stark: StarkProveArgs::new(&layout, &task_path, &c12_task_name, Curve::GL),
+            c12_circom: CircomCompileArgs::new(
+                &layout,
+                &task_path,
+                &c12_task_name,
+                Curve::GL,
+                links,
+            ),
 
-            recursive1_stark: StarkProveArgs::new(basedir, &task_path, &r1_task_name, "GL"),
-            recursive1_circom: CircomCompileArgs::new(basedir, &task_path, &r1_task_name, "GL"),
+            recursive1_stark: StarkProveArgs::new(&layout, &task_path, &r1_task_name, Curve::GL),
+            recursive1_circom: CircomCompileArgs::new(
+                &layout,
+                &task_path,
+                &r1_task_name,
+                Curve::GL,
+                links,
+            ),
             force_bits,
         }
     }
+
+    /// Load the batch data from the BlobStore of the basedir
+    pub fn batch_data(&self) -> Result<String> {
+        BlobStore::new(&self.basedir).get_batch_data(&self.batch_digest)
+    }
+
+    /// The input of the bootloader, written by the executor
+    pub fn bootloader_input(&self) -> String {
+        ProofLayout::new(&self.basedir).chunk_input(&self.task_id, &self.task_name, &self.chunk_id)
+    }
 }
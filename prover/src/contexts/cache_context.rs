@@ -1,6 +1,9 @@
+use crate::curve::Curve;
+use crate::retry::NonRetryableError;
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fs,
     path::{Path, PathBuf},
 };
@@ -9,7 +12,7 @@ use std::{
 pub enum CacheStage {
     Agg(StarkFileType),
     Final(StarkFileType),
-    Snark(SnarkFileType),
+    Snark(Curve, SnarkFileType),
 }
 
 #[derive(Clone, Copy, Default)]
@@ -23,12 +26,6 @@ pub enum StarkFileType {
     Wasm,
 }
 
-#[derive(Clone, Copy)]
-pub enum Curve {
-    BN128(SnarkFileType),
-    BLS12381(SnarkFileType),
-}
-
 #[derive(Clone, Copy, Default)]
 pub enum SnarkFileType {
     #[default]
@@ -45,7 +42,8 @@ pub struct ProveDataCache {
     pub cache_dir: String,
     pub agg_cache: AggData,
     pub final_cache: FinalData,
-    pub snark_cache: SnarkData,
+    /// The groth16 setup of each curve, the keys of the curves coexist
+    pub snark_cache: BTreeMap<Curve, SnarkData>,
 }
 // task_name: String, base_dir: String, cach_dir: String, stage: CacheStage
 impl ProveDataCache {
@@ -69,10 +67,17 @@ impl ProveDataCache {
                 already_cached,
                 ..Default::default()
             },
-            snark_cache: SnarkData {
-                already_cached,
-                ..Default::default()
-            },
+            snark_cache: Curve::SNARK_CURVES
+                .into_iter()
+                .map(|curve| {
+                    let snark = SnarkData {
+                        already_cached,
+                        curve_type: curve,
+                        ..Default::default()
+                    };
+                    (curve, snark)
+                })
+                .collect(),
         }
         .load()
     }
@@ -151,29 +156,36 @@ impl ProveDataCache {
             );
         }
 
-        if self.snark_cache.already_cached {
-            self.snark_cache.add(
-                format!("{}/snark/{}.final.wasm", self.cache_dir, self.task_name),
-                SnarkFileType::Wasm,
-            );
-            self.snark_cache.add(
-                format!("{}/snark/{}.final.r1cs", self.cache_dir, self.task_name),
-                SnarkFileType::R1cs,
-            );
-            self.snark_cache.add(
-                format!("{}/snark/g16.key", self.cache_dir),
-                SnarkFileType::PK,
-            );
-            self.snark_cache.add(
-                format!("{}/snark/verification_key.json", self.cache_dir),
-                SnarkFileType::VK,
-            );
+        for (curve, snark) in self.snark_cache.iter_mut() {
+            // a cache dir may be filled before the curve is supported, its setup is generated then
+            let dir = format!("{}/{}", self.cache_dir, snark_stage(*curve));
+            snark.already_cached &= Path::new(&dir).exists();
+            if snark.already_cached {
+                snark.add(
+                    format!("{}/{}.final.wasm", dir, self.task_name),
+                    SnarkFileType::Wasm,
+                );
+                snark.add(
+                    format!("{}/{}.final.r1cs", dir, self.task_name),
+                    SnarkFileType::R1cs,
+                );
+                snark.add(format!("{}/g16.key", dir), SnarkFileType::PK);
+                snark.add(format!("{}/verification_key.json", dir), SnarkFileType::VK);
+            }
         }
 
         log::debug!("Load cache done, {:?}", self);
         self
     }
 
+    /// The groth16 setup of the curve
+    pub fn snark(&mut self, curve: Curve) -> &mut SnarkData {
+        self.snark_cache.entry(curve).or_insert_with(|| SnarkData {
+            curve_type: curve,
+            ..Default::default()
+        })
+    }
+
     pub fn batch_add(&mut self, caches: Vec<(String, CacheStage)>) -> Result<()> {
         caches
             .iter()
@@ -209,7 +221,9 @@ impl ProveDataCache {
         match stage {
             CacheStage::Agg(file_type) => self.agg_cache.add(cache_path.clone(), file_type),
             CacheStage::Final(file_type) => self.final_cache.add(cache_path.clone(), file_type),
-            CacheStage::Snark(file_type) => self.snark_cache.add(cache_path.clone(), file_type),
+            CacheStage::Snark(curve, file_type) => {
+                self.snark(curve).add(cache_path.clone(), file_type)
+            }
         }
         Ok(())
     }
@@ -231,6 +245,27 @@ pub struct StarkFile {
 }
 
 impl StarkFile {
+    /// Use the files the stage generated in its workdir, they are not shared with other stages
+    pub fn add_all(&mut self, files: &[(String, CacheStage)]) {
+        for (path, stage) in files {
+            if let CacheStage::Agg(file_type) | CacheStage::Final(file_type) = stage {
+                self.add(path.clone(), *file_type);
+            }
+        }
+    }
+
+    /// The cached setup is used as is, the stage can't succeed by retrying if a file of it is missing
+    pub fn check(&self) -> Result<()> {
+        check_cached(&[
+            &self.r1cs_file,
+            &self.pil_file,
+            &self.piljson_file,
+            &self.const_file,
+            &self.exec_file,
+            &self.wasm_file,
+        ])
+    }
+
     pub fn add(&mut self, cache_path: String, file_type: StarkFileType) {
         match file_type {
             StarkFileType::R1cs => self.r1cs_file = cache_path,
@@ -246,7 +281,7 @@ impl StarkFile {
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct SnarkFile {
     pub already_cached: bool,
-    pub curve_type: String,
+    pub curve_type: Curve,
     pub r1cs_file: String,
     pub pk_file: String,
     pub vk_file: String,
@@ -254,6 +289,25 @@ pub struct SnarkFile {
 }
 
 impl SnarkFile {
+    /// Use the files the stage generated in its workdir, they are not shared with other stages
+    pub fn add_all(&mut self, files: &[(String, CacheStage)]) {
+        for (path, stage) in files {
+            if let CacheStage::Snark(_, file_type) = stage {
+                self.add(path.clone(), *file_type);
+            }
+        }
+    }
+
+    /// The cached setup is used as is, the stage can't succeed by retrying if a file of it is missing
+    pub fn check(&self) -> Result<()> {
+        check_cached(&[
+            &self.r1cs_file,
+            &self.pk_file,
+            &self.vk_file,
+            &self.wasm_file,
+        ])
+    }
+
     pub fn add(&mut self, cache_path: String, file_type: SnarkFileType) {
         match file_type {
             SnarkFileType::PK => self.pk_file = cache_path,
@@ -264,16 +318,31 @@ impl SnarkFile {
     }
 }
 
+fn check_cached(files: &[&String]) -> Result<()> {
+    match files.iter().find(|f| !Path::new(f.as_str()).exists()) {
+        Some(f) => Err(NonRetryableError(format!("the cached setup file {} is missing", f)).into()),
+        None => Ok(()),
+    }
+}
+
 impl From<CacheStage> for String {
     fn from(cache_stage: CacheStage) -> Self {
         match cache_stage {
             CacheStage::Agg(_) => String::from("agg"),
             CacheStage::Final(_) => String::from("final"),
-            CacheStage::Snark(_) => String::from("snark"),
+            CacheStage::Snark(curve, _) => snark_stage(curve),
         }
     }
 }
 
+/// The BN128 setup keeps the dir `snark` of the caches filled before the curves were split
+fn snark_stage(curve: Curve) -> String {
+    match curve {
+        Curve::BN128 => String::from("snark"),
+        _ => format!("snark_{}", curve.prime()),
+    }
+}
+
 impl CacheStage {
     pub fn construct_stage_dir(
         self,
@@ -292,3 +361,50 @@ impl CacheStage {
         self.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_snark_cache_per_curve() {
+        let basedir = env::temp_dir().join(format!("cache_{}", Uuid::new_v4()));
+        let cache_dir = basedir.join("cache").to_string_lossy().to_string();
+        fs::create_dir_all(format!("{}/snark", cache_dir)).unwrap();
+
+        // the BLS12381 setup isn't in the cache dir, it's generated and added
+        let mut cache = ProveDataCache::new("evm".into(), "".into(), cache_dir.clone());
+        assert!(cache.snark(Curve::BN128).already_cached);
+        assert_eq!(
+            cache.snark(Curve::BN128).pk_file,
+            format!("{}/snark/g16.key", cache_dir)
+        );
+        assert!(!cache.snark(Curve::BLS12381).already_cached);
+
+        let pk_file = basedir.join("g16.key").to_string_lossy().to_string();
+        fs::write(&pk_file, "bls12381").unwrap();
+        cache.base_dir = basedir.to_string_lossy().to_string();
+        cache
+            .add(
+                pk_file,
+                CacheStage::Snark(Curve::BLS12381, SnarkFileType::PK),
+            )
+            .unwrap();
+        let bls_pk = cache.snark(Curve::BLS12381).pk_file.clone();
+        assert!(bls_pk.ends_with("evm/snark_bls12381/g16.key"));
+        assert_eq!(fs::read_to_string(bls_pk).unwrap(), "bls12381");
+        assert_eq!(
+            cache.snark(Curve::BN128).pk_file,
+            format!("{}/snark/g16.key", cache_dir)
+        );
+        assert_eq!(cache.snark(Curve::BLS12381).curve_type, Curve::BLS12381);
+
+        // the setup of BN128 is not in its cache dir, the missing file is not retried
+        let err = cache.snark(Curve::BN128).check().unwrap_err();
+        assert!(err.is::<NonRetryableError>());
+        assert!(err.to_string().contains("evm.final.r1cs"));
+        fs::remove_dir_all(basedir).unwrap();
+    }
+}
@@ -3,8 +3,14 @@ use serde::{Deserialize, Serialize};
 use super::ProveDataCache;
 use crate::args::CircomCompileArgs;
 use crate::args::FinalProveArgs;
+use crate::args::LinkConfig;
 use crate::args::StarkProveArgs;
-use crate::stage::Stage;
+use crate::curve::Curve;
+use crate::layout::{
+    CircuitFile, ProofLayout, PROOF_FILE, PROVING_KEY_FILE, PUBLIC_INPUT_FILE,
+    VERIFICATION_KEY_FILE,
+};
+use crate::stage::final_task_id;
 use std::sync::{Arc, Mutex};
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -20,52 +26,62 @@ pub struct FinalContext {
     pub recursive2_circom: CircomCompileArgs,
     pub recursive2_stark: StarkProveArgs,
 
+    /// The task id of the aggregation
     pub task_id: String,
     pub task_name: String,
     pub prove_data_cache: Arc<Mutex<ProveDataCache>>,
 }
 
 impl FinalContext {
+    /// Only the recursive2 circuit and zkin are read from the aggregation, everything the stage writes
+    /// is in the dir of the final task, so the final proofs of another curve don't share files.
     pub fn new(
         basedir: String,
         task_id: String,
         task_name: String,
-        curve: String,
+        curve: Curve,
         prover_addr: String,
+        links: &LinkConfig,
         prove_data_cache: Arc<Mutex<ProveDataCache>>,
     ) -> Self {
-        let id = format!("{}_agg", task_id);
-        let prev_task_path = Stage::Aggregate(id, "".into(), "".into()).path();
-        let task_path = Stage::Final(task_id.clone(), curve.clone(), prover_addr.clone()).path();
+        let layout = ProofLayout::new(&basedir);
+        let prev_task_path = ProofLayout::agg_path(&format!("{}_agg", task_id));
         let r2_task_name = format!("{}.recursive2", task_name);
         let final_task_name = format!("{}.final", task_name);
+        let final_task_id = final_task_id(&task_id, curve, &prover_addr);
+        let task_path = ProofLayout::final_path(&final_task_id);
+        let final_file = |name| layout.final_file(&final_task_id, name);
+        let recursive2_stark = StarkProveArgs {
+            zkin: layout.circuit_file(&prev_task_path, &r2_task_name, CircuitFile::Zkin),
+            ..StarkProveArgs::new(&layout, &task_path, &r2_task_name, curve)
+        };
+        let recursive2_circom = CircomCompileArgs {
+            circom_file: layout.circuit_file(&prev_task_path, &r2_task_name, CircuitFile::Circom),
+            ..CircomCompileArgs::new(&layout, &task_path, &r2_task_name, Curve::GL, links)
+        };
         FinalContext {
             basedir: basedir.clone(),
-            task_id,
             prover_addr,
             task_name: task_name.clone(),
-            final_stark_struct: format!("{}/{}/final.stark_struct.json", basedir, task_name),
-            final_stark: StarkProveArgs::new(&basedir, &prev_task_path, &final_task_name, &curve),
-            recursive2_stark: StarkProveArgs::new(&basedir, &prev_task_path, &r2_task_name, &curve),
+            final_stark_struct: layout.final_stark_struct(&task_name, curve),
+            final_stark: StarkProveArgs::new(&layout, &task_path, &final_task_name, curve),
+            recursive2_stark,
             final_circom: CircomCompileArgs::new(
-                &basedir,
-                &prev_task_path,
+                &layout,
+                &task_path,
                 &final_task_name,
-                &curve,
-            ),
-            recursive2_circom: CircomCompileArgs::new(
-                &basedir,
-                &prev_task_path,
-                &r2_task_name,
-                "GL",
+                curve,
+                links,
             ),
+            recursive2_circom,
             final_snark: FinalProveArgs {
                 curve_type: curve,
-                pk_file: format!("{basedir}/{task_path}/g16.key"),
-                vk_file: format!("{basedir}/{task_path}/verification_key.json"),
-                public_input_file: format!("{basedir}/{task_path}/public_input.json"),
-                proof_file: format!("{basedir}/{task_path}/proof.json"),
+                pk_file: final_file(PROVING_KEY_FILE),
+                vk_file: final_file(VERIFICATION_KEY_FILE),
+                public_input_file: final_file(PUBLIC_INPUT_FILE),
+                proof_file: final_file(PROOF_FILE),
             },
+            task_id,
             prove_data_cache,
         }
     }
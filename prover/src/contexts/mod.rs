@@ -8,6 +8,221 @@ mod final_context;
 pub use final_context::FinalContext;
 
 mod cache_context;
-pub use cache_context::{
-    CacheStage, Curve, ProveDataCache, SnarkFile, SnarkFileType, StarkFileType,
-};
+pub use cache_context::{CacheStage, ProveDataCache, SnarkFile, SnarkFileType, StarkFileType};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::LinkConfig;
+    use crate::curve::Curve;
+    use crate::layout::ProofLayout;
+    use crate::stage::{final_task_id, Stage};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_context_layout() {
+        let layout = ProofLayout::new("/data");
+        let links = LinkConfig {
+            circomlib: Some("/circomlib".into()),
+            stark_verifier_gl: Some("/gl".into()),
+            stark_verifier_bn128: Some("/bn128".into()),
+            ..Default::default()
+        };
+        let batch = BatchContext::new("/data", "7", "evm", "1", "".into(), 0, &links);
+        // the circuits are linked with the libraries of their curves
+        assert_eq!(batch.c12_circom.link_directories, vec!["/gl", "/circomlib"]);
+        assert_eq!(
+            batch.bootloader_input(),
+            "/data/proof/7/evm/evm_chunks_1.data"
+        );
+        assert_eq!(
+            batch.bootloader_input(),
+            layout.chunk_input("7", "evm", "1")
+        );
+        assert_eq!(batch.evm_output, "/data/proof/7/evm");
+        assert_eq!(batch.batch_struct, "/data/evm/batch.stark_struct.json");
+        assert_eq!(batch.c12_struct, "/data/evm/c12.stark_struct.json");
+        assert_eq!(
+            batch.batch_circom.circom_file,
+            "/data/proof/7/evm/evm_chunk_1.circom"
+        );
+        assert_eq!(batch.batch_circom.output, "/data/proof/7/batch_proof_1");
+        let bs = &batch.batch_stark;
+        assert_eq!(bs.commit_file, "/data/proof/7/evm/evm_chunk_1/commits.bin");
+        assert_eq!(bs.const_file, "/data/proof/7/evm/constants.bin");
+        assert_eq!(bs.exec_file, "/data/proof/7/batch_proof_1/evm_chunk_1.exec");
+        assert_eq!(bs.pil_file, "/data/proof/7/batch_proof_1/evm_chunk_1.pil");
+        assert_eq!(
+            bs.piljson,
+            "/data/proof/7/batch_proof_1/evm_chunk_1.pil.json"
+        );
+        assert_eq!(bs.r1cs_file, "/data/proof/7/batch_proof_1/evm_chunk_1.r1cs");
+        assert_eq!(
+            bs.wasm_file,
+            "/data/proof/7/batch_proof_1/evm_chunk_1_js/evm_chunk_1.wasm"
+        );
+        assert_eq!(bs.zkin, "/data/proof/7/evm/evm_chunk_1/evm_proof.bin");
+        let c12 = &batch.c12_stark;
+        assert_eq!(c12.commit_file, "/data/proof/7/batch_proof_1/evm.c12.cm");
+        assert_eq!(c12.const_file, "/data/proof/7/batch_proof_1/evm.c12.const");
+        assert_eq!(c12.exec_file, "/data/proof/7/batch_proof_1/evm.c12.exec");
+        assert_eq!(c12.pil_file, "/data/proof/7/batch_proof_1/evm.c12.pil");
+        assert_eq!(c12.piljson, "/data/proof/7/batch_proof_1/evm.c12.pil.json");
+        assert_eq!(c12.r1cs_file, "/data/proof/7/batch_proof_1/evm.c12.r1cs");
+        assert_eq!(
+            c12.wasm_file,
+            "/data/proof/7/batch_proof_1/evm.c12_js/evm.c12.wasm"
+        );
+        assert_eq!(c12.zkin, "/data/proof/7/batch_proof_1/evm.c12.zkin.json");
+        assert_eq!(
+            batch.c12_circom.circom_file,
+            "/data/proof/7/batch_proof_1/evm.c12.circom"
+        );
+        assert_eq!(
+            batch.recursive1_circom.circom_file,
+            "/data/proof/7/batch_proof_1/evm.recursive1.circom"
+        );
+        assert_eq!(
+            batch.recursive1_stark.zkin,
+            "/data/proof/7/batch_proof_1/evm.recursive1.zkin.json"
+        );
+
+        let cache = Arc::new(Mutex::new(ProveDataCache::default()));
+        let agg = AggContext::new(
+            "/data",
+            "a_agg",
+            "evm",
+            "7_chunk_0".into(),
+            "7_chunk_1".into(),
+            0,
+            &links,
+            cache.clone(),
+        );
+        assert_eq!(agg.agg_zkin, "/data/proof/a_agg/agg_zkin.json");
+        assert_eq!(agg.agg_struct, "/data/evm/c12.stark_struct.json");
+        assert_eq!(
+            agg.chunk_zkin("7", 1),
+            "/data/proof/7/batch_proof_1/evm.recursive1.zkin.json"
+        );
+        assert_eq!(
+            agg.joined_zkin("7", 1),
+            "/data/proof/7/batch_proof_1/evm_input.recursive1.zkin.json"
+        );
+        assert_eq!(
+            agg.agg_circom.circom_file,
+            "/data/proof/a_agg/agg_proof/evm.recursive2.circom"
+        );
+        assert_eq!(
+            agg.agg_stark.zkin,
+            "/data/proof/a_agg/agg_proof/evm.recursive2.zkin.json"
+        );
+
+        let fin = FinalContext::new(
+            "/data".into(),
+            "a".into(),
+            "evm".into(),
+            Curve::BN128,
+            "addr".into(),
+            &links,
+            cache.clone(),
+        );
+        assert_eq!(fin.final_stark_struct, "/data/evm/final.stark_struct.json");
+        assert_eq!(
+            fin.recursive2_stark.zkin,
+            "/data/proof/a_agg/agg_proof/evm.recursive2.zkin.json"
+        );
+        assert_eq!(
+            fin.recursive2_circom.circom_file,
+            "/data/proof/a_agg/agg_proof/evm.recursive2.circom"
+        );
+        assert_eq!(
+            fin.final_circom.link_directories,
+            vec!["/bn128", "/circomlib"]
+        );
+        // the final stark and the snark proof are saved by the task id of the final proof
+        let final_dir = format!(
+            "/data/proof/{}/snark_proof",
+            final_task_id("a", Curve::BN128, "addr")
+        );
+        assert_eq!(
+            fin.final_circom.circom_file,
+            format!("{final_dir}/evm.final.circom")
+        );
+        assert_eq!(fin.final_circom.output, final_dir);
+        assert_eq!(
+            fin.final_stark.zkin,
+            format!("{final_dir}/evm.final.zkin.json")
+        );
+        assert_eq!(
+            fin.recursive2_stark.commit_file,
+            format!("{final_dir}/evm.recursive2.cm")
+        );
+        let snark = &fin.final_snark;
+        assert_eq!(snark.pk_file, format!("{final_dir}/g16.key"));
+        assert_eq!(snark.vk_file, format!("{final_dir}/verification_key.json"));
+        assert_eq!(
+            snark.public_input_file,
+            format!("{final_dir}/public_input.json")
+        );
+        assert_eq!(snark.proof_file, format!("{final_dir}/proof.json"));
+
+        let bls = FinalContext::new(
+            "/data".into(),
+            "a".into(),
+            "evm".into(),
+            Curve::BLS12381,
+            "addr".into(),
+            &links,
+            cache,
+        );
+        assert_eq!(
+            bls.final_stark_struct,
+            "/data/evm/final.bls12381.stark_struct.json"
+        );
+        // the final proofs of both curves read the same aggregation, but write no file in common
+        assert_eq!(bls.recursive2_stark.zkin, fin.recursive2_stark.zkin);
+        assert_eq!(
+            bls.recursive2_circom.circom_file,
+            fin.recursive2_circom.circom_file
+        );
+        let outputs = |ctx: &FinalContext| {
+            let starks = [&ctx.final_stark, &ctx.recursive2_stark];
+            let mut files: Vec<String> = starks
+                .iter()
+                .flat_map(|s| {
+                    [
+                        &s.commit_file,
+                        &s.const_file,
+                        &s.exec_file,
+                        &s.pil_file,
+                        &s.piljson,
+                        &s.r1cs_file,
+                        &s.wasm_file,
+                    ]
+                })
+                .cloned()
+                .collect();
+            let snark = &ctx.final_snark;
+            files.extend([
+                ctx.final_stark.zkin.clone(),
+                ctx.final_circom.circom_file.clone(),
+                ctx.final_circom.output.clone(),
+                ctx.recursive2_circom.output.clone(),
+                snark.pk_file.clone(),
+                snark.vk_file.clone(),
+                snark.public_input_file.clone(),
+                snark.proof_file.clone(),
+            ]);
+            files
+        };
+        let bn128_outputs = outputs(&fin);
+        assert!(outputs(&bls).iter().all(|f| !bn128_outputs.contains(f)));
+
+        let stage = Stage::Batch("7".into(), "1".into(), "".into());
+        assert_eq!(stage.path(), "proof/7/batch_proof_1");
+        let stage = Stage::Aggregate("a_agg".into(), "".into(), "".into());
+        assert_eq!(stage.path(), "proof/a_agg/agg_proof");
+        let stage = Stage::Final("a".into(), Curve::BN128, "addr".into());
+        assert_eq!(format!("/data/{}", stage.path()), final_dir);
+    }
+}
@@ -0,0 +1,53 @@
+use crate::config::deserialize_secs;
+use crate::task_queue::Priority;
+
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often the scheduler checks the deadlines of the batch tasks taken by the services,
+/// the pipeline checks its running stages every time it proves.
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The batch stage sent to the scheduler is sent again if it's not acknowledged in the timeout,
+/// unless the scheduler has started it.
+pub const HANDOFF_ACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The max duration of a stage since it's started, the stage is failed and retried after it.
+/// It's the `[deadlines]` of the PipelineConfig in seconds, 0 means the stage never times out.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StageDeadlines {
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub batch: Duration,
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub aggregate: Duration,
+    #[serde(rename = "final", deserialize_with = "deserialize_secs")]
+    pub final_stage: Duration,
+}
+
+impl Default for StageDeadlines {
+    fn default() -> Self {
+        StageDeadlines {
+            batch: Duration::from_secs(60 * 60),
+            aggregate: Duration::from_secs(60 * 30),
+            final_stage: Duration::from_secs(60 * 30),
+        }
+    }
+}
+
+impl StageDeadlines {
+    pub fn of(&self, priority: Priority) -> Option<Duration> {
+        let deadline = match priority {
+            Priority::Batch => self.batch,
+            Priority::Aggregate => self.aggregate,
+            Priority::Final => self.final_stage,
+        };
+        (!deadline.is_zero()).then_some(deadline)
+    }
+
+    /// The instant when the stage started at `start` times out
+    pub fn deadline(&self, priority: Priority, start: Instant) -> Option<Instant> {
+        self.of(priority).map(|d| start + d)
+    }
+}
@@ -1,8 +1,21 @@
 pub mod args;
+pub mod block_proof_job;
+pub mod config;
 pub mod contexts;
+pub mod deadline;
 pub mod provers;
+pub mod retention;
+pub mod retry;
 pub mod stage;
+pub mod task_queue;
+pub mod task_store;
+pub mod worker_pool;
 
 pub mod pipeline;
+pub mod pipeline_set;
+pub mod plan;
 
 pub mod scheduler;
+
+// the layout of the artifacts is shared with the executor
+pub use proof_layout::{blob_store, curve, layout};
@@ -1,86 +1,426 @@
+use crate::args::LinkConfig;
+use crate::blob_store::BlobStore;
+use crate::block_proof_job::{BlockProofJob, JobAction, JobState};
+use crate::config::PipelineConfig;
 use crate::contexts::{AggContext, BatchContext, FinalContext, ProveDataCache};
+use crate::curve::Curve;
+use crate::deadline::{StageDeadlines, HANDOFF_ACK_TIMEOUT};
+use crate::layout::{PROOF_FILE, PUBLIC_INPUT_FILE};
+use crate::plan::{PlanStage, ProofPlan};
 use crate::provers::{AggProver, BatchProver, FinalProver, Prover};
-use crate::stage::Stage;
+use crate::retention::{collect_garbage, GcReport, RetentionPolicy};
+use crate::retry::RetryPolicy;
+use crate::stage::{aggregate_task_id, final_task_id, Stage};
+use crate::task_queue::{Priority, TaskQueue};
+use crate::task_store::{
+    open_task_store, TaskEvent, TaskRecord, TaskStatus, TaskStore, LOCAL_WORKER_ID,
+    SCHEDULER_WORKER_ID, TASK_EVENTS_CAPACITY,
+};
 
-use anyhow::{anyhow, bail, Result};
-use std::collections::{HashMap, VecDeque};
-use std::env;
+use crate::worker_pool::{Job, JobResult, WorkerPool};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender as JobSender};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::Sender;
-use uuid::Uuid;
+use tokio::time::Instant;
 
 /// Each task handled by one pipeline
 pub struct Pipeline {
     basedir: String,
-    queue: VecDeque<String>, // task_id
+    /// The keys of the stages waiting to be proved, ordered by the priority
+    queue: TaskQueue,
     task_map: Mutex<HashMap<String, Stage>>,
     task_name: String,
     /// Cache the reusable data of rec2 during the final stage.
     /// include: R1CS, pil, exec, wasm, const
     prove_data_cache: Arc<Mutex<ProveDataCache>>,
+    /// Checkpoints of the tasks
+    task_store: Arc<dyn TaskStore>,
+    /// Shared with the scheduler, get_proof waits for the events of the task
+    task_events: broadcast::Sender<TaskEvent>,
     task_sender: Option<Sender<BatchContext>>,
+    /// The scheduler acknowledges the keys of the batch stages it takes over
+    acks: Option<broadcast::Receiver<String>>,
+    /// The batch stages sent to the scheduler until they are finished, with their ack deadlines.
+    /// They are owned by the pipeline until acknowledged, and sent again after the deadlines,
+    /// the deadline is None once acknowledged or if the scheduler doesn't acknowledge.
+    handoffs: HashMap<String, Option<Instant>>,
+    /// Notify the scheduler to revoke the batch tasks taken by the services
+    cancel_sender: Option<Sender<String>>,
     prover_model: ProverModel,
+    /// Prove the stages in the background, the budgets are shared by the pipelines of a PipelineSet
+    worker_pool: WorkerPool,
+    job_sender: JobSender<JobResult>,
+    job_receiver: Receiver<JobResult>,
+    /// The stages being proved by the worker pool, keyed by the stage key
+    running: HashMap<String, RunningStage>,
+    next_job_id: u64,
+    /// The running stages are abandoned and retried after their deadlines
+    deadlines: StageDeadlines,
+    /// The block proof jobs keyed by the task id, advanced by `prove`
+    jobs: BTreeMap<String, BlockProofJob>,
+    retry_policy: RetryPolicy,
+    /// Prune the artifacts after the final stages are proved
+    retention: RetentionPolicy,
+    /// The failed stages waiting for the backoff, they are queued again after the instant
+    retries: Vec<(Instant, String)>,
+    /// Shared with the PipelineSet, which reads it while the pipeline is proving
+    status: Arc<Mutex<PipelineStatus>>,
 
     force_bits: usize,
+    /// The circom libraries linked when the circuits are compiled
+    links: LinkConfig,
+    /// Replace the provers of the circuits if it's set
+    stage_prover: Option<StageProver>,
+}
+
+/// Prove a stage in the worker pool instead of the provers of the circuits,
+/// e.g. to run the pipeline without the circuits.
+pub type StageProver = Arc<dyn Fn(&Stage) -> Result<()> + Send + Sync>;
+
+/// A stage proved by the worker pool, its thread holds the budget until it returns
+struct RunningStage {
+    job_id: u64,
+    priority: Priority,
+    deadline: Option<Instant>,
+    /// The stage exceeded its deadline and its result is discarded,
+    /// it's not started again until the thread of this attempt returns
+    abandoned: bool,
+}
+
+/// The progress of a pipeline, the time is in seconds since the unix epoch
+#[derive(Debug, Default, Clone)]
+pub struct PipelineStatus {
+    pub task_name: String,
+    /// The key of the oldest stage being proved
+    pub current_computing_task: Option<String>,
+    pub current_computing_start_time: u64,
+    /// The keys of all the stages being proved, and their start time
+    pub computing_tasks: Vec<(String, u64)>,
+    /// The key of the last stage proved by the pipeline
+    pub last_computed_task: Option<String>,
+    pub last_computed_end_time: u64,
+    /// The keys of the stages waiting in the queue
+    pub pending_tasks: Vec<String>,
+}
+
+impl PipelineStatus {
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    fn start(&mut self, key: &str) {
+        self.computing_tasks.push((key.to_string(), Self::now()));
+        self.update_current();
+    }
+
+    /// `computed` is false if the stage is sent to the scheduler or failed
+    fn finish(&mut self, key: &str, computed: bool) {
+        self.computing_tasks.retain(|(k, _)| k != key);
+        self.update_current();
+        if computed {
+            self.last_computed_task = Some(key.to_string());
+            self.last_computed_end_time = Self::now();
+        }
+    }
+
+    fn update_current(&mut self) {
+        let current = self.computing_tasks.first();
+        self.current_computing_task = current.map(|(k, _)| k.clone());
+        self.current_computing_start_time = current.map(|(_, t)| *t).unwrap_or_default();
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ProverModel {
     Local,
     GRPC,
 }
 
-impl From<String> for ProverModel {
-    fn from(value: String) -> Self {
-        match value.as_str() {
-            "local" => ProverModel::Local,
-            "grpc" => ProverModel::GRPC,
-            // invalid env value, use default local model
-            _ => {
-                log::error!("invalid prover model: {}, please set the env PROVER_MODEL to local or grpc, use default local model", value);
-                ProverModel::Local
-            }
+impl Pipeline {
+    /// Create a pipeline by the loaded configuration, and recover its tasks from the task store.
+    /// Fail if the task store can't be opened.
+    pub fn with_config(config: &PipelineConfig, task_name: String) -> Result<Self> {
+        let task_store = open_task_store(&config.basedir, &config.task_store)
+            .with_context(|| format!("can not open the task store in {}", config.basedir))?;
+        let (task_events, _) = broadcast::channel(TASK_EVENTS_CAPACITY);
+        let mut pipeline = Self::with_task_store(config, task_name, task_store, task_events);
+
+        // the checkpoints saved without the task name belong to the only pipeline
+        let records = pipeline.task_store.list().map(|records| {
+            records
+                .into_iter()
+                .filter(|r| {
+                    r.task_name.is_none() || r.task_name.as_ref() == Some(&pipeline.task_name)
+                })
+                .collect()
+        });
+        match records.and_then(|records| pipeline.recover(records)) {
+            Ok(n) => log::info!("recover {} unfinished tasks from {}", n, pipeline.basedir),
+            Err(e) => log::error!("recover tasks from {} failed, {:?}", pipeline.basedir, e),
         }
+        Ok(pipeline)
     }
-}
 
-impl Pipeline {
-    pub fn new(basedir: String, task_name: String) -> Self {
-        // TODO move those codes out of Pipeline::new.
-        let default_cache_dir = env::var("CACHE_DIR").unwrap_or(String::from(""));
-        let prover_model: ProverModel = env::var("PROVER_MODEL")
-            .unwrap_or("local".to_string())
-            .into();
-        log::info!("start pipeline with prover model: {:?}", prover_model);
-
-        let force_bits = std::env::var("FORCE_BIT").unwrap_or("0".to_string());
-        let force_bits = force_bits
-            .parse::<usize>()
-            .unwrap_or_else(|_| panic!("Can not parse {} to usize", force_bits));
-        log::info!("proof: compress setup, force_bits {force_bits}");
-
-        // TODO: recover tasks from basedir
+    /// Create a pipeline which saves the checkpoints into a shared task store,
+    /// and publishes the task events to a shared channel.
+    /// The tasks in the store are not recovered.
+    pub fn with_task_store(
+        config: &PipelineConfig,
+        task_name: String,
+        task_store: Arc<dyn TaskStore>,
+        task_events: broadcast::Sender<TaskEvent>,
+    ) -> Self {
+        log::info!(
+            "start pipeline {} with prover model: {:?}",
+            task_name,
+            config.prover_model
+        );
+        log::info!("proof: compress setup, force_bits {}", config.force_bits);
+
+        let basedir = config.basedir.clone();
+        let (job_sender, job_receiver) = channel();
         Pipeline {
             basedir: basedir.clone(),
-            queue: VecDeque::new(),
+            queue: TaskQueue::new(config.max_queue_depth),
             task_map: Mutex::new(HashMap::new()),
             task_name: task_name.clone(),
+            status: Arc::new(Mutex::new(PipelineStatus {
+                task_name: task_name.clone(),
+                ..Default::default()
+            })),
             prove_data_cache: Arc::new(Mutex::new(ProveDataCache::new(
                 task_name,
                 basedir,
-                default_cache_dir,
+                config.cache_dir.clone(),
             ))),
+            task_store,
+            task_events,
             task_sender: None,
-            prover_model,
-            force_bits,
+            acks: None,
+            handoffs: HashMap::new(),
+            cancel_sender: None,
+            prover_model: config.prover_model,
+            worker_pool: WorkerPool::new(config.worker_pool.clone()),
+            job_sender,
+            job_receiver,
+            running: HashMap::new(),
+            next_job_id: 0,
+            deadlines: config.deadlines.clone(),
+            jobs: BTreeMap::new(),
+            retry_policy: config.retry.clone(),
+            retention: config.retention.clone(),
+            retries: vec![],
+            force_bits: config.force_bits,
+            links: config.links.clone(),
+            stage_prover: None,
         }
     }
 
+    /// Rebuild the task map from the records of the task store,
+    /// and put the unfinished stages back into the queue in their original order.
+    pub(crate) fn recover(&mut self, records: Vec<TaskRecord>) -> Result<usize> {
+        let task_map = self
+            .task_map
+            .get_mut()
+            .map_err(|e| anyhow!("task map is poisoned, {}", e))?;
+        let mut recovered = 0;
+        // the stages started before are queued first in the order they started,
+        // then the others in the order they were submitted
+        let mut records = records;
+        records.sort_by_key(|r| r.started_at.map_or((1, 0), |t| (0, t)));
+        for record in records {
+            if matches!(
+                record.status,
+                TaskStatus::Queued | TaskStatus::Running | TaskStatus::Failed
+            ) {
+                log::info!("recover unfinished task: {}", record.key);
+                self.queue
+                    .requeue(record.key.clone(), Priority::from(&record.stage));
+                recovered += 1;
+            }
+            task_map.insert(record.key, record.stage);
+        }
+        self.update_pending_tasks();
+
+        for job in BlockProofJob::list(&self.basedir)? {
+            if job.task_name == self.task_name {
+                if !job.is_finished() {
+                    log::info!("recover block proof job: {}", job.task_id);
+                }
+                self.jobs.insert(job.task_id.clone(), job);
+            }
+        }
+        Ok(recovered)
+    }
+
     pub fn set_task_sender(&mut self, task_sender: Sender<BatchContext>) {
         self.task_sender = Some(task_sender);
     }
 
+    /// Subscribe the acks of the scheduler, without them the batch stages are handed off once sent
+    pub fn set_ack_receiver(&mut self, acks: broadcast::Receiver<String>) {
+        self.acks = Some(acks);
+    }
+
+    pub fn set_cancel_sender(&mut self, cancel_sender: Sender<String>) {
+        self.cancel_sender = Some(cancel_sender);
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn set_deadlines(&mut self, deadlines: StageDeadlines) {
+        self.deadlines = deadlines;
+    }
+
+    pub fn set_retention(&mut self, retention: RetentionPolicy) {
+        self.retention = retention;
+    }
+
+    /// Prune the artifacts of the proved tasks in the task store by the retention policy,
+    /// the tasks of other pipelines sharing the task store are included.
+    pub fn collect_garbage(&self, dry_run: bool) -> Result<GcReport> {
+        collect_garbage(
+            &self.basedir,
+            self.task_store.as_ref(),
+            &self.retention,
+            dry_run,
+        )
+    }
+
+    /// Plan a stage of the block proof `task_id` without proving it,
+    /// the contexts are the same as the ones `prove_block` creates for the job.
+    pub fn plan(&self, task_id: &str, chunk_count: usize, stage: &PlanStage) -> Result<ProofPlan> {
+        if chunk_count == 0 {
+            bail!("the batch {} has no chunks", task_id);
+        }
+        // the running aggregate or final stage locks the cache while it adds its setup
+        let cache = match self.prove_data_cache.try_lock() {
+            Ok(cache) => cache.clone(),
+            Err(TryLockError::Poisoned(e)) => e.into_inner().clone(),
+            Err(TryLockError::WouldBlock) => {
+                bail!("the prove data cache is in use by a running stage, plan it later")
+            }
+        };
+        let cache = Arc::new(Mutex::new(cache));
+        let input = format!("{}_chunk_0", task_id);
+        let input2 = format!("{}_chunk_{}", task_id, chunk_count - 1);
+        let agg_task_id = aggregate_task_id(&self.task_name, &input, &input2);
+        match stage {
+            PlanStage::Batch => {
+                let contexts = (0..chunk_count)
+                    .map(|chunk_id| {
+                        let chunk_id = chunk_id.to_string();
+                        // the batch data of the queued chunk is planned too
+                        let key = self.get_key(&task_id.to_string(), &chunk_id);
+                        let batch_digest = match self.task_store.get(&key)? {
+                            Some(TaskRecord {
+                                stage: Stage::Batch(_, _, batch_digest),
+                                ..
+                            }) => batch_digest,
+                            _ => String::new(),
+                        };
+                        Ok(BatchContext::new(
+                            &self.basedir,
+                            task_id,
+                            &self.task_name,
+                            &chunk_id,
+                            batch_digest,
+                            self.force_bits,
+                            &self.links,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(ProofPlan::batch(&contexts))
+            }
+            PlanStage::Aggregate => ProofPlan::aggregate(&AggContext::new(
+                &self.basedir,
+                &self.get_key(&agg_task_id, &"agg".to_string()),
+                &self.task_name,
+                input,
+                input2,
+                self.force_bits,
+                &self.links,
+                cache,
+            )),
+            PlanStage::Final(curve) => Ok(ProofPlan::final_stage(&FinalContext::new(
+                self.basedir.clone(),
+                agg_task_id,
+                self.task_name.clone(),
+                *curve,
+                "".to_string(),
+                &self.links,
+                cache,
+            ))),
+        }
+    }
+
+    /// Share the budgets of the worker pool with other pipelines
+    pub fn set_worker_pool(&mut self, worker_pool: WorkerPool) {
+        self.worker_pool = worker_pool;
+    }
+
+    pub fn set_stage_prover(&mut self, stage_prover: StageProver) {
+        self.stage_prover = Some(stage_prover);
+    }
+
+    /// The task store is shared with the scheduler, which saves the results of the batch proofs
+    pub fn task_store(&self) -> Arc<dyn TaskStore> {
+        self.task_store.clone()
+    }
+
+    /// The task events are shared with the scheduler, which publishes the results of the batch proofs
+    pub fn task_events(&self) -> broadcast::Sender<TaskEvent> {
+        self.task_events.clone()
+    }
+
+    pub fn task_name(&self) -> &str {
+        &self.task_name
+    }
+
+    /// The keys of the tasks waiting in the queue, in the order they will be proved
+    pub fn pending_tasks(&self) -> Vec<String> {
+        self.queue.keys()
+    }
+
+    /// The tasks waiting in the queue with their priorities, in the order they will be proved
+    pub fn queued_tasks(&self) -> Vec<(String, Priority)> {
+        self.queue.entries()
+    }
+
+    /// 0 means the queue is unbounded
+    pub fn max_queue_depth(&self) -> usize {
+        self.queue.max_depth()
+    }
+
+    pub fn set_max_queue_depth(&mut self, max_depth: usize) {
+        self.queue.set_max_depth(max_depth);
+    }
+
+    /// Check that `count` more tasks can be added, the error is a `QueueFullError`
+    pub fn check_capacity(&self, count: usize) -> Result<()> {
+        Ok(self.queue.check_capacity(count)?)
+    }
+
+    fn update_pending_tasks(&self) {
+        self.status.lock().unwrap().pending_tasks = self.pending_tasks();
+    }
+
     pub fn get_key(&self, task_id: &String, chunk_id: &String) -> String {
         format!("{}_{}", task_id, chunk_id)
     }
@@ -89,43 +429,31 @@ impl Pipeline {
         let binding = self.task_map.lock().unwrap();
         let task = binding.get(key);
 
-        if let Some(status) = task {
-            // mkdir
-            let workdir = Path::new(&self.basedir).join(status.path());
-            log::info!("save_checkpoint, mkdir: {:?}", workdir);
-            std::fs::create_dir_all(workdir.clone())?;
-
-            if !finished {
-                let p = workdir.join("status");
-                std::fs::write(p, status.to_string()?)?;
+        if let Some(stage) = task {
+            if finished {
+                self.transition(key, TaskStatus::Succeeded)?;
+            } else {
+                self.task_store.put(&self.task_name, stage)?;
             }
-
-            let p = workdir.join("status.finished");
-            std::fs::write(p, if finished { "1" } else { "0" })?;
         }
         Ok(key.clone())
     }
 
-    fn load_checkpoint(&self, key: &String) -> Result<bool> {
-        let binding = self.task_map.lock().unwrap();
-        let task = binding.get(key);
-
-        if let Some(stage) = task {
-            // mkdir
-            let workdir = Path::new(&self.basedir)
-                .join(stage.path())
-                .join("status.finished");
-            log::info!("load_checkpoint, check file: {:?}", workdir);
-
-            let status = match std::fs::read_to_string(workdir)?.trim() {
-                "1" => true,
-                "0" => false,
-                _ => return Err(anyhow!("Invalid value. Expected '0' or '1'.")),
-            };
+    /// Update the status of the task, and notify the waiters of get_proof
+    fn transition(&self, key: &str, status: TaskStatus) -> Result<()> {
+        self.task_store.transition(key, status)?;
+        // it fails if no one is waiting for the task
+        let _ = self.task_events.send(TaskEvent {
+            key: key.to_string(),
+            status,
+        });
+        Ok(())
+    }
 
-            Ok(status)
-        } else {
-            Ok(false)
+    pub fn is_cancelled(&self, key: &str) -> bool {
+        match self.task_store.get(key) {
+            Ok(Some(record)) => record.status == TaskStatus::Cancelled,
+            _ => false,
         }
     }
 
@@ -138,7 +466,7 @@ impl Pipeline {
             let workdir = Path::new(&self.basedir).join(stage.path());
             log::info!("load_final_proof_and_input, workdir: {:?}", workdir);
 
-            let proof_path = workdir.clone().join("proof.json");
+            let proof_path = workdir.clone().join(PROOF_FILE);
             let proof = std::fs::read_to_string(proof_path.clone()).map_err(|e| {
                 anyhow!(
                     "Failed to load the proof.json: {:?}, err: {}",
@@ -147,7 +475,7 @@ impl Pipeline {
                 )
             })?;
 
-            let input_path = workdir.join("public_input.json");
+            let input_path = workdir.join(PUBLIC_INPUT_FILE);
             let input = std::fs::read_to_string(input_path.clone()).map_err(|e| {
                 anyhow!(
                     "Failed to load the public_input.json: {:?}, err: {}",
@@ -162,147 +490,1516 @@ impl Pipeline {
         }
     }
 
+    /// Deposit the batch data into the BlobStore of the basedir, and return its digest.
+    /// The executor deposits the batch data of the blocks it executes already.
+    pub fn put_batch_data(&self, l2_batch_data: &str) -> Result<String> {
+        if l2_batch_data.is_empty() {
+            return Ok(String::new());
+        }
+        BlobStore::new(&self.basedir).put(l2_batch_data.as_bytes())
+    }
+
+    /// Bail if the batch data of the digest isn't deposited, the empty digest has no batch data
+    fn check_batch_data(&self, batch_digest: &str) -> Result<()> {
+        if !batch_digest.is_empty() && !BlobStore::new(&self.basedir).contains(batch_digest) {
+            bail!("the batch data {:?} is not in the blob store", batch_digest);
+        }
+        Ok(())
+    }
+
+    /// Add a new batch task into task queue, the batch data is referred to by its digest in the BlobStore
     pub fn batch_prove(
         &mut self,
         task_id: String,
         chunk_id: String,
-        l2_batch_data: String,
+        batch_digest: String,
     ) -> Result<String> {
+        self.check_batch_data(&batch_digest)?;
         let key = self.get_key(&task_id, &chunk_id);
-        match self.task_map.get_mut() {
-            Ok(w) => {
-                self.queue.push_back(key.clone());
-                w.insert(
-                    key.clone(),
-                    Stage::Batch(task_id.clone(), chunk_id, l2_batch_data),
-                );
-                self.save_checkpoint(&key, false)
-            }
-            _ => bail!("Task queue is full".to_string()),
-        }
+        let stage = Stage::Batch(task_id.clone(), chunk_id, batch_digest);
+        self.enqueue(key.clone(), stage)?;
+        self.save_checkpoint(&key, false)
     }
 
-    /// Add a new task into task queue
+    /// Add a new task into task queue, the task id is derived from the inputs.
+    /// The identical aggregation which is queued, running or proved is returned instead.
     pub fn aggregate_prove(&mut self, task: String, task2: String) -> Result<String> {
-        let task_id = Uuid::new_v4().to_string();
+        let task_id = aggregate_task_id(&self.task_name, &task, &task2);
         let key = self.get_key(&task_id, &"agg".to_string());
-        match self.task_map.get_mut() {
-            Ok(w) => {
-                self.queue.push_back(key.clone());
-                w.insert(key.clone(), Stage::Aggregate(key.clone(), task, task2));
-                self.save_checkpoint(&key, false)?;
-                Ok(task_id)
+        if let Some(record) = self.task_store.get(&key)? {
+            if self.is_reusable(&record)? {
+                log::info!(
+                    "aggregation of {} to {} exists, task: {} is {}",
+                    task,
+                    task2,
+                    key,
+                    record.status.as_str()
+                );
+                return Ok(task_id);
             }
-            _ => bail!("Task queue is full".to_string()),
         }
+        self.enqueue(key.clone(), Stage::Aggregate(key.clone(), task, task2))?;
+        self.save_checkpoint(&key, false)?;
+        Ok(task_id)
     }
 
-    /// Add a new task into task queue
+    /// Add a new task into task queue, return the task id of the final proof.
+    /// The final proofs of the aggregation with another curve or prover address are kept side by side,
+    /// the identical one which is queued, running or proved is returned instead.
     pub fn final_prove(
         &mut self,
         task_id: String,
-        curve_name: String,
+        curve: Curve,
         prover_addr: String,
     ) -> Result<String> {
-        let key = self.get_key(&task_id, &"final".to_string());
-        match self.task_map.get_mut() {
-            Ok(w) => {
-                self.queue.push_back(key.clone());
-                w.insert(
-                    key.clone(),
-                    Stage::Final(task_id.clone(), curve_name, prover_addr), // use task_id first, then compute the right task_name in final context
+        let final_task_id = final_task_id(&task_id, curve, &prover_addr);
+        let stage = Stage::Final(task_id.clone(), curve, prover_addr);
+        let key = stage.key();
+        if let Some(record) = self.task_store.get(&key)? {
+            if self.is_reusable(&record)? {
+                log::info!(
+                    "final proof of {} exists, task: {} is {}",
+                    task_id,
+                    key,
+                    record.status.as_str()
                 );
-                self.save_checkpoint(&key, false)?;
-                Ok(task_id)
+                return Ok(final_task_id);
             }
-            _ => bail!("Task queue is full".to_string()),
         }
+        self.enqueue(key.clone(), stage)?;
+        self.save_checkpoint(&key, false)?;
+        Ok(final_task_id)
     }
 
-    pub fn cancel(&mut self, task_id: String) -> Result<()> {
-        // TODO find all the tasks with prefix `task_id`
-        if let Ok(w) = self.task_map.get_mut() {
-            let _ = w.remove(&task_id);
+    /// Return true if the stage is queued, running, or proved after all its dependencies,
+    /// so an identical request can wait for it instead of proving it again.
+    fn is_reusable(&self, record: &TaskRecord) -> Result<bool> {
+        match record.status {
+            TaskStatus::Queued | TaskStatus::Running => Ok(true),
+            TaskStatus::Succeeded => {
+                for dependency in record.stage.dependencies() {
+                    match self.task_store.get(&dependency)? {
+                        // the unknown dependencies are treated as proved, same as dependencies_proved
+                        None => {}
+                        Some(d)
+                            if d.status == TaskStatus::Succeeded
+                                && d.finished_at <= record.finished_at => {}
+                        // the dependency is proved again, so is the stage
+                        Some(_) => return Ok(false),
+                    }
+                }
+                Ok(true)
+            }
+            TaskStatus::Failed | TaskStatus::Cancelled => Ok(false),
         }
+    }
+
+    /// Push the stage into the queue by its priority,
+    /// the stage is not added if the queue is full.
+    fn enqueue(&mut self, key: String, stage: Stage) -> Result<()> {
+        let task_map = self
+            .task_map
+            .get_mut()
+            .map_err(|e| anyhow!("task map is poisoned, {}", e))?;
+        self.queue.push(key.clone(), Priority::from(&stage))?;
+        task_map.insert(key, stage);
+        self.update_pending_tasks();
         Ok(())
     }
 
+    /// Cancel all the unfinished stages of the task:
+    ///   - the stages with the prefix `task_id`, and the final proofs if it's an aggregation
+    ///   - the aggregations of the chunks, and their final proofs
+    ///
+    /// Return the keys of the cancelled stages.
+    pub fn cancel(&mut self, task_id: String) -> Result<Vec<String>> {
+        let task_map = self
+            .task_map
+            .get_mut()
+            .map_err(|e| anyhow!("task map is poisoned, {}", e))?;
+        let prefix = format!("{}_", task_id);
+        let mut agg_task_ids = vec![task_id.clone()];
+        for (key, stage) in task_map.iter() {
+            if let Stage::Aggregate(_, input, input2) = stage {
+                if input.starts_with(&prefix) || input2.starts_with(&prefix) {
+                    agg_task_ids.push(key.trim_end_matches("_agg").to_string());
+                }
+            }
+        }
+        // the final proofs have their own task ids, they're found by the aggregation
+        let belongs = |key: &str, stage: &Stage| match stage {
+            Stage::Final(agg_task_id, ..) => agg_task_ids.contains(agg_task_id),
+            _ => agg_task_ids
+                .iter()
+                .any(|id| key.starts_with(&format!("{}_", id))),
+        };
+
+        let mut stages: Vec<(String, Stage)> = task_map
+            .iter()
+            .filter(|(key, stage)| belongs(key, stage))
+            .map(|(key, stage)| (key.clone(), stage.clone()))
+            .collect();
+        stages.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut cancelled = vec![];
+        let mut batch_task_ids = vec![];
+        for (key, stage) in stages {
+            match self.task_store.get(&key)?.map(|r| r.status) {
+                Some(TaskStatus::Succeeded) | Some(TaskStatus::Cancelled) => continue,
+                Some(_) => self.transition(&key, TaskStatus::Cancelled)?,
+                None => {
+                    self.task_store.put(&self.task_name, &stage)?;
+                    self.transition(&key, TaskStatus::Cancelled)?;
+                }
+            }
+            self.queue.remove(&key);
+            self.retries.retain(|(_, k)| *k != key);
+            if self.handoffs.remove(&key).is_some() {
+                self.status.lock().unwrap().finish(&key, false);
+            }
+            if let Stage::Batch(batch_task_id, ..) = &stage {
+                if !batch_task_ids.contains(batch_task_id) {
+                    batch_task_ids.push(batch_task_id.clone());
+                }
+            }
+            log::info!("cancel task: {}", key);
+            cancelled.push(key);
+        }
+        self.update_pending_tasks();
+
+        // the batch tasks may have been sent to the scheduler
+        if let Some(cancel_sender) = &self.cancel_sender {
+            for batch_task_id in batch_task_ids {
+                if let Err(e) = cancel_sender.try_send(batch_task_id) {
+                    log::error!("send cancel to scheduler failed, {:?}", e);
+                }
+            }
+        }
+        Ok(cancelled)
+    }
+
     /// Return prover status
-    pub fn get_status(&mut self) -> Result<()> {
+    pub fn get_status(&self) -> Result<PipelineStatus> {
+        self.status
+            .lock()
+            .map(|status| status.clone())
+            .map_err(|e| anyhow!("pipeline status is poisoned, {}", e))
+    }
+
+    /// The status can be read without locking the pipeline
+    pub fn status_handle(&self) -> Arc<Mutex<PipelineStatus>> {
+        self.status.clone()
+    }
+
+    /// Wait for the task to finish at most `timeout` seconds, return the key if it succeeded.
+    /// `timeout` 0 means checking the status without waiting.
+    ///
+    /// The future doesn't borrow the pipeline, so it can be awaited after the pipeline is unlocked.
+    pub fn get_proof(
+        &self,
+        key: String,
+        timeout: u64,
+    ) -> impl Future<Output = Result<String>> + Send + 'static {
+        let task_store = self.task_store.clone();
+        let load_status = move |key: &str| {
+            Ok(task_store
+                .get(key)
+                .map_err(|e| anyhow!("load checkpoint failed, {:#?}", e))?
+                .map(|r| r.status))
+        };
+        self.wait_for(key, timeout, load_status)
+    }
+
+    /// Wait for the block proof job to finish at most `timeout` seconds,
+    /// return the key of the final proof if it succeeded.
+    pub fn get_block_proof(
+        &self,
+        task_id: String,
+        timeout: u64,
+    ) -> impl Future<Output = Result<String>> + Send + 'static {
+        let basedir = self.basedir.clone();
+        let wait = self.jobs.get(&task_id).map(|job| {
+            let (basedir, task_id) = (basedir.clone(), task_id.clone());
+            let load_status =
+                move |_: &str| Ok(BlockProofJob::load(&basedir, &task_id)?.map(|job| job.status()));
+            self.wait_for(job.key(), timeout, load_status)
+        });
+        async move {
+            let Some(wait) = wait else {
+                bail!("can not find block proof job: {}", task_id)
+            };
+            wait.await?;
+            // the job is saved before the event is sent
+            let job = BlockProofJob::load(&basedir, &task_id)?;
+            job.and_then(|job| job.final_key())
+                .ok_or_else(|| anyhow!("can not find the final proof of job: {}", task_id))
+        }
+    }
+
+    /// Wait for the status of the key changed by the task events,
+    /// `load_status` is called before waiting and after the events are lagged.
+    fn wait_for<F>(
+        &self,
+        key: String,
+        timeout: u64,
+        load_status: F,
+    ) -> impl Future<Output = Result<String>> + Send + 'static
+    where
+        F: Fn(&str) -> Result<Option<TaskStatus>> + Send + 'static,
+    {
+        // subscribe before loading the status, so that no event is missed
+        let mut task_events = self.task_events.subscribe();
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        async move {
+            let mut status = load_status(&key)?;
+            loop {
+                match status {
+                    Some(TaskStatus::Succeeded) => return Ok(key),
+                    Some(TaskStatus::Cancelled) => bail!("task: {} is cancelled", key),
+                    Some(TaskStatus::Failed) => bail!("task: {} failed", key),
+                    Some(TaskStatus::Queued) | Some(TaskStatus::Running) | None => {}
+                }
+
+                // wait for the next event of the task,
+                // the failure is not kept by every TaskStore, so use the status of the event
+                status = match tokio::time::timeout_at(deadline, task_events.recv()).await {
+                    Ok(Ok(event)) if event.key == key => Some(event.status),
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(RecvError::Lagged(n))) => {
+                        log::warn!("get_proof of {} lagged {} events", key, n);
+                        load_status(&key)?
+                    }
+                    Ok(Err(RecvError::Closed)) => bail!("task events are closed"),
+                    Err(_) => bail!("task: {} is not finished in {} seconds", key, timeout),
+                };
+            }
+        }
+    }
+
+    /// Prove the block(s) of the batch `task_id` end to end,
+    /// the chunks are queued now, the aggregation and the final proof are queued by `prove`
+    /// after their inputs are proved.
+    /// The job which is already running is kept, the failed or cancelled one is restarted.
+    pub fn prove_block(
+        &mut self,
+        task_id: String,
+        chunk_count: usize,
+        batch_digest: String,
+        curve: Curve,
+        prover_addr: String,
+    ) -> Result<()> {
+        if chunk_count == 0 {
+            bail!("the batch {} has no chunks", task_id);
+        }
+        self.check_batch_data(&batch_digest)?;
+        if let Some(job) = self.jobs.get(&task_id) {
+            if !matches!(job.state, JobState::Failed | JobState::Cancelled) {
+                log::info!("block proof job: {} already exists, skip", task_id);
+                return Ok(());
+            }
+        }
+
+        let job = BlockProofJob::new(
+            task_id.clone(),
+            self.task_name.clone(),
+            chunk_count,
+            curve,
+            prover_addr,
+        );
+        // skip the finished chunks, all or none of the others are queued
+        let mut unfinished = vec![];
+        for (chunk_id, key) in job.chunk_keys().into_iter().enumerate() {
+            let status = self.task_store.get(&key)?.map(|r| r.status);
+            if status != Some(TaskStatus::Succeeded) {
+                unfinished.push(chunk_id);
+            }
+        }
+        self.check_capacity(unfinished.len())?;
+        for chunk_id in unfinished {
+            self.batch_prove(task_id.clone(), chunk_id.to_string(), batch_digest.clone())?;
+        }
+        job.save(&self.basedir)?;
+        log::info!(
+            "start block proof job: {}, chunks: {}",
+            task_id,
+            chunk_count
+        );
+        self.jobs.insert(task_id, job);
         Ok(())
     }
 
-    pub fn get_proof(&mut self, key: String, _timeout: u64) -> Result<String> {
-        match self.load_checkpoint(&key) {
-            Ok(true) => Ok(key),
-            Ok(false) => bail!("can not find task: {}", key),
-            Err(e) => bail!("load checkpoint failed, {:#?}", e),
+    pub fn block_proof_job(&self, task_id: &str) -> Option<&BlockProofJob> {
+        self.jobs.get(task_id)
+    }
+
+    /// Queue the next stage of the jobs whose current stages are finished
+    fn advance_jobs(&mut self) -> Result<()> {
+        let task_ids: Vec<String> = self
+            .jobs
+            .values()
+            .filter(|job| !job.is_finished())
+            .map(|job| job.task_id.clone())
+            .collect();
+        for task_id in task_ids {
+            let mut job = self.jobs[&task_id].clone();
+            let action = job.poll(|key| Ok(self.task_store.get(key)?.map(|r| r.status)))?;
+            match action {
+                JobAction::Wait => continue,
+                JobAction::Aggregate(input, input2) => match self.aggregate_prove(input, input2) {
+                    Ok(agg_task_id) => {
+                        job.agg_task_id = Some(agg_task_id);
+                        job.state = JobState::Aggregate;
+                    }
+                    Err(e) => {
+                        // the queue may be full, try again later
+                        log::warn!("aggregate the chunks of job: {} failed, {:?}", task_id, e);
+                        continue;
+                    }
+                },
+                JobAction::Final(agg_task_id) => {
+                    let (curve, prover_addr) = (job.curve, job.prover_addr.clone());
+                    if let Err(e) = self.final_prove(agg_task_id, curve, prover_addr) {
+                        log::warn!("start the final proof of job: {} failed, {:?}", task_id, e);
+                        continue;
+                    }
+                    job.state = JobState::Final;
+                }
+                JobAction::Succeed => job.state = JobState::Succeeded,
+                JobAction::Fail(error) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(error);
+                }
+                JobAction::Cancel => job.state = JobState::Cancelled,
+            }
+
+            log::info!("block proof job: {} is {:?}", task_id, job.state);
+            job.save(&self.basedir)?;
+            if job.is_finished() {
+                let _ = self.task_events.send(TaskEvent {
+                    key: job.key(),
+                    status: job.status(),
+                });
+            }
+            self.jobs.insert(task_id, job);
         }
+        Ok(())
     }
 
+    /// Collect the stages finished by the worker pool, retry the ones exceeding their deadlines,
+    /// advance the block proof jobs, then start the stages whose dependencies are proved, in the order of the queue.
+    /// It doesn't wait for the stages, the pipeline is not locked while proving.
     pub fn prove(&mut self) -> Result<()> {
-        if let Some(key) = self.queue.pop_front() {
-            match self.task_map.get_mut().unwrap().get(&key) {
-                Some(v) => match v {
-                    Stage::Batch(task_id, chunk_id, l2_batch_data) => {
-                        let ctx = BatchContext::new(
-                            &self.basedir,
-                            task_id,
-                            &self.task_name.clone(),
-                            chunk_id,
-                            l2_batch_data.clone(),
-                            self.force_bits,
-                        );
+        let mut errors = vec![];
+        let mut final_proved = false;
+        while let Ok(JobResult {
+            job_id,
+            key,
+            result,
+        }) = self.job_receiver.try_recv()
+        {
+            match self.running.get(&key) {
+                Some(running) if running.job_id == job_id && !running.abandoned => {
+                    self.running.remove(&key);
+                }
+                Some(running) if running.job_id == job_id => {
+                    log::warn!("discard the result of the abandoned task: {}", key);
+                    self.running.remove(&key);
+                    continue;
+                }
+                _ => {
+                    log::warn!("discard the result of the unknown task: {}", key);
+                    continue;
+                }
+            };
+            let result = result.and_then(|_| {
+                // the cancelled stage is not saved as succeeded
+                if self.is_cancelled(&key) {
+                    return Ok(false);
+                }
+                self.save_checkpoint(&key, true).map(|_| true)
+            });
+            self.status
+                .lock()
+                .unwrap()
+                .finish(&key, matches!(result, Ok(true)));
+            if matches!(result, Ok(true))
+                && matches!(
+                    self.task_map.get_mut().unwrap().get(&key),
+                    Some(Stage::Final(..))
+                )
+            {
+                final_proved = true;
+            }
+            if let Err(e) = result {
+                self.handle_failure(&key, &e, true);
+                errors.push(e.context(format!("prove task: {} failed", key)));
+            }
+        }
 
-                        match self.prover_model {
-                            ProverModel::Local => {
-                                BatchProver::new().prove(&ctx)?;
-                                self.save_checkpoint(&key, true)?;
-                            }
-                            ProverModel::GRPC => {
-                                // send the task's ctx to scheduler
-                                log::info!(
-                                    "send task to scheduler: [id:{}], [name:{}]",
-                                    ctx.task_id,
-                                    ctx.task_name
-                                );
-                                if let Err(e) = self.task_sender.as_ref().unwrap().try_send(ctx) {
-                                    log::error!("send task to scheduler failed, {:?}", e);
-                                }
+        self.check_deadlines(&mut errors);
+        self.check_handoffs();
+
+        if final_proved && self.retention.is_enabled() {
+            match self.collect_garbage(false) {
+                Ok(report) => log::info!(
+                    "pruned {} artifacts of {} tasks, {} bytes",
+                    report.artifacts.len(),
+                    report.tasks.len(),
+                    report.freed_bytes()
+                ),
+                Err(e) => errors.push(e.context("collect garbage failed")),
+            }
+        }
+
+        if let Err(e) = self.advance_jobs() {
+            errors.push(e.context("advance the block proof jobs failed"));
+        }
+        self.dispatch(&mut errors);
+        self.update_pending_tasks();
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Abandon the running stages which exceed their deadlines and retry them, the results of their threads
+    /// are discarded. The retries wait for the threads to return, which keep their budgets and workdirs.
+    fn check_deadlines(&mut self, errors: &mut Vec<anyhow::Error>) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(_, running)| {
+                !running.abandoned && running.deadline.is_some_and(|deadline| deadline <= now)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            let Some(running) = self.running.get_mut(&key) else {
+                continue;
+            };
+            running.abandoned = true;
+            let priority = running.priority;
+            self.status.lock().unwrap().finish(&key, false);
+            let e = anyhow!(
+                "task: {} exceeded the deadline of {:?}",
+                key,
+                self.deadlines.of(priority).unwrap_or_default()
+            );
+            self.handle_failure(&key, &e, true);
+            errors.push(e);
+        }
+    }
+
+    /// Release the batch stages finished by the scheduler, and queue the ones
+    /// not acknowledged in time again, unless the scheduler has started them.
+    fn check_handoffs(&mut self) {
+        if let Some(acks) = self.acks.as_mut() {
+            loop {
+                match acks.try_recv() {
+                    Ok(key) => {
+                        if let Some(deadline) = self.handoffs.get_mut(&key) {
+                            if deadline.take().is_some() {
+                                log::debug!("task: {} is acknowledged by the scheduler", key);
                             }
                         }
                     }
-                    Stage::Aggregate(task_id, input, input2) => {
-                        let ctx = AggContext::new(
-                            &self.basedir,
-                            task_id,
-                            &self.task_name,
-                            input.clone(),
-                            input2.clone(),
-                            self.force_bits,
-                            self.prove_data_cache.clone(),
-                        );
-                        AggProver::new().prove(&ctx)?;
-                        self.save_checkpoint(&key, true)?;
+                    // the missed ones are checked in the task store after the deadline
+                    Err(TryRecvError::Lagged(n)) => {
+                        log::warn!("missed {} acks of the scheduler", n)
                     }
-                    Stage::Final(task_id, curve_name, prover_addr) => {
-                        let ctx = FinalContext::new(
-                            self.basedir.clone(),
-                            task_id.clone(),
-                            self.task_name.clone(),
-                            curve_name.clone(),
-                            prover_addr.clone(),
-                            self.prove_data_cache.clone(),
-                        );
-                        FinalProver::new().prove(&ctx)?;
-                        self.save_checkpoint(&key, true)?;
+                    Err(_) => break,
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let handoffs: Vec<(String, Option<Instant>)> = self
+            .handoffs
+            .iter()
+            .map(|(key, deadline)| (key.clone(), *deadline))
+            .collect();
+        for (key, deadline) in handoffs {
+            let record = match self.task_store.get(&key) {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("read task: {} failed, {:?}", key, e);
+                    continue;
+                }
+            };
+            if record.as_ref().is_some_and(|r| r.status.is_finished()) {
+                self.handoffs.remove(&key);
+                self.status.lock().unwrap().finish(&key, false);
+                continue;
+            }
+            if deadline.is_none_or(|deadline| deadline > now) {
+                continue;
+            }
+            // the scheduler records the service which starts it
+            let started = record.is_some_and(|r| {
+                r.status != TaskStatus::Queued
+                    && r.worker_id.as_deref() != Some(SCHEDULER_WORKER_ID)
+            });
+            if started {
+                self.handoffs.insert(key, None);
+                continue;
+            }
+            log::warn!(
+                "task: {} is not acknowledged by the scheduler in {:?}, send it again",
+                key,
+                HANDOFF_ACK_TIMEOUT
+            );
+            self.handoffs.remove(&key);
+            self.status.lock().unwrap().finish(&key, false);
+            if let Err(e) = self.task_store.transition(&key, TaskStatus::Queued) {
+                log::warn!("requeue task: {} failed, {:?}", key, e);
+            }
+            self.queue.requeue(key, Priority::Batch);
+        }
+    }
+
+    /// Start the queued stages until the worker pool is busy.
+    /// The batch stages are sent to the scheduler in the GRPC model,
+    /// they stay in the queue while the scheduler is saturated.
+    fn dispatch(&mut self, errors: &mut Vec<anyhow::Error>) {
+        self.requeue_retries();
+        let mut scheduler_full = false;
+        for (key, priority) in self.queue.entries() {
+            if self.running.contains_key(&key) {
+                continue;
+            }
+            let stage = match self.task_map.get_mut().unwrap().get(&key) {
+                Some(stage) => stage.clone(),
+                None => {
+                    log::warn!("can not find task: {}, drop it", key);
+                    self.queue.remove(&key);
+                    continue;
+                }
+            };
+            match self.dependencies_proved(&stage) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    // retrying doesn't help until the dependency is proved again
+                    self.queue.remove(&key);
+                    self.handle_failure(&key, &e, false);
+                    errors.push(e);
+                    continue;
+                }
+            }
+
+            if let (Stage::Batch(..), ProverModel::GRPC) = (&stage, &self.prover_model) {
+                if scheduler_full {
+                    continue;
+                }
+                let Some(task_sender) = self.task_sender.as_ref() else {
+                    log::warn!("no scheduler to send task: {}", key);
+                    continue;
+                };
+                // reserve the slot first, the stage is not taken out of the queue if it fails
+                let permit = match task_sender.try_reserve() {
+                    Ok(permit) => permit,
+                    Err(TrySendError::Full(_)) => {
+                        log::debug!("the scheduler is saturated, task: {} waits", key);
+                        scheduler_full = true;
+                        continue;
                     }
-                },
-                _ => {
-                    log::info!("Task queue is empty...");
+                    Err(TrySendError::Closed(_)) => {
+                        errors.push(anyhow!(
+                            "send task: {} failed, the scheduler is closed",
+                            key
+                        ));
+                        scheduler_full = true;
+                        continue;
+                    }
+                };
+                let ctx = self.batch_context(&stage);
+                // send the task's ctx to scheduler
+                log::info!(
+                    "send task to scheduler: [id:{}], [name:{}]",
+                    ctx.task_id,
+                    ctx.task_name
+                );
+                permit.send(ctx);
+                self.queue.remove(&key);
+                // the stage is running on the scheduler until a service takes it
+                self.status.lock().unwrap().start(&key);
+                if let Err(e) = self.task_store.start(&key, SCHEDULER_WORKER_ID) {
+                    log::warn!("save the handoff of task: {} failed, {:?}", key, e);
+                }
+                let deadline = self
+                    .acks
+                    .as_ref()
+                    .map(|_| Instant::now() + HANDOFF_ACK_TIMEOUT);
+                self.handoffs.insert(key, deadline);
+                continue;
+            }
+
+            // the higher priority stage is not overtaken by the smaller ones
+            let Some(reservation) = self.worker_pool.try_reserve(priority) else {
+                break;
+            };
+            let job = self.prove_job(&stage);
+            self.queue.remove(&key);
+            self.status.lock().unwrap().start(&key);
+            log::info!("start proving task: {}", key);
+            let job_id = self.next_job_id;
+            self.next_job_id += 1;
+            let spawned = self.worker_pool.spawn(
+                job_id,
+                key.clone(),
+                self.job_sender.clone(),
+                reservation,
+                job,
+            );
+            match spawned {
+                Ok(_) => {
+                    if let Err(e) = self.task_store.start(&key, LOCAL_WORKER_ID) {
+                        log::warn!("save the start of task: {} failed, {:?}", key, e);
+                    }
+                    let running = RunningStage {
+                        job_id,
+                        priority,
+                        deadline: self.deadlines.deadline(priority, Instant::now()),
+                        abandoned: false,
+                    };
+                    self.running.insert(key, running);
+                }
+                Err(e) => {
+                    self.status.lock().unwrap().finish(&key, false);
+                    self.handle_failure(&key, &e, true);
+                    errors.push(e.context(format!("start task: {} failed", key)));
                 }
+            }
+        }
+    }
+
+    /// Return true if all the dependencies of the stage are proved,
+    /// the unknown dependencies are treated as proved.
+    fn dependencies_proved(&self, stage: &Stage) -> Result<bool> {
+        for dependency in stage.dependencies() {
+            match self.task_store.get(&dependency)?.map(|r| r.status) {
+                Some(TaskStatus::Succeeded) | None => {}
+                Some(TaskStatus::Queued) | Some(TaskStatus::Running) => return Ok(false),
+                Some(status) => bail!(
+                    "the dependency {} of task: {} is {}",
+                    dependency,
+                    stage.key(),
+                    status.as_str()
+                ),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Save the failed attempt of the stage, retry it after the backoff if the retry policy allows,
+    /// otherwise the stage is failed.
+    fn handle_failure(&mut self, key: &str, error: &anyhow::Error, retryable: bool) {
+        if self.is_cancelled(key) {
+            return;
+        }
+        let message = format!("{:#}", error);
+        let errors: Vec<String> = error.chain().map(|e| e.to_string()).collect();
+        let attempts = match self.task_store.record_failure(key, &errors) {
+            Ok(attempts) => attempts,
+            Err(e) => {
+                log::error!("save the failure of task: {} failed, {:?}", key, e);
+                u32::MAX
+            }
+        };
+        if retryable && self.retry_policy.should_retry(attempts, error) {
+            let backoff = self.retry_policy.backoff(attempts);
+            log::warn!(
+                "task: {} failed {} times, retry in {:?}, err: {}",
+                key,
+                attempts,
+                backoff,
+                message
+            );
+            if let Err(e) = self.transition(key, TaskStatus::Queued) {
+                log::error!("requeue task: {} failed, {:?}", key, e);
+            }
+            self.retries
+                .push((Instant::now() + backoff, key.to_string()));
+            return;
+        }
+
+        log::error!(
+            "task: {} failed {} times, give up, err: {}",
+            key,
+            attempts,
+            message
+        );
+        if let Err(e) = self.transition(key, TaskStatus::Failed) {
+            log::error!("save the failure of task: {} failed, {:?}", key, e);
+        }
+    }
+
+    /// Put the stages whose backoff is over back into the queue
+    fn requeue_retries(&mut self) {
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.retries)
+            .into_iter()
+            .partition(|(at, _)| *at <= now);
+        self.retries = waiting;
+        for (_, key) in due {
+            let priority = match self.task_map.get_mut().unwrap().get(&key) {
+                Some(stage) => Priority::from(stage),
+                None => continue,
             };
+            log::info!("retry task: {}", key);
+            // the stage is accepted before, so the max depth is not checked
+            self.queue.requeue(key, priority);
         }
-        Ok(())
+    }
+
+    fn batch_context(&self, stage: &Stage) -> BatchContext {
+        let Stage::Batch(task_id, chunk_id, batch_digest) = stage else {
+            unreachable!("not a batch stage: {:?}", stage)
+        };
+        BatchContext::new(
+            &self.basedir,
+            task_id,
+            &self.task_name,
+            chunk_id,
+            batch_digest.clone(),
+            self.force_bits,
+            &self.links,
+        )
+    }
+
+    /// The job owns its context, so it runs without the pipeline
+    fn prove_job(&self, stage: &Stage) -> Job {
+        if let Some(stage_prover) = self.stage_prover.clone() {
+            let stage = stage.clone();
+            return Box::new(move || stage_prover(&stage));
+        }
+        match stage {
+            Stage::Batch(..) => {
+                let ctx = self.batch_context(stage);
+                Box::new(move || BatchProver::new().prove(&ctx))
+            }
+            Stage::Aggregate(task_id, input, input2) => {
+                let ctx = AggContext::new(
+                    &self.basedir,
+                    task_id,
+                    &self.task_name,
+                    input.clone(),
+                    input2.clone(),
+                    self.force_bits,
+                    &self.links,
+                    self.prove_data_cache.clone(),
+                );
+                Box::new(move || AggProver::new().prove(&ctx))
+            }
+            Stage::Final(task_id, curve, prover_addr) => {
+                let ctx = FinalContext::new(
+                    self.basedir.clone(),
+                    task_id.clone(),
+                    self.task_name.clone(),
+                    *curve,
+                    prover_addr.clone(),
+                    &self.links,
+                    self.prove_data_cache.clone(),
+                );
+                Box::new(move || FinalProver::new().prove(&ctx))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task_queue::QueueFullError;
+    use crate::worker_pool::WorkerPoolConfig;
+    use std::env;
+    use uuid::Uuid;
+
+    fn temp_basedir(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("pipeline_{}_{}", name, Uuid::new_v4()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// A pipeline of the default configuration in `basedir`, the stages are proved without the circuits
+    fn test_pipeline(basedir: &str) -> Pipeline {
+        let config = PipelineConfig {
+            basedir: basedir.to_string(),
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::with_config(&config, "evm".to_string()).unwrap();
+        pipeline.set_stage_prover(Arc::new(|_| Ok(())));
+        pipeline
+    }
+
+    /// Prove until `done`, the stages are proved by the worker pool in the background
+    fn prove_until(pipeline: &mut Pipeline, done: impl Fn(&Pipeline) -> bool) {
+        for _ in 0..500 {
+            // the failed stages are checked by the callers
+            let _ = pipeline.prove();
+            if done(pipeline) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the pipeline doesn't finish proving");
+    }
+
+    fn record(pipeline: &Pipeline, key: &str) -> TaskRecord {
+        pipeline.task_store().get(key).unwrap().unwrap()
+    }
+
+    fn is_finished(pipeline: &Pipeline, key: &str) -> bool {
+        !matches!(
+            record(pipeline, key).status,
+            TaskStatus::Queued | TaskStatus::Running
+        )
+    }
+
+    #[test]
+    fn test_recover_tasks() {
+        let basedir = temp_basedir("recover");
+        let mut pipeline = test_pipeline(&basedir);
+        // the batch data is deposited before the chunks are queued
+        assert!(pipeline
+            .batch_prove("0".into(), "0".into(), BlobStore::digest(b"data"))
+            .is_err());
+        let batch_digest = pipeline.put_batch_data("data").unwrap();
+        pipeline
+            .batch_prove("0".into(), "0".into(), batch_digest.clone())
+            .unwrap();
+        prove_until(&mut pipeline, |p| is_finished(p, "0_0"));
+        for chunk_id in ["1", "2", "3"] {
+            pipeline
+                .batch_prove("0".into(), chunk_id.into(), batch_digest.clone())
+                .unwrap();
+        }
+        let agg_task_id = pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_1".into())
+            .unwrap();
+        // 0_3 was running when the pipeline stopped
+        pipeline.task_store().start("0_3", LOCAL_WORKER_ID).unwrap();
+        drop(pipeline);
+
+        let pipeline = test_pipeline(&basedir);
+        let agg_key = format!("{}_agg", agg_task_id);
+        // the aggregation is proved before the chunks, the started chunk is resumed first
+        assert_eq!(
+            pipeline.pending_tasks(),
+            vec![agg_key.clone(), "0_3".into(), "0_1".into(), "0_2".into()]
+        );
+
+        assert_eq!(pipeline.task_store().list().unwrap().len(), 5);
+        let record0 = record(&pipeline, "0_0");
+        assert_eq!(record0.status, TaskStatus::Succeeded);
+        assert!(matches!(record0.stage, Stage::Batch(_, _, d) if d == batch_digest));
+        assert!(matches!(
+            record(&pipeline, &agg_key).stage,
+            Stage::Aggregate(..)
+        ));
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_proof() {
+        let basedir = temp_basedir("get_proof");
+        let mut pipeline = test_pipeline(&basedir);
+        pipeline.set_retry_policy(RetryPolicy {
+            initial_backoff: Duration::from_secs(600),
+            non_retryable: vec!["invalid input".to_string()],
+            ..Default::default()
+        });
+        pipeline.set_stage_prover(Arc::new(|stage| match stage.key().as_str() {
+            "0_1" => bail!("invalid input"),
+            "0_2" => bail!("out of memory"),
+            _ => Ok(()),
+        }));
+        for chunk_id in ["0", "1", "2"] {
+            pipeline
+                .batch_prove("0".into(), chunk_id.into(), "".into())
+                .unwrap();
+        }
+
+        let err = pipeline.get_proof("0_0".into(), 0).await.unwrap_err();
+        assert!(err.to_string().contains("not finished"));
+
+        // the waiters are woken up by the events
+        let proof = tokio::spawn(pipeline.get_proof("0_0".into(), 60));
+        let failed = tokio::spawn(pipeline.get_proof("0_1".into(), 60));
+        let timeout = tokio::spawn(pipeline.get_proof("0_2".into(), 1));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // 0_2 waits for the backoff
+        prove_until(&mut pipeline, |p| {
+            is_finished(p, "0_0") && is_finished(p, "0_1") && record(p, "0_2").attempts == 1
+        });
+
+        assert_eq!(proof.await.unwrap().unwrap(), "0_0");
+        let err = failed.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("failed"));
+        let err = timeout.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("not finished"));
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_idempotent_aggregation() {
+        let basedir = temp_basedir("idempotent");
+        let mut pipeline = test_pipeline(&basedir);
+        pipeline.set_retry_policy(RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        });
+        pipeline.set_stage_prover(Arc::new(|stage| match stage {
+            Stage::Final(_, Curve::BLS12381, _) => bail!("prove failed"),
+            _ => Ok(()),
+        }));
+        for chunk_id in ["0", "1"] {
+            pipeline
+                .batch_prove("0".into(), chunk_id.into(), "".into())
+                .unwrap();
+        }
+        prove_until(&mut pipeline, |p| p.pending_tasks().is_empty());
+
+        // the identical request gets the queued aggregation
+        let agg_task_id = pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_1".into())
+            .unwrap();
+        let agg_key = format!("{}_agg", agg_task_id);
+        let retried = pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_1".into())
+            .unwrap();
+        assert_eq!(retried, agg_task_id);
+        let other = pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_0".into())
+            .unwrap();
+        assert_ne!(other, agg_task_id);
+        assert_eq!(pipeline.pending_tasks().len(), 2);
+
+        // and the proved one, until its chunks are proved again
+        prove_until(&mut pipeline, |p| is_finished(p, &agg_key));
+        pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_1".into())
+            .unwrap();
+        assert_eq!(record(&pipeline, &agg_key).status, TaskStatus::Succeeded);
+        pipeline
+            .batch_prove("0".into(), "1".into(), "".into())
+            .unwrap();
+        pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_1".into())
+            .unwrap();
+        assert_eq!(record(&pipeline, &agg_key).status, TaskStatus::Queued);
+        prove_until(&mut pipeline, |p| is_finished(p, &agg_key));
+        assert_eq!(record(&pipeline, &agg_key).status, TaskStatus::Succeeded);
+
+        // the final proofs with another curve are kept side by side
+        let final_task_id = pipeline
+            .final_prove(agg_task_id.clone(), Curve::BN128, "addr".into())
+            .unwrap();
+        assert_eq!(
+            pipeline
+                .final_prove(agg_task_id.clone(), Curve::BN128, "addr".into())
+                .unwrap(),
+            final_task_id
+        );
+        let final_task_id2 = pipeline
+            .final_prove(agg_task_id.clone(), Curve::BLS12381, "addr".into())
+            .unwrap();
+        assert_ne!(final_task_id, final_task_id2);
+        let keys = pipeline.pending_tasks();
+        for id in [&final_task_id, &final_task_id2] {
+            let final_key = format!("{}_final", id);
+            assert_eq!(keys.iter().filter(|k| **k == final_key).count(), 1);
+        }
+
+        // the failed one is proved again
+        let final_key = format!("{}_final", final_task_id2);
+        let final_key1 = format!("{}_final", final_task_id);
+        prove_until(&mut pipeline, |p| {
+            is_finished(p, &final_key) && is_finished(p, &final_key1)
+        });
+        assert_eq!(record(&pipeline, &final_key).status, TaskStatus::Failed);
+        assert_eq!(record(&pipeline, &final_key1).status, TaskStatus::Succeeded);
+        assert_eq!(
+            pipeline
+                .final_prove(agg_task_id.clone(), Curve::BLS12381, "addr".into())
+                .unwrap(),
+            final_task_id2
+        );
+        let final_record = record(&pipeline, &final_key);
+        assert_eq!(final_record.status, TaskStatus::Queued);
+        assert!(matches!(
+            final_record.stage,
+            Stage::Final(_, Curve::BLS12381, _)
+        ));
+        assert_eq!(pipeline.pending_tasks(), vec![final_key]);
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_plan() {
+        let basedir = temp_basedir("plan");
+        let mut pipeline = test_pipeline(&basedir);
+        assert!(pipeline.plan("0", 0, &PlanStage::Batch).is_err());
+
+        let plan = pipeline.plan("0", 2, &PlanStage::Batch).unwrap();
+        assert!(!plan.is_ready());
+        assert!(plan
+            .missing()
+            .contains(&format!("{}/proof/0/evm/evm_chunks_1.data", basedir)));
+
+        // the plan writes the aggregation which aggregate_prove queues
+        let plan = pipeline.plan("0", 2, &PlanStage::Aggregate).unwrap();
+        let agg_task_id = pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_1".into())
+            .unwrap();
+        let agg_zkin = format!("{}/proof/{}_agg/agg_zkin.json", basedir, agg_task_id);
+        assert!(plan.outputs.contains(&agg_zkin));
+
+        let plan = pipeline
+            .plan("0", 2, &PlanStage::Final(Curve::BN128))
+            .unwrap();
+        assert_eq!(plan.stage, PlanStage::Final(Curve::BN128));
+        // it reads the aggregation, and writes into the dir of the final task
+        assert!(plan.inputs.iter().any(|i| i.path.contains(&agg_task_id)));
+        let final_task_id = final_task_id(&agg_task_id, Curve::BN128, "");
+        let proof_dir = format!("{}/proof/", basedir);
+        assert!(plan
+            .outputs
+            .iter()
+            .filter(|o| o.starts_with(&proof_dir))
+            .all(|o| o.contains(&final_task_id)));
+        // nothing is proved
+        assert_eq!(
+            pipeline.pending_tasks(),
+            vec![format!("{}_agg", agg_task_id)]
+        );
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_pipeline_status() {
+        let mut status = PipelineStatus::default();
+        status.start("0_0");
+        assert_eq!(status.current_computing_task.as_deref(), Some("0_0"));
+        assert!(status.current_computing_start_time > 0);
+        status.start("0_1");
+        assert_eq!(status.computing_tasks.len(), 2);
+        assert_eq!(status.current_computing_task.as_deref(), Some("0_0"));
+
+        status.finish("0_0", true);
+        assert_eq!(status.current_computing_task.as_deref(), Some("0_1"));
+        assert_eq!(status.last_computed_task.as_deref(), Some("0_0"));
+        assert!(status.last_computed_end_time > 0);
+
+        // the stage sent to the scheduler is not computed by the pipeline
+        status.finish("0_1", false);
+        assert_eq!(status.current_computing_task, None);
+        assert_eq!(status.current_computing_start_time, 0);
+        assert_eq!(status.last_computed_task.as_deref(), Some("0_0"));
+    }
+
+    #[test]
+    fn test_dependencies_proved() {
+        let basedir = temp_basedir("dependencies");
+        let mut pipeline = test_pipeline(&basedir);
+        pipeline.set_retry_policy(RetryPolicy {
+            initial_backoff: Duration::from_secs(600),
+            ..Default::default()
+        });
+        let proved = Arc::new(Mutex::new(vec![]));
+        let keys = proved.clone();
+        pipeline.set_stage_prover(Arc::new(move |stage| {
+            if stage.key() == "0_1" {
+                bail!("out of memory");
+            }
+            keys.lock().unwrap().push(stage.key());
+            Ok(())
+        }));
+        for chunk_id in ["0", "1"] {
+            pipeline
+                .batch_prove("0".into(), chunk_id.into(), "".into())
+                .unwrap();
+        }
+        let agg_task_id = pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_1".into())
+            .unwrap();
+        let agg_key = format!("{}_agg", agg_task_id);
+
+        // the aggregation waits for 0_1
+        prove_until(&mut pipeline, |p| {
+            is_finished(p, "0_0") && record(p, "0_1").attempts == 1
+        });
+        pipeline.prove().unwrap();
+        assert_eq!(pipeline.pending_tasks(), vec![agg_key.clone()]);
+        assert_eq!(record(&pipeline, &agg_key).status, TaskStatus::Queued);
+        assert_eq!(*proved.lock().unwrap(), vec!["0_0".to_string()]);
+
+        // the aggregation is unknown
+        let final_task_id = pipeline
+            .final_prove("a".into(), Curve::BN128, "addr".into())
+            .unwrap();
+        let final_key = format!("{}_final", final_task_id);
+        prove_until(&mut pipeline, |p| is_finished(p, &final_key));
+        assert_eq!(record(&pipeline, &final_key).status, TaskStatus::Succeeded);
+
+        // the chunk is cancelled, the aggregation fails without retrying
+        pipeline
+            .batch_prove("1".into(), "0".into(), "".into())
+            .unwrap();
+        pipeline.cancel("1".into()).unwrap();
+        let agg_task_id = pipeline
+            .aggregate_prove("1_chunk_0".into(), "1_chunk_0".into())
+            .unwrap();
+        let agg_key = format!("{}_agg", agg_task_id);
+        let err = pipeline.prove().unwrap_err();
+        assert!(err.to_string().contains("cancelled"));
+        assert_eq!(record(&pipeline, &agg_key).status, TaskStatus::Failed);
+        assert!(!pipeline.pending_tasks().contains(&agg_key));
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_task() {
+        let basedir = temp_basedir("cancel");
+        let mut pipeline = test_pipeline(&basedir);
+        let (cancel_sender, mut cancel_receiver) = tokio::sync::mpsc::channel(8);
+        pipeline.set_cancel_sender(cancel_sender);
+        pipeline
+            .batch_prove("0".into(), "0".into(), "".into())
+            .unwrap();
+        prove_until(&mut pipeline, |p| is_finished(p, "0_0"));
+        for chunk_id in ["1", "2"] {
+            pipeline
+                .batch_prove("0".into(), chunk_id.into(), "".into())
+                .unwrap();
+        }
+        pipeline
+            .batch_prove("1".into(), "0".into(), "".into())
+            .unwrap();
+        let agg_task_id = pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_1".into())
+            .unwrap();
+        let final_task_id = pipeline
+            .final_prove(agg_task_id.clone(), Curve::BN128, "addr".into())
+            .unwrap();
+
+        let cancelled = pipeline.cancel("0".into()).unwrap();
+        let agg_key = format!("{}_agg", agg_task_id);
+        let final_key = format!("{}_final", final_task_id);
+        let mut expected = vec![
+            "0_1".to_string(),
+            "0_2".to_string(),
+            agg_key.clone(),
+            final_key.clone(),
+        ];
+        expected.sort();
+        assert_eq!(cancelled, expected);
+        assert_eq!(pipeline.pending_tasks(), vec!["1_0".to_string()]);
+        assert_eq!(
+            pipeline.get_status().unwrap().pending_tasks,
+            vec!["1_0".to_string()]
+        );
+        assert_eq!(cancel_receiver.try_recv().unwrap(), "0");
+
+        // the finished stage is kept
+        assert!(pipeline.get_proof("0_0".into(), 0).await.is_ok());
+        assert!(pipeline.is_cancelled(&final_key));
+        let err = pipeline.get_proof(final_key.clone(), 0).await.unwrap_err();
+        assert!(err.to_string().contains("cancelled"));
+        drop(pipeline);
+
+        // the cancelled stages are not recovered
+        let pipeline = test_pipeline(&basedir);
+        assert_eq!(pipeline.pending_tasks(), vec!["1_0".to_string()]);
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_queue_priority_and_depth() {
+        let basedir = temp_basedir("queue");
+        let mut pipeline = test_pipeline(&basedir);
+        pipeline.set_max_queue_depth(3);
+        pipeline
+            .batch_prove("0".into(), "0".into(), "".into())
+            .unwrap();
+        let agg_task_id = pipeline
+            .aggregate_prove("0_chunk_0".into(), "0_chunk_1".into())
+            .unwrap();
+        let final_task_id = pipeline
+            .final_prove(agg_task_id.clone(), Curve::BN128, "addr".into())
+            .unwrap();
+        let agg_key = format!("{}_agg", agg_task_id);
+        let final_key = format!("{}_final", final_task_id);
+        assert_eq!(
+            pipeline.queued_tasks(),
+            vec![
+                (final_key.clone(), Priority::Final),
+                (agg_key.clone(), Priority::Aggregate),
+                ("0_0".to_string(), Priority::Batch),
+            ]
+        );
+        assert_eq!(
+            pipeline.get_status().unwrap().pending_tasks,
+            vec![final_key, agg_key, "0_0".to_string()]
+        );
+
+        // the rejected task is neither queued nor saved
+        assert!(pipeline.check_capacity(1).is_err());
+        let err = pipeline
+            .batch_prove("0".into(), "1".into(), "".into())
+            .unwrap_err();
+        assert!(err.downcast_ref::<QueueFullError>().is_some());
+        assert_eq!(pipeline.pending_tasks().len(), 3);
+        assert!(pipeline.task_store().get("0_1").unwrap().is_none());
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_proof_job() {
+        let basedir = temp_basedir("block_job");
+        let mut pipeline = test_pipeline(&basedir);
+        pipeline.set_retry_policy(RetryPolicy {
+            initial_backoff: Duration::from_secs(600),
+            ..Default::default()
+        });
+        // the aggregation fails until the pipeline is restarted
+        pipeline.set_stage_prover(Arc::new(|stage| match stage {
+            Stage::Aggregate(..) => bail!("out of memory"),
+            _ => Ok(()),
+        }));
+        pipeline
+            .prove_block("0".into(), 2, "".into(), Curve::BN128, "addr".into())
+            .unwrap();
+        assert_eq!(pipeline.pending_tasks(), vec!["0_0", "0_1"]);
+        assert_eq!(
+            pipeline.block_proof_job("0").unwrap().state,
+            JobState::Chunks
+        );
+
+        // the aggregation is queued after the chunks are proved
+        prove_until(&mut pipeline, |p| {
+            let job = p.block_proof_job("0").unwrap();
+            job.state == JobState::Aggregate && record(p, &job.agg_key().unwrap()).attempts == 1
+        });
+        let job = pipeline.block_proof_job("0").unwrap().clone();
+        let agg_key = job.agg_key().unwrap();
+        assert!(matches!(
+            record(&pipeline, &agg_key).stage,
+            Stage::Aggregate(_, input, input2) if input == "0_chunk_0" && input2 == "0_chunk_1"
+        ));
+        drop(pipeline);
+
+        // the job is resumed after restart
+        let mut pipeline = test_pipeline(&basedir);
+        assert_eq!(pipeline.pending_tasks(), vec![agg_key.clone()]);
+        let proof = tokio::spawn(pipeline.get_block_proof("0".into(), 60));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        prove_until(&mut pipeline, |p| {
+            p.block_proof_job("0").unwrap().state == JobState::Succeeded
+        });
+        let final_key = job.final_key().unwrap();
+        assert_eq!(record(&pipeline, &final_key).status, TaskStatus::Succeeded);
+        assert_eq!(proof.await.unwrap().unwrap(), final_key);
+
+        // the finished job is not started again
+        pipeline
+            .prove_block("0".into(), 2, "".into(), Curve::BN128, "addr".into())
+            .unwrap();
+        assert!(pipeline.pending_tasks().is_empty());
+        let proof = pipeline.get_block_proof("0".into(), 0).await.unwrap();
+        assert_eq!(proof, final_key);
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed_task() {
+        let basedir = temp_basedir("retry");
+        let mut pipeline = test_pipeline(&basedir);
+        pipeline.set_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            non_retryable: vec!["invalid input".to_string()],
+        });
+        pipeline.set_stage_prover(Arc::new(|stage| match stage.key().as_str() {
+            "0_0" => bail!("out of memory"),
+            _ => bail!("invalid input"),
+        }));
+        for chunk_id in ["0", "1"] {
+            pipeline
+                .batch_prove("0".into(), chunk_id.into(), "".into())
+                .unwrap();
+        }
+        let failed = tokio::spawn(pipeline.get_proof("0_0".into(), 60));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        prove_until(&mut pipeline, |p| {
+            is_finished(p, "0_0") && is_finished(p, "0_1")
+        });
+        assert!(pipeline.pending_tasks().is_empty());
+
+        // retried until no attempts left
+        let record0 = record(&pipeline, "0_0");
+        assert_eq!(record0.status, TaskStatus::Failed);
+        assert_eq!(record0.attempts, 2);
+        assert_eq!(record0.last_error().as_deref(), Some("out of memory"));
+        let err = failed.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("failed"));
+
+        // not retryable
+        let record1 = record(&pipeline, "0_1");
+        assert_eq!(record1.status, TaskStatus::Failed);
+        assert_eq!(record1.attempts, 1);
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_stage_deadline() {
+        let basedir = temp_basedir("deadline");
+        let mut pipeline = test_pipeline(&basedir);
+        let worker_pool = WorkerPool::new(WorkerPoolConfig {
+            threads: 2,
+            ..Default::default()
+        });
+        pipeline.set_worker_pool(worker_pool.clone());
+        pipeline.set_retry_policy(RetryPolicy {
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            ..Default::default()
+        });
+        pipeline.set_deadlines(StageDeadlines {
+            batch: Duration::from_millis(100),
+            ..Default::default()
+        });
+        // the first attempt hangs after it's started
+        let attempts = Arc::new(Mutex::new(0));
+        let started = attempts.clone();
+        pipeline.set_stage_prover(Arc::new(move |_| {
+            let attempt = {
+                let mut started = started.lock().unwrap();
+                *started += 1;
+                *started
+            };
+            if attempt == 1 {
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            Ok(())
+        }));
+        pipeline
+            .batch_prove("0".into(), "0".into(), "".into())
+            .unwrap();
+
+        prove_until(&mut pipeline, |p| record(p, "0_0").attempts == 1);
+        let record0 = record(&pipeline, "0_0");
+        assert_eq!(record0.status, TaskStatus::Queued);
+        assert!(record0
+            .last_error()
+            .unwrap()
+            .contains("exceeded the deadline"));
+        assert!(pipeline.get_status().unwrap().computing_tasks.is_empty());
+
+        // the hung thread keeps its budget, and the retry waits for it
+        for _ in 0..5 {
+            pipeline.prove().unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(worker_pool.usage(), (1, 0));
+        assert_eq!(*attempts.lock().unwrap(), 1);
+        assert_eq!(pipeline.pending_tasks(), vec!["0_0".to_string()]);
+
+        // the result of the abandoned thread is discarded, then the stage is retried
+        prove_until(&mut pipeline, |p| is_finished(p, "0_0"));
+        assert_eq!(record(&pipeline, "0_0").status, TaskStatus::Succeeded);
+        assert_eq!(*attempts.lock().unwrap(), 2);
+        assert_eq!(worker_pool.usage(), (0, 0));
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scheduler_handoff() {
+        let basedir = temp_basedir("handoff");
+        let config = PipelineConfig {
+            basedir: basedir.clone(),
+            prover_model: ProverModel::GRPC,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::with_config(&config, "evm".to_string()).unwrap();
+        let (task_sender, mut task_receiver) = tokio::sync::mpsc::channel(1);
+        let (ack_sender, _) = broadcast::channel(8);
+        pipeline.set_task_sender(task_sender);
+        pipeline.set_ack_receiver(ack_sender.subscribe());
+        for chunk_id in ["0", "1"] {
+            pipeline
+                .batch_prove("0".into(), chunk_id.into(), "".into())
+                .unwrap();
+        }
+
+        // the scheduler is saturated, 0_1 stays in the queue
+        pipeline.prove().unwrap();
+        assert_eq!(pipeline.pending_tasks(), vec!["0_1".to_string()]);
+        assert_eq!(task_receiver.try_recv().unwrap().chunk_id, "0");
+        // 0_0 runs on the scheduler until it's finished
+        let record = pipeline.task_store().get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Running);
+        assert_eq!(record.worker_id.as_deref(), Some(SCHEDULER_WORKER_ID));
+        let status = pipeline.get_status().unwrap();
+        assert_eq!(status.current_computing_task.as_deref(), Some("0_0"));
+
+        // 0_0 is acknowledged, 0_1 is sent
+        ack_sender.send("0_0".into()).unwrap();
+        pipeline.prove().unwrap();
+        assert!(pipeline.pending_tasks().is_empty());
+
+        // 0_1 is not acknowledged in time, it's queued until there's room to send it again
+        tokio::time::advance(HANDOFF_ACK_TIMEOUT).await;
+        pipeline.prove().unwrap();
+        assert_eq!(pipeline.pending_tasks(), vec!["0_1".to_string()]);
+        assert_eq!(task_receiver.try_recv().unwrap().chunk_id, "1");
+        let record = pipeline.task_store().get("0_1").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Queued);
+        let status = pipeline.get_status().unwrap();
+        let computing: Vec<&str> = status
+            .computing_tasks
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect();
+        assert_eq!(computing, vec!["0_0"]);
+        pipeline.prove().unwrap();
+        assert!(pipeline.pending_tasks().is_empty());
+        assert_eq!(task_receiver.try_recv().unwrap().chunk_id, "1");
+        // the acknowledged one isn't sent again
+        assert!(task_receiver.try_recv().is_err());
+
+        // the ack is missed, but the scheduler has started it
+        pipeline.task_store().start("0_1", "s1").unwrap();
+        tokio::time::advance(HANDOFF_ACK_TIMEOUT).await;
+        pipeline.prove().unwrap();
+        assert!(pipeline.pending_tasks().is_empty());
+        assert!(task_receiver.try_recv().is_err());
+        assert_eq!(pipeline.get_status().unwrap().computing_tasks.len(), 2);
+
+        // the stages proved by the services are released
+        for key in ["0_0", "0_1"] {
+            pipeline
+                .task_store()
+                .transition(key, TaskStatus::Succeeded)
+                .unwrap();
+        }
+        pipeline.prove().unwrap();
+        let status = pipeline.get_status().unwrap();
+        assert!(status.computing_tasks.is_empty());
+        assert_eq!(status.last_computed_task, None);
+
+        std::fs::remove_dir_all(basedir).unwrap();
     }
 }
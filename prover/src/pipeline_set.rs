@@ -0,0 +1,236 @@
+use crate::config::PipelineConfig;
+use crate::contexts::BatchContext;
+use crate::pipeline::{Pipeline, PipelineStatus};
+use crate::task_store::{open_task_store, TaskEvent, TaskRecord, TaskStore, TASK_EVENTS_CAPACITY};
+use crate::worker_pool::WorkerPool;
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Sender;
+
+/// The pipelines of the guest programs, keyed by the program name.
+/// All the pipelines share the basedir, the task store, the task events and the budgets of the worker pool,
+/// each one has its own ProveDataCache.
+pub struct PipelineSet {
+    /// The program of the requests without program name is the `task_name`
+    config: PipelineConfig,
+    task_store: Arc<dyn TaskStore>,
+    task_events: broadcast::Sender<TaskEvent>,
+    worker_pool: WorkerPool,
+    pipelines: BTreeMap<String, Arc<Mutex<Pipeline>>>,
+    /// The status of the pipelines, read without locking the pipelines
+    statuses: BTreeMap<String, Arc<Mutex<PipelineStatus>>>,
+    task_sender: Option<Sender<BatchContext>>,
+    ack_sender: Option<broadcast::Sender<String>>,
+    cancel_sender: Option<Sender<String>>,
+}
+
+impl PipelineSet {
+    /// Create the pipelines by the loaded configuration, and recover their tasks from the task store.
+    /// Fail if the task store can't be opened.
+    pub fn with_config(config: PipelineConfig) -> Result<Self> {
+        let task_store = open_task_store(&config.basedir, &config.task_store)
+            .with_context(|| format!("can not open the task store in {}", config.basedir))?;
+        let mut set = PipelineSet {
+            task_store,
+            task_events: broadcast::channel(TASK_EVENTS_CAPACITY).0,
+            worker_pool: WorkerPool::new(config.worker_pool.clone()),
+            config,
+            pipelines: BTreeMap::new(),
+            statuses: BTreeMap::new(),
+            task_sender: None,
+            ack_sender: None,
+            cancel_sender: None,
+        };
+        if let Err(e) = set.recover() {
+            log::error!("recover tasks from {} failed, {:?}", set.config.basedir, e);
+        }
+        Ok(set)
+    }
+
+    /// Create the pipelines of the programs found in the task store,
+    /// the checkpoints saved without the program name belong to the default program.
+    fn recover(&mut self) -> Result<()> {
+        let mut records: HashMap<String, Vec<TaskRecord>> = HashMap::new();
+        for record in self.task_store.list()? {
+            let program_name = record
+                .task_name
+                .clone()
+                .unwrap_or_else(|| self.config.task_name.clone());
+            records.entry(program_name).or_default().push(record);
+        }
+
+        for (program_name, records) in records {
+            let pipeline = self.get(&program_name);
+            let mut pipeline = pipeline
+                .lock()
+                .map_err(|e| anyhow!("pipeline {} is poisoned, {}", program_name, e))?;
+            let n = pipeline.recover(records)?;
+            log::info!("recover {} unfinished tasks of {}", n, program_name);
+        }
+        Ok(())
+    }
+
+    /// Get the pipeline of the program, create it if it does not exist.
+    /// An empty program name means the default program.
+    pub fn get(&mut self, program_name: &str) -> Arc<Mutex<Pipeline>> {
+        let program_name = if program_name.is_empty() {
+            self.config.task_name.as_str()
+        } else {
+            program_name
+        };
+        if let Some(pipeline) = self.pipelines.get(program_name) {
+            return pipeline.clone();
+        }
+
+        log::info!("create pipeline for program: {}", program_name);
+        let mut pipeline = Pipeline::with_task_store(
+            &self.config,
+            program_name.to_string(),
+            self.task_store.clone(),
+            self.task_events.clone(),
+        );
+        pipeline.set_worker_pool(self.worker_pool.clone());
+        if let Some(task_sender) = &self.task_sender {
+            pipeline.set_task_sender(task_sender.clone());
+        }
+        if let Some(ack_sender) = &self.ack_sender {
+            pipeline.set_ack_receiver(ack_sender.subscribe());
+        }
+        if let Some(cancel_sender) = &self.cancel_sender {
+            pipeline.set_cancel_sender(cancel_sender.clone());
+        }
+        self.statuses
+            .insert(program_name.to_string(), pipeline.status_handle());
+        let pipeline = Arc::new(Mutex::new(pipeline));
+        self.pipelines
+            .insert(program_name.to_string(), pipeline.clone());
+        pipeline
+    }
+
+    /// All the pipelines, ordered by the program name
+    pub fn pipelines(&self) -> Vec<Arc<Mutex<Pipeline>>> {
+        self.pipelines.values().cloned().collect()
+    }
+
+    pub fn program_names(&self) -> Vec<String> {
+        self.pipelines.keys().cloned().collect()
+    }
+
+    /// The status of all the pipelines, ordered by the program name
+    pub fn get_status(&self) -> Vec<PipelineStatus> {
+        self.statuses
+            .values()
+            .map(|status| status.lock().unwrap().clone())
+            .collect()
+    }
+
+    /// Set the task sender of the existing pipelines and the ones created later
+    pub fn set_task_sender(&mut self, task_sender: Sender<BatchContext>) {
+        for pipeline in self.pipelines.values() {
+            pipeline
+                .lock()
+                .unwrap()
+                .set_task_sender(task_sender.clone());
+        }
+        self.task_sender = Some(task_sender);
+    }
+
+    /// Subscribe the acks of the scheduler for the existing pipelines and the ones created later
+    pub fn set_ack_sender(&mut self, ack_sender: broadcast::Sender<String>) {
+        for pipeline in self.pipelines.values() {
+            pipeline
+                .lock()
+                .unwrap()
+                .set_ack_receiver(ack_sender.subscribe());
+        }
+        self.ack_sender = Some(ack_sender);
+    }
+
+    /// Set the cancel sender of the existing pipelines and the ones created later
+    pub fn set_cancel_sender(&mut self, cancel_sender: Sender<String>) {
+        for pipeline in self.pipelines.values() {
+            pipeline
+                .lock()
+                .unwrap()
+                .set_cancel_sender(cancel_sender.clone());
+        }
+        self.cancel_sender = Some(cancel_sender);
+    }
+
+    /// The task store is shared with the scheduler, which saves the results of the batch proofs
+    pub fn task_store(&self) -> Arc<dyn TaskStore> {
+        self.task_store.clone()
+    }
+
+    /// The task events are shared with the scheduler, which publishes the results of the batch proofs
+    pub fn task_events(&self) -> broadcast::Sender<TaskEvent> {
+        self.task_events.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_pipeline_set() {
+        let basedir = env::temp_dir()
+            .join(format!("pipeline_set_{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .to_string();
+
+        let config = PipelineConfig {
+            basedir: basedir.clone(),
+            task_name: "evm".to_string(),
+            ..Default::default()
+        };
+        let mut set = PipelineSet::with_config(config.clone()).unwrap();
+        assert!(Arc::ptr_eq(&set.get(""), &set.get("evm")));
+        set.get("evm")
+            .lock()
+            .unwrap()
+            .batch_prove("0".into(), "0".into(), "".into())
+            .unwrap();
+        set.get("lr")
+            .lock()
+            .unwrap()
+            .batch_prove("1".into(), "0".into(), "".into())
+            .unwrap();
+        assert_eq!(set.program_names(), vec!["evm", "lr"]);
+        drop(set);
+
+        // each program gets its own tasks back
+        let mut set = PipelineSet::with_config(config).unwrap();
+        assert_eq!(set.program_names(), vec!["evm", "lr"]);
+        let evm = set.get("evm");
+        let evm = evm.lock().unwrap();
+        assert_eq!(evm.task_name(), "evm");
+        assert_eq!(evm.pending_tasks(), vec!["0_0"]);
+        let lr = set.get("lr");
+        assert_eq!(lr.lock().unwrap().pending_tasks(), vec!["1_0"]);
+
+        // the status is read while the pipeline is locked
+        let status = set.get_status();
+        assert_eq!(status.len(), 2);
+        assert_eq!(status[0].task_name, "evm");
+        assert_eq!(status[0].pending_tasks, vec!["0_0"]);
+        assert_eq!(status[1].pending_tasks, vec!["1_0"]);
+        drop(evm);
+
+        // the task store which can't be opened is reported to the caller
+        let config = PipelineConfig {
+            basedir: basedir.clone(),
+            task_store: "unknown".to_string(),
+            ..Default::default()
+        };
+        let err = PipelineSet::with_config(config).err().unwrap();
+        assert!(format!("{:#}", err).contains("invalid task store: unknown"));
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+}
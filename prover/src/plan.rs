@@ -0,0 +1,438 @@
+use crate::args::LinkConfig;
+use crate::args::StarkProveArgs;
+use crate::blob_store::BlobStore;
+use crate::contexts::{
+    AggContext, BatchContext, CacheStage, FinalContext, ProveDataCache, SnarkFileType,
+    StarkFileType,
+};
+use crate::curve::Curve;
+
+use anyhow::Result;
+use std::fmt;
+use std::path::Path;
+
+/// The stage of a block proof to plan
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlanStage {
+    /// All the chunks of the block
+    Batch,
+    /// The aggregation of the chunks
+    Aggregate,
+    /// The final proof of the aggregation on the curve
+    Final(Curve),
+}
+
+/// A file or a directory which must exist before the stage starts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlanInput {
+    pub path: String,
+    pub exists: bool,
+}
+
+/// A file of the ProveDataCache used by the stage
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlanCacheEntry {
+    /// e.g. `agg.r1cs`
+    pub name: String,
+    /// The cached file if it's reused, otherwise the file it's copied from after it's generated
+    pub path: String,
+    pub reused: bool,
+}
+
+/// What a stage will do, built from its contexts without invoking circom or starky
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProofPlan {
+    pub stage: PlanStage,
+    pub inputs: Vec<PlanInput>,
+    /// The files written by the stage, the generated cache files included
+    pub outputs: Vec<String>,
+    pub cache: Vec<PlanCacheEntry>,
+}
+
+impl ProofPlan {
+    fn new(stage: PlanStage) -> Self {
+        ProofPlan {
+            stage,
+            inputs: vec![],
+            outputs: vec![],
+            cache: vec![],
+        }
+    }
+
+    /// Plan the batch stages of the chunks
+    pub fn batch(contexts: &[BatchContext]) -> Self {
+        let mut plan = Self::new(PlanStage::Batch);
+        for ctx in contexts {
+            plan.input(ctx.bootloader_input());
+            if !ctx.batch_digest.is_empty() {
+                let blob = BlobStore::new(&ctx.basedir).path(&ctx.batch_digest);
+                plan.input(blob.to_string_lossy());
+            }
+            plan.input(&ctx.batch_struct);
+            plan.input(&ctx.c12_struct);
+            plan.links(&ctx.batch_circom.link_directories);
+            plan.links(&ctx.c12_circom.link_directories);
+
+            // the executor writes the circuit and the zkin of the chunk
+            plan.output(&ctx.batch_circom.circom_file);
+            plan.output(&ctx.batch_stark.zkin);
+            plan.stark_outputs(&ctx.batch_stark);
+            plan.output(&ctx.c12_circom.circom_file);
+            plan.output(&ctx.c12_stark.zkin);
+            plan.stark_outputs(&ctx.c12_stark);
+            plan.output(&ctx.recursive1_circom.circom_file);
+            plan.output(&ctx.recursive1_stark.zkin);
+        }
+        plan
+    }
+
+    /// Plan the aggregation, the recursive1 setup is reused from the cache if it's there
+    pub fn aggregate(ctx: &AggContext) -> Result<Self> {
+        let mut plan = Self::new(PlanStage::Aggregate);
+        let (task_id, start, end) = ctx.chunks()?;
+        let chunk = |i: usize| {
+            BatchContext::new(
+                &ctx.basedir,
+                &task_id,
+                &ctx.task_name,
+                &i.to_string(),
+                "".to_string(),
+                ctx.force_bits,
+                // only the paths of the chunks are planned, their circuits are compiled already
+                &LinkConfig::default(),
+            )
+        };
+        for i in start..=end {
+            plan.input(ctx.chunk_zkin(&task_id, i));
+        }
+        plan.input(&ctx.agg_struct);
+
+        let first = chunk(start);
+        let r1_stark = &first.recursive1_stark;
+        let cache = ctx.prove_data_cache.lock().unwrap();
+        if !cache.agg_cache.already_cached {
+            plan.input(&first.recursive1_circom.circom_file);
+            plan.links(&ctx.agg_circom.link_directories);
+            plan.stark_outputs(r1_stark);
+        }
+        plan.stark_cache(&cache, "agg", r1_stark);
+
+        plan.output(&ctx.agg_zkin);
+        plan.output(&r1_stark.commit_file);
+        plan.output(ctx.joined_zkin(&task_id, 0));
+        for i in 2..=end {
+            plan.output(ctx.joined_zkin(&task_id, i));
+            plan.output(&chunk(i).recursive1_stark.commit_file);
+        }
+        plan.output(&ctx.agg_circom.circom_file);
+        plan.output(&ctx.agg_stark.zkin);
+        Ok(plan)
+    }
+
+    /// Plan the final stark proof and the snark proof,
+    /// the recursive2 setup and the snark setup are reused from the cache if they're there
+    pub fn final_stage(ctx: &FinalContext) -> Self {
+        let args = &ctx.final_snark;
+        let mut plan = Self::new(PlanStage::Final(args.curve_type));
+        let r2 = &ctx.recursive2_stark;
+        let sp = &ctx.final_stark;
+        plan.input(&r2.zkin);
+        plan.input(&ctx.final_stark_struct);
+
+        let mut cache = ctx.prove_data_cache.lock().unwrap();
+        if !cache.final_cache.already_cached {
+            plan.input(&ctx.recursive2_circom.circom_file);
+            plan.links(&ctx.recursive2_circom.link_directories);
+            plan.stark_outputs(r2);
+        }
+        plan.stark_cache(&cache, "final", r2);
+        plan.output(&r2.commit_file);
+        plan.output(&ctx.final_circom.circom_file);
+        plan.output(&sp.zkin);
+
+        let curve = args.curve_type;
+        let snark = cache.snark(curve).clone();
+        if !snark.already_cached {
+            plan.links(&ctx.final_circom.link_directories);
+            for file in [&sp.r1cs_file, &sp.wasm_file, &args.pk_file, &args.vk_file] {
+                plan.output(file);
+            }
+        }
+        let entries = [
+            ("wasm", SnarkFileType::Wasm, &snark.wasm_file, &sp.wasm_file),
+            ("r1cs", SnarkFileType::R1cs, &snark.r1cs_file, &sp.r1cs_file),
+            ("pk", SnarkFileType::PK, &snark.pk_file, &args.pk_file),
+            ("vk", SnarkFileType::VK, &snark.vk_file, &args.vk_file),
+        ];
+        for (name, file_type, cached, generated) in entries {
+            plan.cache_entry(
+                &cache,
+                format!("snark.{}", name),
+                CacheStage::Snark(curve, file_type),
+                snark.already_cached,
+                cached,
+                generated,
+            );
+        }
+        plan.output(&args.public_input_file);
+        plan.output(&args.proof_file);
+        plan
+    }
+
+    /// The inputs which don't exist, and the reused cache files which don't exist
+    pub fn missing(&self) -> Vec<String> {
+        let inputs = self.inputs.iter().filter(|i| !i.exists).map(|i| &i.path);
+        let cache = self
+            .cache
+            .iter()
+            .filter(|c| c.reused && !Path::new(&c.path).exists())
+            .map(|c| &c.path);
+        inputs.chain(cache).cloned().collect()
+    }
+
+    /// All the prerequisites exist
+    pub fn is_ready(&self) -> bool {
+        self.missing().is_empty()
+    }
+
+    fn input<S: AsRef<str>>(&mut self, path: S) {
+        let path = path.as_ref();
+        if !self.inputs.iter().any(|i| i.path == path) {
+            self.inputs.push(PlanInput {
+                path: path.to_string(),
+                exists: Path::new(path).exists(),
+            });
+        }
+    }
+
+    fn links(&mut self, links: &[String]) {
+        for link in links {
+            self.input(link);
+        }
+    }
+
+    fn output<S: AsRef<str>>(&mut self, path: S) {
+        let path = path.as_ref();
+        if !self.outputs.iter().any(|o| o == path) {
+            self.outputs.push(path.to_string());
+        }
+    }
+
+    /// The files written by the setup, the exec and the proving of the circuit
+    fn stark_outputs(&mut self, sp: &StarkProveArgs) {
+        for file in [
+            &sp.r1cs_file,
+            &sp.wasm_file,
+            &sp.pil_file,
+            &sp.const_file,
+            &sp.exec_file,
+            &sp.commit_file,
+        ] {
+            self.output(file);
+        }
+    }
+
+    /// The cache entries of the setup of the recursive circuit, `stage` is agg or final
+    fn stark_cache(&mut self, cache: &ProveDataCache, stage: &str, sp: &StarkProveArgs) {
+        let (data, cache_stage): (_, fn(StarkFileType) -> CacheStage) = if stage == "agg" {
+            (&cache.agg_cache, CacheStage::Agg)
+        } else {
+            (&cache.final_cache, CacheStage::Final)
+        };
+        let piljson = format!("{}.json", sp.pil_file);
+        let entries = [
+            ("r1cs", StarkFileType::R1cs, &data.r1cs_file, &sp.r1cs_file),
+            ("wasm", StarkFileType::Wasm, &data.wasm_file, &sp.wasm_file),
+            ("pil", StarkFileType::Pil, &data.pil_file, &sp.pil_file),
+            (
+                "pil.json",
+                StarkFileType::PilJson,
+                &data.piljson_file,
+                &piljson,
+            ),
+            (
+                "const",
+                StarkFileType::Const,
+                &data.const_file,
+                &sp.const_file,
+            ),
+            ("exec", StarkFileType::Exec, &data.exec_file, &sp.exec_file),
+        ];
+        for (name, file_type, cached, generated) in entries {
+            self.cache_entry(
+                cache,
+                format!("{}.{}", stage, name),
+                cache_stage(file_type),
+                data.already_cached,
+                cached,
+                generated,
+            );
+        }
+    }
+
+    /// The cached file is reused, or the generated one is copied into the cache dir
+    fn cache_entry(
+        &mut self,
+        cache: &ProveDataCache,
+        name: String,
+        stage: CacheStage,
+        reused: bool,
+        cached: &str,
+        generated: &str,
+    ) {
+        if !reused {
+            let stage_dir = stage.construct_stage_dir(
+                cache.task_name.clone(),
+                cache.base_dir.clone(),
+                cache.cache_dir.clone(),
+            );
+            let file_name = Path::new(generated).file_name().unwrap_or_default();
+            self.output(Path::new(&stage_dir).join(file_name).to_string_lossy());
+        }
+        self.cache.push(PlanCacheEntry {
+            name,
+            path: if reused { cached } else { generated }.to_string(),
+            reused,
+        });
+    }
+}
+
+impl fmt::Display for ProofPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "plan of the {:?} stage", self.stage)?;
+        write!(f, "\ninputs:")?;
+        for input in self.inputs.iter() {
+            let state = if input.exists { "ok" } else { "missing" };
+            write!(f, "\n  [{:>7}] {}", state, input.path)?;
+        }
+        write!(f, "\ncache:")?;
+        for entry in self.cache.iter() {
+            let state = if entry.reused { "reuse" } else { "generate" };
+            write!(f, "\n  [{:>8}] {:<14} {}", state, entry.name, entry.path)?;
+        }
+        write!(f, "\noutputs:")?;
+        for output in self.outputs.iter() {
+            write!(f, "\n  {}", output)?;
+        }
+        let missing = self.missing();
+        if missing.is_empty() {
+            write!(f, "\nready")
+        } else {
+            write!(
+                f,
+                "\nnot ready, {} prerequisites are missing",
+                missing.len()
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::{Arc, Mutex};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_batch_plan() {
+        let basedir = env::temp_dir().join(format!("plan_{}", Uuid::new_v4()));
+        let dir = basedir.to_str().unwrap();
+        let links = LinkConfig::default();
+        let contexts: Vec<_> = (0..2)
+            .map(|i| {
+                let chunk_id = i.to_string();
+                BatchContext::new(dir, "0", "evm", &chunk_id, "".into(), 0, &links)
+            })
+            .collect();
+        let plan = ProofPlan::batch(&contexts);
+        assert_eq!(plan.stage, PlanStage::Batch);
+        assert!(!plan.is_ready());
+        assert!(plan.missing().contains(&contexts[1].bootloader_input()));
+        assert!(plan
+            .outputs
+            .contains(&contexts[0].recursive1_stark.zkin.clone()));
+        assert!(plan.cache.is_empty());
+
+        // the prerequisites are created by the executor and the setup
+        for ctx in contexts.iter() {
+            for file in [&ctx.bootloader_input(), &ctx.batch_struct, &ctx.c12_struct] {
+                std::fs::create_dir_all(Path::new(file).parent().unwrap()).unwrap();
+                std::fs::write(file, "").unwrap();
+            }
+        }
+        let plan = ProofPlan::batch(&contexts);
+        assert!(plan.is_ready(), "{}", plan);
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_aggregate_and_final_plan() {
+        let basedir = env::temp_dir().join(format!("plan_{}", Uuid::new_v4()));
+        let dir = basedir.to_str().unwrap();
+        let links = LinkConfig::default();
+        let cache = Arc::new(Mutex::new(ProveDataCache::new(
+            "evm".into(),
+            dir.into(),
+            "".into(),
+        )));
+        let ctx = AggContext::new(
+            dir,
+            "a_agg",
+            "evm",
+            "0_chunk_0".into(),
+            "0_chunk_2".into(),
+            0,
+            &links,
+            cache.clone(),
+        );
+        let plan = ProofPlan::aggregate(&ctx).unwrap();
+        assert_eq!(plan.missing().len(), 5);
+        assert!(plan.missing().contains(&ctx.chunk_zkin("0", 2)));
+        assert!(plan.cache.iter().all(|c| !c.reused));
+        assert!(plan.outputs.contains(&ctx.agg_stark.zkin));
+        // the generated setup is copied into the cache
+        assert!(plan
+            .outputs
+            .iter()
+            .any(|o| o.ends_with("cache/evm/agg/evm.recursive1.r1cs")));
+
+        let ctx = AggContext::new(
+            dir,
+            "a_agg",
+            "evm",
+            "0_chunk_0".into(),
+            "1_chunk_2".into(),
+            0,
+            &links,
+            cache.clone(),
+        );
+        assert!(ProofPlan::aggregate(&ctx).is_err());
+
+        // the cached setup is reused, it must exist
+        std::fs::create_dir_all(basedir.join("cache/snark")).unwrap();
+        let cache = Arc::new(Mutex::new(ProveDataCache::new(
+            "evm".into(),
+            dir.into(),
+            basedir.join("cache").to_string_lossy().to_string(),
+        )));
+        let ctx = FinalContext::new(
+            dir.into(),
+            "a".into(),
+            "evm".into(),
+            Curve::BN128,
+            "".into(),
+            &links,
+            cache,
+        );
+        let plan = ProofPlan::final_stage(&ctx);
+        assert_eq!(plan.stage, PlanStage::Final(Curve::BN128));
+        assert_eq!(plan.cache.len(), 10);
+        assert!(plan.cache.iter().all(|c| c.reused));
+        assert!(plan.missing().contains(&ctx.recursive2_stark.zkin));
+        assert_eq!(plan.missing().len(), 12);
+        assert!(!plan.outputs.contains(&ctx.final_snark.pk_file));
+        assert!(plan.outputs.contains(&ctx.final_snark.proof_file));
+        assert!(plan.to_string().contains("[   reuse] snark.pk"));
+    }
+}
@@ -1,4 +1,5 @@
 use super::Prover;
+use crate::args::LinkConfig;
 use crate::contexts::AggContext;
 use crate::contexts::BatchContext;
 use crate::contexts::{CacheStage, StarkFileType};
@@ -22,24 +23,25 @@ impl AggProver {
 impl Prover<AggContext> for AggProver {
     fn prove(&self, ctx: &AggContext) -> Result<()> {
         log::info!("start aggregate prove, ctx: {:?}", ctx);
-        let mut prove_data_cache = ctx.prove_data_cache.lock().unwrap();
+        // the cache is locked only to read and add the setup, other stages prove meanwhile
+        let mut agg_cache = ctx.prove_data_cache.lock().unwrap().agg_cache.clone();
+        if agg_cache.already_cached {
+            agg_cache.check()?;
+        }
 
         // 1. Compile circom circuit to r1cs, and generate witness
-        let task_id_slice: Vec<_> = ctx.input.split("_chunk_").collect();
-        let task_id2_slice: Vec<_> = ctx.input2.split("_chunk_").collect();
-        assert_eq!(task_id_slice[0], task_id2_slice[0]);
-
-        let start: usize = task_id_slice[1].parse::<usize>().unwrap();
-        let end: usize = task_id2_slice[1].parse::<usize>().unwrap();
+        let (task_id, start, end) = ctx.chunks()?;
         let mut batch_ctx = vec![];
         for i in start..=end {
             batch_ctx.push(BatchContext::new(
                 &ctx.basedir,
-                task_id_slice[0],
+                &task_id,
                 &ctx.task_name,
                 &format!("{}", i),
-                "".to_string(), // don't have to init the l2_batch_data when aggregate proof
+                "".to_string(), // the batch data is not loaded when aggregate proof
                 ctx.force_bits,
+                // only the paths of the chunks are used, their circuits are not compiled again
+                &LinkConfig::default(),
             ));
             log::info!("batch_ctx[{}]: {:?}", i, batch_ctx[i]);
         }
@@ -54,7 +56,7 @@ impl Prover<AggContext> for AggProver {
         log::info!("agg_circom: {:?}", cc);
 
         let mut cached_files = vec![];
-        if !prove_data_cache.agg_cache.already_cached {
+        if !agg_cache.already_cached {
             circom_compiler(
                 r1_circom.circom_file.clone(),
                 "goldilocks".to_string(),
@@ -78,20 +80,11 @@ impl Prover<AggContext> for AggProver {
         }
 
         // 2. compress inputs
-        let zkin = format!(
-            "{}/proof/{}/batch_proof_{}/{}.recursive1.zkin.json",
-            ctx.basedir, task_id_slice[0], start, ctx.task_name,
-        );
+        let zkin = ctx.chunk_zkin(&task_id, start);
 
         // FIXME: if there is only one chunk for current block, just aggregate the chunk with itself.
         let zkin2 = if end > start {
-            format!(
-                "{}/proof/{}/batch_proof_{}/{}.recursive1.zkin.json",
-                ctx.basedir,
-                task_id_slice[0],
-                start + 1,
-                ctx.task_name,
-            )
+            ctx.chunk_zkin(&task_id, start + 1)
         } else {
             zkin.clone()
         };
@@ -100,7 +93,7 @@ impl Prover<AggContext> for AggProver {
         log::info!("join {} {} -> {}", zkin, zkin2, ctx.agg_zkin);
         join_zkin(&zkin, &zkin2, &ctx.agg_zkin)?;
         // 3. compress setup
-        if !prove_data_cache.agg_cache.already_cached {
+        if !agg_cache.already_cached {
             setup(
                 &r1_stark.r1cs_file,
                 &r1_stark.pil_file,
@@ -129,33 +122,35 @@ impl Prover<AggContext> for AggProver {
                     CacheStage::Agg(StarkFileType::PilJson),
                 ),
             ]);
-            prove_data_cache.batch_add(cached_files)?;
+            // prove by the setup in the workdir, the cached files may be copied by another stage
+            agg_cache.add_all(&cached_files);
+            ctx.prove_data_cache
+                .lock()
+                .unwrap()
+                .batch_add(cached_files)?;
         }
 
         // 4. compress exec
-        log::info!("wasm_file: {}", prove_data_cache.agg_cache.wasm_file);
+        log::info!("wasm_file: {}", agg_cache.wasm_file);
         exec(
             &ctx.agg_zkin,
-            &prove_data_cache.agg_cache.wasm_file,
-            &prove_data_cache.agg_cache.pil_file,
-            &prove_data_cache.agg_cache.exec_file,
+            &agg_cache.wasm_file,
+            &agg_cache.pil_file,
+            &agg_cache.exec_file,
             &r1_stark.commit_file,
         )?;
 
         // 5. stark prove
         log::info!("recursive2: {:?} -> {:?}", r1_stark, cc);
-        let prev_zkin_out = format!(
-            "{}/proof/{}/batch_proof_{}/{}_input.recursive1.zkin.json",
-            ctx.basedir, task_id_slice[0], 0, ctx.task_name,
-        );
+        let prev_zkin_out = ctx.joined_zkin(&task_id, 0);
 
         stark_prove(
             &ctx.agg_struct,
-            &prove_data_cache.agg_cache.piljson_file,
+            &agg_cache.piljson_file,
             true,
             false,
             false,
-            &prove_data_cache.agg_cache.const_file,
+            &agg_cache.const_file,
             &r1_stark.commit_file,
             &cc.circom_file,
             &prev_zkin_out,
@@ -164,14 +159,8 @@ impl Prover<AggContext> for AggProver {
 
         #[allow(clippy::needless_range_loop)]
         for i in 2..=end {
-            let zkin = format!(
-                "{}/proof/{}/batch_proof_{}/{}.recursive1.zkin.json",
-                ctx.basedir, task_id_slice[0], i, ctx.task_name,
-            );
-            let zkin_out = format!(
-                "{}/proof/{}/batch_proof_{}/{}_input.recursive1.zkin.json",
-                ctx.basedir, task_id_slice[0], i, ctx.task_name,
-            );
+            let zkin = ctx.chunk_zkin(&task_id, i);
+            let zkin_out = ctx.joined_zkin(&task_id, i);
             let r_stark = &batch_ctx[i].recursive1_stark;
 
             log::info!("join {} {} -> {}", prev_zkin_out, zkin, zkin_out);
@@ -179,19 +168,19 @@ impl Prover<AggContext> for AggProver {
 
             exec(
                 &zkin_out,
-                &prove_data_cache.agg_cache.wasm_file,
-                &prove_data_cache.agg_cache.pil_file,
-                &prove_data_cache.agg_cache.exec_file,
+                &agg_cache.wasm_file,
+                &agg_cache.pil_file,
+                &agg_cache.exec_file,
                 &r_stark.commit_file,
             )?;
 
             stark_prove(
                 &ctx.agg_struct,
-                &prove_data_cache.agg_cache.piljson_file,
+                &agg_cache.piljson_file,
                 true,
                 false,
                 false,
-                &prove_data_cache.agg_cache.const_file,
+                &agg_cache.const_file,
                 &r_stark.commit_file,
                 &cc.circom_file,
                 &prev_zkin_out,
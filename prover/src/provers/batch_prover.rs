@@ -1,22 +1,39 @@
 use super::Prover;
 use crate::contexts::BatchContext;
+use crate::retry::NonRetryableError;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use powdr::number::{FieldElement, GoldilocksField};
 
 use dsl_compile::circom_compiler;
 use recursion::{compressor12_exec::exec, compressor12_setup::setup};
 use starky::prove::stark_prove;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::{fs, io::Read};
 use zkvm::zkvm_prove_only;
 
 #[derive(Default)]
-pub struct BatchProver {}
+pub struct BatchProver {
+    /// Checked between the phases, the proof stops at the next one once it's set
+    cancelled: Arc<AtomicBool>,
+}
 
 impl BatchProver {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn with_cancel(cancelled: Arc<AtomicBool>) -> Self {
+        BatchProver { cancelled }
+    }
+
+    fn check_cancelled(&self, phase: &str) -> Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            bail!("the batch proof is cancelled before {}", phase);
+        }
+        Ok(())
+    }
 }
 
 impl Prover<BatchContext> for BatchProver {
@@ -30,19 +47,27 @@ impl Prover<BatchContext> for BatchProver {
         let c12_stark = &ctx.c12_stark;
         let r1_circom = &ctx.recursive1_circom; // output
         let r1_stark = &ctx.recursive1_stark; // output
-                                              // given that the l2batch data has been stored in ctx.l2_data.
-        let serde_data = ctx.l2_batch_data.clone();
+        let serde_data = ctx.batch_data()?;
         // the circom: $output/main_proof.bin_1
         // the zkin(stark proof): $output/main_proof.bin_0
-        let bootloader_input_path = format!(
-            "{}/proof/{}/{}/{}_chunks_{}.data",
-            &ctx.basedir, ctx.task_id, ctx.task_name, ctx.task_name, ctx.chunk_id
-        );
+        let bootloader_input_path = ctx.bootloader_input();
         log::info!("bootloader_input_path: {}", bootloader_input_path);
-        let mut f = fs::File::open(bootloader_input_path.clone())?;
-        let metadata = fs::metadata(bootloader_input_path)?;
+        // the input is written by the executor before the task, it's not retried if it's missing or invalid
+        let mut f = fs::File::open(bootloader_input_path.clone()).map_err(|e| {
+            NonRetryableError(format!(
+                "open the bootloader input {} failed, {}",
+                bootloader_input_path, e
+            ))
+        })?;
+        let metadata = fs::metadata(&bootloader_input_path)?;
         let file_size = metadata.len() as usize;
-        assert!(file_size % 8 == 0);
+        if file_size < 8 || file_size % 8 != 0 {
+            return Err(NonRetryableError(format!(
+                "the size of the bootloader input {} is {}, it should be a multiple of 8",
+                bootloader_input_path, file_size
+            ))
+            .into());
+        }
         // read the start_of_shutdown_routine
         let mut buffer = [0u8; 8];
         f.read_exact(&mut buffer).unwrap();
@@ -58,15 +83,19 @@ impl Prover<BatchContext> for BatchProver {
         });
         log::debug!("read bootstrap input done");
 
+        self.check_cancelled("the zkvm prove")?;
         zkvm_prove_only(
             &ctx.task_name,
             &serde_data,
             bi,
             start_of_shutdown_routine,
-            ctx.chunk_id.parse()?,
+            ctx.chunk_id.parse().map_err(|_| {
+                NonRetryableError(format!("the chunk id {} is invalid", ctx.chunk_id))
+            })?,
             &ctx.evm_output,
         )?;
         log::debug!("zkvm_prove_only done");
+        self.check_cancelled("the batch compression")?;
         /*
         stark_prove(
             &ctx.batch_struct,
@@ -112,6 +141,7 @@ impl Prover<BatchContext> for BatchProver {
         )?;
 
         // 3. stark prove
+        self.check_cancelled("the batch stark prove")?;
         stark_prove(
             &ctx.batch_struct,
             &batch_stark.piljson,
@@ -126,6 +156,7 @@ impl Prover<BatchContext> for BatchProver {
         )?;
         log::info!("end batch prove");
 
+        self.check_cancelled("the c12 prove")?;
         log::info!("start c12 prove: {:?}", c12_stark);
 
         // 2. Compile circom circuit to r1cs, and generate witness
@@ -157,6 +188,7 @@ impl Prover<BatchContext> for BatchProver {
         )?;
 
         // 3. stark prove
+        self.check_cancelled("the c12 stark prove")?;
         stark_prove(
             &ctx.c12_struct,
             &c12_stark.piljson,
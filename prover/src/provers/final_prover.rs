@@ -20,17 +20,32 @@ impl FinalProver {
 impl Prover<FinalContext> for FinalProver {
     fn prove(&self, ctx: &FinalContext) -> Result<()> {
         log::info!("start final_stark prove, ctx: {:?}", ctx);
-        let mut prove_data_cache = ctx.prove_data_cache.lock().unwrap();
+        let args = &ctx.final_snark;
+        let curve = args.curve_type;
+        // the cache is locked only to read and add the setup, other stages prove meanwhile
+        let (mut final_cache, mut curve_cache) = {
+            let mut prove_data_cache = ctx.prove_data_cache.lock().unwrap();
+            let final_cache = prove_data_cache.final_cache.clone();
+            (final_cache, prove_data_cache.snark(curve).clone())
+        };
+        if final_cache.already_cached {
+            final_cache.check()?;
+        }
+        if curve_cache.already_cached {
+            curve_cache.check()?;
+        }
 
         // 1. compress setup
         let rc2 = &ctx.recursive2_circom;
         let r2 = ctx.recursive2_stark.clone();
         let sp = &ctx.final_stark;
         let cc = &ctx.final_circom;
+        // the workdir of the final task, the aggregation is read from its own
+        std::fs::create_dir_all(&cc.output)?;
 
         let mut cached_files = vec![];
 
-        if !prove_data_cache.final_cache.already_cached {
+        if !final_cache.already_cached {
             circom_compiler(
                 rc2.circom_file.clone(),
                 "goldilocks".to_string(),
@@ -66,7 +81,12 @@ impl Prover<FinalContext> for FinalProver {
                     CacheStage::Final(StarkFileType::PilJson),
                 ),
             ]);
-            prove_data_cache.batch_add(cached_files.clone())?;
+            // prove by the setup in the workdir, the cached files may be copied by another stage
+            final_cache.add_all(&cached_files);
+            ctx.prove_data_cache
+                .lock()
+                .unwrap()
+                .batch_add(cached_files.clone())?;
         }
 
         log::info!("2. compress exec");
@@ -76,20 +96,20 @@ impl Prover<FinalContext> for FinalProver {
         // );
         exec(
             &r2.zkin,
-            &prove_data_cache.final_cache.wasm_file,
-            &prove_data_cache.final_cache.pil_file,
-            &prove_data_cache.final_cache.exec_file,
+            &final_cache.wasm_file,
+            &final_cache.pil_file,
+            &final_cache.exec_file,
             &r2.commit_file,
         )?;
 
         log::info!("3. generate final proof");
         stark_prove(
             &ctx.final_stark_struct,
-            &prove_data_cache.final_cache.piljson_file,
+            &final_cache.piljson_file,
             false,
             false,
             false,
-            &prove_data_cache.final_cache.const_file,
+            &final_cache.const_file,
             &r2.commit_file,
             &cc.circom_file,
             &sp.zkin,
@@ -97,12 +117,10 @@ impl Prover<FinalContext> for FinalProver {
         )?;
 
         log::info!("end final stark prove");
-        let args = &ctx.final_snark;
-
-        if !prove_data_cache.snark_cache.already_cached {
+        if !curve_cache.already_cached {
             circom_compiler(
                 cc.circom_file.clone(),
-                args.curve_type.to_lowercase(),
+                curve.prime().to_string(),
                 "full".to_string(),
                 cc.link_directories.clone(),
                 cc.output.clone(),
@@ -110,7 +128,7 @@ impl Prover<FinalContext> for FinalProver {
                 false,
             )?;
             groth16_setup(
-                &args.curve_type,
+                curve.as_str(),
                 &sp.r1cs_file,
                 &args.pk_file,
                 &args.vk_file,
@@ -118,18 +136,32 @@ impl Prover<FinalContext> for FinalProver {
             )?;
 
             cached_files.extend_from_slice(&[
-                (sp.wasm_file.clone(), CacheStage::Snark(SnarkFileType::Wasm)),
-                (sp.r1cs_file.clone(), CacheStage::Snark(SnarkFileType::R1cs)),
-                (args.pk_file.clone(), CacheStage::Snark(SnarkFileType::PK)),
-                (args.vk_file.clone(), CacheStage::Snark(SnarkFileType::VK)),
+                (
+                    sp.wasm_file.clone(),
+                    CacheStage::Snark(curve, SnarkFileType::Wasm),
+                ),
+                (
+                    sp.r1cs_file.clone(),
+                    CacheStage::Snark(curve, SnarkFileType::R1cs),
+                ),
+                (
+                    args.pk_file.clone(),
+                    CacheStage::Snark(curve, SnarkFileType::PK),
+                ),
+                (
+                    args.vk_file.clone(),
+                    CacheStage::Snark(curve, SnarkFileType::VK),
+                ),
             ]);
-            prove_data_cache.batch_add(cached_files)?;
+            curve_cache.add_all(&cached_files);
+            ctx.prove_data_cache
+                .lock()
+                .unwrap()
+                .batch_add(cached_files)?;
         }
 
-        let curve_cache = &prove_data_cache.snark_cache;
-
         groth16_prove(
-            &args.curve_type,
+            curve.as_str(),
             &curve_cache.r1cs_file,
             &curve_cache.wasm_file,
             &curve_cache.pk_file,
@@ -140,7 +172,7 @@ impl Prover<FinalContext> for FinalProver {
         )?;
 
         groth16_verify(
-            &args.curve_type,
+            curve.as_str(),
             &curve_cache.vk_file,
             &args.public_input_file,
             &args.proof_file,
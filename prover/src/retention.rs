@@ -0,0 +1,451 @@
+use crate::layout::{
+    ProofLayout, BLOB_DIR, JOB_FILE, PROOF_FILE, PUBLIC_INPUT_FILE, VERIFICATION_KEY_FILE,
+};
+use crate::stage::Stage;
+use crate::task_store::{TaskRecord, TaskStatus, TaskStore};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The outputs of the final stage, they are loaded by get_proof and never pruned
+pub const FINAL_OUTPUTS: [&str; 3] = [PROOF_FILE, PUBLIC_INPUT_FILE, VERIFICATION_KEY_FILE];
+
+/// How the intermediate artifacts of the proved tasks are pruned, it's the `[retention]` of the PipelineConfig.
+///
+/// A task is proved once the proof of its final stage exists. Its artifacts are the files under the workdirs
+/// of the final stage, the aggregation and the chunks, e.g. the circom outputs, r1cs, wasm, `.const`, `.cm`,
+/// `.exec` and zkin files, the executor outputs next to the workdirs, and the blobs of the batch data.
+/// The final outputs, the checkpoints and the block proof jobs are always kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    /// Prune the artifacts of all the proved tasks
+    pub keep_final_only: bool,
+    /// Keep the artifacts of the N latest proved tasks, 0 keeps all
+    pub keep_latest: usize,
+    /// Prune the artifacts of the oldest proved tasks until `basedir/proof` and `basedir/blobs` are at most max_bytes,
+    /// 0 means no limit
+    pub max_bytes: u64,
+}
+
+impl RetentionPolicy {
+    /// Nothing is pruned by the default policy
+    pub fn is_enabled(&self) -> bool {
+        self.keep_final_only || self.keep_latest > 0 || self.max_bytes > 0
+    }
+}
+
+/// A file or a directory to prune
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Artifact {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// The artifacts pruned by the garbage collection, or to be pruned in the dry run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub dry_run: bool,
+    /// The keys of the final stages whose artifacts are pruned
+    pub tasks: Vec<String>,
+    pub artifacts: Vec<Artifact>,
+}
+
+impl GcReport {
+    pub fn freed_bytes(&self) -> u64 {
+        self.artifacts.iter().map(|a| a.bytes).sum()
+    }
+}
+
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} artifacts of {} tasks, {} bytes",
+            if self.dry_run {
+                "would remove"
+            } else {
+                "removed"
+            },
+            self.artifacts.len(),
+            self.tasks.len(),
+            self.freed_bytes()
+        )?;
+        for artifact in self.artifacts.iter() {
+            write!(f, "\n  {:>14}  {}", artifact.bytes, artifact.path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// A proved task and its artifacts
+struct ProvedTask {
+    key: String,
+    finished_at: u64,
+    artifacts: Vec<Artifact>,
+}
+
+/// Prune the artifacts of the proved tasks in the task store by the policy, the oldest ones first.
+/// Nothing is removed in the dry run, the report lists what would be removed.
+pub fn collect_garbage(
+    basedir: &str,
+    task_store: &dyn TaskStore,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<GcReport> {
+    let layout = ProofLayout::new(basedir);
+    let basedir = Path::new(basedir);
+    let records: HashMap<String, TaskRecord> = task_store
+        .list()?
+        .into_iter()
+        .map(|r| (r.key.clone(), r))
+        .collect();
+
+    let mut proved = vec![];
+    for record in records.values() {
+        if !matches!(record.stage, Stage::Final(..)) || record.status != TaskStatus::Succeeded {
+            continue;
+        }
+        let workdir = basedir.join(record.stage.path());
+        if workdir.join(PROOF_FILE).is_file() {
+            proved.push(proved_task(basedir, record, &records)?);
+        }
+    }
+    // the latest first, the tasks finished in the same second are ordered by key
+    proved.sort_by(|a, b| {
+        b.finished_at
+            .cmp(&a.finished_at)
+            .then_with(|| a.key.cmp(&b.key))
+    });
+
+    let mut usage = if policy.max_bytes > 0 {
+        disk_usage(&layout.proof_dir())? + disk_usage(&basedir.join(BLOB_DIR))?
+    } else {
+        0
+    };
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut pruned = HashSet::new();
+    for (age, mut task) in proved.into_iter().enumerate().rev() {
+        let prune = policy.keep_final_only
+            || (policy.keep_latest > 0 && age >= policy.keep_latest)
+            || (policy.max_bytes > 0 && usage > policy.max_bytes);
+        // the blobs are pruned with the first task of them
+        task.artifacts.retain(|a| !pruned.contains(&a.path));
+        if !prune || task.artifacts.is_empty() {
+            continue;
+        }
+        for artifact in task.artifacts.iter() {
+            pruned.insert(artifact.path.clone());
+            usage = usage.saturating_sub(artifact.bytes);
+            if !dry_run {
+                remove(&artifact.path)
+                    .with_context(|| format!("remove {} failed", artifact.path.display()))?;
+            }
+        }
+        log::info!(
+            "{} the artifacts of task: {}",
+            if dry_run { "would prune" } else { "prune" },
+            task.key
+        );
+        report.tasks.push(task.key);
+        report.artifacts.extend(task.artifacts);
+    }
+    Ok(report)
+}
+
+/// Collect the artifacts of the final stage and its finished dependencies,
+/// the unfinished ones may still be read by other stages.
+fn proved_task(
+    basedir: &Path,
+    record: &TaskRecord,
+    records: &HashMap<String, TaskRecord>,
+) -> Result<ProvedTask> {
+    let mut stages = vec![&record.stage];
+    let mut keys = HashSet::from([record.key.clone()]);
+    let mut dependencies = record.stage.dependencies();
+    while let Some(key) = dependencies.pop() {
+        let Some(dependency) = records.get(&key) else {
+            continue;
+        };
+        if dependency.status.is_finished() && keys.insert(key) {
+            dependencies.extend(dependency.stage.dependencies());
+            stages.push(&dependency.stage);
+        }
+    }
+
+    let mut artifacts = vec![];
+    let mut task_dirs = HashSet::new();
+    for stage in stages.iter() {
+        let workdir = basedir.join(stage.path());
+        let is_final = matches!(stage, Stage::Final(..));
+        artifacts.extend(list_artifacts(&workdir, |name| {
+            is_checkpoint(name) || (is_final && FINAL_OUTPUTS.contains(&name))
+        })?);
+        if let Some(task_dir) = workdir.parent() {
+            task_dirs.insert(task_dir.to_path_buf());
+        }
+    }
+
+    // the executor outputs and zkin files next to the workdirs are shared by the stages of the task dir
+    for task_dir in task_dirs {
+        let stages: Vec<(&TaskRecord, PathBuf)> = records
+            .values()
+            .map(|r| (r, basedir.join(r.stage.path())))
+            .filter(|(_, workdir)| workdir.parent() == Some(task_dir.as_path()))
+            .collect();
+        if stages.iter().any(|(r, _)| !r.status.is_finished()) {
+            continue;
+        }
+        artifacts.extend(list_artifacts(&task_dir, |name| {
+            name == JOB_FILE || stages.iter().any(|(_, workdir)| workdir.ends_with(name))
+        })?);
+    }
+
+    // the blob of the batch data may be shared by the chunks of other tasks, it's kept until they're all proved
+    let digests: HashSet<&String> = stages
+        .iter()
+        .filter_map(|stage| match stage {
+            Stage::Batch(_, _, digest) if !digest.is_empty() => Some(digest),
+            _ => None,
+        })
+        .collect();
+    for digest in digests {
+        let shared = records.values().any(|r| {
+            matches!(&r.stage, Stage::Batch(_, _, d) if d == digest)
+                && r.status != TaskStatus::Succeeded
+        });
+        let path = basedir.join(ProofLayout::blob_path(digest));
+        if !shared && path.is_file() {
+            artifacts.push(Artifact {
+                bytes: disk_usage(&path)?,
+                path,
+            });
+        }
+    }
+
+    Ok(ProvedTask {
+        key: record.key.clone(),
+        finished_at: record.finished_at.unwrap_or_default(),
+        artifacts,
+    })
+}
+
+/// The checkpoints of the task store in the workdir
+fn is_checkpoint(name: &str) -> bool {
+    name == "status" || name.starts_with("status.")
+}
+
+/// The entries of the dir except the kept ones, the symlinks are not followed
+fn list_artifacts<F: Fn(&str) -> bool>(dir: &Path, keep: F) -> Result<Vec<Artifact>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut artifacts = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if keep(&name) {
+            continue;
+        }
+        artifacts.push(Artifact {
+            bytes: disk_usage(&path)?,
+            path,
+        });
+    }
+    artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(artifacts)
+}
+
+fn disk_usage(path: &Path) -> Result<u64> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(path)? {
+        bytes += disk_usage(&entry?.path())?;
+    }
+    Ok(bytes)
+}
+
+fn remove(path: &Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::BlobStore;
+    use crate::curve::Curve;
+    use crate::task_store::FsTaskStore;
+    use std::env;
+    use uuid::Uuid;
+
+    /// Prove a block of 2 chunks in `proof/{batch}`, aggregated into `proof/{id}_agg` and finalized,
+    /// return the final stage
+    fn prove_block(
+        basedir: &Path,
+        store: &FsTaskStore,
+        batch: &str,
+        id: &str,
+        digest: &str,
+    ) -> Stage {
+        let agg_key = format!("{id}_agg");
+        let stages = [
+            Stage::Batch(batch.into(), "0".into(), digest.into()),
+            Stage::Batch(batch.into(), "1".into(), digest.into()),
+            Stage::Aggregate(
+                agg_key.clone(),
+                format!("{batch}_chunk_0"),
+                format!("{batch}_chunk_1"),
+            ),
+            Stage::Final(id.into(), Curve::BN128, "addr".into()),
+        ];
+        for stage in stages.iter() {
+            store.put("evm", stage).unwrap();
+            store
+                .transition(&stage.key(), TaskStatus::Succeeded)
+                .unwrap();
+            let workdir = basedir.join(stage.path());
+            std::fs::write(workdir.join("circuit.r1cs"), vec![0u8; 100]).unwrap();
+        }
+        let final_dir = basedir.join(stages[3].path());
+        std::fs::write(final_dir.join("proof.json"), "{}").unwrap();
+        std::fs::write(final_dir.join("public_input.json"), "[]").unwrap();
+        let evm_output = basedir.join("proof").join(batch).join("evm");
+        std::fs::create_dir_all(&evm_output).unwrap();
+        std::fs::write(evm_output.join("constants.bin"), vec![0u8; 50]).unwrap();
+        std::fs::write(basedir.join("proof").join(batch).join(JOB_FILE), "{}").unwrap();
+        std::fs::write(
+            basedir.join("proof").join(&agg_key).join("agg_zkin.json"),
+            vec![0u8; 10],
+        )
+        .unwrap();
+        stages[3].clone()
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let basedir = env::temp_dir().join(format!("retention_{}", Uuid::new_v4()));
+        let dir = basedir.to_str().unwrap();
+        let store = FsTaskStore::new(dir).unwrap();
+        let final_stage = prove_block(&basedir, &store, "0", "a", "");
+
+        // the unfinished chunk keeps the executor outputs of its task dir
+        let pending = Stage::Batch("0".into(), "2".into(), "".into());
+        store.put("evm", &pending).unwrap();
+        let policy = RetentionPolicy {
+            keep_final_only: true,
+            ..Default::default()
+        };
+        let report = collect_garbage(dir, &store, &policy, true).unwrap();
+        assert_eq!(report.tasks, vec![final_stage.key()]);
+        // r1cs of 2 chunks, the aggregation and the final stage, and agg_zkin.json
+        assert_eq!(report.artifacts.len(), 5);
+        assert_eq!(report.freed_bytes(), 410);
+        assert!(report
+            .to_string()
+            .starts_with("would remove 5 artifacts of 1 tasks"));
+        assert!(basedir.join("proof/0/batch_proof_0/circuit.r1cs").exists());
+
+        store.transition("0_2", TaskStatus::Cancelled).unwrap();
+        let report = collect_garbage(dir, &store, &policy, false).unwrap();
+        assert_eq!(report.artifacts.len(), 6);
+        assert!(!basedir.join("proof/0/batch_proof_0/circuit.r1cs").exists());
+        assert!(!basedir.join("proof/0/evm").exists());
+        assert!(!basedir.join("proof/a_agg/agg_zkin.json").exists());
+        let final_dir = basedir.join(final_stage.path());
+        for kept in [
+            basedir.join("proof/0/job.json"),
+            basedir.join("proof/0/batch_proof_0/status"),
+            basedir.join("proof/0/batch_proof_0/status.json"),
+            final_dir.join("proof.json"),
+            final_dir.join("public_input.json"),
+        ] {
+            assert!(kept.exists(), "{:?} is removed", kept);
+        }
+        assert!(!final_dir.join("circuit.r1cs").exists());
+        let report = collect_garbage(dir, &store, &policy, false).unwrap();
+        assert!(report.tasks.is_empty());
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_blobs() {
+        let basedir = env::temp_dir().join(format!("retention_{}", Uuid::new_v4()));
+        let dir = basedir.to_str().unwrap();
+        let store = FsTaskStore::new(dir).unwrap();
+        let digest = BlobStore::new(dir).put(b"data").unwrap();
+        let blob = basedir.join(ProofLayout::blob_path(&digest));
+        let final_stage = prove_block(&basedir, &store, "0", "a", &digest);
+
+        // the chunk of another batch shares the batch data
+        let pending = Stage::Batch("1".into(), "0".into(), digest.clone());
+        store.put("evm", &pending).unwrap();
+        let policy = RetentionPolicy {
+            keep_final_only: true,
+            ..Default::default()
+        };
+        let report = collect_garbage(dir, &store, &policy, false).unwrap();
+        assert!(!report.artifacts.iter().any(|a| a.path == blob));
+        assert!(blob.exists());
+
+        store.transition("1_0", TaskStatus::Succeeded).unwrap();
+        let report = collect_garbage(dir, &store, &policy, false).unwrap();
+        assert_eq!(report.tasks, vec![final_stage.key()]);
+        assert_eq!(report.artifacts.len(), 1);
+        assert_eq!(report.artifacts[0].path, blob);
+        assert!(!blob.exists());
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_retention_policy() {
+        let basedir = env::temp_dir().join(format!("retention_{}", Uuid::new_v4()));
+        let dir = basedir.to_str().unwrap();
+        let store = FsTaskStore::new(dir).unwrap();
+        prove_block(&basedir, &store, "0", "a", "");
+        prove_block(&basedir, &store, "1", "b", "");
+
+        let report = collect_garbage(dir, &store, &RetentionPolicy::default(), false).unwrap();
+        assert!(report.tasks.is_empty());
+
+        let policy = RetentionPolicy {
+            keep_latest: 1,
+            ..Default::default()
+        };
+        let report = collect_garbage(dir, &store, &policy, true).unwrap();
+        assert_eq!(report.tasks.len(), 1);
+
+        // the oldest one is pruned to get under the limit
+        let usage = disk_usage(&basedir.join("proof")).unwrap();
+        let policy = RetentionPolicy {
+            max_bytes: usage - 1,
+            ..Default::default()
+        };
+        let report = collect_garbage(dir, &store, &policy, false).unwrap();
+        assert_eq!(report.tasks.len(), 1);
+        assert!(disk_usage(&basedir.join("proof")).unwrap() < usage);
+        let policy = RetentionPolicy {
+            max_bytes: 1,
+            ..Default::default()
+        };
+        let report = collect_garbage(dir, &store, &policy, false).unwrap();
+        assert_eq!(report.tasks.len(), 1);
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+}
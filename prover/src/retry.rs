@@ -0,0 +1,99 @@
+use crate::config::deserialize_secs;
+
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+
+/// Return it from a prover if retrying the stage can't succeed, e.g. the input is invalid
+/// or the setup is missing. It's found in the chain of the error, so the context can be added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonRetryableError(pub String);
+
+impl fmt::Display for NonRetryableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for NonRetryableError {}
+
+/// How the failed stages are retried, shared by the local pipeline and the scheduler.
+/// It's the `[retry]` of the PipelineConfig, the backoffs are in seconds.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// The max number of attempts of a stage, including the first one
+    pub max_attempts: u32,
+    /// The delay before the first retry, it's doubled after each failure
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub initial_backoff: Duration,
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub max_backoff: Duration,
+    /// The errors whose message contains any of them are not retried
+    pub non_retryable: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(600),
+            non_retryable: vec![],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retrying the stage which has failed `attempts` times
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let exp = attempts.saturating_sub(1).min(31);
+        self.initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff)
+    }
+
+    pub fn is_retryable(&self, error: &anyhow::Error) -> bool {
+        if error.chain().any(|e| e.is::<NonRetryableError>()) {
+            return false;
+        }
+        let message = format!("{:#}", error);
+        !self.non_retryable.iter().any(|e| message.contains(e))
+    }
+
+    /// Return true if the stage which has failed `attempts` times with the `error` should be retried
+    pub fn should_retry(&self, attempts: u32, error: &anyhow::Error) -> bool {
+        attempts < self.max_attempts && self.is_retryable(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(30),
+            non_retryable: vec!["invalid witness".to_string()],
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(10));
+        assert_eq!(policy.backoff(2), Duration::from_secs(20));
+        assert_eq!(policy.backoff(3), Duration::from_secs(30));
+        assert_eq!(policy.backoff(100), Duration::from_secs(30));
+
+        let io_error = anyhow::anyhow!("io error");
+        assert!(policy.should_retry(1, &io_error));
+        assert!(policy.should_retry(2, &io_error));
+        assert!(!policy.should_retry(3, &io_error));
+        let err = anyhow::anyhow!("invalid witness").context("prove failed");
+        assert!(!policy.should_retry(1, &err));
+        let err = anyhow::Error::new(NonRetryableError("bad input".into())).context("prove failed");
+        assert!(!policy.should_retry(1, &err));
+        // only the type is checked, not the message
+        let err = anyhow::anyhow!("non-retryable error: bad input");
+        assert!(policy.should_retry(1, &err));
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+/// What a batch prover can run, it's advertised when the batch prover registers.
+/// The memory is in MB, the unknown cores or memory don't satisfy the requirements.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The programs whose setup the batch prover has, empty means any program
+    pub task_names: Vec<String>,
+    pub cores: u32,
+    pub memory: u64,
+    /// The features of the cpu, e.g. avx2, avx512f
+    pub cpu_features: Vec<String>,
+    /// The version of the batch prover, with `+avx512` if it's built with the avx512 feature
+    pub build_version: String,
+}
+
+impl Capabilities {
+    /// Probe the cores, the cpu features and the build of this host
+    pub fn probe(task_names: Vec<String>, memory: u64) -> Self {
+        let cores = std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or_default();
+        #[allow(unused_mut)]
+        let mut cpu_features: Vec<String> = vec![];
+        #[cfg(target_arch = "x86_64")]
+        {
+            if std::arch::is_x86_feature_detected!("avx2") {
+                cpu_features.push("avx2".to_string());
+            }
+            if std::arch::is_x86_feature_detected!("avx512f") {
+                cpu_features.push("avx512f".to_string());
+            }
+        }
+        Capabilities {
+            task_names,
+            cores,
+            memory,
+            cpu_features,
+            build_version: build_version(),
+        }
+    }
+
+    /// The batch prover of the old version advertises no build, it predates the leases,
+    /// so its results don't carry the lease_id.
+    pub fn predates_leases(&self) -> bool {
+        self.build_version.is_empty()
+    }
+
+    /// Check that the batch task of the program can run on the batch prover,
+    /// the error tells the first requirement it misses.
+    pub fn check(&self, task_name: &str, requirements: &TaskRequirements) -> Result<()> {
+        if !self.task_names.is_empty() && !self.task_names.iter().any(|t| t == task_name) {
+            bail!("program {} is not in {:?}", task_name, self.task_names);
+        }
+        if self.cores < requirements.min_cores {
+            bail!(
+                "{} cores, {} are required",
+                self.cores,
+                requirements.min_cores
+            );
+        }
+        if self.memory < requirements.min_memory {
+            bail!(
+                "{} MB memory, {} MB are required",
+                self.memory,
+                requirements.min_memory
+            );
+        }
+        if let Some(feature) = requirements
+            .cpu_features
+            .iter()
+            .find(|f| !self.cpu_features.contains(f))
+        {
+            bail!("cpu feature {} is missing", feature);
+        }
+        if !requirements.build_version.is_empty()
+            && self.build_version != requirements.build_version
+        {
+            bail!(
+                "build {} is not {}",
+                self.build_version,
+                requirements.build_version
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The version of this build, with `+avx512` if it's built with the avx512 feature
+pub fn build_version() -> String {
+    let version = env!("CARGO_PKG_VERSION");
+    if cfg!(feature = "avx512") {
+        format!("{}+avx512", version)
+    } else {
+        version.to_string()
+    }
+}
+
+/// What the batch tasks require of the batch provers, it's the `[requirements]` of the PipelineConfig.
+/// The memory is in MB, 0 and empty mean no requirement.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaskRequirements {
+    pub min_cores: u32,
+    pub min_memory: u64,
+    pub cpu_features: Vec<String>,
+    pub build_version: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = Capabilities {
+            task_names: vec!["evm".into()],
+            cores: 8,
+            memory: 1024,
+            cpu_features: vec!["avx2".into()],
+            build_version: "0.1.0".into(),
+        };
+        let mut requirements = TaskRequirements::default();
+        assert!(capabilities.check("evm", &requirements).is_ok());
+        let err = capabilities.check("lr", &requirements).unwrap_err();
+        assert!(err.to_string().contains("program lr"));
+        // the batch prover without capabilities runs any program, but misses the resources
+        assert!(Capabilities::default().check("lr", &requirements).is_ok());
+
+        requirements.min_memory = 2048;
+        let err = capabilities.check("evm", &requirements).unwrap_err();
+        assert!(err.to_string().contains("2048 MB"));
+        requirements.min_memory = 1024;
+        requirements.cpu_features = vec!["avx512f".into()];
+        let err = capabilities.check("evm", &requirements).unwrap_err();
+        assert!(err.to_string().contains("avx512f"));
+        requirements.cpu_features = vec!["avx2".into()];
+        requirements.build_version = "0.1.0+avx512".into();
+        assert!(capabilities.check("evm", &requirements).is_err());
+        requirements.build_version = "0.1.0".into();
+        requirements.min_cores = 8;
+        assert!(capabilities.check("evm", &requirements).is_ok());
+        assert!(Capabilities::default().check("evm", &requirements).is_err());
+
+        let probed = Capabilities::probe(vec![], 1024);
+        assert!(probed.cores > 0);
+        assert_eq!(probed.build_version, build_version());
+        assert!(!probed.predates_leases());
+        assert!(Capabilities::default().predates_leases());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::contexts::BatchContext;
 use crate::scheduler::scheduler::ServiceId;
+use crate::scheduler::Capabilities;
 use tokio::sync::mpsc::Sender;
 
 /// Event is used to communicate between scheduler and scheduler_server
@@ -12,6 +13,10 @@ pub enum Event {
         /// relay_to is the channel that used to send the AddServiceResult back to the service
         /// service will wait for the result on the other side of the channel
         relay_to: Sender<AddServiceResult>,
+        /// notice_to is the channel that used to send the ServiceNotice to the service
+        notice_to: Sender<ServiceNotice>,
+        /// the tasks are only relayed to the service if it can run them
+        capabilities: Capabilities,
     },
 
     /// Used to remove the service from the scheduler
@@ -23,6 +28,13 @@ pub enum Event {
         service_id: ServiceId,
         relay_to: Sender<TakeTaskResult>,
     },
+
+    /// Renew the lease of the task which the service is proving, it's sent by the heartbeat.
+    /// The empty lease_id renews all the leases held by the service.
+    RenewLease {
+        service_id: ServiceId,
+        lease_id: String,
+    },
     // Used to send the proof result to the scheduler
     // TaskResult {
     //     service_id: ServiceId,
@@ -41,6 +53,10 @@ pub struct ProofResult {
     pub task_id: String,
     pub chunk_id: String,
     pub result_code: ResultStatus,
+    /// The reason of ResultStatus::Fail or ResultStatus::NonRetryable
+    pub error_message: String,
+    /// The lease of the task, the result is rejected if it's not from the current lease holder
+    pub lease_id: String,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -48,6 +64,24 @@ pub enum ResultStatus {
     #[default]
     Success,
     Fail,
+    /// The task failed with a NonRetryableError, it's not retried
+    NonRetryable,
+    /// The service aborted the task after the ServiceNotice::CancelTask
+    Cancelled,
+}
+
+/// ServiceNotice is sent by the scheduler to the service on its own initiative
+#[derive(Debug, Clone)]
+pub enum ServiceNotice {
+    /// Abort the task which the service is proving
+    CancelTask { task_id: String, chunk_id: String },
+    /// The result of the task is rejected, it's not from the current lease holder
+    RejectResult {
+        task_id: String,
+        chunk_id: String,
+        lease_id: String,
+        reason: String,
+    },
 }
 
 pub enum AddServiceResult {
@@ -58,6 +92,7 @@ pub enum AddServiceResult {
 #[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug)]
 pub enum TakeTaskResult {
-    Success(BatchContext),
+    /// The task and the id of the lease granted to the service
+    Success(BatchContext, String),
     Fail(ServiceId),
 }
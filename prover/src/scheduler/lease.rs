@@ -0,0 +1,131 @@
+use crate::contexts::BatchContext;
+use crate::scheduler::scheduler::ServiceId;
+use anyhow::{bail, Result};
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// How long a lease lasts without renewal, the holder renews it by the heartbeats.
+/// The lease which isn't renewed in time is taken back, and its task is requeued.
+/// The legacy holder sends no heartbeat, its lease lasts until the deadline.
+pub const LEASE_TTL: Duration = Duration::from_secs(60);
+
+/// The right of a service to prove a batch task, only the result of the current holder is accepted.
+/// A new lease is granted every time the task is taken, so the results of the previous holders are stale.
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub lease_id: String,
+    pub holder: ServiceId,
+    pub task: BatchContext,
+    /// The lease expires unless the holder renews it before
+    pub expiry: Instant,
+    /// The deadline of the batch stage, the lease can't be renewed beyond it
+    pub deadline: Option<Instant>,
+    /// The holder predates the leases, its results without the lease_id are accepted
+    pub legacy: bool,
+}
+
+impl Lease {
+    pub fn new(
+        holder: ServiceId,
+        task: BatchContext,
+        deadline: Option<Instant>,
+        legacy: bool,
+    ) -> Self {
+        let mut lease = Lease {
+            lease_id: Uuid::new_v4().to_string(),
+            holder,
+            task,
+            expiry: Instant::now(),
+            deadline,
+            legacy,
+        };
+        lease.renew(Instant::now());
+        lease
+    }
+
+    /// Extend the lease by the LEASE_TTL from now, but not beyond the deadline
+    pub fn renew(&mut self, now: Instant) {
+        let expiry = now + LEASE_TTL;
+        self.expiry = match self.deadline {
+            Some(deadline) => expiry.min(deadline),
+            None => expiry,
+        };
+    }
+
+    /// Check that the result is from the holder of this lease, the error tells why it's stale.
+    /// Only the legacy holder may leave the lease_id empty, it's checked by the holder only.
+    pub fn check(&self, service_id: &str, lease_id: &str) -> Result<()> {
+        if self.holder != service_id {
+            bail!("the lease is held by service: {}", self.holder);
+        }
+        if lease_id.is_empty() {
+            if self.legacy {
+                return Ok(());
+            }
+            bail!(
+                "the lease_id is missing, the current one is {}",
+                self.lease_id
+            );
+        }
+        if lease_id != self.lease_id {
+            bail!(
+                "lease: {} is stale, the current one is {}",
+                lease_id,
+                self.lease_id
+            );
+        }
+        Ok(())
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        if self.legacy {
+            return self.is_overdue(now);
+        }
+        self.expiry <= now
+    }
+
+    pub fn is_overdue(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease() {
+        let now = Instant::now();
+        let mut lease = Lease::new("s1".into(), BatchContext::default(), None, false);
+        assert!(!lease.is_expired(now));
+        assert!(!lease.is_overdue(now + LEASE_TTL * 2));
+        assert!(lease.is_expired(now + LEASE_TTL * 2));
+        lease.renew(now + LEASE_TTL);
+        assert!(!lease.is_expired(now + LEASE_TTL + LEASE_TTL / 2));
+
+        // the lease isn't renewed beyond the deadline
+        lease.deadline = Some(now + LEASE_TTL / 2);
+        lease.renew(now);
+        assert_eq!(lease.expiry, now + LEASE_TTL / 2);
+        assert!(lease.is_overdue(now + LEASE_TTL));
+
+        assert!(lease.check("s1", &lease.lease_id.clone()).is_ok());
+        let err = lease.check("s1", "").unwrap_err();
+        assert!(err.to_string().contains("lease_id is missing"));
+        let err = lease.check("s2", &lease.lease_id.clone()).unwrap_err();
+        assert!(err.to_string().contains("held by service: s1"));
+        let err = lease.check("s1", "l0").unwrap_err();
+        assert!(err.to_string().contains("lease: l0 is stale"));
+
+        // the legacy holder doesn't send the lease_id, nor renew the lease
+        lease.legacy = true;
+        assert!(!lease.is_expired(now + LEASE_TTL / 4));
+        assert!(lease.is_expired(now + LEASE_TTL / 2));
+        lease.deadline = None;
+        assert!(!lease.is_expired(now + LEASE_TTL * 10));
+        assert!(lease.check("s1", "").is_ok());
+        assert!(lease.check("s2", "").is_err());
+        assert!(lease.check("s1", "l0").is_err());
+    }
+}
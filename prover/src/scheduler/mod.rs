@@ -1,5 +1,11 @@
+mod capability;
 mod event;
+mod lease;
 #[allow(clippy::module_inception)]
 mod scheduler;
-pub use event::{AddServiceResult, Event, ProofResult, ResultStatus, TakeTaskResult, TaskResult};
+pub use capability::{build_version, Capabilities, TaskRequirements};
+pub use event::{
+    AddServiceResult, Event, ProofResult, ResultStatus, ServiceNotice, TakeTaskResult, TaskResult,
+};
+pub use lease::{Lease, LEASE_TTL};
 pub use scheduler::{BatchProver, Scheduler, Service, ServiceStatus};
@@ -1,64 +1,71 @@
 use super::event::{Event, ResultStatus, TaskResult};
 use crate::contexts::BatchContext;
-use crate::scheduler::{AddServiceResult, ProofResult, TakeTaskResult};
+use crate::deadline::{StageDeadlines, WATCHDOG_INTERVAL};
+use crate::retry::{NonRetryableError, RetryPolicy};
+use crate::scheduler::{
+    AddServiceResult, Capabilities, Lease, ProofResult, ServiceNotice, TakeTaskResult,
+    TaskRequirements,
+};
 use crate::stage::Stage;
-use std::collections::HashMap;
-use std::path::Path;
+use crate::task_queue::Priority;
+use crate::task_store::{TaskEvent, TaskStatus, TaskStore};
+use anyhow::anyhow;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::{mpsc, Mutex as TokioMutex};
+use tokio::sync::{broadcast, mpsc, Mutex as TokioMutex};
+use tokio::time::Instant;
 
+/// The max number of the tasks waiting for an idle service,
+/// the scheduler stops receiving the tasks from the pipelines beyond it
+pub const MAX_READY_TASKS: usize = 128;
+
+const ACKS_CAPACITY: usize = 1024;
+
+/// The scheduler dispatches the batch tasks of the pipeline to the services.
+/// It never waits for a task or a service, the idle services are parked in the waiting_services,
+/// the tasks are parked in the ready_tasks, and they are matched as either side arrives.
 pub struct Scheduler {
     pub service_table: HashMap<ServiceId, Service>,
 
-    // it's concurrency safe
-    // handle_take_task will put the task into the pending_results
-    // handle_task_result will take the task from the pending_results
-    // but they are in different branches of the same select
-    pub pending_results: HashMap<ServiceId, BatchContext>,
+    // the idle services which wait for a task, in the order they ask,
+    // the task is relayed to the service by the sender
+    pub waiting_services: VecDeque<(ServiceId, Sender<TakeTaskResult>)>,
+    // the tasks which wait for an idle service, in the order they arrive
+    pub ready_tasks: VecDeque<BatchContext>,
+    pub max_ready_tasks: usize,
+    // the task is only relayed to the service whose capabilities meet the requirements
+    pub requirements: TaskRequirements,
+    // acknowledge the keys of the tasks taken over from the pipelines,
+    // the pipelines send the tasks again if they are not acknowledged
+    pub ack_sender: broadcast::Sender<String>,
+
+    // the leases of the tasks taken by the services, keyed by the task key,
+    // handle_take_task grants the lease, handle_task_result only accepts the result of its holder
+    pub leases: HashMap<String, Lease>,
+    // the leased tasks are failed and retried after the deadline of the batch stage
+    pub deadlines: StageDeadlines,
 
     pub retry_to: Sender<BatchContext>,
+    // the failed tasks are sent to the retry_to again after the backoff
+    pub retry_policy: RetryPolicy,
 
-    pub event_handler: Option<EventHandler>,
-    pub result_handler: ResultHandler,
-}
+    // shared with the pipeline, save the results of the batch proofs
+    pub task_store: Arc<dyn TaskStore>,
+    // shared with the pipeline, wake up the request handlers waiting for the batch proofs
+    pub task_events: broadcast::Sender<TaskEvent>,
+
+    // the pipeline sends the task_id to cancel by the cancel_sender,
+    // it's not an Event, the events are sent by the services
+    pub cancel_sender: Sender<String>,
+    pub cancel_receiver: mpsc::Receiver<String>,
 
-pub struct EventHandler {
     // Service <-> Scheduler
-    pub event_receiver: Arc<TokioMutex<mpsc::Receiver<Event>>>,
+    pub event_receiver: mpsc::Receiver<Event>,
     // Pipeline <-> Scheduler
     // Pipeline send task to the channel
-    pub task_receiver: Arc<TokioMutex<mpsc::Receiver<BatchContext>>>,
-    pub retry_to: Sender<BatchContext>,
-}
-
-impl EventHandler {
-    fn new(
-        event_receiver: Arc<TokioMutex<mpsc::Receiver<Event>>>,
-        task_receiver: Arc<TokioMutex<mpsc::Receiver<BatchContext>>>,
-        retry_to: Sender<BatchContext>,
-    ) -> Self {
-        EventHandler {
-            event_receiver,
-            task_receiver,
-            retry_to,
-        }
-    }
-    pub async fn recv_both(&self) -> (Event, BatchContext) {
-        let event = self.event_receiver.lock().await.recv().await.unwrap();
-        match event {
-            Event::TakeTask { .. } => {
-                // wait for the task
-                log::info!("[scheduler] wait for task");
-                let task = self.task_receiver.lock().await.recv().await.unwrap();
-                (event, task)
-            }
-            // don't need task
-            Event::AddService { .. } | Event::RemoveService { .. } => {
-                (event, BatchContext::default())
-            }
-        }
-    }
+    pub task_receiver: mpsc::Receiver<BatchContext>,
+    pub result_handler: ResultHandler,
 }
 
 pub struct ResultHandler {
@@ -87,7 +94,7 @@ pub type ServiceId = String;
 #[derive(Clone)]
 pub struct Service {
     pub service_id: String,
-    pub task_name: Option<String>,
+    pub capabilities: Capabilities,
     pub service_type: BatchProver,
     pub status: ServiceStatus,
     pub current_task_id: Option<String>,
@@ -95,6 +102,8 @@ pub struct Service {
 
     // Service will send batch_proof result to this channel
     pub proof_receiver: Option<Arc<mpsc::Receiver<BatchContext>>>,
+    // Scheduler will send the notice to the service by this channel
+    pub notice_to: Option<Sender<ServiceNotice>>,
 }
 
 impl Scheduler {
@@ -103,75 +112,80 @@ impl Scheduler {
         event_receiver: mpsc::Receiver<Event>,
         task_receiver: mpsc::Receiver<BatchContext>,
         retry_to: Sender<BatchContext>,
+        task_store: Arc<dyn TaskStore>,
+        task_events: broadcast::Sender<TaskEvent>,
     ) -> Self {
+        let (cancel_sender, cancel_receiver) = mpsc::channel(128);
         Scheduler {
             service_table: HashMap::new(),
+            waiting_services: Default::default(),
+            ready_tasks: Default::default(),
+            max_ready_tasks: MAX_READY_TASKS,
+            requirements: TaskRequirements::default(),
+            ack_sender: broadcast::channel(ACKS_CAPACITY).0,
             retry_to: retry_to.clone(),
-            pending_results: Default::default(),
+            retry_policy: RetryPolicy::default(),
+            task_store,
+            task_events,
+            cancel_sender,
+            cancel_receiver,
+            leases: Default::default(),
+            deadlines: StageDeadlines::default(),
+            event_receiver,
+            task_receiver,
             result_handler: ResultHandler::new(
                 Arc::new(TokioMutex::new(result_receiver)),
-                retry_to.clone(),
+                retry_to,
             ),
-            event_handler: Some(EventHandler::new(
-                Arc::new(TokioMutex::new(event_receiver)),
-                Arc::new(TokioMutex::new(task_receiver)),
-                retry_to.clone(),
-            )),
         }
     }
 
-    // pub async fn run(&mut self) {
-    //     loop {
-    //         tokio::select! {
-    //             // listen the event from the scheduler serve and task from the pipeline
-    //             (event, ctx) = self.event_handler.recv_both()  => {
-    //                      self.handle_event(event, ctx).await;
-    //                  },
-    //             // listen the result from the scheduler server
-    //             result = self.result_handler.take_result() => {
-    //                 self.handle_result(result).await;
-    //             },
-    //         }
-    //     }
-    // }
     pub async fn run(&mut self) {
-        let (interval_event_tx, mut interval_event_rx) =
-            mpsc::channel::<(Event, BatchContext)>(128);
-        // take the event_handler, and spawn a new coroutine to listen the event from the event_receiver and task_receiver
-        // the self.event_handler will be None after this
-        let event_handler = self.event_handler.take().unwrap();
-
-        tokio::spawn(async move {
-            loop {
-                let (event, ctx) = event_handler.recv_both().await;
-                if let Err(e) = interval_event_tx.send((event, ctx)).await {
-                    log::error!("Failed to send event to interval_event_tx, err: {}", e);
-                }
-            }
-        });
-
+        let mut watchdog = tokio::time::interval(WATCHDOG_INTERVAL);
         loop {
             tokio::select! {
-                // listen the event from the scheduler serve and task from the pipeline
-                interval_event = interval_event_rx.recv() => {
-                    let (event, ctx) = interval_event.unwrap();
-                    self.handle_event(event, ctx).await;
+                // listen the event from the scheduler server
+                Some(event) = self.event_receiver.recv() => {
+                    self.handle_event(event).await;
+                },
+                // listen the task from the pipeline, the pipeline keeps the task until there's room
+                Some(task) = self.task_receiver.recv(), if self.ready_tasks.len() < self.max_ready_tasks => {
+                    self.handle_new_task(task).await;
                 },
                 // listen the result from the scheduler server
                 result = self.result_handler.take_result() => {
                     self.handle_result(result).await;
                 },
+                // listen the task to cancel from the pipeline
+                Some(task_id) = self.cancel_receiver.recv() => {
+                    log::info!("[scheduler] cancel task: {}", task_id);
+                    self.handle_cancel_task(task_id).await;
+                },
+                // take back the tasks whose lease expires or which exceed the deadline
+                _ = watchdog.tick() => {
+                    self.check_deadlines().await;
+                },
             }
         }
     }
-    pub async fn handle_event(&mut self, event: Event, ctx: BatchContext) {
+
+    pub async fn handle_event(&mut self, event: Event) {
         match event {
             Event::AddService {
                 service_id,
                 relay_to,
+                notice_to,
+                capabilities,
             } => {
-                log::info!("[scheduler] add service: {}", service_id);
-                self.handle_add_service(service_id, relay_to).await
+                log::info!(
+                    "[scheduler] add service: {}, {:?}",
+                    service_id,
+                    capabilities
+                );
+                self.handle_add_service(service_id, relay_to, notice_to, capabilities)
+                    .await;
+                // the service may run the tasks which no one else can
+                self.dispatch().await
             }
             Event::RemoveService { service_id } => {
                 log::info!("[scheduler] remove service: {}", service_id);
@@ -182,11 +196,113 @@ impl Scheduler {
                 relay_to,
             } => {
                 log::info!("[scheduler] [service:{}] take a task", service_id);
-                self.handle_take_task(service_id, relay_to, ctx).await
+                self.waiting_services.push_back((service_id, relay_to));
+                self.dispatch().await
             }
+            Event::RenewLease {
+                service_id,
+                lease_id,
+            } => self.handle_renew_lease(service_id, lease_id),
         }
     }
 
+    /// Park the task from the pipeline until a service takes it, and acknowledge it.
+    /// The task sent again by the pipeline is acknowledged without parking it twice.
+    pub async fn handle_new_task(&mut self, task: BatchContext) {
+        let key = self.construct_task_key(&task.task_id, &task.chunk_id);
+        let taken = self.leases.contains_key(&key)
+            || self
+                .ready_tasks
+                .iter()
+                .any(|t| t.task_id == task.task_id && t.chunk_id == task.chunk_id);
+        if taken {
+            log::info!("[scheduler] task: {} is taken over already", key);
+        } else if matches!(
+            self.task_store.get(&key),
+            Ok(Some(record)) if record.status == TaskStatus::Succeeded
+        ) {
+            log::info!("[scheduler] task: {} is proved already", key);
+        } else {
+            log::info!("[scheduler] new task: {}", key);
+            let reasons: Vec<String> = self
+                .service_table
+                .values()
+                .filter_map(|service| {
+                    let checked = service
+                        .capabilities
+                        .check(&task.task_name, &self.requirements);
+                    checked
+                        .err()
+                        .map(|e| format!("{}: {}", service.service_id, e))
+                })
+                .collect();
+            if !reasons.is_empty() && reasons.len() == self.service_table.len() {
+                log::warn!(
+                    "[scheduler] no service can run task: {} for now, {}",
+                    key,
+                    reasons.join("; ")
+                );
+            }
+            self.ready_tasks.push_back(task);
+        }
+        // it fails if no pipeline subscribes the acks
+        let _ = self.ack_sender.send(key);
+        self.dispatch().await
+    }
+
+    /// Relay the ready tasks in their order to the first waiting services which can run them.
+    /// The task which no waiting service can run doesn't hold back the ones after it,
+    /// the cancelled tasks are dropped.
+    pub async fn dispatch(&mut self) {
+        let mut i = 0;
+        while i < self.ready_tasks.len() && !self.waiting_services.is_empty() {
+            let task = &self.ready_tasks[i];
+            let key = self.construct_task_key(&task.task_id, &task.chunk_id);
+            if matches!(
+                self.task_store.get(&key),
+                Ok(Some(record)) if record.status == TaskStatus::Cancelled
+            ) {
+                log::info!("[scheduler] skip the cancelled task: {}", key);
+                self.ready_tasks.remove(i);
+                continue;
+            }
+            let Some(j) = self
+                .waiting_services
+                .iter()
+                .position(|(service_id, _)| self.can_run(service_id, task))
+            else {
+                i += 1;
+                continue;
+            };
+            let task = self.ready_tasks.remove(i).unwrap();
+            let (service_id, relay_to) = self.waiting_services.remove(j).unwrap();
+            self.handle_take_task(service_id, relay_to, task).await;
+            // the task is put back to the front if the service is gone
+            i = 0;
+        }
+    }
+
+    /// Return true if the capabilities of the service meet the requirements of the task,
+    /// the service which is not added has no capabilities
+    fn can_run(&self, service_id: &str, task: &BatchContext) -> bool {
+        let checked = match self.service_table.get(service_id) {
+            Some(service) => service
+                .capabilities
+                .check(&task.task_name, &self.requirements),
+            None => Capabilities::default().check(&task.task_name, &self.requirements),
+        };
+        if let Err(e) = &checked {
+            log::debug!(
+                "[scheduler] service: {} can not run task: {}_{}, {}",
+                service_id,
+                task.task_id,
+                task.chunk_id,
+                e
+            );
+        }
+        checked.is_ok()
+    }
+
     pub async fn handle_result(&mut self, result: TaskResult) {
         log::info!(
             "[service:{}] task result: {:?}",
@@ -201,15 +317,18 @@ impl Scheduler {
         &mut self,
         service_id: ServiceId,
         relay_to: Sender<AddServiceResult>,
+        notice_to: Sender<ServiceNotice>,
+        capabilities: Capabilities,
     ) {
         let new_service = Service {
             service_id: service_id.clone(),
-            task_name: None,
+            capabilities,
             service_type: BatchProver::GRPC,
             status: ServiceStatus::Prepare,
             current_task_id: None,
             current_task: None,
             proof_receiver: None,
+            notice_to: Some(notice_to),
         };
         self.service_table.insert(service_id.clone(), new_service);
 
@@ -222,6 +341,7 @@ impl Scheduler {
         }
     }
 
+    /// Relay the task to the waiting service, and grant the service a new lease of it
     pub async fn handle_take_task(
         &mut self,
         service_id: ServiceId,
@@ -229,113 +349,366 @@ impl Scheduler {
         task: BatchContext,
     ) {
         // record the task, so that we can retry it when an error occurs during the proof generation
-        if let Err(e) = relay_to.send(TakeTaskResult::Success(task.clone())).await {
-            if let Err(e) = self.retry_to.send(task.clone()).await {
-                // TODO: in the case, is it allowed to discard the task?
-                log::error!(
-                    "Failed to retry task: {}, the task will be discarded here, err: {}",
-                    service_id,
-                    e
-                );
-            }
-
-            // retry the task
+        let deadline = self.deadlines.deadline(Priority::Batch, Instant::now());
+        let legacy = self
+            .service_table
+            .get(&service_id)
+            .is_some_and(|service| service.capabilities.predates_leases());
+        let lease = Lease::new(service_id.clone(), task.clone(), deadline, legacy);
+        if let Err(e) = relay_to
+            .send(TakeTaskResult::Success(
+                task.clone(),
+                lease.lease_id.clone(),
+            ))
+            .await
+        {
+            // the service is gone, the task isn't taken, it's the first to take by the next one
             log::error!("Failed to take task: {}, err: {}", service_id, e);
+            self.ready_tasks.push_front(task);
+            return;
         }
 
-        // put task to pending_results
         log::info!(
-            "put task to pending_results: {}, task: {:?}",
+            "grant lease: {} to service: {}, task: {:?}",
+            lease.lease_id,
             service_id,
-            task.clone()
+            task
         );
         let task_key = self.construct_task_key(&task.task_id, &task.chunk_id);
-        self.pending_results.insert(task_key, task.clone());
-
-        /*
+        if let Err(e) = self
+            .ensure_task_record(&task)
+            .and_then(|_| self.task_store.start(&task_key, &service_id))
+        {
+            log::warn!("Failed to save the start of task: {}, err: {}", task_key, e);
+        }
         if let Some(service) = self.service_table.get_mut(&service_id) {
-            if service.status != ServiceStatus::Idle {
-                // the service is not idle, can't take task
-                if let Err(e) = relay_to
-                    .send(TakeTaskResult::Fail(service_id.clone()))
-                    .await
-                {
-                    log::error!("Failed to take task: {}, err: {}", service_id, e);
-                }
-            }
-            } else {
-            // the service has not been added to the scheduler, please add it first
-            if let Err(e) = relay_to
-                .send(TakeTaskResult::Fail(service_id.clone()))
-                .await
-            {
-                log::error!("Failed to take task: {}, err: {}", service_id, e);
-            }
+            service.current_task_id = Some(task_key.clone());
+            service.current_task = Some(task.clone());
         }
-         */
+        self.leases.insert(task_key, lease);
     }
 
+    /// Remove the lost service, and requeue the tasks taken by it
     pub async fn handle_remove_service(&mut self, service_id: ServiceId) {
         self.service_table.remove(&service_id);
+        self.waiting_services.retain(|(id, _)| *id != service_id);
+
+        let orphaned: Vec<String> = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.holder == service_id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in orphaned {
+            if let Some(lease) = self.leases.remove(&key) {
+                log::warn!(
+                    "[scheduler] service: {} is removed, requeue its task: {}",
+                    service_id,
+                    key
+                );
+                self.requeue_task(lease.task);
+            }
+        }
+        self.dispatch().await
     }
 
+    /// Accept the result of the task from the holder of its lease, and save the checkpoint.
+    /// The late or duplicate result is rejected with the reason, it never overwrites the checkpoint.
     pub async fn handle_task_result(
         &mut self,
         service_id: ServiceId,
         recursive_proof_result: ProofResult,
     ) {
-        let finished = match recursive_proof_result.result_code {
-            ResultStatus::Success => true,
-            ResultStatus::Fail => false,
+        let status = match recursive_proof_result.result_code {
+            ResultStatus::Success => TaskStatus::Succeeded,
+            ResultStatus::Fail | ResultStatus::NonRetryable => TaskStatus::Failed,
+            ResultStatus::Cancelled => TaskStatus::Cancelled,
         };
 
         let key = self.construct_task_key(
             &recursive_proof_result.task_id,
             &recursive_proof_result.chunk_id,
         );
-        if let Some(task_ctx) = self.pending_results.remove(&key) {
-            let task_stage =
-                Stage::Batch(task_ctx.task_id, task_ctx.chunk_id, task_ctx.l2_batch_data);
-            let workdir = Path::new(&task_ctx.basedir).join(task_stage.path());
-
-            log::info!("save_checkpoint, mkdir: {:?}", workdir);
-            if let Err(e) = std::fs::create_dir_all(workdir.clone()) {
-                log::error!("Failed to create checkpoint dir: {:?}, err: {}", workdir, e);
+        // the task is taken back after the deadline, and may be taken by another service
+        let checked = match self.leases.get(&key) {
+            Some(lease) => lease.check(&service_id, &recursive_proof_result.lease_id),
+            None => Err(anyhow!("the task is finished or taken back")),
+        };
+        if let Err(e) = checked {
+            if status == TaskStatus::Cancelled {
+                log::info!(
+                    "the task: {} has been cancelled by service: {}",
+                    key,
+                    service_id
+                );
                 return;
             }
+            log::warn!(
+                "reject the result of task: {} from service: {}, {}",
+                key,
+                service_id,
+                e
+            );
+            let notice = ServiceNotice::RejectResult {
+                task_id: recursive_proof_result.task_id,
+                chunk_id: recursive_proof_result.chunk_id,
+                lease_id: recursive_proof_result.lease_id,
+                reason: e.to_string(),
+            };
+            self.notify(&service_id, notice).await;
+            return;
+        }
 
-            if !finished {
-                let p = workdir.join("status");
-                let stage_str = match task_stage.to_string() {
-                    Ok(str) => str,
-                    Err(e) => {
-                        log::error!("Failed to serialize stage: {:?}, err: {}", task_stage, e);
-                        return;
-                    }
+        let Some(lease) = self.leases.remove(&key) else {
+            return;
+        };
+        if let Some(service) = self.service_table.get_mut(&service_id) {
+            if service.current_task_id.as_ref() == Some(&key) {
+                service.current_task_id = None;
+                service.current_task = None;
+            }
+        }
+        let task_ctx = lease.task;
+        if status == TaskStatus::Failed {
+            let message = recursive_proof_result.error_message.clone();
+            let error = match recursive_proof_result.result_code {
+                ResultStatus::NonRetryable => anyhow::Error::new(NonRetryableError(message)),
+                _ => anyhow!(message),
+            };
+            if self.retry_task(&task_ctx, &error) {
+                return;
+            }
+        }
+        let task_name = task_ctx.task_name;
+        let task_stage = Stage::Batch(task_ctx.task_id, task_ctx.chunk_id, task_ctx.batch_digest);
+        log::info!(
+            "batch proof finished! save_checkpoint with result: {:?}, task: {}",
+            status,
+            key
+        );
+        if let Err(e) = self.save_checkpoint(&task_name, &task_stage, status) {
+            log::error!("Failed to save checkpoint: {}, err: {}", key, e);
+        }
+    }
+
+    /// Renew the leases held by the service, the empty lease_id renews all of them.
+    /// The unknown lease is stale, the service is told by the rejection of its result.
+    pub fn handle_renew_lease(&mut self, service_id: ServiceId, lease_id: String) {
+        let now = Instant::now();
+        let mut renewed = false;
+        for lease in self.leases.values_mut() {
+            if lease.holder == service_id && (lease_id.is_empty() || lease.lease_id == lease_id) {
+                lease.renew(now);
+                renewed = true;
+            }
+        }
+        if !renewed && !lease_id.is_empty() {
+            log::warn!(
+                "[scheduler] service: {} renews the unknown lease: {}",
+                service_id,
+                lease_id
+            );
+        }
+    }
+
+    /// Drop the ready chunks of the task, revoke the chunks taken by the services,
+    /// and notify the services to abort them
+    pub async fn handle_cancel_task(&mut self, task_id: String) {
+        self.ready_tasks.retain(|task| task.task_id != task_id);
+        self.leases.retain(|key, lease| {
+            if lease.task.task_id == task_id {
+                log::info!("revoke the lease: {} of task: {}", lease.lease_id, key);
+                return false;
+            }
+            true
+        });
+
+        for service in self.service_table.values_mut() {
+            let chunk_id = match &service.current_task {
+                Some(task) if task.task_id == task_id => task.chunk_id.clone(),
+                _ => continue,
+            };
+            service.current_task_id = None;
+            service.current_task = None;
+            if let Some(notice_to) = &service.notice_to {
+                let notice = ServiceNotice::CancelTask {
+                    task_id: task_id.clone(),
+                    chunk_id,
                 };
+                if let Err(e) = notice_to.send(notice).await {
+                    log::error!(
+                        "Failed to notify service: {} to cancel the task, err: {}",
+                        service.service_id,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Take back the leased tasks which exceed the deadline or whose lease isn't renewed in time,
+    /// and notify their holders to abort them.
+    /// The overdue tasks are retried, or failed if the retry policy doesn't allow,
+    /// the tasks of the expired leases are requeued, their holders are likely lost.
+    pub async fn check_deadlines(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .leases
+            .iter()
+            // the lease never lasts beyond the deadline
+            .filter(|(_, lease)| lease.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
 
-                if let Err(e) = std::fs::write(p.clone(), stage_str) {
-                    log::error!("Failed to write status file: {:?}, err: {}", p, e);
-                    return;
+        for key in expired {
+            let Some(lease) = self.leases.remove(&key) else {
+                continue;
+            };
+            if let Some(service) = self.service_table.get_mut(&lease.holder) {
+                if service.current_task_id.as_ref() == Some(&key) {
+                    service.current_task_id = None;
+                    service.current_task = None;
                 }
             }
+            let notice = ServiceNotice::CancelTask {
+                task_id: lease.task.task_id.clone(),
+                chunk_id: lease.task.chunk_id.clone(),
+            };
+            self.notify(&lease.holder, notice).await;
 
-            let p = workdir.join("status.finished");
-            let proof_result = if finished { "1" } else { "0" };
-            log::info!(
-                "batch proof finished! save_checkpoint with result: {}, dir: {:?}",
-                proof_result,
-                workdir
+            if !lease.is_overdue(now) {
+                log::warn!(
+                    "[scheduler] lease: {} of task: {} is not renewed by service: {}, requeue it",
+                    lease.lease_id,
+                    key,
+                    lease.holder
+                );
+                self.requeue_task(lease.task);
+                continue;
+            }
+            let task = lease.task;
+            let error = anyhow!(
+                "task: {} exceeded the deadline of {:?} on service: {}",
+                key,
+                self.deadlines.batch,
+                lease.holder
             );
-            if let Err(e) = std::fs::write(p.clone(), proof_result) {
-                log::error!("Failed to write status.finished file: {:?}, err: {}", p, e);
+            log::warn!("[scheduler] {}", error);
+            if !self.retry_task(&task, &error) {
+                let stage = Stage::Batch(
+                    task.task_id.clone(),
+                    task.chunk_id.clone(),
+                    task.batch_digest.clone(),
+                );
+                if let Err(e) = self.save_checkpoint(&task.task_name, &stage, TaskStatus::Failed) {
+                    log::error!("Failed to save checkpoint: {}, err: {}", key, e);
+                }
             }
-        } else {
+        }
+        // the requeued tasks may be taken by the waiting services
+        self.dispatch().await
+    }
+
+    /// Send the notice to the service, it's dropped if the service is gone
+    async fn notify(&self, service_id: &str, notice: ServiceNotice) {
+        let Some(notice_to) = self
+            .service_table
+            .get(service_id)
+            .and_then(|service| service.notice_to.as_ref())
+        else {
+            return;
+        };
+        if let Err(e) = notice_to.send(notice).await {
+            log::error!("Failed to notify service: {}, err: {}", service_id, e);
+        }
+    }
+
+    /// Save the batch task into the task store if it isn't there,
+    /// the task may be submitted by a pipeline with another task store.
+    fn ensure_task_record(&self, task: &BatchContext) -> anyhow::Result<()> {
+        let stage = Stage::Batch(
+            task.task_id.clone(),
+            task.chunk_id.clone(),
+            task.batch_digest.clone(),
+        );
+        if self.task_store.get(&stage.key())?.is_none() {
+            self.task_store.put(&task.task_name, &stage)?;
+        }
+        Ok(())
+    }
+
+    /// Record the failure of the batch task, and send it to the task queue again after the backoff.
+    /// Return false if the retry policy doesn't allow, the task should be failed.
+    fn retry_task(&self, task: &BatchContext, error: &anyhow::Error) -> bool {
+        let key = self.construct_task_key(&task.task_id, &task.chunk_id);
+        let attempts = self.ensure_task_record(task).and_then(|_| {
+            self.task_store
+                .record_failure(&key, &[format!("{:#}", error)])
+        });
+        let attempts = match attempts {
+            Ok(attempts) => attempts,
+            Err(e) => {
+                log::error!("Failed to save the failure of task: {}, err: {}", key, e);
+                return false;
+            }
+        };
+        if !self.retry_policy.should_retry(attempts, error) {
             log::error!(
-                "Failed to find task in pending_results, discard the result from service: {}",
-                service_id
+                "task: {} failed {} times, give up, err: {}",
+                key,
+                attempts,
+                error
             );
+            return false;
+        }
+
+        let backoff = self.retry_policy.backoff(attempts);
+        log::warn!(
+            "task: {} failed {} times, retry in {:?}, err: {}",
+            key,
+            attempts,
+            backoff,
+            error
+        );
+        if let Err(e) = self.task_store.transition(&key, TaskStatus::Queued) {
+            log::error!("Failed to requeue task: {}, err: {}", key, e);
+        }
+        let retry_to = self.retry_to.clone();
+        let task = task.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(backoff).await;
+            if let Err(e) = retry_to.send(task).await {
+                log::error!("Failed to retry task: {}, err: {}", key, e);
+            }
+        });
+        true
+    }
+
+    /// Put the task taken by a lost service in the ready_tasks again,
+    /// it's not a failure of the task, so it doesn't count as an attempt.
+    fn requeue_task(&mut self, task: BatchContext) {
+        let key = self.construct_task_key(&task.task_id, &task.chunk_id);
+        if let Err(e) = self.task_store.transition(&key, TaskStatus::Queued) {
+            log::error!("Failed to requeue task: {}, err: {}", key, e);
+        }
+        self.ready_tasks.push_front(task);
+    }
+
+    fn save_checkpoint(
+        &self,
+        task_name: &str,
+        stage: &Stage,
+        status: TaskStatus,
+    ) -> anyhow::Result<()> {
+        let key = stage.key();
+        // the task may be submitted by a pipeline with another task store
+        if self.task_store.get(&key)?.is_none() {
+            self.task_store.put(task_name, stage)?;
         }
+        self.task_store.transition(&key, status)?;
+        // it fails if no one is waiting for the task
+        let _ = self.task_events.send(TaskEvent { key, status });
+        Ok(())
     }
 
     fn construct_task_key(&self, task_id: &String, chunk_id: &String) -> String {
@@ -355,3 +728,571 @@ pub enum ServiceStatus {
     Running,
     Exit,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::{build_version, LEASE_TTL};
+    use crate::task_store::FsTaskStore;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    /// A scheduler on a temporary task store, with the receiver of the tasks it sends back to retry.
+    /// The other channels are closed, the tests call the handlers directly.
+    fn test_scheduler(
+        name: &str,
+    ) -> (
+        Scheduler,
+        Arc<FsTaskStore>,
+        mpsc::Receiver<BatchContext>,
+        PathBuf,
+    ) {
+        let basedir = std::env::temp_dir().join(format!("scheduler_{}_{}", name, Uuid::new_v4()));
+        let task_store = Arc::new(FsTaskStore::new(basedir.to_str().unwrap()).unwrap());
+        let (retry_to, retried) = mpsc::channel(4);
+        let scheduler = Scheduler::new(
+            mpsc::channel(1).1,
+            mpsc::channel(1).1,
+            mpsc::channel(1).1,
+            retry_to,
+            task_store.clone(),
+            broadcast::channel(16).0,
+        );
+        (scheduler, task_store, retried, basedir)
+    }
+
+    #[tokio::test]
+    async fn test_task_deadline() {
+        let (mut scheduler, task_store, mut retried, basedir) = test_scheduler("deadline");
+        scheduler.retry_policy.initial_backoff = Duration::ZERO;
+        scheduler.deadlines.batch = Duration::from_millis(1);
+
+        let (relay_to, _relay) = mpsc::channel(4);
+        let (notice_to, mut notices) = mpsc::channel(4);
+        for service_id in ["s1", "s2"] {
+            scheduler
+                .handle_add_service(
+                    service_id.into(),
+                    relay_to.clone(),
+                    notice_to.clone(),
+                    Capabilities::default(),
+                )
+                .await;
+        }
+        let task = BatchContext {
+            task_id: "0".into(),
+            chunk_id: "0".into(),
+            task_name: "evm".into(),
+            ..Default::default()
+        };
+        let (take_to, _take) = mpsc::channel(4);
+        scheduler
+            .handle_take_task("s1".into(), take_to.clone(), task.clone())
+            .await;
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Running);
+        assert_eq!(record.worker_id.as_deref(), Some("s1"));
+
+        // s1 doesn't answer in time, the task is taken back and retried
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        scheduler.check_deadlines().await;
+        assert!(scheduler.leases.is_empty());
+        assert!(scheduler.service_table["s1"].current_task_id.is_none());
+        assert!(matches!(
+            notices.recv().await,
+            Some(ServiceNotice::CancelTask { task_id, .. }) if task_id == "0"
+        ));
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Queued);
+        assert_eq!(record.attempts, 1);
+        assert!(record
+            .last_error()
+            .unwrap()
+            .contains("exceeded the deadline"));
+        let task = retried.recv().await.unwrap();
+
+        // the late result of s1 is discarded after s2 takes the task,
+        // s2 predates the leases, its result is accepted without the lease_id
+        scheduler.deadlines.batch = Duration::ZERO;
+        scheduler.handle_take_task("s2".into(), take_to, task).await;
+        let result = ProofResult {
+            task_id: "0".into(),
+            chunk_id: "0".into(),
+            ..Default::default()
+        };
+        scheduler
+            .handle_task_result("s1".into(), result.clone())
+            .await;
+        assert_eq!(scheduler.leases.len(), 1);
+        assert!(matches!(
+            notices.recv().await,
+            Some(ServiceNotice::RejectResult { reason, .. }) if reason.contains("held by service: s2")
+        ));
+        scheduler.handle_task_result("s2".into(), result).await;
+        assert!(scheduler.leases.is_empty());
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Succeeded);
+        assert_eq!(record.worker_id.as_deref(), Some("s2"));
+        assert!(record.finished_at.is_some());
+
+        // the NonRetryableError of the service fails the task at once
+        let task = BatchContext {
+            task_id: "1".into(),
+            chunk_id: "0".into(),
+            task_name: "evm".into(),
+            ..Default::default()
+        };
+        let (take_to, _take) = mpsc::channel(4);
+        scheduler.handle_take_task("s1".into(), take_to, task).await;
+        let result = ProofResult {
+            task_id: "1".into(),
+            chunk_id: "0".into(),
+            result_code: ResultStatus::NonRetryable,
+            error_message: "the chunk id x is invalid".into(),
+            ..Default::default()
+        };
+        scheduler.handle_task_result("s1".into(), result).await;
+        let record = task_store.get("1_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Failed);
+        assert_eq!(record.attempts, 1);
+        assert!(retried.try_recv().is_err());
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_service() {
+        let (mut scheduler, task_store, _retried, basedir) = test_scheduler("remove");
+
+        let (relay_to, _relay) = mpsc::channel(4);
+        let (notice_to, _notices) = mpsc::channel(4);
+        for service_id in ["s1", "s2"] {
+            scheduler
+                .handle_add_service(
+                    service_id.into(),
+                    relay_to.clone(),
+                    notice_to.clone(),
+                    Capabilities::default(),
+                )
+                .await;
+        }
+        let (take_to, _take) = mpsc::channel(4);
+        for (service_id, chunk_id) in [("s1", "0"), ("s2", "1")] {
+            let task = BatchContext {
+                task_id: "0".into(),
+                chunk_id: chunk_id.into(),
+                task_name: "evm".into(),
+                ..Default::default()
+            };
+            scheduler
+                .handle_take_task(service_id.into(), take_to.clone(), task)
+                .await;
+        }
+
+        // s1 is lost, only its task is requeued at once, and it's not a failed attempt
+        scheduler.handle_remove_service("s1".into()).await;
+        assert!(!scheduler.service_table.contains_key("s1"));
+        assert_eq!(scheduler.leases.len(), 1);
+        assert_eq!(scheduler.leases["0_1"].holder, "s2");
+        assert_eq!(scheduler.ready_tasks.len(), 1);
+        assert_eq!(scheduler.ready_tasks[0].chunk_id, "0");
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Queued);
+        assert_eq!(record.attempts, 0);
+
+        // the late result of s1 is discarded
+        let result = ProofResult {
+            task_id: "0".into(),
+            chunk_id: "0".into(),
+            ..Default::default()
+        };
+        scheduler.handle_task_result("s1".into(), result).await;
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Queued);
+
+        // the requeued task is taken by the next service
+        let (take_to, mut take) = mpsc::channel(1);
+        scheduler
+            .handle_event(Event::TakeTask {
+                service_id: "s3".into(),
+                relay_to: take_to,
+            })
+            .await;
+        assert!(matches!(
+            take.try_recv(),
+            Ok(TakeTaskResult::Success(task, _)) if task.chunk_id == "0"
+        ));
+        assert_eq!(scheduler.leases["0_0"].holder, "s3");
+        assert!(scheduler.ready_tasks.is_empty());
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait_queue() {
+        let (mut scheduler, task_store, _retried, basedir) = test_scheduler("wait");
+        let task = |chunk_id: &str| BatchContext {
+            task_id: "0".into(),
+            chunk_id: chunk_id.into(),
+            task_name: "evm".into(),
+            ..Default::default()
+        };
+
+        // no task, the services wait without blocking the other events
+        let mut takes = HashMap::new();
+        for service_id in ["s1", "s2", "s3"] {
+            let (take_to, take) = mpsc::channel(1);
+            scheduler
+                .handle_event(Event::TakeTask {
+                    service_id: service_id.into(),
+                    relay_to: take_to,
+                })
+                .await;
+            takes.insert(service_id, take);
+        }
+        assert_eq!(scheduler.waiting_services.len(), 3);
+        let (relay_to, mut relay) = mpsc::channel(1);
+        let (notice_to, _notices) = mpsc::channel(1);
+        scheduler
+            .handle_event(Event::AddService {
+                service_id: "s4".into(),
+                relay_to,
+                notice_to,
+                capabilities: Capabilities::default(),
+            })
+            .await;
+        assert!(matches!(relay.try_recv(), Ok(AddServiceResult::Success(_))));
+        scheduler
+            .handle_event(Event::RemoveService {
+                service_id: "s1".into(),
+            })
+            .await;
+        assert_eq!(scheduler.waiting_services.len(), 2);
+
+        // s2 is gone before the task is relayed, the task is taken by s3
+        drop(takes.remove("s2"));
+        scheduler.handle_new_task(task("0")).await;
+        assert!(matches!(
+            takes.get_mut("s3").unwrap().try_recv(),
+            Ok(TakeTaskResult::Success(task, _)) if task.chunk_id == "0"
+        ));
+        assert_eq!(scheduler.leases["0_0"].holder, "s3");
+        assert!(scheduler.waiting_services.is_empty());
+
+        // no service, the tasks wait, the cancelled one is skipped
+        scheduler.handle_new_task(task("1")).await;
+        scheduler.handle_new_task(task("2")).await;
+        assert_eq!(scheduler.ready_tasks.len(), 2);
+        task_store
+            .put("evm", &Stage::Batch("0".into(), "1".into(), "".into()))
+            .unwrap();
+        task_store.transition("0_1", TaskStatus::Cancelled).unwrap();
+        let (take_to, mut take) = mpsc::channel(1);
+        scheduler
+            .handle_event(Event::TakeTask {
+                service_id: "s4".into(),
+                relay_to: take_to,
+            })
+            .await;
+        assert!(matches!(
+            take.try_recv(),
+            Ok(TakeTaskResult::Success(task, _)) if task.chunk_id == "2"
+        ));
+        assert!(scheduler.ready_tasks.is_empty());
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_new_task_ack() {
+        let (mut scheduler, task_store, _retried, basedir) = test_scheduler("ack");
+        let mut acks = scheduler.ack_sender.subscribe();
+        let task = |chunk_id: &str| BatchContext {
+            task_id: "0".into(),
+            chunk_id: chunk_id.into(),
+            task_name: "evm".into(),
+            ..Default::default()
+        };
+
+        // the task sent again is acknowledged, and parked once
+        scheduler.handle_new_task(task("0")).await;
+        scheduler.handle_new_task(task("0")).await;
+        assert_eq!(acks.try_recv().unwrap(), "0_0");
+        assert_eq!(acks.try_recv().unwrap(), "0_0");
+        assert_eq!(scheduler.ready_tasks.len(), 1);
+
+        // the proved task is not proved again
+        task_store
+            .put("evm", &Stage::Batch("0".into(), "1".into(), "".into()))
+            .unwrap();
+        task_store.transition("0_1", TaskStatus::Succeeded).unwrap();
+        scheduler.handle_new_task(task("1")).await;
+        assert_eq!(acks.try_recv().unwrap(), "0_1");
+        assert_eq!(scheduler.ready_tasks.len(), 1);
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_capability_matching() {
+        let (mut scheduler, _task_store, _retried, basedir) = test_scheduler("match");
+        scheduler.requirements.min_memory = 1024;
+
+        let (relay_to, _relay) = mpsc::channel(4);
+        let (notice_to, _notices) = mpsc::channel(4);
+        let services = [
+            ("s1", vec!["lr".to_string()], 4096),
+            ("s2", vec!["evm".to_string()], 512),
+            ("s3", vec![], 2048),
+        ];
+        for (service_id, task_names, memory) in services {
+            let capabilities = Capabilities {
+                task_names,
+                memory,
+                ..Default::default()
+            };
+            scheduler
+                .handle_add_service(
+                    service_id.into(),
+                    relay_to.clone(),
+                    notice_to.clone(),
+                    capabilities,
+                )
+                .await;
+        }
+        for (task_id, task_name) in [("0", "evm"), ("1", "lr")] {
+            let task = BatchContext {
+                task_id: task_id.into(),
+                chunk_id: "0".into(),
+                task_name: task_name.into(),
+                ..Default::default()
+            };
+            scheduler.handle_new_task(task).await;
+        }
+
+        let take = |service_id: &str| {
+            let (take_to, take) = mpsc::channel(1);
+            let event = Event::TakeTask {
+                service_id: service_id.into(),
+                relay_to: take_to,
+            };
+            (event, take)
+        };
+        // s1 only has the setup of lr, it takes the task behind the evm one
+        let (event, mut s1) = take("s1");
+        scheduler.handle_event(event).await;
+        assert!(matches!(
+            s1.try_recv(),
+            Ok(TakeTaskResult::Success(task, _)) if task.task_name == "lr"
+        ));
+        // s2 doesn't have enough memory
+        let (event, mut s2) = take("s2");
+        scheduler.handle_event(event).await;
+        assert!(s2.try_recv().is_err());
+        assert_eq!(scheduler.ready_tasks.len(), 1);
+        let (event, mut s3) = take("s3");
+        scheduler.handle_event(event).await;
+        assert!(matches!(
+            s3.try_recv(),
+            Ok(TakeTaskResult::Success(task, _)) if task.task_name == "evm"
+        ));
+        assert_eq!(scheduler.leases["0_0"].holder, "s3");
+        assert_eq!(scheduler.waiting_services.len(), 1);
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_legacy_lease() {
+        let (mut scheduler, task_store, _retried, basedir) = test_scheduler("legacy");
+        let (relay_to, _relay) = mpsc::channel(4);
+        let (notice_to, _notices) = mpsc::channel(4);
+        // s1 predates the leases, s2 advertises its build
+        for (service_id, build) in [("s1", String::new()), ("s2", build_version())] {
+            let capabilities = Capabilities {
+                build_version: build,
+                ..Default::default()
+            };
+            scheduler
+                .handle_add_service(
+                    service_id.into(),
+                    relay_to.clone(),
+                    notice_to.clone(),
+                    capabilities,
+                )
+                .await;
+        }
+        let (take_to, _take) = mpsc::channel(4);
+        for (service_id, chunk_id) in [("s1", "0"), ("s2", "1")] {
+            let task = BatchContext {
+                task_id: "0".into(),
+                chunk_id: chunk_id.into(),
+                task_name: "evm".into(),
+                ..Default::default()
+            };
+            scheduler
+                .handle_take_task(service_id.into(), take_to.clone(), task)
+                .await;
+        }
+        assert!(scheduler.leases["0_0"].legacy);
+        assert!(!scheduler.leases["0_1"].legacy);
+
+        // neither sends the heartbeat, only the lease of s2 expires
+        tokio::time::advance(LEASE_TTL * 3).await;
+        scheduler.check_deadlines().await;
+        assert_eq!(scheduler.leases.keys().collect::<Vec<_>>(), vec!["0_0"]);
+        let record = task_store.get("0_1").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Queued);
+
+        // the proof of s1 outlasts the LEASE_TTL, it's accepted without the lease_id
+        let result = ProofResult {
+            task_id: "0".into(),
+            chunk_id: "0".into(),
+            ..Default::default()
+        };
+        scheduler.handle_task_result("s1".into(), result).await;
+        assert!(scheduler.leases.is_empty());
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Succeeded);
+
+        // but it's still bounded by the deadline
+        scheduler.deadlines.batch = LEASE_TTL * 2;
+        let (take_to, _take) = mpsc::channel(4);
+        let task = BatchContext {
+            task_id: "1".into(),
+            chunk_id: "0".into(),
+            task_name: "evm".into(),
+            ..Default::default()
+        };
+        scheduler.handle_take_task("s1".into(), take_to, task).await;
+        tokio::time::advance(LEASE_TTL).await;
+        scheduler.check_deadlines().await;
+        assert!(scheduler.leases.contains_key("1_0"));
+        tokio::time::advance(LEASE_TTL).await;
+        scheduler.check_deadlines().await;
+        assert!(scheduler.leases.is_empty());
+        let record = task_store.get("1_0").unwrap().unwrap();
+        assert_eq!(record.attempts, 1);
+        assert!(record
+            .last_error()
+            .unwrap()
+            .contains("exceeded the deadline"));
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_task_lease() {
+        let (mut scheduler, task_store, _retried, basedir) = test_scheduler("lease");
+
+        let (relay_to, _relay) = mpsc::channel(4);
+        let mut notices = HashMap::new();
+        for service_id in ["s1", "s2"] {
+            let (notice_to, notice) = mpsc::channel(4);
+            let capabilities = Capabilities {
+                build_version: build_version(),
+                ..Default::default()
+            };
+            scheduler
+                .handle_add_service(service_id.into(), relay_to.clone(), notice_to, capabilities)
+                .await;
+            notices.insert(service_id, notice);
+        }
+        let take = |scheduler: &mut Scheduler, service_id: &str| {
+            let (take_to, take) = mpsc::channel(1);
+            scheduler
+                .waiting_services
+                .push_back((service_id.into(), take_to));
+            take
+        };
+        let mut s1 = take(&mut scheduler, "s1");
+        scheduler
+            .handle_new_task(BatchContext {
+                task_id: "0".into(),
+                chunk_id: "0".into(),
+                task_name: "evm".into(),
+                ..Default::default()
+            })
+            .await;
+        let Ok(TakeTaskResult::Success(_, s1_lease)) = s1.try_recv() else {
+            panic!("s1 didn't take the task");
+        };
+
+        // the lease is renewed by the heartbeat of its holder only
+        let expiry = scheduler.leases["0_0"].expiry;
+        scheduler.handle_renew_lease("s2".into(), s1_lease.clone());
+        assert_eq!(scheduler.leases["0_0"].expiry, expiry);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        scheduler.handle_renew_lease("s1".into(), s1_lease.clone());
+        assert!(scheduler.leases["0_0"].expiry > expiry);
+
+        // s1 stops renewing the lease, the task is requeued without counting an attempt
+        scheduler.leases.get_mut("0_0").unwrap().expiry = Instant::now();
+        scheduler.check_deadlines().await;
+        assert!(scheduler.leases.is_empty());
+        assert!(matches!(
+            notices.get_mut("s1").unwrap().try_recv(),
+            Ok(ServiceNotice::CancelTask { task_id, .. }) if task_id == "0"
+        ));
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Queued);
+        assert_eq!(record.attempts, 0);
+
+        // s2 takes the task with a new lease, the results of the stale leases are rejected
+        let mut s2 = take(&mut scheduler, "s2");
+        scheduler.dispatch().await;
+        let Ok(TakeTaskResult::Success(_, s2_lease)) = s2.try_recv() else {
+            panic!("s2 didn't take the task");
+        };
+        assert_ne!(s1_lease, s2_lease);
+        let result = |lease_id: &str| ProofResult {
+            task_id: "0".into(),
+            chunk_id: "0".into(),
+            lease_id: lease_id.into(),
+            ..Default::default()
+        };
+        scheduler
+            .handle_task_result("s1".into(), result(&s1_lease))
+            .await;
+        assert!(matches!(
+            notices.get_mut("s1").unwrap().try_recv(),
+            Ok(ServiceNotice::RejectResult { reason, .. }) if reason.contains("held by service: s2")
+        ));
+        scheduler
+            .handle_task_result("s2".into(), result(&s1_lease))
+            .await;
+        assert!(matches!(
+            notices.get_mut("s2").unwrap().try_recv(),
+            Ok(ServiceNotice::RejectResult { reason, .. }) if reason.contains("is stale")
+        ));
+        // s2 advertises its build, so it can't leave the lease_id empty
+        scheduler.handle_task_result("s2".into(), result("")).await;
+        assert!(matches!(
+            notices.get_mut("s2").unwrap().try_recv(),
+            Ok(ServiceNotice::RejectResult { reason, .. }) if reason.contains("lease_id is missing")
+        ));
+        assert_eq!(scheduler.leases["0_0"].holder, "s2");
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Running);
+
+        // the duplicate result doesn't overwrite the checkpoint
+        scheduler
+            .handle_task_result("s2".into(), result(&s2_lease))
+            .await;
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Succeeded);
+        let mut failed = result(&s2_lease);
+        failed.result_code = ResultStatus::Fail;
+        scheduler.handle_task_result("s2".into(), failed).await;
+        assert!(matches!(
+            notices.get_mut("s2").unwrap().try_recv(),
+            Ok(ServiceNotice::RejectResult { reason, .. }) if reason.contains("finished or taken back")
+        ));
+        let record = task_store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Succeeded);
+        assert_eq!(record.attempts, 0);
+
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+}
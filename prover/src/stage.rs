@@ -1,30 +1,265 @@
-use anyhow::Result;
+use crate::blob_store::BlobStore;
+use crate::curve::Curve;
+use crate::layout::ProofLayout;
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-/// Stage of the proof
-#[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+/// The task id of the aggregation of the program from `input` to `input2`, e.g. `0_chunk_0` to `0_chunk_2`.
+/// It's derived from the inputs, so an identical request gets the same aggregation.
+pub fn aggregate_task_id(task_name: &str, input: &str, input2: &str) -> String {
+    hash_id(&[task_name, input, input2])
+}
+
+/// The task id of the final proof of the aggregation `agg_task_id` on the curve for the prover address.
+/// The final proofs of an aggregation with another curve or prover address are kept side by side.
+pub fn final_task_id(agg_task_id: &str, curve: Curve, prover_addr: &str) -> String {
+    hash_id(&[agg_task_id, &curve.to_string(), prover_addr])
+}
+
+fn hash_id(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // prefixed by the length, so the parts can't be shifted into each other
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    format!("{:x}", hasher.finalize())[..32].to_string()
+}
+
+/// Stage of the proof, it's saved as a Checkpoint
+#[derive(Clone, Debug)]
 pub enum Stage {
-    Batch(String, String, String),     // task_key, chunk_id, l2_batch_data
+    Batch(String, String, String),     // task_key, chunk_id, batch_digest
     Aggregate(String, String, String), // task_key, input, input2
-    Final(String, String, String),     // task_key, curve, prover_addr
+    Final(String, Curve, String),      // agg_task_id, curve, prover_addr
 }
 
 impl Stage {
     /// get the path of the stage
     pub fn path(&self) -> String {
         match self {
-            Self::Batch(task_id, chunk_id, _) => {
-                format!("proof/{task_id}/batch_proof_{chunk_id}")
+            Self::Batch(task_id, chunk_id, _) => ProofLayout::batch_path(task_id, chunk_id),
+            Self::Aggregate(task_id, _, _) => ProofLayout::agg_path(task_id),
+            Self::Final(task_id, curve, prover_addr) => {
+                ProofLayout::final_path(&final_task_id(task_id, *curve, prover_addr))
+            }
+        }
+    }
+
+    /// get the key of the stage in the task map
+    pub fn key(&self) -> String {
+        match self {
+            Self::Batch(task_id, chunk_id, _) => format!("{task_id}_{chunk_id}"),
+            Self::Aggregate(task_id, _, _) => task_id.clone(),
+            Self::Final(task_id, curve, prover_addr) => {
+                format!("{}_final", final_task_id(task_id, *curve, prover_addr))
+            }
+        }
+    }
+
+    /// The keys of the stages which must be proved before this one:
+    ///   - the aggregation `{task_id}_chunk_{start}` to `{task_id}_chunk_{end}` needs the chunks in the range
+    ///   - the final proof needs its aggregation
+    ///
+    /// The inputs which can not be parsed have no dependencies.
+    pub fn dependencies(&self) -> Vec<String> {
+        match self {
+            Self::Batch(..) => vec![],
+            Self::Aggregate(_, input, input2) => {
+                let parse = |input: &str| {
+                    let (task_id, chunk_id) = input.split_once("_chunk_")?;
+                    Some((task_id.to_string(), chunk_id.parse::<usize>().ok()?))
+                };
+                match (parse(input), parse(input2)) {
+                    (Some((task_id, start)), Some((task_id2, end))) if task_id == task_id2 => {
+                        (start..=end).map(|i| format!("{task_id}_{i}")).collect()
+                    }
+                    _ => vec![],
+                }
+            }
+            Self::Final(task_id, _, _) => vec![format!("{task_id}_agg")],
+        }
+    }
+
+    /// Serialize the stage into a checkpoint of the latest version,
+    /// it refers to the batch data in the BlobStore by the digest.
+    pub fn to_checkpoint(&self) -> Result<String> {
+        let stage = match self.clone() {
+            Self::Batch(task_id, chunk_id, batch_digest) => StageCheckpoint::Batch {
+                batch_data: BatchDataRef::new(&batch_digest),
+                task_id,
+                chunk_id,
+            },
+            Self::Aggregate(task_id, input, input2) => StageCheckpoint::Aggregate {
+                task_id,
+                input,
+                input2,
+            },
+            Self::Final(task_id, curve, prover_addr) => StageCheckpoint::Final {
+                task_id,
+                curve: curve.to_string(),
+                prover_addr,
+            },
+        };
+        Ok(serde_json::to_string(&Checkpoint {
+            version: CHECKPOINT_VERSION,
+            stage,
+        })?)
+    }
+
+    /// Rebuild the stage from the checkpoint saved in `workdir_name`.
+    /// The legacy checkpoint is an untagged array, so its variant is taken from the workdir name.
+    pub fn from_checkpoint(basedir: &str, workdir_name: &str, status: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(status)?;
+        if value.is_array() {
+            return Self::from_legacy_checkpoint(basedir, workdir_name, value);
+        }
+        let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+        if version == 0 || version > CHECKPOINT_VERSION as u64 {
+            bail!(
+                "unsupported checkpoint version: {}, the latest is {}",
+                version,
+                CHECKPOINT_VERSION
+            );
+        }
+        let checkpoint: Checkpoint = serde_json::from_value(value)?;
+        let stage = match checkpoint.stage {
+            StageCheckpoint::Batch {
+                task_id,
+                chunk_id,
+                batch_data,
+            } => {
+                let batch_digest = match batch_data {
+                    Some(batch_data) => batch_data.digest()?,
+                    None => String::new(),
+                };
+                Self::Batch(task_id, chunk_id, batch_digest)
             }
-            Self::Aggregate(task_id, _, _) => format!("proof/{task_id}/agg_proof"),
-            Self::Final(task_id, _, _) => format!("proof/{task_id}/snark_proof"),
+            StageCheckpoint::Aggregate {
+                task_id,
+                input,
+                input2,
+            } => Self::Aggregate(task_id, input, input2),
+            StageCheckpoint::Final {
+                task_id,
+                curve,
+                prover_addr,
+            } => Self::Final(task_id, curve.parse()?, prover_addr),
+        };
+        if stage.path().rsplit('/').next() != Some(workdir_name) {
+            bail!(
+                "the checkpoint of {} is saved in another workdir: {}",
+                stage.key(),
+                workdir_name
+            );
+        }
+        Ok(stage)
+    }
+
+    /// Read the checkpoint and return it in the latest version if it's a legacy one, None if it's up to date
+    pub fn migrate_checkpoint(
+        basedir: &str,
+        workdir_name: &str,
+        status: &str,
+    ) -> Result<Option<String>> {
+        if !status.trim_start().starts_with('[') {
+            return Ok(None);
+        }
+        let stage = Self::from_checkpoint(basedir, workdir_name, status)?;
+        stage.to_checkpoint().map(Some)
+    }
+
+    /// The version 0 checkpoint: `["task_id","chunk_id","l2_batch_data"]`,
+    /// its batch data is moved into the BlobStore
+    fn from_legacy_checkpoint(
+        basedir: &str,
+        workdir_name: &str,
+        value: serde_json::Value,
+    ) -> Result<Self> {
+        let (task_id, arg, arg2): (String, String, String) = serde_json::from_value(value)?;
+        let stage = if workdir_name.starts_with("batch_proof_") {
+            let batch_digest = if arg2.is_empty() {
+                arg2
+            } else {
+                BlobStore::new(basedir).put(arg2.as_bytes())?
+            };
+            Self::Batch(task_id, arg, batch_digest)
+        } else if workdir_name == "agg_proof" {
+            Self::Aggregate(task_id, arg, arg2)
+        } else if workdir_name == "snark_proof" {
+            Self::Final(task_id, arg.parse()?, arg2)
+        } else {
+            bail!("unknown stage workdir: {}", workdir_name)
+        };
+        Ok(stage)
+    }
+}
+
+/// The version of the checkpoint written by this build, the legacy untagged checkpoint is version 0
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// The checkpoint of a stage, saved as the `status` file by the FsTaskStore
+/// and the `stage` column by the SqliteTaskStore, e.g.
+/// `{"version":1,"stage":"aggregate","task_id":"x_agg","input":"0_chunk_0","input2":"0_chunk_1"}`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    #[serde(flatten)]
+    pub stage: StageCheckpoint,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "stage", rename_all = "lowercase")]
+pub enum StageCheckpoint {
+    Batch {
+        task_id: String,
+        chunk_id: String,
+        /// None if the batch data is empty
+        batch_data: Option<BatchDataRef>,
+    },
+    Aggregate {
+        task_id: String,
+        input: String,
+        input2: String,
+    },
+    Final {
+        task_id: String,
+        /// The name of the Curve
+        curve: String,
+        prover_addr: String,
+    },
+}
+
+/// The batch data of a stage in the BlobStore
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchDataRef {
+    /// Relative to the basedir
+    pub path: String,
+    /// The sha256 of the batch data in hex
+    pub digest: String,
+}
+
+impl BatchDataRef {
+    /// None if the batch data is empty
+    fn new(batch_digest: &str) -> Option<Self> {
+        if batch_digest.is_empty() {
+            return None;
         }
+        Some(BatchDataRef {
+            path: ProofLayout::blob_path(batch_digest),
+            digest: batch_digest.to_string(),
+        })
     }
 
-    /// keep track of task status
-    pub fn to_string(&self) -> Result<String> {
-        Ok(serde_json::to_string(self)?)
+    /// Return the digest, it must refer to the batch data in the BlobStore.
+    /// The batch data may be pruned after the chunks are proved, it's not checked here.
+    fn digest(&self) -> Result<String> {
+        if !BlobStore::is_digest(&self.digest) || self.path != ProofLayout::blob_path(&self.digest)
+        {
+            bail!("invalid reference of the batch data: {:?}", self);
+        }
+        Ok(self.digest.clone())
     }
 }
 
@@ -57,19 +292,161 @@ mod tests {
     fn test_final_stage_path() {
         let stage = Stage::Final(
             "task_id".to_string(),
-            "curve".to_string(),
+            Curve::BN128,
             "prover_addr".to_string(),
         );
-        assert_eq!(stage.path(), "proof/task_id/snark_proof");
+        let id = final_task_id("task_id", Curve::BN128, "prover_addr");
+        assert_eq!(stage.path(), format!("proof/{id}/snark_proof"));
     }
 
     #[test]
-    fn test_stage_to_string() {
-        let stage = Stage::Batch(
-            "task_id".to_string(),
-            "chunk_id".to_string(),
-            "".to_string(),
+    fn test_checkpoint() {
+        let basedir = std::env::temp_dir().join(format!("checkpoint_{}", uuid::Uuid::new_v4()));
+        let dir = basedir.to_str().unwrap();
+        let stage = Stage::Aggregate(
+            "id_agg".to_string(),
+            "0_chunk_0".to_string(),
+            "0_chunk_1".to_string(),
+        );
+        let checkpoint = stage.to_checkpoint().unwrap();
+        assert_eq!(
+            checkpoint,
+            r#"{"version":1,"stage":"aggregate","task_id":"id_agg","input":"0_chunk_0","input2":"0_chunk_1"}"#
+        );
+        let stage = Stage::from_checkpoint(dir, "agg_proof", &checkpoint).unwrap();
+        assert_eq!(stage.dependencies(), vec!["0_0", "0_1"]);
+        // the variant is tagged, the workdir must match it
+        assert!(Stage::from_checkpoint(dir, "snark_proof", &checkpoint).is_err());
+
+        // the checkpoint refers to the batch data in the BlobStore
+        let store = BlobStore::new(dir);
+        let digest = store.put(b"data").unwrap();
+        let stage = Stage::Batch("0".to_string(), "1".to_string(), digest.clone());
+        let checkpoint = stage.to_checkpoint().unwrap();
+        assert!(checkpoint.contains(&ProofLayout::blob_path(&digest)));
+        let Stage::Batch(_, chunk_id, batch_digest) =
+            Stage::from_checkpoint(dir, "batch_proof_1", &checkpoint).unwrap()
+        else {
+            panic!("not a batch stage");
+        };
+        assert_eq!(
+            (chunk_id.as_str(), batch_digest.as_str()),
+            ("1", digest.as_str())
+        );
+        let invalid = checkpoint.replace(&digest, "data");
+        assert!(Stage::from_checkpoint(dir, "batch_proof_1", &invalid).is_err());
+
+        let elsewhere = checkpoint.replace(&ProofLayout::blob_path(&digest), "proof/0/data");
+        assert!(Stage::from_checkpoint(dir, "batch_proof_1", &elsewhere).is_err());
+
+        let stage = Stage::Final("id".to_string(), Curve::BLS12381, "addr".to_string());
+        let checkpoint = stage.to_checkpoint().unwrap();
+        assert!(checkpoint.contains(r#""curve":"BLS12381""#));
+        let stage = Stage::from_checkpoint(dir, "snark_proof", &checkpoint).unwrap();
+        assert!(matches!(stage, Stage::Final(_, Curve::BLS12381, _)));
+        let unknown = checkpoint.replace("BLS12381", "BN254");
+        assert!(Stage::from_checkpoint(dir, "snark_proof", &unknown).is_err());
+
+        let checkpoint = Stage::Batch("0".to_string(), "1".to_string(), "".to_string())
+            .to_checkpoint()
+            .unwrap();
+        let newer = checkpoint.replace(r#""version":1"#, r#""version":2"#);
+        assert!(Stage::from_checkpoint(dir, "batch_proof_1", &newer).is_err());
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_checkpoint() {
+        let basedir = std::env::temp_dir().join(format!("checkpoint_{}", uuid::Uuid::new_v4()));
+        let dir = basedir.to_str().unwrap();
+        let legacy = r#"["0","1","data"]"#;
+        let checkpoint = Stage::migrate_checkpoint(dir, "batch_proof_1", legacy)
+            .unwrap()
+            .unwrap();
+        assert!(checkpoint.starts_with(r#"{"version":1,"stage":"batch""#));
+        let stage = Stage::from_checkpoint(dir, "batch_proof_1", &checkpoint).unwrap();
+        let Stage::Batch(_, _, batch_digest) = stage else {
+            panic!("not a batch stage");
+        };
+        let l2_batch_data = BlobStore::new(dir).get_batch_data(&batch_digest).unwrap();
+        assert_eq!(l2_batch_data, "data");
+        assert!(Stage::migrate_checkpoint(dir, "batch_proof_1", &checkpoint)
+            .unwrap()
+            .is_none());
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_stage_key() {
+        let stage = Stage::Batch("0".to_string(), "1".to_string(), "".to_string());
+        assert_eq!(stage.key(), "0_1");
+        let stage = Stage::Aggregate("id_agg".to_string(), "".to_string(), "".to_string());
+        assert_eq!(stage.key(), "id_agg");
+        let stage = Stage::Final("id".to_string(), Curve::BN128, "".to_string());
+        let id = final_task_id("id", Curve::BN128, "");
+        assert_eq!(stage.key(), format!("{id}_final"));
+    }
+
+    #[test]
+    fn test_aggregate_task_id() {
+        let id = aggregate_task_id("evm", "0_chunk_0", "0_chunk_2");
+        assert_eq!(id.len(), 32);
+        assert_eq!(id, aggregate_task_id("evm", "0_chunk_0", "0_chunk_2"));
+        assert_ne!(id, aggregate_task_id("lr", "0_chunk_0", "0_chunk_2"));
+        assert_ne!(id, aggregate_task_id("evm", "0_chunk_2", "0_chunk_0"));
+        assert_ne!(
+            aggregate_task_id("evm", "0_chunk_0", "1"),
+            aggregate_task_id("evm", "0_chunk_", "01")
+        );
+    }
+
+    #[test]
+    fn test_final_task_id() {
+        let id = final_task_id("a", Curve::BN128, "addr");
+        assert_eq!(id.len(), 32);
+        assert_eq!(id, final_task_id("a", Curve::BN128, "addr"));
+        assert_ne!(id, final_task_id("a", Curve::BLS12381, "addr"));
+        assert_ne!(id, final_task_id("a", Curve::BN128, "addr2"));
+        assert_ne!(id, final_task_id("b", Curve::BN128, "addr"));
+    }
+
+    #[test]
+    fn test_stage_dependencies() {
+        let stage = Stage::Batch("0".to_string(), "1".to_string(), "".to_string());
+        assert!(stage.dependencies().is_empty());
+        let stage = Stage::Aggregate(
+            "id_agg".to_string(),
+            "0_chunk_0".to_string(),
+            "0_chunk_2".to_string(),
+        );
+        assert_eq!(stage.dependencies(), vec!["0_0", "0_1", "0_2"]);
+        let stage = Stage::Aggregate(
+            "id_agg".to_string(),
+            "0_chunk_0".to_string(),
+            "1_chunk_2".to_string(),
         );
-        assert_eq!(stage.to_string().unwrap(), r#"["task_id","chunk_id",""]"#);
+        assert!(stage.dependencies().is_empty());
+        let stage = Stage::Final("id".to_string(), Curve::BN128, "".to_string());
+        assert_eq!(stage.dependencies(), vec!["id_agg"]);
+    }
+
+    #[test]
+    fn test_stage_from_checkpoint() {
+        let status = r#"["task_id","input","input2"]"#;
+        let stage = Stage::from_checkpoint("", "agg_proof", status).unwrap();
+        assert!(matches!(stage, Stage::Aggregate(..)));
+        assert_eq!(stage.path(), "proof/task_id/agg_proof");
+
+        let stage = Stage::from_checkpoint("", "batch_proof_input", status).unwrap();
+        assert!(matches!(stage, Stage::Batch(..)));
+        assert_eq!(stage.path(), "proof/task_id/batch_proof_input");
+
+        // the curve of the final stage is checked
+        assert!(Stage::from_checkpoint("", "snark_proof", status).is_err());
+        let status = r#"["task_id","BLS12381","addr"]"#;
+        let stage = Stage::from_checkpoint("", "snark_proof", status).unwrap();
+        assert!(matches!(stage, Stage::Final(_, Curve::BLS12381, _)));
+
+        assert!(Stage::from_checkpoint("", "unknown", status).is_err());
     }
 }
@@ -0,0 +1,188 @@
+use crate::stage::Stage;
+
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Priority of the stages in the TaskQueue, the higher one is proved first.
+/// The final and aggregated proofs finish the tasks that are almost done,
+/// so they are not starved by the chunks of the new batches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Batch = 0,
+    Aggregate = 1,
+    Final = 2,
+}
+
+impl Priority {
+    /// From the highest to the lowest
+    pub const ALL: [Priority; 3] = [Priority::Final, Priority::Aggregate, Priority::Batch];
+}
+
+impl From<&Stage> for Priority {
+    fn from(stage: &Stage) -> Self {
+        match stage {
+            Stage::Batch(..) => Priority::Batch,
+            Stage::Aggregate(..) => Priority::Aggregate,
+            Stage::Final(..) => Priority::Final,
+        }
+    }
+}
+
+/// Returned when the TaskQueue can not take more tasks,
+/// the caller should retry later
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueFullError {
+    pub depth: usize,
+    pub max_depth: usize,
+}
+
+impl fmt::Display for QueueFullError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Task queue is full, depth: {}, max depth: {}",
+            self.depth, self.max_depth
+        )
+    }
+}
+
+impl std::error::Error for QueueFullError {}
+
+/// The keys of the stages waiting to be proved.
+/// The stages are popped by priority, and in FIFO order within the same priority.
+#[derive(Debug, Default)]
+pub struct TaskQueue {
+    /// Indexed by the Priority
+    queues: [VecDeque<String>; 3],
+    /// 0 means unbounded
+    max_depth: usize,
+}
+
+impl TaskQueue {
+    pub fn new(max_depth: usize) -> Self {
+        TaskQueue {
+            queues: Default::default(),
+            max_depth,
+        }
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of stages waiting with the priority
+    pub fn depth_of(&self, priority: Priority) -> usize {
+        self.queues[priority as usize].len()
+    }
+
+    /// Check that `count` more stages can be pushed
+    pub fn check_capacity(&self, count: usize) -> Result<(), QueueFullError> {
+        let depth = self.len();
+        if self.max_depth > 0 && depth + count > self.max_depth {
+            return Err(QueueFullError {
+                depth,
+                max_depth: self.max_depth,
+            });
+        }
+        Ok(())
+    }
+
+    /// Push a new stage, fail if the queue is full
+    pub fn push(&mut self, key: String, priority: Priority) -> Result<(), QueueFullError> {
+        self.check_capacity(1)?;
+        self.queues[priority as usize].push_back(key);
+        Ok(())
+    }
+
+    /// Put a recovered stage back regardless of the max depth,
+    /// the accepted tasks are never dropped
+    pub fn requeue(&mut self, key: String, priority: Priority) {
+        self.queues[priority as usize].push_back(key);
+    }
+
+    /// Pop the oldest stage with the highest priority
+    pub fn pop(&mut self) -> Option<String> {
+        Priority::ALL
+            .iter()
+            .find_map(|p| self.queues[*p as usize].pop_front())
+    }
+
+    /// Remove the stage from the queue, return true if it was queued
+    pub fn remove(&mut self, key: &str) -> bool {
+        let len = self.len();
+        for queue in self.queues.iter_mut() {
+            queue.retain(|k| k != key);
+        }
+        len != self.len()
+    }
+
+    /// The keys with their priorities, in the order they will be popped
+    pub fn entries(&self) -> Vec<(String, Priority)> {
+        Priority::ALL
+            .iter()
+            .flat_map(|p| self.queues[*p as usize].iter().map(|k| (k.clone(), *p)))
+            .collect()
+    }
+
+    /// The keys in the order they will be popped
+    pub fn keys(&self) -> Vec<String> {
+        self.entries().into_iter().map(|(k, _)| k).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_task_queue() {
+        let mut queue = TaskQueue::new(4);
+        queue.push("0_0".into(), Priority::Batch).unwrap();
+        queue.push("0_1".into(), Priority::Batch).unwrap();
+        queue.push("a_agg".into(), Priority::Aggregate).unwrap();
+        queue.push("a_final".into(), Priority::Final).unwrap();
+        assert_eq!(queue.keys(), vec!["a_final", "a_agg", "0_0", "0_1"]);
+        assert_eq!(queue.depth_of(Priority::Batch), 2);
+
+        let err = queue.push("0_2".into(), Priority::Batch).unwrap_err();
+        assert_eq!(
+            err,
+            QueueFullError {
+                depth: 4,
+                max_depth: 4
+            }
+        );
+        assert!(queue.check_capacity(1).is_err());
+        // the recovered stages are always accepted
+        queue.requeue("1_0".into(), Priority::Batch);
+        assert_eq!(queue.len(), 5);
+
+        assert!(queue.remove("0_1"));
+        assert!(!queue.remove("0_1"));
+        assert_eq!(queue.pop().as_deref(), Some("a_final"));
+        assert_eq!(queue.pop().as_deref(), Some("a_agg"));
+        assert_eq!(queue.pop().as_deref(), Some("0_0"));
+        assert_eq!(queue.pop().as_deref(), Some("1_0"));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+
+        // 0 means unbounded
+        queue.set_max_depth(0);
+        for i in 0..10 {
+            queue.push(format!("2_{}", i), Priority::Batch).unwrap();
+        }
+        assert_eq!(queue.len(), 10);
+    }
+}
@@ -0,0 +1,311 @@
+use super::{now, StatusRecord, TaskRecord, TaskStatus, TaskStore, STATUS_RECORD_VERSION};
+use crate::layout::ProofLayout;
+use crate::stage::Stage;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The status of the stage
+const STATUS_FILE: &str = "status.json";
+
+/// The status file before the StatusRecord, "1" if the stage is proved, or "0".
+/// It's removed when the status is saved.
+const LEGACY_STATUS_FILE: &str = "status.finished";
+
+/// Keep the checkpoints in the workdir of each stage:
+///   - status: the Checkpoint of the stage, written when the task is submitted
+///   - status.json: the StatusRecord of the stage
+///
+/// The legacy untagged `status` files are migrated when the store is opened,
+/// and the legacy final proofs are moved to the workdir of their final task id.
+/// The legacy checkpoints without `status.json` are still loaded,
+/// a failed task can not be told apart from a queued one in them.
+pub struct FsTaskStore {
+    basedir: String,
+    /// key -> stage, the workdir can not be derived from the key
+    index: Mutex<HashMap<String, Stage>>,
+    /// Held across the load and the save of a status, so that the concurrent updates
+    /// by the pipeline and the scheduler don't overwrite each other
+    status_lock: Mutex<()>,
+}
+
+impl FsTaskStore {
+    pub fn new(basedir: &str) -> Result<Self> {
+        let store = FsTaskStore {
+            basedir: basedir.to_string(),
+            index: Mutex::new(HashMap::new()),
+            status_lock: Mutex::new(()),
+        };
+        store.migrate()?;
+        let stages = store.scan()?;
+        store
+            .index
+            .lock()
+            .map_err(|e| anyhow!("task index is poisoned, {}", e))?
+            .extend(stages.into_iter().map(|s| (s.key(), s)));
+        Ok(store)
+    }
+
+    fn workdir(&self, stage: &Stage) -> PathBuf {
+        Path::new(&self.basedir).join(stage.path())
+    }
+
+    /// The `status` files of the stages under `basedir/proof`, with the names of their workdirs
+    fn checkpoint_files(&self) -> Result<Vec<(PathBuf, String)>> {
+        let proof_dir = ProofLayout::new(&self.basedir).proof_dir();
+        if !proof_dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut files = vec![];
+        for task_dir in std::fs::read_dir(proof_dir)? {
+            let task_dir = task_dir?.path();
+            if !task_dir.is_dir() {
+                continue;
+            }
+            for workdir in std::fs::read_dir(task_dir)? {
+                let workdir = workdir?.path();
+                let status_path = workdir.join("status");
+                if !status_path.is_file() {
+                    continue;
+                }
+                let workdir_name = workdir
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string();
+                files.push((status_path, workdir_name));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Migrate the legacy checkpoints, it's done once when the store is opened
+    fn migrate(&self) -> Result<()> {
+        for (status_path, workdir_name) in self.checkpoint_files()? {
+            let migrated = std::fs::read_to_string(&status_path)
+                .map_err(|e| anyhow!(e))
+                .and_then(|status| self.migrate_checkpoint(&status_path, &workdir_name, &status));
+            if let Err(e) = migrated {
+                log::warn!("skip invalid checkpoint: {:?}, err: {}", status_path, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Load all the stages under `basedir/proof`, in the order they were submitted.
+    /// Nothing is written, the legacy checkpoints are migrated when the store is opened.
+    fn scan(&self) -> Result<Vec<Stage>> {
+        let mut checkpoints = vec![];
+        for (status_path, workdir_name) in self.checkpoint_files()? {
+            // the status file is written when the task is submitted
+            let submitted_at = std::fs::metadata(&status_path)?.modified()?;
+            let stage = match std::fs::read_to_string(&status_path)
+                .map_err(|e| anyhow!(e))
+                .and_then(|status| Stage::from_checkpoint(&self.basedir, &workdir_name, &status))
+            {
+                Ok(stage) => stage,
+                Err(e) => {
+                    log::warn!("skip invalid checkpoint: {:?}, err: {}", status_path, e);
+                    continue;
+                }
+            };
+            checkpoints.push((submitted_at, stage));
+        }
+
+        // stages submitted at the same time are ordered by their dependencies
+        checkpoints.sort_by_key(|(submitted_at, stage)| {
+            let rank = match stage {
+                Stage::Batch(..) => 0,
+                Stage::Aggregate(..) => 1,
+                Stage::Final(..) => 2,
+            };
+            (*submitted_at, rank, stage.key())
+        });
+        // the legacy workdir which isn't moved has the key of its target
+        checkpoints.dedup_by_key(|(_, stage)| stage.key());
+        Ok(checkpoints.into_iter().map(|(_, stage)| stage).collect())
+    }
+
+    /// Save the legacy checkpoint again in the latest version, nothing is done to the latest one.
+    /// The submission time is kept, the stages are ordered by it.
+    fn migrate_checkpoint(
+        &self,
+        status_path: &Path,
+        workdir_name: &str,
+        status: &str,
+    ) -> Result<()> {
+        let Some(checkpoint) = Stage::migrate_checkpoint(&self.basedir, workdir_name, status)?
+        else {
+            return Ok(());
+        };
+        log::info!("migrate checkpoint: {:?}", status_path);
+        let submitted_at = std::fs::metadata(status_path)?.modified()?;
+        let tmp = status_path.with_extension("tmp");
+        std::fs::write(&tmp, &checkpoint)?;
+        std::fs::File::options()
+            .write(true)
+            .open(&tmp)?
+            .set_modified(submitted_at)?;
+        std::fs::rename(tmp, status_path)?;
+        let stage = Stage::from_checkpoint(&self.basedir, workdir_name, &checkpoint)?;
+
+        // the legacy final proof is saved under the task id of its aggregation,
+        // it's moved to the task id of the final proof
+        let (workdir, target) = (status_path.parent(), self.workdir(&stage));
+        if let Some(workdir) = workdir.filter(|w| *w != target && !target.exists()) {
+            log::info!("move the workdir: {:?} to {:?}", workdir, target);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(workdir, &target)?;
+            if let Some(task_dir) = workdir.parent() {
+                // it's kept if other stages are in it
+                let _ = std::fs::remove_dir(task_dir);
+            }
+        }
+        Ok(())
+    }
+
+    fn stage_of(&self, key: &str) -> Result<Option<Stage>> {
+        Ok(self
+            .index
+            .lock()
+            .map_err(|e| anyhow!("task index is poisoned, {}", e))?
+            .get(key)
+            .cloned())
+    }
+
+    fn load_status(&self, stage: &Stage) -> Result<StatusRecord> {
+        let workdir = self.workdir(stage);
+        let p = workdir.join(STATUS_FILE);
+        log::debug!("load_status, check file: {:?}", p);
+        if p.exists() {
+            return StatusRecord::parse(&std::fs::read_to_string(p)?);
+        }
+        Self::load_legacy_status(&workdir)
+    }
+
+    fn load_legacy_status(workdir: &Path) -> Result<StatusRecord> {
+        let p = workdir.join(LEGACY_STATUS_FILE);
+        let finished = if p.exists() {
+            Some(std::fs::read_to_string(p)?)
+        } else {
+            None
+        };
+        let state = match finished.as_deref().map(str::trim) {
+            Some("1") => TaskStatus::Succeeded,
+            Some("0") | None => TaskStatus::Queued,
+            _ => return Err(anyhow!("Invalid value. Expected '0' or '1'.")),
+        };
+        Ok(StatusRecord {
+            version: 0,
+            state,
+            ..Default::default()
+        })
+    }
+
+    /// Write the status then rename it, so it's never half written
+    fn save_status(&self, stage: &Stage, status: &StatusRecord) -> Result<()> {
+        let workdir = self.workdir(stage);
+        let p = workdir.join(STATUS_FILE);
+        log::info!(
+            "save_checkpoint with result: {:?}, file: {:?}",
+            status.state,
+            p
+        );
+        let tmp = p.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string(status)?)?;
+        std::fs::rename(tmp, p)?;
+        match std::fs::remove_file(workdir.join(LEGACY_STATUS_FILE)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Load the status of an existing task, update and save it in the latest version
+    fn update_status<F>(&self, key: &str, update: F) -> Result<StatusRecord>
+    where
+        F: FnOnce(&mut StatusRecord),
+    {
+        let stage = self
+            .stage_of(key)?
+            .ok_or_else(|| anyhow!("can not find task: {}", key))?;
+        let _guard = self
+            .status_lock
+            .lock()
+            .map_err(|e| anyhow!("task status is poisoned, {}", e))?;
+        let mut status = self.load_status(&stage)?;
+        update(&mut status);
+        status.version = STATUS_RECORD_VERSION;
+        self.save_status(&stage, &status)?;
+        Ok(status)
+    }
+
+    fn to_record(&self, stage: Stage) -> Result<TaskRecord> {
+        Ok(self.load_status(&stage)?.into_record(stage))
+    }
+}
+
+impl TaskStore for FsTaskStore {
+    fn put(&self, task_name: &str, stage: &Stage) -> Result<()> {
+        let workdir = self.workdir(stage);
+        log::info!("save_checkpoint, mkdir: {:?}", workdir);
+        std::fs::create_dir_all(workdir.clone())?;
+        {
+            let _guard = self
+                .status_lock
+                .lock()
+                .map_err(|e| anyhow!("task status is poisoned, {}", e))?;
+            std::fs::write(workdir.join("status"), stage.to_checkpoint()?)?;
+            self.save_status(stage, &StatusRecord::new(task_name))?;
+        }
+
+        self.index
+            .lock()
+            .map_err(|e| anyhow!("task index is poisoned, {}", e))?
+            .insert(stage.key(), stage.clone());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<TaskRecord>> {
+        match self.stage_of(key)? {
+            Some(stage) => self.to_record(stage).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<TaskRecord>> {
+        self.scan()?
+            .into_iter()
+            .map(|stage| self.to_record(stage))
+            .collect()
+    }
+
+    fn transition(&self, key: &str, status: TaskStatus) -> Result<()> {
+        self.update_status(key, |record| {
+            record.state = status;
+            record.finished_at = status.is_finished().then(now);
+        })?;
+        Ok(())
+    }
+
+    fn start(&self, key: &str, worker_id: &str) -> Result<()> {
+        self.update_status(key, |record| {
+            record.state = TaskStatus::Running;
+            record.started_at = Some(now());
+            record.finished_at = None;
+            record.worker_id = Some(worker_id.to_string());
+        })?;
+        Ok(())
+    }
+
+    fn record_failure(&self, key: &str, errors: &[String]) -> Result<u32> {
+        let record = self.update_status(key, |record| {
+            record.attempts += 1;
+            record.errors = errors.to_vec();
+        })?;
+        Ok(record.attempts)
+    }
+}
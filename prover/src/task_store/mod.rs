@@ -0,0 +1,348 @@
+mod fs_store;
+pub use fs_store::FsTaskStore;
+
+mod sqlite_store;
+pub use sqlite_store::SqliteTaskStore;
+
+mod status_record;
+pub use status_record::{StatusRecord, STATUS_RECORD_VERSION};
+
+use crate::stage::Stage;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The worker id of the stages proved by the local worker pool
+pub const LOCAL_WORKER_ID: &str = "local";
+/// The worker id of the batch stages sent to the scheduler, until a service takes them
+pub const SCHEDULER_WORKER_ID: &str = "scheduler";
+
+/// Status of a task in the TaskStore
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskStatus {
+    #[default]
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl TaskStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskStatus::Queued => "queued",
+            TaskStatus::Running => "running",
+            TaskStatus::Succeeded => "succeeded",
+            TaskStatus::Failed => "failed",
+            TaskStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(status: &str) -> Result<Self> {
+        match status {
+            "queued" => Ok(TaskStatus::Queued),
+            "running" => Ok(TaskStatus::Running),
+            "succeeded" => Ok(TaskStatus::Succeeded),
+            "failed" => Ok(TaskStatus::Failed),
+            "cancelled" => Ok(TaskStatus::Cancelled),
+            _ => bail!("invalid task status: {}", status),
+        }
+    }
+
+    /// The task won't be proved again unless it's submitted again
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Succeeded | TaskStatus::Failed | TaskStatus::Cancelled
+        )
+    }
+}
+
+/// Seconds since the unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A stage and its status, keyed by `Stage::key`
+#[derive(Clone, Debug)]
+pub struct TaskRecord {
+    pub key: String,
+    pub stage: Stage,
+    pub status: TaskStatus,
+    /// The program which the task belongs to, None for the checkpoints saved without it
+    pub task_name: Option<String>,
+    /// The number of the failed attempts
+    pub attempts: u32,
+    /// When the last attempt started and the task finished, in seconds since the unix epoch
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    /// The prover of the last attempt, LOCAL_WORKER_ID or the id of the service which took it from the scheduler
+    pub worker_id: Option<String>,
+    /// The error chain of the last failure, from the outermost context to the root cause
+    pub errors: Vec<String>,
+}
+
+impl TaskRecord {
+    /// The error chain of the last failure in one line
+    pub fn last_error(&self) -> Option<String> {
+        (!self.errors.is_empty()).then(|| self.errors.join(": "))
+    }
+}
+
+/// Published by the Pipeline and the Scheduler when a task is finished, failed or cancelled
+#[derive(Clone, Debug)]
+pub struct TaskEvent {
+    pub key: String,
+    pub status: TaskStatus,
+}
+
+/// The capacity of the task events channel,
+/// a receiver which lags behind should load the status from the TaskStore again
+pub const TASK_EVENTS_CAPACITY: usize = 1024;
+
+/// TaskStore keeps the checkpoints of the stages, shared by the Pipeline and the Scheduler
+pub trait TaskStore: Send + Sync {
+    /// Save the stage of the program `task_name` as a queued task,
+    /// overwrite the task if it already exists
+    fn put(&self, task_name: &str, stage: &Stage) -> Result<()>;
+
+    /// Get the task by key, return None if the task does not exist
+    fn get(&self, key: &str) -> Result<Option<TaskRecord>>;
+
+    /// List all the tasks in the order they were submitted
+    fn list(&self) -> Result<Vec<TaskRecord>>;
+
+    /// Update the status of an existing task, the finish time is recorded if it's finished
+    fn transition(&self, key: &str, status: TaskStatus) -> Result<()>;
+
+    /// Mark an existing task running on the worker, the start time is recorded
+    fn start(&self, key: &str, worker_id: &str) -> Result<()>;
+
+    /// Record a failed attempt of an existing task and its error chain without changing its status,
+    /// return the number of the failed attempts.
+    /// The attempts are reset when the task is put again.
+    fn record_failure(&self, key: &str, errors: &[String]) -> Result<u32>;
+}
+
+/// Open the task store under `basedir`, the backend is the `task_store` of the PipelineConfig:
+///   - fs: the `status` and `status.json` files in the workdir of each stage (default)
+///   - sqlite: the embedded database `basedir/tasks.db`
+pub fn open_task_store(basedir: &str, backend: &str) -> Result<Arc<dyn TaskStore>> {
+    log::info!("open task store: {}, basedir: {}", backend, basedir);
+    match backend {
+        "fs" => Ok(Arc::new(FsTaskStore::new(basedir)?)),
+        "sqlite" => Ok(Arc::new(SqliteTaskStore::new(basedir)?)),
+        _ => bail!("invalid task store: {}, it should be fs or sqlite", backend),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::BlobStore;
+    use crate::curve::Curve;
+    use std::env;
+    use uuid::Uuid;
+
+    fn check_task_store(store: &dyn TaskStore) {
+        let batch = Stage::Batch("0".into(), "0".into(), BlobStore::digest(b"data"));
+        let batch2 = Stage::Batch("0".into(), "1".into(), BlobStore::digest(b"data"));
+        let agg = Stage::Aggregate("1_agg".into(), "0_chunk_0".into(), "0_chunk_1".into());
+
+        assert!(store.get("0_0").unwrap().is_none());
+        assert!(store.transition("0_0", TaskStatus::Succeeded).is_err());
+
+        store.put("evm", &batch).unwrap();
+        store.put("evm", &batch2).unwrap();
+        store.put("lr", &agg).unwrap();
+        store.transition("0_0", TaskStatus::Succeeded).unwrap();
+        store.transition("0_1", TaskStatus::Cancelled).unwrap();
+
+        let record = store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Succeeded);
+        assert!(matches!(record.stage, Stage::Batch(..)));
+        assert_eq!(record.task_name.as_deref(), Some("evm"));
+
+        let record = store.get("0_1").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Cancelled);
+
+        let record = store.get("1_agg").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Queued);
+        assert!(matches!(record.stage, Stage::Aggregate(..)));
+        assert_eq!(record.task_name.as_deref(), Some("lr"));
+
+        let keys: Vec<_> = store.list().unwrap().into_iter().map(|r| r.key).collect();
+        assert_eq!(keys, vec!["0_0", "0_1", "1_agg"]);
+
+        assert_eq!(record.attempts, 0);
+        assert_eq!(record.last_error(), None);
+        store.start("1_agg", "service-0").unwrap();
+        let record = store.get("1_agg").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Running);
+        assert_eq!(record.worker_id.as_deref(), Some("service-0"));
+        assert!(record.started_at.is_some());
+        assert_eq!(record.finished_at, None);
+        assert!(store.start("2_agg", "service-0").is_err());
+
+        let errors = |e: &[&str]| e.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            store
+                .record_failure("1_agg", &errors(&["out of memory"]))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            store
+                .record_failure("1_agg", &errors(&["prove failed", "io error"]))
+                .unwrap(),
+            2
+        );
+        store.transition("1_agg", TaskStatus::Failed).unwrap();
+        let record = store.get("1_agg").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Failed);
+        assert_eq!(record.attempts, 2);
+        assert_eq!(record.errors, errors(&["prove failed", "io error"]));
+        assert_eq!(
+            record.last_error().as_deref(),
+            Some("prove failed: io error")
+        );
+        assert!(record.finished_at.is_some());
+        assert!(store
+            .record_failure("2_agg", &errors(&["io error"]))
+            .is_err());
+
+        // submitted again
+        store.put("lr", &agg).unwrap();
+        let record = store.get("1_agg").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Queued);
+        assert_eq!(record.attempts, 0);
+        assert_eq!(record.last_error(), None);
+        assert_eq!(record.worker_id, None);
+        assert_eq!(record.finished_at, None);
+    }
+
+    #[test]
+    fn test_fs_task_store() {
+        let basedir = env::temp_dir().join(format!("fs_task_store_{}", Uuid::new_v4()));
+        let store = FsTaskStore::new(basedir.to_str().unwrap()).unwrap();
+        check_task_store(&store);
+
+        // the checkpoints are loaded again by a new store, the legacy untagged one is migrated
+        let status = basedir.join("proof/0/batch_proof_0/status");
+        std::fs::write(&status, r#"["0","0","data"]"#).unwrap();
+        let store = FsTaskStore::new(basedir.to_str().unwrap()).unwrap();
+        let record = store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Succeeded);
+        assert!(
+            matches!(record.stage, Stage::Batch(_, _, ref d) if *d == BlobStore::digest(b"data"))
+        );
+        let checkpoint = std::fs::read_to_string(&status).unwrap();
+        assert!(checkpoint.starts_with(r#"{"version":1,"stage":"batch""#));
+        assert_eq!(store.list().unwrap().len(), 3);
+
+        // the legacy checkpoints are still loaded
+        let workdir = basedir.join("proof/0/batch_proof_1");
+        std::fs::remove_file(workdir.join("status.json")).unwrap();
+        std::fs::write(workdir.join("status.finished"), "1").unwrap();
+        let record = store.get("0_1").unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Succeeded);
+        assert_eq!(record.task_name, None);
+        assert_eq!(record.attempts, 0);
+        std::fs::write(workdir.join("status.finished"), "cancelled").unwrap();
+        assert!(store.get("0_1").is_err());
+        std::fs::write(workdir.join("status.finished"), "0").unwrap();
+        assert_eq!(
+            store.get("0_1").unwrap().unwrap().status,
+            TaskStatus::Queued
+        );
+
+        // and upgraded when they are updated
+        store.transition("0_1", TaskStatus::Cancelled).unwrap();
+        assert!(!workdir.join("status.finished").exists());
+        let status: StatusRecord =
+            serde_json::from_str(&std::fs::read_to_string(workdir.join("status.json")).unwrap())
+                .unwrap();
+        assert_eq!(status.version, STATUS_RECORD_VERSION);
+        assert_eq!(status.state, TaskStatus::Cancelled);
+
+        // the legacy final proof is moved to the workdir of its final task id
+        let legacy = basedir.join("proof/a/snark_proof");
+        std::fs::create_dir_all(&legacy).unwrap();
+        std::fs::write(legacy.join("status"), r#"["a","BN128","addr"]"#).unwrap();
+        std::fs::write(legacy.join("status.finished"), "1").unwrap();
+        std::fs::write(legacy.join("proof.json"), "{}").unwrap();
+        let store = FsTaskStore::new(basedir.to_str().unwrap()).unwrap();
+        let stage = Stage::Final("a".into(), Curve::BN128, "addr".into());
+        let record = store.get(&stage.key()).unwrap().unwrap();
+        assert_eq!(record.status, TaskStatus::Succeeded);
+        assert!(basedir.join(stage.path()).join("proof.json").is_file());
+        assert!(!basedir.join("proof/a").exists());
+        assert_eq!(store.list().unwrap().len(), 4);
+
+        // the store is migrated when it's opened, list doesn't write
+        let legacy = basedir.join("proof/b/snark_proof");
+        std::fs::create_dir_all(&legacy).unwrap();
+        std::fs::write(legacy.join("status"), r#"["b","BN128","addr"]"#).unwrap();
+        store.list().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(legacy.join("status")).unwrap(),
+            r#"["b","BN128","addr"]"#
+        );
+        let stage = Stage::Final("b".into(), Curve::BN128, "addr".into());
+        assert!(!basedir.join(stage.path()).exists());
+
+        // the records written by a newer version are rejected
+        std::fs::write(
+            workdir.join("status.json"),
+            r#"{"version": 100, "state": "queued"}"#,
+        )
+        .unwrap();
+        assert!(store.get("0_1").is_err());
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_fs_task_store_concurrency() {
+        let basedir = env::temp_dir().join(format!("fs_task_store_{}", Uuid::new_v4()));
+        let store = Arc::new(FsTaskStore::new(basedir.to_str().unwrap()).unwrap());
+        let stage = Stage::Batch("0".into(), "0".into(), "".into());
+        store.put("evm", &stage).unwrap();
+
+        // the pipeline and the scheduler update the same task at once, no update is lost
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        store
+                            .record_failure("0_0", &[format!("error {}", i)])
+                            .unwrap();
+                        store.start("0_0", &format!("service-{}", i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let record = store.get("0_0").unwrap().unwrap();
+        assert_eq!(record.attempts, 80);
+        assert_eq!(record.status, TaskStatus::Running);
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_task_store() {
+        let basedir = env::temp_dir().join(format!("sqlite_task_store_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&basedir).unwrap();
+        let store = SqliteTaskStore::new(basedir.to_str().unwrap()).unwrap();
+        check_task_store(&store);
+        std::fs::remove_dir_all(basedir).unwrap();
+    }
+}
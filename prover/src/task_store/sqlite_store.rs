@@ -0,0 +1,212 @@
+use super::{now, TaskRecord, TaskStatus, TaskStore};
+use crate::stage::Stage;
+use anyhow::{anyhow, bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS tasks (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    key TEXT NOT NULL UNIQUE,
+    path TEXT NOT NULL,
+    stage TEXT NOT NULL,
+    status TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    task_name TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    started_at INTEGER,
+    finished_at INTEGER,
+    worker_id TEXT,
+    -- the error chain of the last failure in JSON
+    errors TEXT
+);";
+
+const SELECT_TASKS: &str = "SELECT key, path, stage, status, task_name, attempts,
+    started_at, finished_at, worker_id, errors FROM tasks";
+
+/// A row of SELECT_TASKS
+struct Row {
+    key: String,
+    path: String,
+    stage: String,
+    status: String,
+    task_name: String,
+    attempts: u32,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+    worker_id: Option<String>,
+    errors: Option<String>,
+}
+
+/// Keep the checkpoints in an embedded SQLite database `basedir/tasks.db`, one row per stage.
+/// The batch data of the checkpoints is in the BlobStore under `basedir`.
+pub struct SqliteTaskStore {
+    basedir: String,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteTaskStore {
+    pub fn new(basedir: &str) -> Result<Self> {
+        let conn = Connection::open(Path::new(basedir).join("tasks.db"))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteTaskStore {
+            basedir: basedir.to_string(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn workdir_name(path: &str) -> Result<String> {
+        Ok(Path::new(path)
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid stage path: {}", path))?
+            .to_string_lossy()
+            .to_string())
+    }
+
+    fn read_row(row: &rusqlite::Row) -> rusqlite::Result<Row> {
+        Ok(Row {
+            key: row.get(0)?,
+            path: row.get(1)?,
+            stage: row.get(2)?,
+            status: row.get(3)?,
+            task_name: row.get(4)?,
+            attempts: row.get(5)?,
+            started_at: row.get(6)?,
+            finished_at: row.get(7)?,
+            worker_id: row.get(8)?,
+            errors: row.get(9)?,
+        })
+    }
+
+    fn to_record(&self, row: Row) -> Result<TaskRecord> {
+        let workdir_name = Self::workdir_name(&row.path)?;
+        let errors = match row.errors {
+            Some(errors) => serde_json::from_str(&errors)?,
+            None => vec![],
+        };
+        Ok(TaskRecord {
+            key: row.key,
+            stage: Stage::from_checkpoint(&self.basedir, &workdir_name, &row.stage)?,
+            status: TaskStatus::parse(&row.status)?,
+            task_name: Some(row.task_name),
+            attempts: row.attempts,
+            started_at: row.started_at,
+            finished_at: row.finished_at,
+            worker_id: row.worker_id,
+            errors,
+        })
+    }
+}
+
+impl TaskStore for SqliteTaskStore {
+    fn put(&self, task_name: &str, stage: &Stage) -> Result<()> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|e| anyhow!("task store is poisoned, {}", e))?;
+        // keep the seq of an existing task, so that the submission order is stable
+        conn.execute(
+            "INSERT INTO tasks (key, path, stage, status, updated_at, task_name)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(key) DO UPDATE SET
+                path = excluded.path,
+                stage = excluded.stage,
+                status = excluded.status,
+                updated_at = excluded.updated_at,
+                task_name = excluded.task_name,
+                attempts = 0,
+                started_at = NULL,
+                finished_at = NULL,
+                worker_id = NULL,
+                errors = NULL",
+            params![
+                stage.key(),
+                stage.path(),
+                stage.to_checkpoint()?,
+                TaskStatus::Queued.as_str(),
+                now(),
+                task_name
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<TaskRecord>> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|e| anyhow!("task store is poisoned, {}", e))?;
+        let row = conn
+            .query_row(
+                &format!("{} WHERE key = ?1", SELECT_TASKS),
+                params![key],
+                Self::read_row,
+            )
+            .optional()?;
+        row.map(|row| self.to_record(row)).transpose()
+    }
+
+    fn list(&self) -> Result<Vec<TaskRecord>> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|e| anyhow!("task store is poisoned, {}", e))?;
+        let mut stmt = conn.prepare(&format!("{} ORDER BY seq", SELECT_TASKS))?;
+        let rows = stmt.query_map([], Self::read_row)?;
+        rows.map(|row| self.to_record(row?)).collect()
+    }
+
+    fn transition(&self, key: &str, status: TaskStatus) -> Result<()> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|e| anyhow!("task store is poisoned, {}", e))?;
+        let now = now();
+        let updated = conn.execute(
+            "UPDATE tasks SET status = ?1, updated_at = ?2, finished_at = ?3 WHERE key = ?4",
+            params![
+                status.as_str(),
+                now,
+                status.is_finished().then_some(now),
+                key
+            ],
+        )?;
+        if updated == 0 {
+            bail!("can not find task: {}", key);
+        }
+        Ok(())
+    }
+
+    fn start(&self, key: &str, worker_id: &str) -> Result<()> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|e| anyhow!("task store is poisoned, {}", e))?;
+        let updated = conn.execute(
+            "UPDATE tasks SET status = ?1, updated_at = ?2, started_at = ?2, finished_at = NULL,
+                worker_id = ?3
+             WHERE key = ?4",
+            params![TaskStatus::Running.as_str(), now(), worker_id, key],
+        )?;
+        if updated == 0 {
+            bail!("can not find task: {}", key);
+        }
+        Ok(())
+    }
+
+    fn record_failure(&self, key: &str, errors: &[String]) -> Result<u32> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|e| anyhow!("task store is poisoned, {}", e))?;
+        let attempts = conn
+            .query_row(
+                "UPDATE tasks SET attempts = attempts + 1, errors = ?1, updated_at = ?2
+                 WHERE key = ?3 RETURNING attempts",
+                params![serde_json::to_string(errors)?, now(), key],
+                |row| row.get::<_, u32>(0),
+            )
+            .optional()?;
+        attempts.ok_or_else(|| anyhow!("can not find task: {}", key))
+    }
+}
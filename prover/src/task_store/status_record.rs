@@ -0,0 +1,66 @@
+use super::{TaskRecord, TaskStatus};
+use crate::stage::Stage;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// The version of the StatusRecord written by this build
+pub const STATUS_RECORD_VERSION: u32 = 1;
+
+/// The status of a stage, saved as `status.json` in its workdir by the FsTaskStore.
+///
+/// It replaces the legacy `status.finished` file ("1" or "0"),
+/// which is read as version 0 if `status.json` doesn't exist.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatusRecord {
+    pub version: u32,
+    pub state: TaskStatus,
+    /// The program which the task belongs to, None for the checkpoints saved without it
+    pub task_name: Option<String>,
+    /// The number of the failed attempts
+    pub attempts: u32,
+    /// In seconds since the unix epoch
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub worker_id: Option<String>,
+    /// The error chain of the last failure, from the outermost context to the root cause
+    pub errors: Vec<String>,
+}
+
+impl StatusRecord {
+    /// A queued task of the program
+    pub fn new(task_name: &str) -> Self {
+        StatusRecord {
+            version: STATUS_RECORD_VERSION,
+            task_name: Some(task_name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Parse `status.json`, the records of a newer version are rejected
+    pub fn parse(contents: &str) -> Result<Self> {
+        let record: StatusRecord = serde_json::from_str(contents)?;
+        if record.version > STATUS_RECORD_VERSION {
+            bail!(
+                "unsupported status record version: {}, the latest is {}",
+                record.version,
+                STATUS_RECORD_VERSION
+            );
+        }
+        Ok(record)
+    }
+
+    pub fn into_record(self, stage: Stage) -> TaskRecord {
+        TaskRecord {
+            key: stage.key(),
+            stage,
+            status: self.state,
+            task_name: self.task_name,
+            attempts: self.attempts,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            worker_id: self.worker_id,
+            errors: self.errors,
+        }
+    }
+}
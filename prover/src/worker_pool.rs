@@ -0,0 +1,240 @@
+use crate::task_queue::Priority;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The budgets of the local worker pool, the `[worker_pool]` of the PipelineConfig
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerPoolConfig {
+    /// The max number of the stages proved at the same time
+    pub threads: usize,
+    /// The memory in MB which can be used by the running stages, 0 means unbounded
+    pub memory_budget: u64,
+    /// The estimated memory in MB of each stage
+    pub batch_memory: u64,
+    pub aggregate_memory: u64,
+    pub final_memory: u64,
+}
+
+impl Default for WorkerPoolConfig {
+    /// Prove one stage at a time
+    fn default() -> Self {
+        WorkerPoolConfig {
+            threads: 1,
+            memory_budget: 0,
+            batch_memory: 0,
+            aggregate_memory: 0,
+            final_memory: 0,
+        }
+    }
+}
+
+impl WorkerPoolConfig {
+    pub fn memory_of(&self, priority: Priority) -> u64 {
+        match priority {
+            Priority::Batch => self.batch_memory,
+            Priority::Aggregate => self.aggregate_memory,
+            Priority::Final => self.final_memory,
+        }
+    }
+}
+
+/// Prove a stage, it owns everything it needs
+pub type Job = Box<dyn FnOnce() -> Result<()> + Send>;
+
+/// The result of a stage proved by the worker pool
+#[derive(Debug)]
+pub struct JobResult {
+    /// Tell the attempts of the same stage apart, the result of an abandoned attempt is discarded
+    pub job_id: u64,
+    pub key: String,
+    pub result: Result<()>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    threads: usize,
+    memory: u64,
+}
+
+/// Prove the stages in the background threads, bounded by the thread and memory budgets.
+/// The pool is cloned to share the budgets between the pipelines.
+#[derive(Clone, Debug)]
+pub struct WorkerPool {
+    config: WorkerPoolConfig,
+    usage: Arc<Mutex<Usage>>,
+}
+
+/// The budget taken by a running stage, it's released when dropped
+#[derive(Debug)]
+pub struct Reservation {
+    memory: u64,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.threads -= 1;
+        usage.memory -= self.memory;
+    }
+}
+
+impl WorkerPool {
+    pub fn new(config: WorkerPoolConfig) -> Self {
+        WorkerPool {
+            config,
+            usage: Arc::new(Mutex::new(Usage::default())),
+        }
+    }
+
+    pub fn config(&self) -> &WorkerPoolConfig {
+        &self.config
+    }
+
+    /// The number of the running stages and their memory in MB
+    pub fn usage(&self) -> (usize, u64) {
+        let usage = self.usage.lock().unwrap();
+        (usage.threads, usage.memory)
+    }
+
+    /// Take the budget of a stage, return None if the pool is busy.
+    /// A stage larger than the memory budget can run alone, otherwise it never runs.
+    pub fn try_reserve(&self, priority: Priority) -> Option<Reservation> {
+        let memory = self.config.memory_of(priority);
+        let mut usage = self.usage.lock().unwrap();
+        if usage.threads >= self.config.threads {
+            return None;
+        }
+        if self.config.memory_budget > 0
+            && usage.threads > 0
+            && usage.memory + memory > self.config.memory_budget
+        {
+            return None;
+        }
+        usage.threads += 1;
+        usage.memory += memory;
+        Some(Reservation {
+            memory,
+            usage: self.usage.clone(),
+        })
+    }
+
+    /// Run the job in a new thread, the result is sent to `results` even if it panics.
+    /// The thread holds the reservation of the job until the job returns, a thread can not be killed,
+    /// so the budget of an abandoned job is released only when it really finishes.
+    pub fn spawn<F>(
+        &self,
+        job_id: u64,
+        key: String,
+        results: Sender<JobResult>,
+        reservation: Reservation,
+        job: F,
+    ) -> Result<()>
+    where
+        F: FnOnce() -> Result<()> + Send + 'static,
+    {
+        thread::Builder::new()
+            .name(format!("prove-{}", key))
+            .spawn(move || {
+                let result = catch_unwind(AssertUnwindSafe(job))
+                    .unwrap_or_else(|_| Err(anyhow!("prove task: {} panicked", key)));
+                drop(reservation);
+                if results
+                    .send(JobResult {
+                        job_id,
+                        key,
+                        result,
+                    })
+                    .is_err()
+                {
+                    log::warn!("the pipeline is dropped, discard the job result");
+                }
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_worker_pool_budget() {
+        let pool = WorkerPool::new(WorkerPoolConfig {
+            threads: 3,
+            memory_budget: 100,
+            batch_memory: 40,
+            aggregate_memory: 60,
+            final_memory: 200,
+        });
+
+        let batch = pool.try_reserve(Priority::Batch).unwrap();
+        let batch2 = pool.try_reserve(Priority::Batch).unwrap();
+        assert_eq!(pool.usage(), (2, 80));
+        // out of memory
+        assert!(pool.try_reserve(Priority::Aggregate).is_none());
+        drop(batch2);
+        let agg = pool.try_reserve(Priority::Aggregate).unwrap();
+        assert!(pool.try_reserve(Priority::Batch).is_none());
+        drop(batch);
+        drop(agg);
+        assert_eq!(pool.usage(), (0, 0));
+
+        // the stage larger than the budget runs alone
+        let final_stage = pool.try_reserve(Priority::Final).unwrap();
+        assert!(pool.try_reserve(Priority::Batch).is_none());
+        drop(final_stage);
+
+        // out of threads
+        let pool = WorkerPool::new(WorkerPoolConfig {
+            threads: 1,
+            ..Default::default()
+        });
+        let shared = pool.clone();
+        let _batch = pool.try_reserve(Priority::Batch).unwrap();
+        assert!(shared.try_reserve(Priority::Batch).is_none());
+    }
+
+    #[test]
+    fn test_worker_pool_spawn() {
+        let pool = WorkerPool::new(WorkerPoolConfig {
+            threads: 3,
+            ..Default::default()
+        });
+        let (sender, receiver) = channel();
+        let jobs: Vec<(&str, Job)> = vec![
+            ("0_0", Box::new(|| Ok(()))),
+            ("0_1", Box::new(|| bail!("prove failed"))),
+            ("0_2", Box::new(|| panic!("prove panicked"))),
+        ];
+        for (job_id, (key, job)) in jobs.into_iter().enumerate() {
+            let reservation = pool.try_reserve(Priority::Batch).unwrap();
+            pool.spawn(
+                job_id as u64,
+                key.to_string(),
+                sender.clone(),
+                reservation,
+                job,
+            )
+            .unwrap();
+        }
+
+        let mut results: Vec<JobResult> = receiver.iter().take(3).collect();
+        // the budgets are released before the results are sent
+        assert_eq!(pool.usage(), (0, 0));
+        results.sort_by(|a, b| a.key.cmp(&b.key));
+        assert!(results[0].result.is_ok());
+        assert_eq!(results[1].job_id, 1);
+        assert!(results[1].result.is_err());
+        let err = results[2].result.as_ref().unwrap_err();
+        assert!(err.to_string().contains("panicked"));
+    }
+}
@@ -1,6 +1,53 @@
 use std::env;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 
+use prover::config::{Component, PipelineConfig};
+use prover::curve::Curve;
+use prover::layout::{ProofLayout, PROOF_FILE, PUBLIC_INPUT_FILE};
 use prover::pipeline::Pipeline;
+use prover::task_store::TaskStatus;
+
+fn basedir() -> String {
+    env::var("BASEDIR").unwrap_or("data".to_string())
+}
+
+/// A pipeline of the configuration loaded like the service, in `BASEDIR` or `data`
+fn new_pipeline(task_name: &str) -> anyhow::Result<Pipeline> {
+    let config = PipelineConfig::load(Vec::<String>::new(), Component::Service)?;
+    let config = PipelineConfig {
+        basedir: basedir(),
+        ..config
+    };
+    Pipeline::with_config(
+        &config,
+        env::var("TASK_NAME").unwrap_or(task_name.to_string()),
+    )
+}
+
+/// Prove until the stage `key` is proved, `prove` only starts the stages without waiting for them
+fn prove_until_succeeded(pipeline: &mut Pipeline, key: &str) -> anyhow::Result<()> {
+    loop {
+        pipeline.prove()?;
+        match pipeline.task_store().get(key)?.map(|r| r.status) {
+            Some(TaskStatus::Succeeded) => return Ok(()),
+            Some(TaskStatus::Queued) | Some(TaskStatus::Running) => {
+                thread::sleep(Duration::from_secs(1))
+            }
+            status => anyhow::bail!("task: {} is {:?}", key, status),
+        }
+    }
+}
+
+/// The proof and the public input of the final stage are written
+fn assert_final_proof(final_task_id: &str) {
+    let layout = ProofLayout::new(&basedir());
+    for name in [PROOF_FILE, PUBLIC_INPUT_FILE] {
+        let file = layout.final_file(final_task_id, name);
+        assert!(Path::new(&file).exists(), "{} is not written", file);
+    }
+}
 
 #[test]
 #[ignore = "slow"]
@@ -8,64 +55,71 @@ fn integration_test() -> anyhow::Result<()> {
     env_logger::try_init().unwrap_or_default();
 
     // init pipeline.
-    let mut pipeline = Pipeline::new(
-        env::var("BASEDIR").unwrap_or("data".to_string()),
-        env::var("TASK_NAME").unwrap_or("evm".to_string()),
-    );
+    let mut pipeline = new_pipeline("evm")?;
     let l2_batch_data = std::fs::read_to_string(
         env::var("SUITE_JSON")
             .unwrap_or("../executor/test-vectors/solidityExample.json".to_string()),
     )
     .unwrap();
+    let batch_digest = pipeline.put_batch_data(&l2_batch_data).unwrap();
     let task1 = pipeline
-        .batch_prove("0".into(), "0".into(), l2_batch_data.clone())
+        .batch_prove("0".into(), "0".into(), batch_digest.clone())
         .unwrap();
-    pipeline.prove().unwrap();
+    prove_until_succeeded(&mut pipeline, &task1)?;
     log::info!("task: {task1}");
 
     let task2 = pipeline
-        .batch_prove("0".into(), "1".into(), l2_batch_data.clone())
+        .batch_prove("0".into(), "1".into(), batch_digest.clone())
         .unwrap();
-    pipeline.prove().unwrap();
+    prove_until_succeeded(&mut pipeline, &task2)?;
     log::info!("task2: {task2}");
 
     let task3 = pipeline
-        .batch_prove("0".into(), "2".into(), l2_batch_data)
+        .batch_prove("0".into(), "2".into(), batch_digest)
         .unwrap();
-    pipeline.prove().unwrap();
+    prove_until_succeeded(&mut pipeline, &task3)?;
     log::info!("task3: {task3}");
 
     let task4 = pipeline
         .aggregate_prove("0_chunk_0".to_string(), "0_chunk_2".to_string())
         .unwrap();
-    pipeline.prove().unwrap();
+    prove_until_succeeded(&mut pipeline, &format!("{task4}_agg"))?;
     log::info!("agg task: {task4}");
 
     let task5 = pipeline
         .final_prove(
-            task4,
-            "BN128".into(),
+            task4.clone(),
+            Curve::BN128,
             "273030697313060285579891744179749754319274977764".into(),
         )
         .unwrap();
-    pipeline.prove().unwrap();
+    prove_until_succeeded(&mut pipeline, &format!("{task5}_final"))?;
     log::info!("final task: {task5}");
+    assert_final_proof(&task5);
 
     let task6 = pipeline
         .aggregate_prove("0_chunk_0".to_string(), "0_chunk_2".to_string())
         .unwrap();
-    pipeline.prove().unwrap();
+    // the identical aggregation is proved once
+    assert_eq!(task6, task4);
+    let agg = pipeline.task_store().get(&format!("{task6}_agg"))?.unwrap();
+    assert_eq!(agg.status, TaskStatus::Succeeded);
     log::info!("agg task: {task6}");
 
+    // the final proof of the same aggregation on BLS12381, its setup is cached beside the BN128 one
     let task7 = pipeline
         .final_prove(
             task6,
-            "BN128".into(),
+            Curve::BLS12381,
             "273030697313060285579891744179749754319274977764".into(),
         )
         .unwrap();
-    pipeline.prove().unwrap();
+    prove_until_succeeded(&mut pipeline, &format!("{task7}_final"))?;
     log::info!("final task: {task7}");
+    // both final proofs are kept
+    assert_ne!(task7, task5);
+    assert_final_proof(&task7);
+    assert_final_proof(&task5);
     Ok(())
 }
 
@@ -76,17 +130,21 @@ fn integration_test_lr() -> anyhow::Result<()> {
     env_logger::try_init().unwrap_or_default();
 
     // init pipeline.
-    let mut pipeline = Pipeline::new(
-        env::var("BASEDIR").unwrap_or("data".to_string()),
-        env::var("TASK_NAME").unwrap_or("lr".to_string()),
-    );
+    let mut pipeline = new_pipeline("lr")?;
     let l2_batch_data =
         std::fs::read_to_string(env::var("SUIT_JSON").unwrap_or("data/test.json".to_string()))
             .unwrap();
+    let batch_digest = pipeline.put_batch_data(&l2_batch_data).unwrap();
     let task1 = pipeline
-        .batch_prove("0".into(), "0".into(), l2_batch_data)
+        .batch_prove("0".into(), "0".into(), batch_digest)
         .unwrap();
-    pipeline.prove().unwrap();
+    prove_until_succeeded(&mut pipeline, &task1)?;
     log::info!("task: {task1}");
+    let proof_dir = ProofLayout::new(&basedir()).path(&ProofLayout::batch_path("0", "0"));
+    assert!(
+        Path::new(&proof_dir).exists(),
+        "{} is not written",
+        proof_dir
+    );
     Ok(())
 }
@@ -1,14 +1,27 @@
+use prover::config::{Component, PipelineConfig};
+use prover::scheduler::Capabilities;
 use prover_scheduler::service::batch_prover_service::{
     BatchProverService, BatchProverServiceHandler,
 };
 use std::sync::Arc;
+use sysinfo::System;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::try_init().unwrap_or_default();
 
-    let addr = std::env::var("SCHEDULER_ADDR").unwrap_or("http://127.0.0.1:50051".to_string());
+    let config = PipelineConfig::load_or_exit(Component::BatchProver);
+    log::info!("start batch prover with config: {:?}", config);
     let batch_prover_handler = Arc::new(BatchProverServiceHandler::default());
-    let batch_prover_service = BatchProverService::new(addr, batch_prover_handler);
+    let mut batch_prover_service =
+        BatchProverService::new(config.scheduler_addr, batch_prover_handler);
+    let mut sys = System::new();
+    sys.refresh_memory();
+    batch_prover_service.capabilities =
+        Capabilities::probe(config.task_names, sys.total_memory() / 1024 / 1024);
+    log::info!(
+        "advertise the capabilities: {:?}",
+        batch_prover_service.capabilities
+    );
     batch_prover_service.launch_service().await
 }
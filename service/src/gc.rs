@@ -0,0 +1,25 @@
+use prover::config::{Component, PipelineConfig};
+use prover::retention::collect_garbage;
+use prover::task_store::open_task_store;
+
+/// Prune the artifacts of the proved tasks by the `[retention]` of the config,
+/// `--dry-run` lists them without removing anything.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::try_init().unwrap_or_default();
+
+    let (dry_run, args): (Vec<String>, Vec<String>) =
+        std::env::args().skip(1).partition(|arg| arg == "--dry-run");
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("Usage: gc [--dry-run] [options]\n");
+    }
+    let config = PipelineConfig::load_args_or_exit(args, Component::Gc);
+    let task_store = open_task_store(&config.basedir, &config.task_store)?;
+    let report = collect_garbage(
+        &config.basedir,
+        task_store.as_ref(),
+        &config.retention,
+        !dry_run.is_empty(),
+    )?;
+    println!("{}", report);
+    Ok(())
+}
@@ -4,16 +4,13 @@
 use std::sync::Arc;
 use tonic::transport::Server;
 mod batch_prover_service;
-mod config;
 mod executor_service;
 
 mod prover_service;
-
-#[macro_use]
-extern crate lazy_static;
 use crate::prover_service::prover_service::prover_service_server::ProverServiceServer;
 use crate::prover_service::ProverServiceSVC;
 use executor_service::executor_service::executor_service_server::ExecutorServiceServer;
+use prover::config::{Component, PipelineConfig};
 use prover::scheduler::Scheduler;
 use prover_scheduler::scheduler_server::scheduler_service::scheduler_service_server::SchedulerServiceServer;
 use prover_scheduler::scheduler_server::{SchedulerServerHandler, SchedulerServiceSVC};
@@ -28,13 +25,13 @@ use tokio::{
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
-    let conf_path = std::env::var("CONF_DIR").unwrap_or("conf".to_string());
-    let base_dir = std::env::var("BASEDIR").unwrap_or("/tmp/prover/data".to_string());
-    let executor_base_dir = format!("{}/proof", base_dir);
-
-    let conf_path = std::path::Path::new(&conf_path).join("base_config.toml");
-    let runtime_config = config::RuntimeConfig::from_toml(conf_path).expect("Config is missing");
-    let addr = runtime_config.addr.as_str().parse()?;
+    let config = PipelineConfig::load_or_exit(Component::Service);
+    log::info!("start service with config: {:?}", config);
+    let addr = config.addr.as_str().parse()?;
+    if let Err(e) = prover_service::init(config.clone()) {
+        eprintln!("{:#}", e);
+        std::process::exit(2);
+    }
 
     let executor = executor_service::ExecutorServiceSVC::new();
 
@@ -53,7 +50,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     //   - event_rx: receive the event from the SchedulerServiceSVC
     //   - task_rx: receive the task from the Pipeline
     //   - task_tx: used to retry tasks by itself
-    let mut scheduler = Scheduler::new(result_rx, event_rx, task_rx, task_tx_clone);
+    //   - task_store: shared with the Pipeline, save the results of the batch proofs
+    //   - task_events: shared with the Pipeline, publish the results of the batch proofs
+    let mut scheduler = Scheduler::new(
+        result_rx,
+        event_rx,
+        task_rx,
+        task_tx_clone,
+        prover_service::task_store(),
+        prover_service::task_events(),
+    );
+    scheduler.retry_policy = config.retry.clone();
+    scheduler.deadlines = config.deadlines.clone();
+    scheduler.requirements = config.requirements.clone();
+    // the pipelines send the cancelled task to the scheduler by the cancel_sender
+    prover_service::set_cancel_sender(scheduler.cancel_sender.clone());
+    // the scheduler acknowledges the tasks it takes over by the ack_sender
+    prover_service::set_ack_sender(scheduler.ack_sender.clone());
     tokio::spawn(async move {
         // TODO: quit signal
         scheduler.run().await;
@@ -88,9 +101,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     log::info!("Prover service Listening on {}", addr);
 
     log::info!("BatchProverScheduler service Listening on {}", addr);
-    let prover_request_handler =
-        Arc::new(prover_service::ProverRequestHandler::new(executor_base_dir));
-    let prover_server = ProverServiceSVC::new(prover_request_handler);
+    let prover_request_handler = Arc::new(prover_service::ProverRequestHandler::new(&config));
+    let prover_server = ProverServiceSVC::new(prover_request_handler, &config.url);
 
     // SchedulerServiceSVC holds the event_tx
     // all client will connect to this instance
@@ -1,33 +1,36 @@
-use prover::stage::Stage;
-use std::env::var;
-use std::path::Path;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
+use sysinfo::System;
 
 use crate::prover_service::prover_service::gen_batch_proof_request::Step;
-use crate::prover_service::prover_service::get_status_response::Status::Idle;
+use crate::prover_service::prover_service::get_status_response::Status::{Computing, Idle};
 use crate::prover_service::prover_service::prover_request::RequestType;
 use crate::prover_service::prover_service::prover_response::ResponseType;
 use crate::prover_service::prover_service::{
-    gen_batch_proof_response, FinalProof, GenBatchChunks, GenBatchChunksResult, GenChunkProof,
+    gen_batch_proof_response, CancelTaskRequest, CancelTaskResponse, FinalProof, GenBatchChunks,
+    GenBatchChunksResult, GenBlockProofRequest, GenBlockProofResponse, GenChunkProof,
     GenChunkProofResult,
 };
 use crate::prover_service::prover_service::{
-    get_status_response, BatchProofResult, ChunkProof, GenAggregatedProofRequest,
-    GenAggregatedProofResponse, GenBatchProofResponse, GenFinalProofRequest, GenFinalProofResponse,
-    GetStatusRequest, GetStatusResponse, GetStatusResultCode, ProofResultCode, ProverRequest,
-    ProverResponse, ProverStatus,
+    BatchProofResult, ChunkProof, GenAggregatedProofRequest, GenAggregatedProofResponse,
+    GenBatchProofResponse, GenFinalProofRequest, GenFinalProofResponse, GetStatusRequest,
+    GetStatusResponse, GetStatusResultCode, ProofResultCode, ProverRequest, ProverResponse,
+    ProverStatus,
 };
 use anyhow::{anyhow, bail, Result};
 use ethers_providers::{Http, Middleware, Provider};
 use executor::batch_process;
+use prover::blob_store::BlobStore;
+use prover::config::PipelineConfig;
 use prover::contexts::BatchContext;
-use prover::pipeline::Pipeline;
+use prover::curve::Curve;
+use prover::pipeline_set::PipelineSet;
+use prover::task_queue::QueueFullError;
+use prover::task_store::{TaskEvent, TaskStore};
 use prover_service::prover_service_server::ProverService;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::{mpsc, watch};
-use tokio::time;
+use tokio::sync::{broadcast, mpsc};
 use tokio_stream::{Stream, StreamExt};
 use tonic::{async_trait, Request, Response, Status, Streaming};
 
@@ -36,34 +39,73 @@ pub mod prover_service {
     tonic::include_proto!("prover.v1"); // The string specified here must match the proto package name
 }
 
-const DEFAULT_BATCH_PROOF_POLLING_INTERVAL: Duration = Duration::from_secs(60);
-const DEFAULT_BATCH_PROOF_POLLING_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// The version of prover.v1, see the message Version
+const PROTO_VERSION: &str = "v0.0.1";
 
-const DEFAULT_AGGREGATED_PROOF_POLLING_INTERVAL: Duration = Duration::from_secs(10);
-const DEFAULT_AGGREGATED_PROOF_POLLING_TIMEOUT: Duration = Duration::from_secs(60 * 30);
+const DEFAULT_BATCH_PROOF_TIMEOUT: Duration = Duration::from_secs(60 * 60);
 
-const DEFAULT_FINAL_PROOF_POLLING_INTERVAL: Duration = Duration::from_secs(10);
-const DEFAULT_FINAL_PROOF_POLLING_TIMEOUT: Duration = Duration::from_secs(60 * 30);
+const DEFAULT_AGGREGATED_PROOF_TIMEOUT: Duration = Duration::from_secs(60 * 30);
 
-lazy_static! {
-    static ref BASE_DIR: String = var("BASEDIR").unwrap_or_else(|_| "/tmp/prover/data".to_string());
+const DEFAULT_FINAL_PROOF_TIMEOUT: Duration = Duration::from_secs(60 * 30);
+
+const DEFAULT_BLOCK_PROOF_TIMEOUT: Duration = Duration::from_secs(
+    DEFAULT_BATCH_PROOF_TIMEOUT.as_secs()
+        + DEFAULT_AGGREGATED_PROOF_TIMEOUT.as_secs()
+        + DEFAULT_FINAL_PROOF_TIMEOUT.as_secs(),
+);
+
+// Each pipeline handles one guest program, the requests are routed by the program_name,
+// the task_name of the config is the program of the requests without program_name
+static PIPELINES: OnceLock<Mutex<PipelineSet>> = OnceLock::new();
+
+/// Create the pipelines by the loaded config, it should be called before the service is started
+pub fn init(config: PipelineConfig) -> Result<()> {
+    if PIPELINES.get().is_some() {
+        log::warn!("the prover service is initialized already");
+        return Ok(());
+    }
+    let _ = PIPELINES.set(Mutex::new(PipelineSet::with_config(config)?));
+    Ok(())
 }
 
-// FIXME: Since each pipeline handles one task, we should create a pipeline set to handle different tasks
-lazy_static! {
-    static ref PIPELINE: Mutex<Pipeline> = Mutex::new(Pipeline::new(
-        var("BASEDIR").unwrap_or("/tmp/prover/data".to_string()),
-        var("TASK_NAME").unwrap_or("fibonacci".to_string())
-    ));
-    static ref PROVER_FORK_ID: u64 = {
-        let fork_id = var("PROVER_FORK_ID").unwrap_or("0".into());
-        fork_id.parse().unwrap_or(0)
-    };
+fn pipelines() -> &'static Mutex<PipelineSet> {
+    PIPELINES
+        .get()
+        .expect("the prover service is not initialized")
+}
+
+pub fn task_store() -> Arc<dyn TaskStore> {
+    pipelines().lock().unwrap().task_store()
 }
 
+pub fn task_events() -> broadcast::Sender<TaskEvent> {
+    pipelines().lock().unwrap().task_events()
+}
+
+/// The pipelines notify the scheduler to revoke the cancelled batch tasks
+pub fn set_cancel_sender(cancel_sender: Sender<String>) {
+    pipelines().lock().unwrap().set_cancel_sender(cancel_sender);
+}
+
+/// The scheduler acknowledges the batch tasks sent by the pipelines
+pub fn set_ack_sender(ack_sender: broadcast::Sender<String>) {
+    pipelines().lock().unwrap().set_ack_sender(ack_sender);
+}
+
+/// Prove one task of each pipeline
 pub async fn run_prover(task_sender: Sender<BatchContext>) -> Result<()> {
-    PIPELINE.lock().unwrap().set_task_sender(task_sender);
-    PIPELINE.lock().unwrap().prove()
+    let pipelines = {
+        let mut pipelines = pipelines().lock().unwrap();
+        pipelines.set_task_sender(task_sender);
+        pipelines.pipelines()
+    };
+    for pipeline in pipelines {
+        let mut pipeline = pipeline.lock().unwrap();
+        if let Err(e) = pipeline.prove() {
+            log::warn!("Task error of program {}: {:?}", pipeline.task_name(), e);
+        }
+    }
+    Ok(())
 }
 
 pub struct ProverServiceSVC {
@@ -72,8 +114,7 @@ pub struct ProverServiceSVC {
 }
 
 impl ProverServiceSVC {
-    pub fn new(handler: Arc<dyn ProverHandler + Send + Sync>) -> Self {
-        let url = std::env::var("URL").unwrap_or(String::from("http://localhost:8545"));
+    pub fn new(handler: Arc<dyn ProverHandler + Send + Sync>, url: &str) -> Self {
         let client = Provider::<Http>::try_from(url).unwrap();
         ProverServiceSVC {
             handler,
@@ -157,7 +198,7 @@ impl ProverService for ProverServiceSVC {
                                             response_type: Some(ResponseType::GenBatchProof(
                                                 GenBatchProofResponse {
                                                     step: Some(gen_batch_proof_response::Step::GenChunkProof(GenChunkProofResult{
-                                                        result_code: ProofResultCode::CompletedError as i32,
+                                                        result_code: proof_result_code(&e) as i32,
                                                         error_message: e.to_string(),
                                                         ..Default::default()
                                                     })),
@@ -181,7 +222,7 @@ impl ProverService for ProverServiceSVC {
                                 id: request_id.clone(),
                                 response_type: Some(ResponseType::GenAggregatedProof(
                                     GenAggregatedProofResponse {
-                                        result_code: ProofResultCode::CompletedError as i32,
+                                        result_code: proof_result_code(&e) as i32,
                                         error_message: e.to_string(),
                                         ..Default::default()
                                     },
@@ -194,13 +235,41 @@ impl ProverService for ProverServiceSVC {
                                 id: request_id.clone(),
                                 response_type: Some(ResponseType::GenFinalProof(
                                     GenFinalProofResponse {
-                                        result_code: ProofResultCode::CompletedError as i32,
+                                        result_code: proof_result_code(&e) as i32,
                                         final_proof: None,
                                         error_message: e.to_string(),
                                         ..Default::default()
                                     },
                                 )),
                             }),
+                        RequestType::GenBlockProof(r) => handler_clone
+                            .handle_gen_block_proof_request(
+                                request_id.clone(),
+                                r,
+                                eth_client_clone.clone(),
+                            )
+                            .await
+                            .unwrap_or_else(|e| ProverResponse {
+                                id: request_id.clone(),
+                                response_type: Some(ResponseType::GenBlockProof(
+                                    GenBlockProofResponse {
+                                        result_code: proof_result_code(&e) as i32,
+                                        error_message: e.to_string(),
+                                        ..Default::default()
+                                    },
+                                )),
+                            }),
+                        RequestType::CancelTask(r) => handler_clone
+                            .handle_cancel_task_request(request_id.clone(), r)
+                            .await
+                            .unwrap_or_else(|e| ProverResponse {
+                                id: request_id.clone(),
+                                response_type: Some(ResponseType::CancelTask(CancelTaskResponse {
+                                    result_code: ProofResultCode::CompletedError as i32,
+                                    error_message: e.to_string(),
+                                    ..Default::default()
+                                })),
+                            }),
                     };
 
                     log::info!("send the response to eigen-zeth, response: {:?}", resp);
@@ -218,6 +287,15 @@ impl ProverService for ProverServiceSVC {
     }
 }
 
+/// The full queue is reported separately, so the client can retry later
+fn proof_result_code(e: &anyhow::Error) -> ProofResultCode {
+    if e.is::<QueueFullError>() {
+        ProofResultCode::QueueFull
+    } else {
+        ProofResultCode::CompletedError
+    }
+}
+
 #[async_trait]
 pub trait ProverHandler {
     async fn handle_get_status_request(
@@ -250,11 +328,25 @@ pub trait ProverHandler {
         msg_id: String,
         request: GenFinalProofRequest,
     ) -> Result<ProverResponse>;
+
+    async fn handle_gen_block_proof_request(
+        &self,
+        msg_id: String,
+        request: GenBlockProofRequest,
+        client: Arc<Provider<Http>>,
+    ) -> Result<ProverResponse>;
+
+    async fn handle_cancel_task_request(
+        &self,
+        msg_id: String,
+        request: CancelTaskRequest,
+    ) -> Result<ProverResponse>;
 }
 
 #[derive(Default, Clone)]
 pub struct ProverRequestHandler {
     executor_base_dir: String,
+    fork_id: u64,
 }
 
 pub struct BatchStateRoot {
@@ -263,55 +355,20 @@ pub struct BatchStateRoot {
 }
 
 impl ProverRequestHandler {
-    pub fn new(executor_base_dir: String) -> Self {
-        ProverRequestHandler { executor_base_dir }
-    }
-}
-
-#[async_trait]
-impl ProverHandler for ProverRequestHandler {
-    async fn handle_get_status_request(
-        &self,
-        msg_id: String,
-        _request: GetStatusRequest,
-    ) -> Result<ProverResponse> {
-        let status = match PIPELINE.lock().unwrap().get_status() {
-            Ok(_) => Idle,
-            _ => get_status_response::Status::Unspecified,
-        };
-
-        Ok(ProverResponse {
-            id: msg_id,
-            response_type: Some(ResponseType::GetStatus(GetStatusResponse {
-                id: "".to_string(),
-                result_code: GetStatusResultCode::Ok as i32,
-                status: status.into(),
-                prover_status: Some(ProverStatus {
-                    last_computed_request_id: "".to_string(),
-                    last_computed_end_time: 0,
-                    current_computing_request_id: "".to_string(),
-                    current_computing_start_time: 0,
-                    version_proto: "".to_string(),
-                    version_server: "".to_string(),
-                    pending_request_queue_ids: vec![],
-                    prover_name: "".to_string(),
-                    prover_id: "".to_string(),
-                    number_of_cores: 0,
-                    total_memory: 0,
-                    free_memory: 0,
-                    fork_id: 0,
-                }),
-                error_message: "".to_string(),
-            })),
-        })
+    pub fn new(config: &PipelineConfig) -> Self {
+        ProverRequestHandler {
+            executor_base_dir: config.basedir.clone(),
+            fork_id: config.fork_id,
+        }
     }
 
-    async fn handle_gen_batch_chunks_request(
+    /// Execute the batch, and split it into chunks
+    async fn gen_batch_chunks(
         &self,
-        msg_id: String,
+        msg_id: &str,
         request: GenBatchChunks,
         client: Arc<Provider<Http>>,
-    ) -> Result<ProverResponse> {
+    ) -> Result<GenBatchChunksResult> {
         // parse the block number from the request
         let block_number = match request.batch {
             None => {
@@ -338,7 +395,7 @@ impl ProverHandler for ProverRequestHandler {
         );
 
         // gen chunk
-        let (_res, l2_batch_data, cnt_chunks) = batch_process(
+        let (_res, l2_batch_data, batch_digest, cnt_chunks) = batch_process(
             client.clone(),
             block_number,
             request.chain_id,
@@ -378,21 +435,94 @@ impl ProverHandler for ProverRequestHandler {
         let pre_state_root = <[u8; 32]>::from(pre_state_root);
         let post_state_root = *post_state_hash;
 
+        Ok(GenBatchChunksResult {
+            batch_id: request.batch_id,
+            task_id: execute_task_id,
+            result_code: 0,
+            chunk_count: cnt_chunks as u64,
+            batch_digest,
+            pre_state_root: Vec::from(pre_state_root),
+            post_state_root: Vec::from(post_state_root),
+            error_message: "".to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl ProverHandler for ProverRequestHandler {
+    async fn handle_get_status_request(
+        &self,
+        msg_id: String,
+        _request: GetStatusRequest,
+    ) -> Result<ProverResponse> {
+        // the pipelines are locked while proving, read the status from the PipelineSet
+        let statuses = pipelines().lock().unwrap().get_status();
+        let current = statuses
+            .iter()
+            .filter(|s| s.current_computing_task.is_some())
+            .min_by_key(|s| s.current_computing_start_time);
+        let last = statuses
+            .iter()
+            .filter(|s| s.last_computed_task.is_some())
+            .max_by_key(|s| s.last_computed_end_time);
+        let pending_request_queue_ids = statuses
+            .iter()
+            .flat_map(|s| s.pending_tasks.clone())
+            .collect();
+        let status = if current.is_some() { Computing } else { Idle };
+
+        let number_of_cores = std::thread::available_parallelism()
+            .map(|n| n.get() as u64)
+            .unwrap_or_default();
+        let mut sys = System::new();
+        sys.refresh_memory();
+
+        Ok(ProverResponse {
+            id: msg_id,
+            response_type: Some(ResponseType::GetStatus(GetStatusResponse {
+                id: "".to_string(),
+                result_code: GetStatusResultCode::Ok as i32,
+                status: status.into(),
+                prover_status: Some(ProverStatus {
+                    last_computed_request_id: last
+                        .and_then(|s| s.last_computed_task.clone())
+                        .unwrap_or_default(),
+                    last_computed_end_time: last
+                        .map(|s| s.last_computed_end_time)
+                        .unwrap_or_default(),
+                    current_computing_request_id: current
+                        .and_then(|s| s.current_computing_task.clone())
+                        .unwrap_or_default(),
+                    current_computing_start_time: current
+                        .map(|s| s.current_computing_start_time)
+                        .unwrap_or_default(),
+                    version_proto: PROTO_VERSION.to_string(),
+                    version_server: env!("CARGO_PKG_VERSION").to_string(),
+                    pending_request_queue_ids,
+                    prover_name: "".to_string(),
+                    prover_id: "".to_string(),
+                    number_of_cores,
+                    // in bytes, the free memory is the memory available for the new tasks
+                    total_memory: sys.total_memory(),
+                    free_memory: sys.available_memory(),
+                    fork_id: self.fork_id,
+                }),
+                error_message: "".to_string(),
+            })),
+        })
+    }
+
+    async fn handle_gen_batch_chunks_request(
+        &self,
+        msg_id: String,
+        request: GenBatchChunks,
+        client: Arc<Provider<Http>>,
+    ) -> Result<ProverResponse> {
+        let result = self.gen_batch_chunks(&msg_id, request, client).await?;
         Ok(ProverResponse {
             id: msg_id,
             response_type: Some(ResponseType::GenBatchProof(GenBatchProofResponse {
-                step: Some(gen_batch_proof_response::Step::GenBatchChunks(
-                    GenBatchChunksResult {
-                        batch_id: request.batch_id,
-                        task_id: execute_task_id,
-                        result_code: 0,
-                        chunk_count: cnt_chunks as u64,
-                        batch_data: l2_batch_data,
-                        pre_state_root: Vec::from(pre_state_root),
-                        post_state_root: Vec::from(post_state_root),
-                        error_message: "".to_string(),
-                    },
-                )),
+                step: Some(gen_batch_proof_response::Step::GenBatchChunks(result)),
             })),
         })
     }
@@ -404,139 +534,76 @@ impl ProverHandler for ProverRequestHandler {
     ) -> Result<ProverResponse> {
         let execute_task_id = request.task_id.clone();
         let cnt_chunk = request.chunk_count as usize;
-        let l2_batch_data = request.batch_data;
+        let batch_digest = request.batch_digest;
+        if batch_digest.is_empty() {
+            // the client of the earlier versions sends the raw batch data in the reserved field
+            bail!("missing the digest of the batch data, the client should be upgraded");
+        }
+        if !BlobStore::is_digest(&batch_digest) {
+            bail!("invalid digest of the batch data: {:?}", batch_digest);
+        }
+        let pipeline = pipelines().lock().unwrap().get(&request.program_name);
 
         // gen chunks proof
         // distribute tasks according to the number of chunks
 
         // key:  format!("{}_{}", task_id, chunk_id)
-        // pending task
-        let mut pending_tasks = Vec::<String>::new();
-        for chunk_id in 0..cnt_chunk {
-            pending_tasks.push(format!("{}_{}", execute_task_id, chunk_id))
-        }
-
-        let mut finished_tasks = vec![];
         let mut results = vec![String::new(); cnt_chunk];
-
-        // put the task into the pipeline, skip the finished tasks
-        for (index, key) in pending_tasks.iter().enumerate() {
-            // let proof_result = PIPELINE.lock().unwrap().get_proof(key.clone(), 0);
-            let tmp_stage = Stage::Batch(
-                execute_task_id.clone(),
-                index.to_string(),
-                l2_batch_data.clone(),
-            );
-            let task_result_dir = Path::new(&*BASE_DIR)
-                .join(tmp_stage.path())
-                .join("status.finished");
-            log::info!("check the task status: {}", task_result_dir.display());
-            let status = match std::fs::read_to_string(task_result_dir) {
-                Ok(flag) => {
-                    log::info!("task({}) status: {}", key, flag);
-                    match flag.trim() {
-                        "1" => true,
-                        "0" => false,
-                        _ => false,
-                    }
-                }
-                Err(e) => {
-                    log::error!(
-                        "Failed to read task status, gen proof again, err: {:?}, ",
-                        e
-                    );
-                    false
-                }
-            };
-
-            match status {
-                true => {
-                    // already finished, skip the task
-                    log::info!("task: {:?}, index: {}, already finished, skip", key, index);
-                    finished_tasks.push(index);
-                    results.insert(index, key.clone());
-                }
-                false => {
-                    // not finished, put the task to the pipeline
-                    log::info!("task: {:?} not finished, put the task to pipeline", key);
-                    match PIPELINE.lock().unwrap().batch_prove(
-                        execute_task_id.to_string(),
-                        index.to_string(),
-                        l2_batch_data.clone(),
-                    ) {
-                        Ok(_) => continue,
-                        Err(err) => {
-                            bail!("Failed to generate batch proof: {:?}", err);
-                        }
-                    };
-                }
+        let mut pending_proofs = vec![];
+
+        // skip the finished tasks
+        let mut unfinished = vec![];
+        for (chunk_id, result) in results.iter_mut().enumerate() {
+            let key = format!("{}_{}", execute_task_id, chunk_id);
+            let proof = pipeline.lock().unwrap().get_proof(key.clone(), 0);
+            if proof.await.is_ok() {
+                // already finished, skip the task
+                log::info!("task: {:?} already finished, skip", key);
+                *result = key;
+            } else {
+                unfinished.push((chunk_id, key));
             }
         }
 
-        // remove finished tasks from pending tasks
-        for &index in finished_tasks.iter().rev() {
-            pending_tasks.remove(index);
+        // not finished, put the tasks to the pipeline, all or none of them are queued
+        {
+            let mut pipeline = pipeline.lock().unwrap();
+            pipeline.check_capacity(unfinished.len())?;
+            for (chunk_id, key) in unfinished {
+                log::info!("task: {:?} not finished, put the task to pipeline", key);
+                if let Err(err) = pipeline.batch_prove(
+                    execute_task_id.to_string(),
+                    chunk_id.to_string(),
+                    batch_digest.clone(),
+                ) {
+                    bail!("Failed to generate batch proof: {:?}", err);
+                }
+                let proof = pipeline.get_proof(key, DEFAULT_BATCH_PROOF_TIMEOUT.as_secs());
+                pending_proofs.push((chunk_id, proof));
+            }
         }
-        finished_tasks.clear();
-        log::info!("pending tasks: {:?}", pending_tasks);
-        log::info!("load results of the task list: {:?}", results);
-
-        // waiting for the proof result
-        let mut polling_ticker = time::interval(DEFAULT_BATCH_PROOF_POLLING_INTERVAL);
-        let timeout_start = time::Instant::now() + DEFAULT_BATCH_PROOF_POLLING_TIMEOUT;
-        let mut timeout_ticker =
-            time::interval_at(timeout_start, DEFAULT_BATCH_PROOF_POLLING_TIMEOUT);
-        let (finish_tx, mut finish_rx) = watch::channel::<()>(());
 
+        // waiting for the proof result, all the proofs share the same deadline
         log::info!(
-            "polling the batch proof of task id: {:?}, request id {:?}",
+            "waiting for the batch proof of task id: {:?}, pending chunks: {}, request id {:?}",
             execute_task_id,
+            pending_proofs.len(),
             msg_id
         );
-        loop {
-            tokio::select! {
-                _ = polling_ticker.tick() => {
-                    // get proof result
-                    for (index, key) in pending_tasks.iter().enumerate() {
-                        let proof_result = PIPELINE.lock().unwrap().get_proof(key.clone(), 0);
-                        match proof_result {
-                            Ok(task_key) => {
-                                // do nothing
-                                finished_tasks.push(index);
-                                results.insert(index, task_key);
-                                log::info!("task: {:?} is finished", key);
-                            }
-                            Err(e) => {
-                                log::info!("task: {:?} is not finished, check again later, check result: {:#?}", key, e);
-
-                                // false, continue
-                                continue;
-                                // TODO: other error, stop and return error
-                            }
-                        }
-                    }
-
-                    // remove finished tasks from pending tasks
-                    for &index in finished_tasks.iter().rev() {
-                        if index < pending_tasks.len() {
-                            pending_tasks.remove(index);
-                        }
-                    }
-                    finished_tasks.clear();
-                    if pending_tasks.is_empty() {
-                        // finished
-                        log::info!("all tasks are finished");
-                        finish_tx.send(()).unwrap();
-                        continue;
-                    }
-                    log::info!("end of polling, try again later, pending tasks: {:?}", pending_tasks);
+        for (chunk_id, proof) in pending_proofs {
+            match proof.await {
+                Ok(key) => {
+                    log::info!("task: {:?} is finished", key);
+                    results[chunk_id] = key;
                 }
-                _ = finish_rx.changed() => {
-                    break;
-                }
-                _ = timeout_ticker.tick() => {
-                    log::info!("generate the proof timeout, task id: {:?}, request id {:?}", execute_task_id, msg_id);
-                    bail!("generate batch proof timeout");
+                Err(e) => {
+                    log::info!(
+                        "generate the proof failed, task id: {:?}, request id {:?}, err: {:?}",
+                        execute_task_id,
+                        msg_id,
+                        e
+                    );
+                    bail!("Failed to generate batch proof: {:?}", e.to_string());
                 }
             }
         }
@@ -584,11 +651,13 @@ impl ProverHandler for ProverRequestHandler {
         request: GenAggregatedProofRequest,
     ) -> Result<ProverResponse> {
         // put the task into the pipeline
-        let task_id = match PIPELINE.lock().unwrap().aggregate_prove(
+        let pipeline = pipelines().lock().unwrap().get(&request.program_name);
+        let task_id = match pipeline.lock().unwrap().aggregate_prove(
             request.recursive_proof_1.clone(),
             request.recursive_proof_2.clone(),
         ) {
             Ok(id) => id,
+            Err(e) if e.is::<QueueFullError>() => return Err(e),
             Err(e) => bail!("Failed to generate aggregated proof: {:?}", e.to_string()),
         };
 
@@ -599,40 +668,25 @@ impl ProverHandler for ProverRequestHandler {
         );
 
         // waiting for the proof result
-        let mut polling_ticker = time::interval(DEFAULT_AGGREGATED_PROOF_POLLING_INTERVAL);
-        let timeout_start = time::Instant::now() + DEFAULT_AGGREGATED_PROOF_POLLING_TIMEOUT;
-        let mut timeout_ticker =
-            time::interval_at(timeout_start, DEFAULT_AGGREGATED_PROOF_POLLING_TIMEOUT);
-
         log::info!(
-            "polling the agg proof of agg_task: {:?}, request id {:?}",
+            "waiting for the agg proof of agg_task: {:?}, request id {:?}",
             task_id,
             msg_id
         );
 
         let checkpoint_key = format!("{}_agg", task_id.clone());
-        // let result_key: String;
-        loop {
-            tokio::select! {
-                _ = polling_ticker.tick() => {
-                    let proof_result = PIPELINE.lock().unwrap().get_proof(checkpoint_key.clone(), 0);
-                    match proof_result {
-                        Ok(_) => {
-                            // result_key = task_key;
-                            break;
-                        }
-                        Err(_) => {
-                                // false, continue
-                                continue;
-                                // TODO: other error, stop and return error
-                        }
-                    }
-                }
-                _ = timeout_ticker.tick() => {
-                    log::info!("generate agg proof timeout, task_id: {:?}, request id {:?}", task_id, msg_id);
-                    bail!("generate aggregated proof timeout");
-                }
-            }
+        let proof = pipeline.lock().unwrap().get_proof(
+            checkpoint_key.clone(),
+            DEFAULT_AGGREGATED_PROOF_TIMEOUT.as_secs(),
+        );
+        if let Err(e) = proof.await {
+            log::info!(
+                "generate agg proof failed, task_id: {:?}, request id {:?}, err: {:?}",
+                task_id,
+                msg_id,
+                e
+            );
+            bail!("Failed to generate aggregated proof: {:?}", e.to_string());
         }
 
         Ok(ProverResponse {
@@ -653,12 +707,15 @@ impl ProverHandler for ProverRequestHandler {
         msg_id: String,
         request: GenFinalProofRequest,
     ) -> Result<ProverResponse> {
-        let task_id = match PIPELINE.lock().unwrap().final_prove(
+        let curve = Curve::parse_snark(&request.curve_name)?;
+        let pipeline = pipelines().lock().unwrap().get(&request.program_name);
+        let task_id = match pipeline.lock().unwrap().final_prove(
             request.recursive_proof.clone(),
-            request.curve_name.clone(),
+            curve,
             request.aggregator_addr.clone(),
         ) {
             Ok(id) => id,
+            Err(e) if e.is::<QueueFullError>() => return Err(e),
             Err(e) => bail!("Failed to generate final proof: {:?}", e.to_string()),
         };
 
@@ -669,42 +726,23 @@ impl ProverHandler for ProverRequestHandler {
         );
 
         // waiting for the proof result
-        let mut polling_ticker = time::interval(DEFAULT_FINAL_PROOF_POLLING_INTERVAL);
-        let timeout_start = time::Instant::now() + DEFAULT_FINAL_PROOF_POLLING_TIMEOUT;
-        let mut timeout_ticker =
-            time::interval_at(timeout_start, DEFAULT_FINAL_PROOF_POLLING_TIMEOUT);
-
         log::info!(
-            "polling the final proof of agg_task: {:?}, request id {:?}",
-            task_id,
+            "waiting for the final proof of agg_task: {:?}, request id {:?}",
+            request.recursive_proof,
             msg_id
         );
 
         let checkpoint_key = format!("{}_final", task_id.clone());
-
-        loop {
-            tokio::select! {
-                _ = polling_ticker.tick() => {
-                    let proof_result = PIPELINE.lock().unwrap().get_proof(checkpoint_key.clone(), 0);
-                    match proof_result {
-                        Ok(_task_key) => {
-                            log::info!("finished the final stage!");
-                            break;
-                        }
-                        Err(_) => {
-                                // false, continue
-                                continue;
-                                // TODO: other error, stop and return error
-                        }
-                    }
-                }
-                _ = timeout_ticker.tick() => {
-                    bail!("generate final proof timeout");
-                }
-            }
+        let proof = pipeline.lock().unwrap().get_proof(
+            checkpoint_key.clone(),
+            DEFAULT_FINAL_PROOF_TIMEOUT.as_secs(),
+        );
+        if let Err(e) = proof.await {
+            bail!("Failed to generate final proof: {:?}", e.to_string());
         }
+        log::info!("finished the final stage!");
 
-        let (proof, public_input) = PIPELINE
+        let (proof, public_input) = pipeline
             .lock()
             .unwrap()
             .load_final_proof_and_input(&checkpoint_key)?;
@@ -723,4 +761,98 @@ impl ProverHandler for ProverRequestHandler {
             })),
         })
     }
+
+    async fn handle_gen_block_proof_request(
+        &self,
+        msg_id: String,
+        request: GenBlockProofRequest,
+        client: Arc<Provider<Http>>,
+    ) -> Result<ProverResponse> {
+        // reject the unsupported curve before the batch is executed
+        let curve = Curve::parse_snark(&request.curve_name)?;
+        let chunks = GenBatchChunks {
+            batch_id: request.batch_id.clone(),
+            batch: request.batch,
+            chain_id: request.chain_id,
+            program_name: request.program_name.clone(),
+        };
+        let chunks = self.gen_batch_chunks(&msg_id, chunks, client).await?;
+        let task_id = chunks.task_id.clone();
+
+        // the aggregated proof and the final proof are started by the pipeline
+        let pipeline = pipelines().lock().unwrap().get(&request.program_name);
+        let proof = {
+            let mut pipeline = pipeline.lock().unwrap();
+            pipeline.prove_block(
+                task_id.clone(),
+                chunks.chunk_count as usize,
+                chunks.batch_digest,
+                curve,
+                request.aggregator_addr,
+            )?;
+            pipeline.get_block_proof(task_id.clone(), DEFAULT_BLOCK_PROOF_TIMEOUT.as_secs())
+        };
+
+        log::info!(
+            "waiting for the block proof of task id: {:?}, chunks: {}, request id {:?}",
+            task_id,
+            chunks.chunk_count,
+            msg_id
+        );
+        let final_key = match proof.await {
+            Ok(key) => key,
+            Err(e) if e.is::<QueueFullError>() => return Err(e),
+            Err(e) => bail!("Failed to generate block proof: {:?}", e.to_string()),
+        };
+        let (proof, public_input) = pipeline
+            .lock()
+            .unwrap()
+            .load_final_proof_and_input(&final_key)?;
+        log::info!("finished the block proof of task id: {:?}", task_id);
+
+        Ok(ProverResponse {
+            id: msg_id,
+            response_type: Some(ResponseType::GenBlockProof(GenBlockProofResponse {
+                batch_id: request.batch_id,
+                task_id,
+                result_code: ProofResultCode::CompletedOk as i32,
+                final_proof: Some(FinalProof {
+                    proof,
+                    public_input,
+                }),
+                pre_state_root: chunks.pre_state_root,
+                post_state_root: chunks.post_state_root,
+                error_message: "".to_string(),
+            })),
+        })
+    }
+
+    async fn handle_cancel_task_request(
+        &self,
+        msg_id: String,
+        request: CancelTaskRequest,
+    ) -> Result<ProverResponse> {
+        let pipeline = pipelines().lock().unwrap().get(&request.program_name);
+        let cancelled_keys = match pipeline.lock().unwrap().cancel(request.task_id.clone()) {
+            Ok(keys) => keys,
+            Err(e) => bail!("Failed to cancel task: {:?}", e.to_string()),
+        };
+
+        log::info!(
+            "cancel task: {:?}, cancelled: {:?}, request id {:?}",
+            request.task_id,
+            cancelled_keys,
+            msg_id
+        );
+
+        Ok(ProverResponse {
+            id: msg_id,
+            response_type: Some(ResponseType::CancelTask(CancelTaskResponse {
+                task_id: request.task_id,
+                result_code: ProofResultCode::CompletedOk as i32,
+                cancelled_keys,
+                error_message: "".to_string(),
+            })),
+        })
+    }
 }